@@ -0,0 +1,482 @@
+use crate::{
+    game_client::{GameClient, StartOrContinueResponse},
+    save_file::{SaveFile, UndoEntry},
+};
+use anyhow::Result as AnyResult;
+use libscsudoku::{
+    generators::from_file::libsudoku::generators::load_puzzle,
+    models::{
+        board::libsudoku::models::{Board, BLOCK_WIDTH, BOARD_SIZE, BOARD_WIDTH},
+        rules::libsudoku::models::GameRules,
+    },
+};
+use std::{
+    collections::BTreeSet,
+    io::{self, BufRead, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+const HELP: &str = r#"Commands (rows and columns are 1..9):
+  set <row> <col> <digit>   place a digit
+  clear <row> <col>         erase a digit
+  note <row> <col> <digit>  toggle a pencil mark
+  undo                      undo last set/clear/note
+  check                     validate current entries (duplicates)
+  hint                      ask for a hint
+  submit                    submit current board
+  new [difficulty]          start a new game (1=easy .. 4=hard)
+  save <path>               save the game
+  load <path>               load a saved game
+  import <path>             start a game from a puzzle text file
+  help                      this text
+  quit                      exit"#;
+
+pub struct App<TClient: GameClient> {
+    client: TClient,
+    session_token: String,
+    givens: Board, // cells that came from the generator, cannot be altered by the player
+    board: Board,  // givens + player's entries
+    pencil_marks: Vec<BTreeSet<u8>>, // one set per cell
+    undo_history: Vec<UndoEntry>,
+    elapsed_before: Duration, // time played before this session resumed (i.e. from a save file)
+    resumed_at: Instant,
+    is_finished: bool,
+}
+
+impl<TClient: GameClient> App<TClient> {
+    fn from_response(client: TClient, response: StartOrContinueResponse) -> Self {
+        App {
+            client,
+            session_token: response.session_token,
+            givens: response.board,
+            board: response.board,
+            pencil_marks: vec![BTreeSet::new(); BOARD_SIZE],
+            undo_history: Vec::new(),
+            elapsed_before: Duration::from_secs(0),
+            resumed_at: Instant::now(),
+            is_finished: false,
+        }
+    }
+
+    pub fn new(mut client: TClient, difficulty: i32) -> AnyResult<Self> {
+        let response = client.start_or_continue(difficulty, None)?;
+        Ok(Self::from_response(client, response))
+    }
+
+    pub fn from_puzzle_file(mut client: TClient, path: &Path, difficulty: i32) -> AnyResult<Self> {
+        let givens = load_puzzle(path)?;
+        let response = client.start_with_puzzle(&givens, GameRules::new(difficulty), None)?;
+        Ok(Self::from_response(client, response))
+    }
+
+    pub fn from_save_file(mut client: TClient, path: &Path) -> AnyResult<Self> {
+        let save_file = SaveFile::load(path)?;
+        let response = client.start_with_puzzle(
+            &save_file.givens()?,
+            GameRules::from(&save_file.rules),
+            Some(save_file.session_token.clone()),
+        )?;
+        let mut app = Self::from_response(client, response);
+        app.restore(&save_file)?;
+        Ok(app)
+    }
+
+    fn reset(&mut self, response: StartOrContinueResponse) {
+        self.session_token = response.session_token;
+        self.givens = response.board;
+        self.board = response.board;
+        self.pencil_marks = vec![BTreeSet::new(); BOARD_SIZE];
+        self.undo_history.clear();
+        self.elapsed_before = Duration::from_secs(0);
+        self.resumed_at = Instant::now();
+        self.is_finished = false;
+    }
+
+    // hints and wrong submits are counted by the game (see GameClient::rules()), not here, so that
+    // what is shown and saved is always what the game goes by
+    fn rules(&self) -> AnyResult<GameRules> {
+        match self.client.rules() {
+            Some(rules) => Ok(rules.clone()),
+            None => anyhow::bail!("No game in progress"),
+        }
+    }
+
+    fn new_game(&mut self, difficulty: i32) -> AnyResult<()> {
+        let response = self.client.start_or_continue(difficulty, None)?;
+        self.reset(response);
+        Ok(())
+    }
+
+    fn import(&mut self, path: &Path) -> AnyResult<()> {
+        let givens = load_puzzle(path)?;
+        let difficulty = self.rules()?.difficulty;
+        let response = self
+            .client
+            .start_with_puzzle(&givens, GameRules::new(difficulty), None)?;
+        self.reset(response);
+        Ok(())
+    }
+
+    fn load(&mut self, path: &Path) -> AnyResult<()> {
+        let save_file = SaveFile::load(path)?;
+        let response = self.client.start_with_puzzle(
+            &save_file.givens()?,
+            GameRules::from(&save_file.rules),
+            Some(save_file.session_token.clone()),
+        )?;
+        self.reset(response);
+        self.restore(&save_file)
+    }
+
+    // apply the player's part of the save file on top of the (already reset) givens
+    fn restore(&mut self, save_file: &SaveFile) -> AnyResult<()> {
+        for (index, entry) in save_file.entries()?.cells().iter().enumerate() {
+            if entry.is_some() {
+                self.board
+                    .set(index, *entry)
+                    .map_err(|e| anyhow::anyhow!(e))?;
+            }
+        }
+        self.pencil_marks = save_file
+            .pencil_marks
+            .iter()
+            .map(|digits| digits.iter().copied().collect())
+            .collect();
+        self.undo_history = save_file.undo_history.clone();
+        self.elapsed_before = Duration::from_secs(save_file.elapsed_secs);
+        self.resumed_at = Instant::now();
+        Ok(())
+    }
+
+    fn save(&self, path: &Path) -> AnyResult<()> {
+        // entries are whatever is on the board that is not a given
+        let mut entries = Board::new();
+        for (index, (given, cell)) in self
+            .givens
+            .cells()
+            .iter()
+            .zip(self.board.cells().iter())
+            .enumerate()
+        {
+            if given.is_none() {
+                entries.set(index, *cell).map_err(|e| anyhow::anyhow!(e))?;
+            }
+        }
+        SaveFile::new(
+            &self.session_token,
+            &self.givens,
+            &entries,
+            self.pencil_marks
+                .iter()
+                .map(|digits| digits.iter().copied().collect())
+                .collect(),
+            self.undo_history.clone(),
+            self.elapsed().as_secs(),
+            &self.rules()?,
+        )
+        .save(path)
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed_before + self.resumed_at.elapsed()
+    }
+
+    pub fn render(&self) -> AnyResult<String> {
+        let elapsed = self.elapsed().as_secs();
+        let rules = self.rules()?;
+        let mut ret = format!(
+            "Session: {} (time={:02}:{:02}, difficulty={}, hints={}, wrong submits={})\n",
+            self.session_token,
+            elapsed / 60,
+            elapsed % 60,
+            rules.difficulty,
+            rules.hints_offered_so_far,
+            rules.wrong_submits_so_far
+        );
+        ret.push_str("    1 2 3   4 5 6   7 8 9\n");
+        for row in 0..BOARD_WIDTH {
+            if row > 0 && row % BLOCK_WIDTH == 0 {
+                ret.push_str("   -------+-------+-------\n");
+            }
+            ret.push_str(&format!(" {} ", row + 1));
+            for col in 0..BOARD_WIDTH {
+                if col > 0 && col % BLOCK_WIDTH == 0 {
+                    ret.push_str(" |");
+                }
+                match self.board.get_at(row, col) {
+                    Some(v) => ret.push_str(&format!(" {}", v)),
+                    None => ret.push_str(" ."),
+                }
+            }
+            ret.push('\n');
+        }
+        let marks: Vec<String> = self
+            .pencil_marks
+            .iter()
+            .enumerate()
+            .filter(|(index, digits)| !digits.is_empty() && self.board.get(*index).is_none())
+            .map(|(index, digits)| {
+                let (row, col) = Board::to_row_col(index);
+                let digits: Vec<String> = digits.iter().map(|d| d.to_string()).collect();
+                format!("({}, {})={}", row + 1, col + 1, digits.join(","))
+            })
+            .collect();
+        if !marks.is_empty() {
+            ret.push_str(&format!("Pencil marks: {}\n", marks.join(" ")));
+        }
+        Ok(ret)
+    }
+
+    // parses 1-based "<row> <col>" into 0-based (row, col)
+    fn parse_row_col(args: &[&str]) -> AnyResult<(usize, usize)> {
+        match args {
+            [row, col, ..] => {
+                let row: usize = row.parse()?;
+                let col: usize = col.parse()?;
+                if !(1..=BOARD_WIDTH).contains(&row) || !(1..=BOARD_WIDTH).contains(&col) {
+                    anyhow::bail!("Row and column must be in range 1..9");
+                }
+                Ok((row - 1, col - 1))
+            }
+            _ => anyhow::bail!("Expected <row> <col>"),
+        }
+    }
+
+    fn set_cell(&mut self, row: usize, col: usize, value: Option<u8>) -> AnyResult<()> {
+        if self.givens.get_at(row, col).is_some() {
+            anyhow::bail!(
+                "Cell ({}, {}) is a given and cannot be changed",
+                row + 1,
+                col + 1
+            );
+        }
+        let before = self.board.get_at(row, col);
+        self.board
+            .set_at(row, col, value)
+            .map_err(|e| anyhow::anyhow!(e))?;
+        self.undo_history.push(UndoEntry::Entry {
+            index: Board::to_index(row, col),
+            before,
+            after: value,
+        });
+        Ok(())
+    }
+
+    fn toggle_pencil_mark(&mut self, index: usize, digit: u8) -> AnyResult<()> {
+        if !(1..=9).contains(&digit) {
+            anyhow::bail!("Pencil mark must be in range 1..9");
+        }
+        let digits = &mut self.pencil_marks[index];
+        if !digits.remove(&digit) {
+            digits.insert(digit);
+        }
+        Ok(())
+    }
+
+    fn undo(&mut self) -> AnyResult<()> {
+        match self.undo_history.pop() {
+            Some(UndoEntry::Entry { index, before, .. }) => self
+                .board
+                .set(index, before)
+                .map_err(|e| anyhow::anyhow!(e)),
+            Some(UndoEntry::PencilMark { index, digit }) => self.toggle_pencil_mark(index, digit),
+            None => anyhow::bail!("Nothing to undo"),
+        }
+    }
+
+    // returns a message to display to the player, or Ok(None) to quit
+    pub fn handle_command(&mut self, line: &str) -> AnyResult<Option<String>> {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match tokens.as_slice() {
+            [] => Ok(Some(String::new())),
+            ["quit"] | ["q"] | ["exit"] => Ok(None),
+            ["help"] | ["?"] => Ok(Some(HELP.to_string())),
+            ["set", args @ ..] => {
+                let (row, col) = Self::parse_row_col(args)?;
+                let digit: u8 = match args.get(2) {
+                    Some(d) => d.parse()?,
+                    None => anyhow::bail!("Expected set <row> <col> <digit>"),
+                };
+                self.set_cell(row, col, Some(digit))?;
+                Ok(Some(self.render()?))
+            }
+            ["clear", args @ ..] => {
+                let (row, col) = Self::parse_row_col(args)?;
+                self.set_cell(row, col, None)?;
+                Ok(Some(self.render()?))
+            }
+            ["note", args @ ..] => {
+                let (row, col) = Self::parse_row_col(args)?;
+                let digit: u8 = match args.get(2) {
+                    Some(d) => d.parse()?,
+                    None => anyhow::bail!("Expected note <row> <col> <digit>"),
+                };
+                let index = Board::to_index(row, col);
+                self.toggle_pencil_mark(index, digit)?;
+                self.undo_history
+                    .push(UndoEntry::PencilMark { index, digit });
+                Ok(Some(self.render()?))
+            }
+            ["undo"] => {
+                self.undo()?;
+                Ok(Some(self.render()?))
+            }
+            ["check"] => {
+                let validation = self.client.validate(&self.board)?;
+                match validation.is_valid() {
+                    true => Ok(Some("No duplicates found".to_string())),
+                    false => Ok(Some(format!(
+                        "Duplicates at: {}",
+                        validation
+                            .conflicting_indices
+                            .iter()
+                            .map(|index| {
+                                let (row, col) = Board::to_row_col(*index);
+                                format!("({}, {})", row + 1, col + 1)
+                            })
+                            .collect::<Vec<String>>()
+                            .join(" ")
+                    ))),
+                }
+            }
+            ["hint"] => match self.client.get_hint(&self.board)? {
+                Some((index, digit)) => {
+                    let (row, col) = Board::to_row_col(index);
+                    Ok(Some(format!("Try {} at ({}, {})", digit, row + 1, col + 1)))
+                }
+                None => Ok(Some(
+                    "No hint available (is there a wrong entry?)".to_string(),
+                )),
+            },
+            ["submit"] => {
+                let response = self.client.submit(&self.board)?;
+                if !response.is_correct {
+                    return Ok(Some("Not quite, something is wrong".to_string()));
+                }
+                self.is_finished = response.possible_is_finished.unwrap_or(false);
+                match self.is_finished {
+                    true => Ok(Some("Solved! type 'new' to play again".to_string())),
+                    false => Ok(Some("So far so good".to_string())),
+                }
+            }
+            ["new"] => {
+                self.new_game(self.rules()?.difficulty)?;
+                Ok(Some(self.render()?))
+            }
+            ["new", difficulty] => {
+                self.new_game(difficulty.parse()?)?;
+                Ok(Some(self.render()?))
+            }
+            ["save", path] => {
+                self.save(Path::new(path))?;
+                Ok(Some(format!("Saved to {}", path)))
+            }
+            ["load", path] => {
+                self.load(Path::new(path))?;
+                Ok(Some(self.render()?))
+            }
+            ["import", path] => {
+                self.import(Path::new(path))?;
+                Ok(Some(self.render()?))
+            }
+            _ => anyhow::bail!("Unknown command '{}', type 'help'", line.trim()),
+        }
+    }
+
+    // simple read-eval-print loop on stdin/stdout
+    pub fn run(&mut self) -> AnyResult<()> {
+        println!("{}\n{}", self.render()?, HELP);
+        let stdin = io::stdin();
+        loop {
+            print!("> ");
+            io::stdout().flush()?;
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                return Ok(()); // EOF
+            }
+            match self.handle_command(&line) {
+                Ok(Some(message)) => println!("{}", message),
+                Ok(None) => return Ok(()),
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_client::offline::OfflineGameClient;
+    use libscsudoku::generators::from_file::libsudoku::generators::parse_puzzle;
+
+    const PUZZLE: &str =
+        "53..7....6..195....98....6.8...6...34..8.3..17...2...6.6....28....419..5....8..79";
+
+    fn make_app(mut client: OfflineGameClient) -> App<OfflineGameClient> {
+        let givens = parse_puzzle(PUZZLE).unwrap();
+        let response = client
+            .start_with_puzzle(&givens, GameRules::new(2), None)
+            .unwrap();
+        App::from_response(client, response)
+    }
+
+    fn save_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("sudoku_app_{}_{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn hint_and_wrong_submit_are_counted_once() {
+        let mut app = make_app(OfflineGameClient::new());
+        app.handle_command("hint").unwrap();
+        // 5 is already on the first row
+        app.handle_command("set 1 3 5").unwrap();
+        assert_eq!(
+            app.handle_command("submit").unwrap().unwrap(),
+            "Not quite, something is wrong"
+        );
+
+        let rules = app.rules().unwrap();
+        assert_eq!(rules.hints_offered_so_far, 1);
+        assert_eq!(rules.wrong_submits_so_far, 1);
+        assert!(app
+            .render()
+            .unwrap()
+            .contains("difficulty=2, hints=1, wrong submits=1"));
+    }
+
+    #[test]
+    fn counters_carry_over_save_and_load() {
+        let path = save_path("counters");
+        let mut app = make_app(OfflineGameClient::new());
+        app.handle_command("hint").unwrap();
+        app.handle_command("set 1 3 5").unwrap();
+        app.handle_command("submit").unwrap();
+        app.handle_command(&format!("save {}", path.display()))
+            .unwrap();
+
+        let mut loaded = App::from_save_file(OfflineGameClient::new(), &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.rules().unwrap(), app.rules().unwrap());
+        // and keep counting from there
+        loaded.handle_command("clear 1 3").unwrap();
+        loaded.handle_command("hint").unwrap();
+        assert_eq!(loaded.rules().unwrap().hints_offered_so_far, 2);
+        assert_eq!(loaded.rules().unwrap().wrong_submits_so_far, 1);
+    }
+
+    #[test]
+    fn new_game_after_load_gets_its_own_session_token() {
+        let path = save_path("session_token");
+        let app = make_app(OfflineGameClient::new());
+        assert_eq!(app.session_token, "offline-1");
+        app.save(&path).unwrap();
+
+        let mut loaded = App::from_save_file(OfflineGameClient::new(), &path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.session_token, "offline-1");
+        loaded.handle_command("new").unwrap();
+        assert_eq!(loaded.session_token, "offline-2");
+        assert_eq!(loaded.rules().unwrap(), GameRules::new(2));
+    }
+}
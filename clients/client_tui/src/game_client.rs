@@ -0,0 +1,52 @@
+//#include
+pub mod offline;
+
+use anyhow::Result as AnyResult;
+use libscsudoku::{
+    models::{board::libsudoku::models::Board, rules::libsudoku::models::GameRules},
+    solvers::validator::libsudoku::solvers::Validation,
+};
+
+// Client-side view of game.proto `StartOrContinueResponse`
+#[derive(Clone, Debug)]
+pub struct StartOrContinueResponse {
+    pub board: Board,
+    pub session_token: String,
+    pub heartbeat_ttl: i32, // in seconds, 0 means no heartbeat required
+    pub is_new_game: bool,
+    pub rules: GameRules,
+}
+
+// Client-side view of game.proto `SubmitResponse`
+#[derive(Clone, Debug)]
+pub struct SubmitResponse {
+    pub board: Board,
+    pub is_correct: bool,
+    pub possible_is_finished: Option<bool>,
+}
+
+// Everything the TUI needs from the game (SCSudokuGame) and resolver (SCSudokuResolver) services.
+// The TUI only talks to this trait, so whether the calls go over gRPC to the docker-compose
+// cluster or are answered locally by libs (see offline::OfflineGameClient) is transparent to it.
+pub trait GameClient {
+    fn start_or_continue(
+        &mut self,
+        difficulty: i32,
+        possible_last_session_token: Option<String>,
+    ) -> AnyResult<StartOrContinueResponse>;
+    // Start (or resume) a game on puzzle the player brought along, either imported from a text file
+    // or from a save file, in which case the rules counters are carried over as well
+    fn start_with_puzzle(
+        &mut self,
+        givens: &Board,
+        rules: GameRules,
+        possible_last_session_token: Option<String>,
+    ) -> AnyResult<StartOrContinueResponse>;
+    fn submit(&mut self, board: &Board) -> AnyResult<SubmitResponse>;
+    fn validate(&mut self, board: &Board) -> AnyResult<Validation>;
+    // (index, digit) of the suggested cell, None if there is nothing (left) to hint
+    fn get_hint(&mut self, board: &Board) -> AnyResult<Option<(usize, u8)>>;
+    // Rules of the game in progress, None until one is started; the counters (hints offered, wrong
+    // submits) are kept by the game as it answers get_hint() and submit(), the TUI only reads them
+    fn rules(&self) -> Option<&GameRules>;
+}
@@ -0,0 +1,164 @@
+use super::{GameClient, StartOrContinueResponse, SubmitResponse};
+use anyhow::Result as AnyResult;
+use libscsudoku::{
+    generators::random::libsudoku::generators,
+    models::{board::libsudoku::models::Board, rules::libsudoku::models::GameRules},
+    solvers::{
+        simple::libsudoku::solvers,
+        validator::libsudoku::solvers::{validate, Validation},
+    },
+};
+
+struct OfflineGame {
+    session_token: String,
+    givens: Board,
+    rules: GameRules,
+}
+
+// Single-player (monolithic) game client; the generator, resolver and game "services" all run
+// in-process via libs, so it can be played (and debugged) without the docker-compose stack
+#[derive(Default)]
+pub struct OfflineGameClient {
+    possible_game: Option<OfflineGame>,
+    games_started: u32,
+}
+
+impl OfflineGameClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn current_game(&mut self) -> AnyResult<&mut OfflineGame> {
+        match self.possible_game.as_mut() {
+            Some(game) => Ok(game),
+            None => anyhow::bail!("No game in progress, call start_or_continue() first"),
+        }
+    }
+}
+
+impl GameClient for OfflineGameClient {
+    fn start_or_continue(
+        &mut self,
+        difficulty: i32,
+        possible_last_session_token: Option<String>,
+    ) -> AnyResult<StartOrContinueResponse> {
+        // continue only if the caller asks for the game we already have
+        if let (Some(game), Some(last_session_token)) =
+            (self.possible_game.as_ref(), possible_last_session_token)
+        {
+            if game.session_token == last_session_token {
+                return Ok(StartOrContinueResponse {
+                    board: game.givens,
+                    session_token: game.session_token.clone(),
+                    heartbeat_ttl: 0,
+                    is_new_game: false,
+                    rules: game.rules.clone(),
+                });
+            }
+        }
+
+        self.games_started += 1;
+        let game = OfflineGame {
+            session_token: format!("offline-{}", self.games_started),
+            givens: generators::generate(difficulty),
+            rules: GameRules::new(difficulty),
+        };
+        let response = StartOrContinueResponse {
+            board: game.givens,
+            session_token: game.session_token.clone(),
+            heartbeat_ttl: 0, // nobody to send heartbeats to
+            is_new_game: true,
+            rules: game.rules.clone(),
+        };
+        self.possible_game = Some(game);
+        Ok(response)
+    }
+
+    fn start_with_puzzle(
+        &mut self,
+        givens: &Board,
+        rules: GameRules,
+        possible_last_session_token: Option<String>,
+    ) -> AnyResult<StartOrContinueResponse> {
+        // there is no point playing a puzzle that cannot be solved
+        if solvers::solve(givens).is_none() {
+            anyhow::bail!("Puzzle has no solution");
+        }
+        let is_new_game = possible_last_session_token.is_none();
+        let session_token = match possible_last_session_token {
+            Some(token) => {
+                // restored "offline-N" (i.e. save file): the next game started must not reuse it
+                if let Some(n) = token
+                    .strip_prefix("offline-")
+                    .and_then(|n| n.parse::<u32>().ok())
+                {
+                    self.games_started = self.games_started.max(n);
+                }
+                token
+            }
+            None => {
+                self.games_started += 1;
+                format!("offline-{}", self.games_started)
+            }
+        };
+        let game = OfflineGame {
+            session_token,
+            givens: *givens,
+            rules,
+        };
+        let response = StartOrContinueResponse {
+            board: game.givens,
+            session_token: game.session_token.clone(),
+            heartbeat_ttl: 0,
+            is_new_game,
+            rules: game.rules.clone(),
+        };
+        self.possible_game = Some(game);
+        Ok(response)
+    }
+
+    fn submit(&mut self, board: &Board) -> AnyResult<SubmitResponse> {
+        let game = self.current_game()?;
+        if !game.rules.can_submit() {
+            anyhow::bail!("No more submits allowed for this game");
+        }
+        // givens must not have been altered
+        let givens_intact = game
+            .givens
+            .cells()
+            .iter()
+            .zip(board.cells().iter())
+            .all(|(given, entry)| given.is_none() || given == entry);
+        let validation = validate(board);
+        // correct so far means no duplicates AND the entries still lead to a solution
+        let is_correct = givens_intact && validation.is_valid() && solvers::solve(board).is_some();
+        if !is_correct {
+            game.rules.wrong_submits_so_far += 1;
+        }
+        Ok(SubmitResponse {
+            board: *board,
+            is_correct,
+            possible_is_finished: Some(is_correct && validation.is_filled),
+        })
+    }
+
+    fn validate(&mut self, board: &Board) -> AnyResult<Validation> {
+        Ok(validate(board))
+    }
+
+    fn get_hint(&mut self, board: &Board) -> AnyResult<Option<(usize, u8)>> {
+        let game = self.current_game()?;
+        if !game.rules.can_offer_hint() {
+            anyhow::bail!("No more hints allowed for this game");
+        }
+        let possible_hint = solvers::get_hint(board);
+        if possible_hint.is_some() {
+            game.rules.hints_offered_so_far += 1;
+        }
+        Ok(possible_hint)
+    }
+
+    fn rules(&self) -> Option<&GameRules> {
+        self.possible_game.as_ref().map(|game| &game.rules)
+    }
+}
@@ -1,3 +1,53 @@
-fn main() {
-    println!("Hello, world!");
+//#include modules:
+pub mod app;
+pub mod game_client;
+pub mod save_file;
+
+use app::App;
+use game_client::offline::OfflineGameClient;
+use std::{env, path::Path};
+
+const USAGE: &str = "Usage: sudoku_client_tui --offline [--difficulty <1..4>] [--load <save_file> | --import <puzzle_file>]";
+
+// value that follows `--flag`, Err if the flag is there but the value is missing
+fn get_arg_value<'a>(args: &'a [String], flag: &str) -> anyhow::Result<Option<&'a String>> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(i) => match args.get(i + 1) {
+            Some(value) => Ok(Some(value)),
+            None => anyhow::bail!("{} requires a value\n{}", flag, USAGE),
+        },
+        None => Ok(None),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let is_offline = args.iter().any(|arg| arg == "--offline");
+    let difficulty: i32 = match get_arg_value(&args, "--difficulty")? {
+        Some(d) => d.parse()?,
+        None => 1,
+    };
+    let possible_load = get_arg_value(&args, "--load")?;
+    let possible_import = get_arg_value(&args, "--import")?;
+
+    match is_offline {
+        true => {
+            let client = OfflineGameClient::new();
+            let mut app = match (possible_load, possible_import) {
+                (Some(_), Some(_)) => anyhow::bail!("Use either --load or --import\n{}", USAGE),
+                (Some(path), None) => App::from_save_file(client, Path::new(path))?,
+                (None, Some(path)) => App::from_puzzle_file(client, Path::new(path), difficulty)?,
+                (None, None) => App::new(client, difficulty)?,
+            };
+            app.run()
+        }
+        false => {
+            // TODO: gRPC game client (GameClient over SCSudokuGame/SCSudokuResolver)
+            println!(
+                "Networked game client is not available yet, use --offline\n{}",
+                USAGE
+            );
+            Ok(())
+        }
+    }
 }
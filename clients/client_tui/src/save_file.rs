@@ -0,0 +1,312 @@
+use anyhow::Result as AnyResult;
+use libscsudoku::{
+    generators::from_file::libsudoku::generators::{parse_puzzle, to_line},
+    models::{board::libsudoku::models::Board, rules::libsudoku::models::GameRules},
+};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+// Bump this whenever SaveFile changes shape, and teach load() how to upgrade the older versions
+pub const SAVE_FILE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum UndoEntry {
+    // player's entry on index went from `before` to `after`
+    Entry {
+        index: usize,
+        before: Option<u8>,
+        after: Option<u8>,
+    },
+    // pencil mark toggle, undoing it is just toggling it again
+    PencilMark {
+        index: usize,
+        digit: u8,
+    },
+}
+
+// Mirror of libs GameRules, kept separate so that the file format does not change behind our
+// back whenever the (protobuf-based) rules do
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SavedRules {
+    pub difficulty: i32,
+    pub is_time_based: bool,
+    pub possible_time_limit_submit: Option<i32>,
+    pub possible_max_hint_count: Option<i32>,
+    pub possible_max_wrong_submit: Option<i32>,
+    pub hints_offered_so_far: i32,
+    pub wrong_submits_so_far: i32,
+}
+impl From<&GameRules> for SavedRules {
+    fn from(rules: &GameRules) -> Self {
+        SavedRules {
+            difficulty: rules.difficulty,
+            is_time_based: rules.is_time_based,
+            possible_time_limit_submit: rules.possible_time_limit_submit,
+            possible_max_hint_count: rules.possible_max_hint_count,
+            possible_max_wrong_submit: rules.possible_max_wrong_submit,
+            hints_offered_so_far: rules.hints_offered_so_far,
+            wrong_submits_so_far: rules.wrong_submits_so_far,
+        }
+    }
+}
+impl From<&SavedRules> for GameRules {
+    fn from(rules: &SavedRules) -> Self {
+        GameRules {
+            difficulty: rules.difficulty,
+            is_time_based: rules.is_time_based,
+            possible_time_limit_submit: rules.possible_time_limit_submit,
+            possible_max_hint_count: rules.possible_max_hint_count,
+            possible_max_wrong_submit: rules.possible_max_wrong_submit,
+            hints_offered_so_far: rules.hints_offered_so_far,
+            wrong_submits_so_far: rules.wrong_submits_so_far,
+        }
+    }
+}
+
+// JSON on disk; boards are in the one-line (81 cells) text format so that the file is still
+// readable (and hand-editable) by humans
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SaveFile {
+    pub version: u32,
+    pub session_token: String,
+    pub givens: String,
+    pub entries: String,            // player's entries only (givens are '.')
+    pub pencil_marks: Vec<Vec<u8>>, // 81 lists of digits
+    pub undo_history: Vec<UndoEntry>,
+    pub elapsed_secs: u64,
+    pub rules: SavedRules,
+}
+
+impl SaveFile {
+    pub fn new(
+        session_token: &str,
+        givens: &Board,
+        entries: &Board,
+        pencil_marks: Vec<Vec<u8>>,
+        undo_history: Vec<UndoEntry>,
+        elapsed_secs: u64,
+        rules: &GameRules,
+    ) -> Self {
+        SaveFile {
+            version: SAVE_FILE_VERSION,
+            session_token: session_token.to_string(),
+            givens: to_line(givens),
+            entries: to_line(entries),
+            pencil_marks,
+            undo_history,
+            elapsed_secs,
+            rules: SavedRules::from(rules),
+        }
+    }
+
+    pub fn givens(&self) -> AnyResult<Board> {
+        parse_puzzle(&self.givens)
+    }
+    pub fn entries(&self) -> AnyResult<Board> {
+        parse_puzzle(&self.entries)
+    }
+
+    pub fn save(&self, path: &Path) -> AnyResult<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> AnyResult<Self> {
+        let json = std::fs::read_to_string(path)?;
+        // peek at the version first, so that we can give a sane error instead of a serde one
+        let value: serde_json::Value = serde_json::from_str(&json)?;
+        let version = match value.get("version").and_then(|v| v.as_u64()) {
+            Some(v) => v as u32,
+            None => anyhow::bail!("{:?} is not a save file (no version)", path),
+        };
+        if version > SAVE_FILE_VERSION {
+            anyhow::bail!(
+                "{:?} is version {}, but this client only understands up to version {}",
+                path,
+                version,
+                SAVE_FILE_VERSION
+            );
+        }
+        let save_file: SaveFile = serde_json::from_value(value)?;
+        save_file.validate()?;
+        Ok(save_file)
+    }
+
+    fn validate(&self) -> AnyResult<()> {
+        let givens = self.givens()?;
+        let entries = self.entries()?;
+        if givens
+            .cells()
+            .iter()
+            .zip(entries.cells().iter())
+            .any(|(given, entry)| given.is_some() && entry.is_some())
+        {
+            anyhow::bail!("Entries cannot overwrite givens");
+        }
+        if self.pencil_marks.len() != givens.cells().len() {
+            anyhow::bail!(
+                "Expected {} pencil mark lists, found {}",
+                givens.cells().len(),
+                self.pencil_marks.len()
+            );
+        }
+        if self
+            .pencil_marks
+            .iter()
+            .flatten()
+            .any(|digit| !(1..=9).contains(digit))
+        {
+            anyhow::bail!("Pencil marks must be in range 1..9");
+        }
+        // undo replays these as is, so they must not point outside the board nor at givens
+        for undo_entry in self.undo_history.iter() {
+            match *undo_entry {
+                UndoEntry::Entry { index, .. } if index >= givens.cells().len() => {
+                    anyhow::bail!("Undo history entry on index {} is off the board", index)
+                }
+                UndoEntry::Entry { index, .. } if givens.get(index).is_some() => {
+                    anyhow::bail!("Undo history cannot overwrite the given on index {}", index)
+                }
+                UndoEntry::Entry { before, after, .. }
+                    if before
+                        .into_iter()
+                        .chain(after)
+                        .any(|digit| !(1..=9).contains(&digit)) =>
+                {
+                    anyhow::bail!("Undo history entries must be in range 1..9")
+                }
+                UndoEntry::PencilMark { index, .. } if index >= givens.cells().len() => {
+                    anyhow::bail!(
+                        "Undo history pencil mark on index {} is off the board",
+                        index
+                    )
+                }
+                UndoEntry::PencilMark { digit, .. } if !(1..=9).contains(&digit) => {
+                    anyhow::bail!("Undo history pencil marks must be in range 1..9")
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load_roundtrip() {
+        let givens = parse_puzzle(
+            "53..7....6..195....98....6.8...6...34..8.3..17...2...6.6....28....419..5....8..79",
+        )
+        .unwrap();
+        let mut entries = Board::new();
+        entries.set_at(0, 2, Some(4)).unwrap();
+        let mut pencil_marks = vec![Vec::new(); 81];
+        pencil_marks[3] = vec![2, 6];
+        let mut rules = GameRules::new(2);
+        rules.hints_offered_so_far = 1;
+        let history = vec![
+            UndoEntry::Entry {
+                index: 2,
+                before: None,
+                after: Some(4),
+            },
+            UndoEntry::PencilMark { index: 3, digit: 2 },
+        ];
+        let save_file = SaveFile::new(
+            "offline-1",
+            &givens,
+            &entries,
+            pencil_marks,
+            history,
+            42,
+            &rules,
+        );
+
+        let path = std::env::temp_dir().join(format!("sudoku_save_{}.json", std::process::id()));
+        save_file.save(&path).unwrap();
+        let loaded = SaveFile::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(loaded, save_file);
+        assert_eq!(loaded.givens().unwrap(), givens);
+        assert_eq!(GameRules::from(&loaded.rules), rules);
+    }
+
+    // save file with (only) the given undo history, written as is (save() does not validate)
+    fn load_with_undo_history(name: &str, undo_history: Vec<UndoEntry>) -> AnyResult<SaveFile> {
+        let givens = parse_puzzle(
+            "53..7....6..195....98....6.8...6...34..8.3..17...2...6.6....28....419..5....8..79",
+        )
+        .unwrap();
+        let save_file = SaveFile::new(
+            "offline-1",
+            &givens,
+            &Board::new(),
+            vec![Vec::new(); 81],
+            undo_history,
+            0,
+            &GameRules::new(2),
+        );
+        let path =
+            std::env::temp_dir().join(format!("sudoku_{}_{}.json", name, std::process::id()));
+        save_file.save(&path).unwrap();
+        let result = SaveFile::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn refuses_undo_history_off_the_board() {
+        for undo_entry in [
+            UndoEntry::PencilMark {
+                index: 81,
+                digit: 2,
+            },
+            UndoEntry::Entry {
+                index: 81,
+                before: None,
+                after: Some(4),
+            },
+        ] {
+            assert!(load_with_undo_history("undo_off_board", vec![undo_entry]).is_err());
+        }
+        assert!(load_with_undo_history(
+            "undo_on_board",
+            vec![UndoEntry::PencilMark {
+                index: 80,
+                digit: 2
+            }]
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn refuses_undo_history_on_givens() {
+        // index 0 is the given 5, index 2 is empty
+        let undo_entry = UndoEntry::Entry {
+            index: 0,
+            before: None,
+            after: Some(4),
+        };
+        assert!(load_with_undo_history("undo_on_given", vec![undo_entry]).is_err());
+        let undo_entry = UndoEntry::Entry {
+            index: 2,
+            before: None,
+            after: Some(4),
+        };
+        assert!(load_with_undo_history("undo_on_entry", vec![undo_entry]).is_ok());
+    }
+
+    #[test]
+    fn refuses_newer_version() {
+        let path = std::env::temp_dir().join(format!("sudoku_newer_{}.json", std::process::id()));
+        std::fs::write(&path, format!("{{\"version\": {}}}", SAVE_FILE_VERSION + 1)).unwrap();
+        let result = SaveFile::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,444 @@
+pub mod libsudoku {
+    pub mod auth_client {
+        use anyhow::Result as AnyResult;
+        use serde::Deserialize;
+        use std::{
+            sync::Arc,
+            time::{Duration, SystemTime, UNIX_EPOCH},
+        };
+        use tokio::{sync::RwLock, task::JoinHandle, time::sleep};
+
+        pub use crate::auth_interceptor::libsudoku::auth_interceptor::SESSION_TOKEN_METADATA_KEY;
+
+        // gRPC metadata key in which the session id is passed to the Sudoku services
+        pub const SESSION_METADATA_KEY: &str = "x-session-id";
+
+        // how long before next_expected_time we send the keepalive, so that round-trip latency
+        // does not make us late
+        const KEEPALIVE_MARGIN: Duration = Duration::from_secs(5);
+        const DEFAULT_MAX_RETRIES: u32 = 5;
+        const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(2);
+        // how long the player gets to consent on the browser before we give up polling
+        const DEFAULT_CONSENT_TIMEOUT: Duration = Duration::from_secs(300);
+
+        // Mirror of oauth_relay_service's LoginResponse (see micro-services/oauth_relay_service/src/data.rs)
+        #[derive(Deserialize, Clone, Debug)]
+        pub struct LoginResponse {
+            pub possible_session_id: Option<u64>,
+            pub possible_state_token: Option<String>,
+            #[serde(default)]
+            pub possible_auth_url: Option<String>,
+            #[serde(default)]
+            pub possible_session_token: Option<String>, // signed JWT, see SessionTokenClaims on the relay
+            pub possible_login_error: Option<String>,
+        }
+
+        // Mirror of oauth_relay_service's KeepaliveResponse
+        #[derive(Deserialize, Clone, Debug)]
+        pub struct KeepaliveResponse {
+            pub next_expected_time: u64, // absolute time, EPOCH based
+            pub ttl: u64,
+            pub status: String,
+            pub message: String,
+            #[serde(default)]
+            pub possible_session_token: Option<String>, // re-issued on every keepalive
+        }
+
+        #[derive(Clone, Debug, PartialEq, Eq)]
+        pub struct Session {
+            pub session_id: u64,
+            pub state_token: String,
+            // latest session token (JWT) from the relay, what the Sudoku services verify callers with
+            pub possible_session_token: Option<String>,
+        }
+
+        // shared with whoever needs the current session (i.e. the gRPC clients); None while logged out
+        pub type TSessionLock = Arc<RwLock<Option<Session>>>;
+
+        // Rust version of test_client_auth.sh, but against the relay (/login and /keepalive)
+        // rather than against Google directly
+        pub struct AuthClient {
+            relay_url: String, // i.e. "http://localhost:8080"
+            http_client: reqwest::Client,
+            session: TSessionLock,
+            is_headless: bool, // if true, only print the auth URL, never try to open a browser
+            possible_provider: Option<String>, // i.e. "github", None for the relay's default
+            max_retries: u32,
+            retry_delay: Duration,
+            consent_timeout: Duration,
+        }
+
+        impl AuthClient {
+            pub fn new(relay_url: &str) -> Self {
+                AuthClient {
+                    relay_url: relay_url.trim_end_matches('/').to_string(),
+                    http_client: reqwest::Client::new(),
+                    session: Arc::new(RwLock::new(None)),
+                    is_headless: false,
+                    possible_provider: None,
+                    max_retries: DEFAULT_MAX_RETRIES,
+                    retry_delay: DEFAULT_RETRY_DELAY,
+                    consent_timeout: DEFAULT_CONSENT_TIMEOUT,
+                }
+            }
+            pub fn headless(mut self, is_headless: bool) -> Self {
+                self.is_headless = is_headless;
+                self
+            }
+            // which of the relay's OAuth2 providers (OAUTH2_PROVIDERS) to login with
+            pub fn provider(mut self, provider: &str) -> Self {
+                self.possible_provider = Some(provider.to_string());
+                self
+            }
+            pub fn retries(mut self, max_retries: u32, retry_delay: Duration) -> Self {
+                self.max_retries = max_retries;
+                self.retry_delay = retry_delay;
+                self
+            }
+            pub fn consent_timeout(mut self, consent_timeout: Duration) -> Self {
+                self.consent_timeout = consent_timeout;
+                self
+            }
+
+            pub fn session(&self) -> TSessionLock {
+                self.session.clone() // cloning an Arc<T> just means incrementing the reference count
+            }
+            pub async fn current_session(&self) -> Option<Session> {
+                self.session.read().await.clone()
+            }
+
+            // retries `f` up to max_retries with retry_delay in between, returns the last error
+            async fn with_retries<T, TFut, TFn>(&self, what: &str, f: TFn) -> AnyResult<T>
+            where
+                TFn: Fn() -> TFut,
+                TFut: std::future::Future<Output = AnyResult<T>>,
+            {
+                let mut attempt = 0;
+                loop {
+                    match f().await {
+                        Ok(ret) => return Ok(ret),
+                        Err(e) if attempt < self.max_retries => {
+                            attempt += 1;
+                            println!(
+                                "AuthClient: {} failed (attempt {}/{}): {}",
+                                what, attempt, self.max_retries, e
+                            );
+                            sleep(self.retry_delay).await;
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+            }
+
+            async fn request_login(
+                &self,
+                possible_session: &Option<Session>,
+                possible_state_token: &Option<String>,
+            ) -> AnyResult<LoginResponse> {
+                let mut query: Vec<(&str, String)> = Vec::new();
+                if let Some(session) = possible_session {
+                    query.push(("last_session_id", session.session_id.to_string()));
+                }
+                if let Some(state_token) = possible_state_token {
+                    query.push(("last_state_token", state_token.clone()));
+                }
+                if let Some(provider) = &self.possible_provider {
+                    query.push(("provider", provider.clone()));
+                }
+                let response = self
+                    .http_client
+                    .get(format!("{}/login", self.relay_url))
+                    .query(&query)
+                    .send()
+                    .await?;
+                // relay answers RequestTimeout (with a LoginResponse body) when the player has
+                // not consented yet, so do not bail on the status alone; the other errors come as
+                // KeepaliveResponse (status "ERROR"), same as /keepalive and /logout
+                let status = response.status();
+                if !status.is_success() && status != reqwest::StatusCode::REQUEST_TIMEOUT {
+                    let message = match response.json::<KeepaliveResponse>().await {
+                        Ok(error_response) => error_response.message,
+                        Err(_) => status.to_string(),
+                    };
+                    anyhow::bail!("Relay refused login: {} {}", status, message);
+                }
+                let login_response = response.json::<LoginResponse>().await?;
+                Ok(login_response)
+            }
+
+            fn open_auth_url(&self, auth_url: &str) {
+                println!("############################################################");
+                println!("# Open your web browser to the following URL to authorize:");
+                println!("\n{}\n", auth_url);
+                println!("############################################################");
+                if self.is_headless {
+                    return;
+                }
+                let result = if cfg!(target_os = "windows") {
+                    std::process::Command::new("cmd")
+                        .args(["/C", "start", "", auth_url])
+                        .spawn()
+                } else if cfg!(target_os = "macos") {
+                    std::process::Command::new("open").arg(auth_url).spawn()
+                } else {
+                    std::process::Command::new("xdg-open").arg(auth_url).spawn()
+                };
+                if let Err(e) = result {
+                    println!(
+                        "AuthClient: Could not open browser ({}), please copy-paste the URL",
+                        e
+                    );
+                }
+            }
+
+            // Drives /login until the relay hands back a session id:
+            //  1. GET /login (with last session, if any) -> relay returns auth URL and state token
+            //  2. open (or print) auth URL so the player can consent
+            //  3. GET /login?last_state_token=... until the relay has the session (it blocks for
+            //     a while on its side, so this is more of a long-poll than a busy loop)
+            pub async fn login(&self) -> AnyResult<Session> {
+                let possible_last_session = self.current_session().await;
+                let first = self
+                    .with_retries("login", || {
+                        self.request_login(&possible_last_session, &None)
+                    })
+                    .await?;
+                if let Some(session) = Self::to_session(&first) {
+                    return Ok(self.set_session(Some(session)).await.unwrap());
+                }
+                let state_token = match (&first.possible_state_token, &first.possible_login_error) {
+                    (Some(state_token), _) => state_token.clone(),
+                    (None, Some(e)) => anyhow::bail!("Login failed: {}", e),
+                    (None, None) => {
+                        anyhow::bail!("Login failed: relay did not return a state token")
+                    }
+                };
+                if let Some(auth_url) = &first.possible_auth_url {
+                    self.open_auth_url(auth_url);
+                }
+
+                let start_time = SystemTime::now();
+                let possible_state_token = Some(state_token);
+                loop {
+                    let response = self
+                        .with_retries("login (poll)", || {
+                            self.request_login(&None, &possible_state_token)
+                        })
+                        .await?;
+                    if let Some(session) = Self::to_session(&response) {
+                        return Ok(self.set_session(Some(session)).await.unwrap());
+                    }
+                    if start_time.elapsed().unwrap_or_default() > self.consent_timeout {
+                        anyhow::bail!(
+                            "Login timed out waiting for consent: {}",
+                            response.possible_login_error.unwrap_or_default()
+                        );
+                    }
+                    sleep(self.retry_delay).await;
+                }
+            }
+
+            fn to_session(response: &LoginResponse) -> Option<Session> {
+                match (
+                    &response.possible_session_id,
+                    &response.possible_state_token,
+                ) {
+                    (Some(session_id), Some(state_token)) => Some(Session {
+                        session_id: *session_id,
+                        state_token: state_token.clone(),
+                        possible_session_token: response.possible_session_token.clone(),
+                    }),
+                    _ => None,
+                }
+            }
+
+            async fn set_session(&self, possible_session: Option<Session>) -> Option<Session> {
+                let mut session = self.session.write().await;
+                *session = possible_session;
+                session.clone()
+            }
+
+            // Ends the session on the relay (which revokes the token), and forgets it locally even
+            // if the relay could not be reached (it will get reaped once expired anyways)
+            pub async fn logout(&self) -> AnyResult<KeepaliveResponse> {
+                let session = match self.current_session().await {
+                    Some(session) => session,
+                    None => anyhow::bail!("Not logged in"),
+                };
+                self.drop_session().await;
+                let response = Self::with_session(
+                    self.http_client.post(format!("{}/logout", self.relay_url)),
+                    &session,
+                )
+                .send()
+                .await?
+                .error_for_status()?;
+                Ok(response.json::<KeepaliveResponse>().await?)
+            }
+
+            // forget the session locally only (i.e. relay already told us it's gone)
+            async fn drop_session(&self) {
+                self.set_session(None).await;
+            }
+
+            // the relay only takes last_session_id along with the session token it was issued with
+            fn with_session(
+                request_builder: reqwest::RequestBuilder,
+                session: &Session,
+            ) -> reqwest::RequestBuilder {
+                let request_builder =
+                    request_builder.query(&[("last_session_id", session.session_id.to_string())]);
+                match &session.possible_session_token {
+                    Some(session_token) => request_builder.bearer_auth(session_token),
+                    None => request_builder,
+                }
+            }
+
+            pub async fn keepalive(&self) -> AnyResult<KeepaliveResponse> {
+                let session = match self.current_session().await {
+                    Some(session) => session,
+                    None => anyhow::bail!("Not logged in"),
+                };
+                let response = Self::with_session(
+                    self.http_client
+                        .get(format!("{}/keepalive", self.relay_url)),
+                    &session,
+                )
+                .send()
+                .await?
+                .error_for_status()?;
+                let keepalive_response = response.json::<KeepaliveResponse>().await?;
+                // the session token from login (or last keepalive) is about to expire, swap it
+                if keepalive_response.possible_session_token.is_some() {
+                    match self.session.write().await.as_mut() {
+                        Some(current) if current.session_id == session.session_id => {
+                            current.possible_session_token =
+                                keepalive_response.possible_session_token.clone();
+                        }
+                        _ => {} // logged out (or in again) meanwhile
+                    }
+                }
+                Ok(keepalive_response)
+            }
+
+            // Sends keepalives on the schedule the relay dictates (next_expected_time).  If the
+            // relay keeps rejecting us (i.e. session expired), the session is dropped and we log
+            // in again.  Only returns if re-login fails too.
+            pub async fn run_keepalive_loop(&self) -> AnyResult<()> {
+                loop {
+                    match self.with_retries("keepalive", || self.keepalive()).await {
+                        Ok(keepalive_response) => {
+                            sleep(Self::time_until(keepalive_response.next_expected_time)).await;
+                        }
+                        Err(e) => {
+                            println!("AuthClient: Keepalive failed ({}), logging in again", e);
+                            self.drop_session().await;
+                            self.login().await?;
+                        }
+                    }
+                }
+            }
+
+            // keepalive loop on its own task, so that (as the relay README rants about) game
+            // requests can never block heartbeats
+            pub fn spawn_keepalive(self: Arc<Self>) -> JoinHandle<AnyResult<()>> {
+                tokio::spawn(async move { self.run_keepalive_loop().await })
+            }
+
+            fn time_until(next_expected_time: u64) -> Duration {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                Duration::from_secs(next_expected_time.saturating_sub(now))
+                    .checked_sub(KEEPALIVE_MARGIN)
+                    .unwrap_or_default()
+            }
+
+            // attaches the current session to a (tonic) gRPC request
+            pub async fn authorize_request<T>(
+                &self,
+                mut request: tonic::Request<T>,
+            ) -> AnyResult<tonic::Request<T>> {
+                let session = match self.current_session().await {
+                    Some(session) => session,
+                    None => anyhow::bail!("Not logged in"),
+                };
+                request.metadata_mut().insert(
+                    SESSION_METADATA_KEY,
+                    session.session_id.to_string().parse()?,
+                );
+                // what the services actually verify (see auth_interceptor::AuthInterceptor)
+                if let Some(session_token) = &session.possible_session_token {
+                    request.metadata_mut().insert(
+                        SESSION_TOKEN_METADATA_KEY,
+                        format!("Bearer {}", session_token).parse()?,
+                    );
+                }
+                Ok(request)
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+
+            #[test]
+            fn keepalive_is_sent_before_next_expected_time() {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                let wait = AuthClient::time_until(now + 60);
+                assert!(wait <= Duration::from_secs(60) - KEEPALIVE_MARGIN);
+                assert_eq!(AuthClient::time_until(now - 10), Duration::from_secs(0));
+            }
+
+            #[test]
+            fn relay_requests_carry_session_token() {
+                let session = Session {
+                    session_id: 42,
+                    state_token: "state".to_string(),
+                    possible_session_token: Some("jwt".to_string()),
+                };
+                let request = AuthClient::with_session(
+                    reqwest::Client::new().get("http://localhost:8080/keepalive"),
+                    &session,
+                )
+                .build()
+                .unwrap();
+                assert_eq!(request.url().query(), Some("last_session_id=42"));
+                assert_eq!(
+                    request
+                        .headers()
+                        .get(reqwest::header::AUTHORIZATION)
+                        .unwrap(),
+                    "Bearer jwt"
+                );
+            }
+
+            #[tokio::test]
+            async fn request_without_session_is_rejected() {
+                let client = AuthClient::new("http://localhost:8080/");
+                assert!(client
+                    .authorize_request(tonic::Request::new(()))
+                    .await
+                    .is_err());
+                *client.session().write().await = Some(Session {
+                    session_id: 42,
+                    state_token: "state".to_string(),
+                    possible_session_token: Some("jwt".to_string()),
+                });
+                let request = client
+                    .authorize_request(tonic::Request::new(()))
+                    .await
+                    .unwrap();
+                assert_eq!(request.metadata().get(SESSION_METADATA_KEY).unwrap(), "42");
+                assert_eq!(
+                    request.metadata().get(SESSION_TOKEN_METADATA_KEY).unwrap(),
+                    "Bearer jwt"
+                );
+            }
+        }
+    }
+}
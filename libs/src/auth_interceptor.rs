@@ -0,0 +1,347 @@
+pub mod libsudoku {
+    pub mod auth_interceptor {
+        use anyhow::Result as AnyResult;
+        use jsonwebtoken::{
+            jwk::{AlgorithmParameters, JwkSet},
+            Algorithm, DecodingKey, Validation,
+        };
+        use serde::Deserialize;
+        use std::{collections::HashMap, env, sync::Arc};
+        use tonic::{service::Interceptor, Request, Status};
+
+        // gRPC metadata key in which the relay-issued session token (JWT) is passed, as
+        // "Bearer <session token>" (see auth_client::AuthClient::authorize_request())
+        pub const SESSION_TOKEN_METADATA_KEY: &str = "authorization";
+        // i.e. AUTH_DEV_MODE=true to play locally without the relay (NEVER on a shared cluster)
+        pub const DEV_MODE_ENV_KEY: &str = "AUTH_DEV_MODE";
+        pub const DEV_MODE_EMAIL: &str = "dev@localhost";
+        const JWKS_PATH: &str = "/.well-known/jwks.json";
+
+        // Mirror of oauth_relay_service's SessionTokenClaims (see micro-services/oauth_relay_service/src/data.rs)
+        #[derive(Deserialize, Clone, Debug)]
+        struct SessionTokenClaims {
+            sub: String, // player's email
+            sid: u64,    // session_id
+            exp: u64,
+        }
+
+        // Who is calling; attached to the request extensions by AuthInterceptor, so the service
+        // handlers only need identity_of(&request)
+        #[derive(Clone, Debug, PartialEq, Eq)]
+        pub struct SessionIdentity {
+            pub email: String,
+            pub session_id: u64,
+            pub expires_at: u64, // EPOCH based
+        }
+
+        // Verifies session tokens offline, with the public key(s) the relay publishes as JWKS
+        pub struct SessionTokenVerifier {
+            issuer: String,
+            decoding_keys: HashMap<String, DecodingKey>, // by "kid"
+        }
+
+        impl SessionTokenVerifier {
+            pub fn from_jwks(jwks: &JwkSet, issuer: &str) -> AnyResult<Self> {
+                let mut decoding_keys = HashMap::new();
+                for jwk in jwks.keys.iter() {
+                    // relay only signs with EdDSA, skip whatever else may show up
+                    if !matches!(jwk.algorithm, AlgorithmParameters::OctetKeyPair(_)) {
+                        continue;
+                    }
+                    let key_id = match &jwk.common.key_id {
+                        Some(key_id) => key_id.clone(),
+                        None => continue,
+                    };
+                    decoding_keys.insert(key_id, DecodingKey::from_jwk(jwk)?);
+                }
+                if decoding_keys.is_empty() {
+                    anyhow::bail!("JWKS has no usable (EdDSA) key");
+                }
+                Ok(SessionTokenVerifier {
+                    issuer: issuer.to_string(),
+                    decoding_keys,
+                })
+            }
+
+            // GET {relay_url}/.well-known/jwks.json, once at startup (restart to pick up a new key)
+            pub async fn from_relay(relay_url: &str, issuer: &str) -> AnyResult<Self> {
+                let jwks =
+                    reqwest::get(format!("{}{}", relay_url.trim_end_matches('/'), JWKS_PATH))
+                        .await?
+                        .error_for_status()?
+                        .json::<JwkSet>()
+                        .await?;
+                Self::from_jwks(&jwks, issuer)
+            }
+
+            // signature (by kid), exp and iss
+            pub fn verify(&self, session_token: &str) -> AnyResult<SessionIdentity> {
+                let header = jsonwebtoken::decode_header(session_token)?;
+                let decoding_key = match header
+                    .kid
+                    .as_ref()
+                    .and_then(|kid| self.decoding_keys.get(kid))
+                {
+                    Some(decoding_key) => decoding_key,
+                    None => anyhow::bail!("Unknown session token key id {:?}", header.kid),
+                };
+                let mut validation = Validation::new(Algorithm::EdDSA);
+                validation.set_issuer(&[self.issuer.as_str()]);
+                validation.set_required_spec_claims(&["exp", "iss", "sub"]);
+                let claims = jsonwebtoken::decode::<SessionTokenClaims>(
+                    session_token,
+                    decoding_key,
+                    &validation,
+                )?
+                .claims;
+                Ok(SessionIdentity {
+                    email: claims.sub,
+                    session_id: claims.sid,
+                    expires_at: claims.exp,
+                })
+            }
+        }
+
+        #[derive(Clone)]
+        enum AuthMode {
+            Verify(Arc<SessionTokenVerifier>),
+            // local play: let everybody in, see AuthInterceptor::dev_bypass()
+            DevBypass,
+        }
+
+        // Shared tonic interceptor for the Sudoku services (game, generator, resolver, ...), i.e.
+        //      GameServer::with_interceptor(game_service, auth_interceptor)
+        // Calls without a valid session token are rejected with Unauthenticated before they reach
+        // the handler, the rest get SessionIdentity attached (see identity_of())
+        #[derive(Clone)]
+        pub struct AuthInterceptor {
+            mode: AuthMode,
+        }
+
+        impl AuthInterceptor {
+            pub fn new(verifier: SessionTokenVerifier) -> Self {
+                AuthInterceptor {
+                    mode: AuthMode::Verify(Arc::new(verifier)),
+                }
+            }
+
+            // Does NOT verify anything: a session token (if any) is taken at face value, and
+            // calls without one are let in as DEV_MODE_EMAIL (session_id 0)
+            pub fn dev_bypass() -> Self {
+                println!("AuthInterceptor: Dev mode, callers are NOT authenticated!");
+                AuthInterceptor {
+                    mode: AuthMode::DevBypass,
+                }
+            }
+
+            // dev_bypass() if AUTH_DEV_MODE=true, otherwise verify against the relay's JWKS
+            pub async fn from_env(relay_url: &str, issuer: &str) -> AnyResult<Self> {
+                if env::var(DEV_MODE_ENV_KEY)
+                    .map(|s| s.eq_ignore_ascii_case("true") || s == "1")
+                    .unwrap_or(false)
+                {
+                    return Ok(Self::dev_bypass());
+                }
+                Ok(Self::new(
+                    SessionTokenVerifier::from_relay(relay_url, issuer).await?,
+                ))
+            }
+
+            fn session_token_of(request: &Request<()>) -> Option<&str> {
+                request
+                    .metadata()
+                    .get(SESSION_TOKEN_METADATA_KEY)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .map(|session_token| session_token.trim())
+            }
+
+            fn dev_identity(possible_session_token: Option<&str>) -> SessionIdentity {
+                let mut validation = Validation::new(Algorithm::EdDSA);
+                validation.insecure_disable_signature_validation();
+                validation.validate_exp = false;
+                validation.set_required_spec_claims::<&str>(&[]);
+                match possible_session_token.and_then(|session_token| {
+                    jsonwebtoken::decode::<SessionTokenClaims>(
+                        session_token,
+                        &DecodingKey::from_secret(&[]),
+                        &validation,
+                    )
+                    .ok()
+                }) {
+                    Some(token_data) => SessionIdentity {
+                        email: token_data.claims.sub,
+                        session_id: token_data.claims.sid,
+                        expires_at: token_data.claims.exp,
+                    },
+                    None => SessionIdentity {
+                        email: DEV_MODE_EMAIL.to_string(),
+                        session_id: 0,
+                        expires_at: u64::MAX,
+                    },
+                }
+            }
+        }
+
+        impl Interceptor for AuthInterceptor {
+            fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+                let possible_session_token = Self::session_token_of(&request);
+                let identity = match &self.mode {
+                    AuthMode::DevBypass => Self::dev_identity(possible_session_token),
+                    AuthMode::Verify(verifier) => match possible_session_token {
+                        None => return Err(Status::unauthenticated("Missing session token")),
+                        Some(session_token) => match verifier.verify(session_token) {
+                            Ok(identity) => identity,
+                            Err(e) => {
+                                println!("AuthInterceptor: Rejected session token: {}", e);
+                                return Err(Status::unauthenticated("Invalid session token"));
+                            }
+                        },
+                    },
+                };
+                request.extensions_mut().insert(identity);
+                Ok(request)
+            }
+        }
+
+        // for the service handlers, i.e. `let identity = identity_of(&request)?;`
+        #[allow(clippy::result_large_err)] // Status is what tonic handlers return anyways
+        pub fn identity_of<T>(request: &Request<T>) -> Result<&SessionIdentity, Status> {
+            request
+                .extensions()
+                .get::<SessionIdentity>()
+                .ok_or_else(|| Status::unauthenticated("Not authenticated"))
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use base64::{engine::general_purpose, Engine};
+            use jsonwebtoken::{EncodingKey, Header};
+            use ring::{
+                rand::SystemRandom,
+                signature::{Ed25519KeyPair, KeyPair},
+            };
+            use std::time::{SystemTime, UNIX_EPOCH};
+
+            const ISSUER: &str = "test_relay";
+
+            // plays the relay: (signing key, JWKS with its public key)
+            fn make_relay_key(key_id: &str) -> (EncodingKey, JwkSet) {
+                let private_key_pkcs8 =
+                    Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+                let key_pair = Ed25519KeyPair::from_pkcs8(private_key_pkcs8.as_ref()).unwrap();
+                let jwks = serde_json::from_value(serde_json::json!({"keys": [{
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "use": "sig",
+                    "alg": "EdDSA",
+                    "kid": key_id,
+                    "x": general_purpose::URL_SAFE_NO_PAD.encode(key_pair.public_key().as_ref()),
+                }]}))
+                .unwrap();
+                (EncodingKey::from_ed_der(private_key_pkcs8.as_ref()), jwks)
+            }
+
+            fn make_session_token(
+                encoding_key: &EncodingKey,
+                key_id: &str,
+                issuer: &str,
+                expires_in: i64,
+            ) -> String {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                let mut header = Header::new(Algorithm::EdDSA);
+                header.kid = Some(key_id.to_string());
+                jsonwebtoken::encode(
+                    &header,
+                    &serde_json::json!({
+                        "sub": "player@example.com",
+                        "sid": 42,
+                        "iss": issuer,
+                        "iat": now,
+                        "exp": now.saturating_add_signed(expires_in),
+                    }),
+                    encoding_key,
+                )
+                .unwrap()
+            }
+
+            fn make_request(possible_session_token: Option<&str>) -> Request<()> {
+                let mut request = Request::new(());
+                if let Some(session_token) = possible_session_token {
+                    request.metadata_mut().insert(
+                        SESSION_TOKEN_METADATA_KEY,
+                        format!("Bearer {}", session_token).parse().unwrap(),
+                    );
+                }
+                request
+            }
+
+            #[test]
+            fn valid_session_token_attaches_identity() {
+                let (encoding_key, jwks) = make_relay_key("k1");
+                let mut interceptor =
+                    AuthInterceptor::new(SessionTokenVerifier::from_jwks(&jwks, ISSUER).unwrap());
+                let session_token = make_session_token(&encoding_key, "k1", ISSUER, 3600);
+                let request = interceptor
+                    .call(make_request(Some(&session_token)))
+                    .unwrap();
+                let identity = identity_of(&request).unwrap();
+                assert_eq!(identity.email, "player@example.com");
+                assert_eq!(identity.session_id, 42);
+            }
+
+            #[test]
+            fn rejects_missing_and_invalid_session_tokens() {
+                let (encoding_key, jwks) = make_relay_key("k1");
+                let (other_encoding_key, _) = make_relay_key("k1");
+                let mut interceptor =
+                    AuthInterceptor::new(SessionTokenVerifier::from_jwks(&jwks, ISSUER).unwrap());
+                let rejected = [
+                    None,
+                    Some("not a jwt".to_string()),
+                    Some(make_session_token(&other_encoding_key, "k1", ISSUER, 3600)), // not signed by the relay
+                    Some(make_session_token(&encoding_key, "k2", ISSUER, 3600)), // unknown kid
+                    Some(make_session_token(
+                        &encoding_key,
+                        "k1",
+                        "someone_else",
+                        3600,
+                    )),
+                    Some(make_session_token(&encoding_key, "k1", ISSUER, -3600)), // expired
+                ];
+                for possible_session_token in rejected.iter() {
+                    let status = interceptor
+                        .call(make_request(possible_session_token.as_deref()))
+                        .unwrap_err();
+                    assert_eq!(status.code(), tonic::Code::Unauthenticated);
+                }
+                assert!(identity_of(&make_request(None)).is_err());
+            }
+
+            #[test]
+            fn dev_bypass_lets_everybody_in() {
+                let (encoding_key, _) = make_relay_key("k1");
+                let mut interceptor = AuthInterceptor::dev_bypass();
+                let request = interceptor.call(make_request(None)).unwrap();
+                assert_eq!(identity_of(&request).unwrap().email, DEV_MODE_EMAIL);
+
+                // token (if any) is taken at face value, even if expired
+                let session_token = make_session_token(&encoding_key, "k1", ISSUER, -3600);
+                let request = interceptor
+                    .call(make_request(Some(&session_token)))
+                    .unwrap();
+                assert_eq!(identity_of(&request).unwrap().session_id, 42);
+            }
+
+            #[test]
+            fn jwks_without_usable_key_is_refused() {
+                let jwks: JwkSet = serde_json::from_value(serde_json::json!({"keys": []})).unwrap();
+                assert!(SessionTokenVerifier::from_jwks(&jwks, ISSUER).is_err());
+            }
+        }
+    }
+}
@@ -0,0 +1,2 @@
+pub mod from_file;
+pub mod random;
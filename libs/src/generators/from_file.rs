@@ -0,0 +1,139 @@
+pub mod libsudoku {
+    pub mod generators {
+        use crate::models::board::libsudoku::models::{Board, BOARD_SIZE};
+        use anyhow::Result as AnyResult;
+        use std::path::Path;
+
+        // Supported text formats (all of them can have '#' comment lines and blank lines):
+        //  - one puzzle per line, 81 cells (i.e. `qqwing --one-line` output):
+        //      53..7....6..195....98....6.8...6...34..8.3..17...2...6.6....28....419..5....8..79
+        //  - 9x9 grid, one row per line, with optional '|', '-', '+' and space separators (i.e.
+        //    `qqwing` default output, or what Board::to_string() renders):
+        //      5 3 . | . 7 . | . . .
+        //      6 . . | 1 9 5 | . . .
+        //      ------+-------+------
+        // Empty cells can be any of '.', '0', '_', '*' or 'x'
+        fn to_cell(c: char) -> Option<Option<u8>> {
+            match c {
+                '1'..='9' => Some(c.to_digit(10).map(|d| d as u8)),
+                '.' | '0' | '_' | '*' | 'x' | 'X' => Some(None),
+                _ => None, // separators and anything else is ignored
+            }
+        }
+        fn is_separator(c: char) -> bool {
+            matches!(c, '|' | '-' | '+' | ' ' | '\t' | ',')
+        }
+
+        // parses all the puzzles found in text, in order of appearance
+        pub fn parse_puzzles(text: &str) -> AnyResult<Vec<Board>> {
+            let mut puzzles = Vec::new();
+            let mut cells: Vec<Option<u8>> = Vec::with_capacity(BOARD_SIZE);
+            for (line_number, line) in text.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                for c in line.chars() {
+                    match to_cell(c) {
+                        Some(cell) => cells.push(cell),
+                        None if is_separator(c) => (),
+                        None => anyhow::bail!(
+                            "Unexpected character '{}' on line {}",
+                            c,
+                            line_number + 1
+                        ),
+                    }
+                }
+                if cells.len() > BOARD_SIZE {
+                    anyhow::bail!(
+                        "Too many cells ({}) for a puzzle ending on line {}",
+                        cells.len(),
+                        line_number + 1
+                    );
+                }
+                if cells.len() == BOARD_SIZE {
+                    let mut board_cells = [None; BOARD_SIZE];
+                    board_cells.copy_from_slice(&cells);
+                    puzzles.push(Board::from_cells(board_cells).map_err(|e| anyhow::anyhow!(e))?);
+                    cells.clear();
+                }
+            }
+            if !cells.is_empty() {
+                anyhow::bail!(
+                    "Incomplete puzzle at end of text: {} of {} cells",
+                    cells.len(),
+                    BOARD_SIZE
+                );
+            }
+            Ok(puzzles)
+        }
+
+        // exactly one puzzle is expected
+        pub fn parse_puzzle(text: &str) -> AnyResult<Board> {
+            let mut puzzles = parse_puzzles(text)?;
+            match puzzles.len() {
+                1 => Ok(puzzles.remove(0)),
+                n => anyhow::bail!("Expected 1 puzzle, found {}", n),
+            }
+        }
+
+        pub fn load_puzzles(path: &Path) -> AnyResult<Vec<Board>> {
+            let text = std::fs::read_to_string(path)?;
+            parse_puzzles(&text)
+        }
+
+        // first puzzle in the file
+        pub fn load_puzzle(path: &Path) -> AnyResult<Board> {
+            match load_puzzles(path)?.into_iter().next() {
+                Some(board) => Ok(board),
+                None => anyhow::bail!("No puzzle found in {:?}", path),
+            }
+        }
+
+        // the one-line (81 cells) format, '.' as empty cells
+        pub fn to_line(board: &Board) -> String {
+            board
+                .cells()
+                .iter()
+                .map(|cell| match cell {
+                    Some(v) => (b'0' + v) as char,
+                    None => '.',
+                })
+                .collect()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::generators::*;
+
+        const LINE: &str =
+            "53..7....6..195....98....6.8...6...34..8.3..17...2...6.6....28....419..5....8..79";
+
+        #[test]
+        fn line_and_grid_formats_are_the_same() {
+            let from_line = parse_puzzle(LINE).unwrap();
+            let from_grid = parse_puzzle(&from_line.to_string()).unwrap();
+            assert_eq!(from_line, from_grid);
+            assert_eq!(to_line(&from_grid), LINE);
+        }
+
+        #[test]
+        fn multiple_puzzles_and_comments() {
+            let text = format!(
+                "# qqwing --one-line\n{}\n\n{}\n",
+                LINE,
+                LINE.replace('.', "0")
+            );
+            let puzzles = parse_puzzles(&text).unwrap();
+            assert_eq!(puzzles.len(), 2);
+            assert_eq!(puzzles[0], puzzles[1]);
+        }
+
+        #[test]
+        fn rejects_incomplete_puzzle() {
+            assert!(parse_puzzle(&LINE[..80]).is_err());
+            assert!(parse_puzzle("53..7....6..195..?.").is_err());
+        }
+    }
+}
@@ -0,0 +1,72 @@
+pub mod libsudoku {
+    pub mod generators {
+        use crate::{
+            models::board::libsudoku::models::{Board, BLOCK_WIDTH, BOARD_SIZE},
+            solvers::simple::libsudoku::solvers::{count_solutions, solve},
+        };
+        use rand::seq::SliceRandom;
+
+        // Number of clues (given cells) left on the board for each difficulty; note that we never
+        // go below 17 since (see generator/README.md) 16 or fewer clues has multiple solutions
+        pub fn clue_count_for_difficulty(difficulty: i32) -> usize {
+            match difficulty {
+                d if d <= 1 => 40,
+                2 => 32,
+                3 => 28,
+                _ => 24,
+            }
+        }
+
+        // The 3 diagonal blocks (top-left, center, bottom-right) do not share any row/column, so
+        // each of them can be a random permutation of 1..9; the rest is left to the solver
+        pub fn generate_solved() -> Board {
+            let mut rng = rand::thread_rng();
+            let mut board = Board::new();
+            for block in (0..BLOCK_WIDTH).map(|n| n * BLOCK_WIDTH + n) {
+                let mut digits: Vec<u8> = (1..=9).collect();
+                digits.shuffle(&mut rng);
+                for (index, digit) in Board::block_indices(block).iter().zip(digits) {
+                    board.set(*index, Some(digit)).unwrap(); // always in range
+                }
+            }
+            solve(&board).expect("diagonal blocks should always be solvable")
+        }
+
+        // As described in generator/README.md, auto-generate a solved data and then randomly erase
+        // cells; a cell is only erased if the puzzle still has exactly one solution
+        pub fn generate(difficulty: i32) -> Board {
+            let mut rng = rand::thread_rng();
+            let target = clue_count_for_difficulty(difficulty);
+            let mut board = generate_solved();
+            let mut indices: Vec<usize> = (0..BOARD_SIZE).collect();
+            indices.shuffle(&mut rng);
+            for index in indices {
+                if board.filled_count() <= target {
+                    break;
+                }
+                let digit = board.get(index);
+                board.set(index, None).unwrap();
+                if count_solutions(&board, 2) != 1 {
+                    board.set(index, digit).unwrap(); // put it back, removing it makes it ambiguous
+                }
+            }
+            board
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::generators::*;
+        use crate::solvers::{
+            simple::libsudoku::solvers::count_solutions, validator::libsudoku::solvers::validate,
+        };
+
+        #[test]
+        fn generated_puzzle_has_unique_solution() {
+            let board = generate(1);
+            assert!(validate(&board).is_valid());
+            assert!(board.filled_count() >= clue_count_for_difficulty(1));
+            assert_eq!(count_solutions(&board, 2), 1);
+        }
+    }
+}
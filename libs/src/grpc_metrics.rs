@@ -0,0 +1,375 @@
+pub mod libsudoku {
+    pub mod grpc_metrics {
+        use anyhow::Result as AnyResult;
+        use prometheus::{
+            Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+        };
+        use std::{
+            collections::{HashMap, HashSet},
+            future::Future,
+            net::SocketAddr,
+            pin::Pin,
+            sync::Arc,
+            task::{Context, Poll},
+            time::{Duration, Instant},
+        };
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpListener,
+            task::JoinHandle,
+            time::timeout,
+        };
+        use tower_layer::Layer;
+        use tower_service::Service;
+
+        // gRPC status code when the call fails before the handler answers (i.e. transport error)
+        const UNKNOWN_CODE: &str = "unknown";
+        // method label of a path the service does not serve (see GrpcMetrics::method_label())
+        const UNMATCHED_METHOD: &str = "unmatched";
+        // a scrape is one request in and one response out, a connection that takes longer than
+        // this on either (i.e. opened and left idle) is dropped rather than holding its task forever
+        const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+        // Prometheus metrics of a tonic service (game, generator, resolver, ...), same idea as
+        // oauth_relay_service's /metrics (see micro-services/oauth_relay_service/src/metrics.rs):
+        //  - grpc_requests_total{method, code} and grpc_request_duration_seconds{method, code},
+        //    for every call (see GrpcMetricsLayer), method being the gRPC path (i.e.
+        //    "/sudoku.Game/NewGame") and code the gRPC status code ("0" is OK)
+        // Every metric also carries service, and whatever backend labels the service has (i.e.
+        // db_type, mq_type) so that they line up with the relay's on the same dashboard
+        // NOTE: same as the relay's route label, method is one of the paths the service serves,
+        // anything else is "unmatched", so that whatever clients make up does not blow up the
+        // number of series
+        #[derive(Clone)]
+        pub struct GrpcMetrics {
+            registry: Registry,
+            requests: IntCounterVec,
+            request_duration: HistogramVec,
+            methods: Arc<HashSet<String>>,
+        }
+
+        impl GrpcMetrics {
+            // i.e. GrpcMetrics::new("game", &["/sudoku.Game/StartOrContinue", "/sudoku.Game/Submit"],
+            //      &[("db_type", "postgres")])
+            pub fn new(
+                service: &str,
+                methods: &[&str],
+                backend_labels: &[(&str, &str)],
+            ) -> AnyResult<Self> {
+                let mut const_labels: HashMap<String, String> = backend_labels
+                    .iter()
+                    .map(|(label, value)| (label.to_string(), value.to_string()))
+                    .collect();
+                const_labels.insert("service".to_string(), service.to_string());
+                let registry = Registry::new_custom(None, Some(const_labels))?;
+                let requests = IntCounterVec::new(
+                    Opts::new("grpc_requests_total", "gRPC calls served"),
+                    &["method", "code"],
+                )?;
+                let request_duration = HistogramVec::new(
+                    HistogramOpts::new("grpc_request_duration_seconds", "gRPC call latency"),
+                    &["method", "code"],
+                )?;
+                registry.register(Box::new(requests.clone()))?;
+                registry.register(Box::new(request_duration.clone()))?;
+                Ok(GrpcMetrics {
+                    registry,
+                    requests,
+                    request_duration,
+                    methods: Arc::new(methods.iter().map(|method| method.to_string()).collect()),
+                })
+            }
+
+            pub fn method_label(&self, path: &str) -> String {
+                match self.methods.contains(path) {
+                    true => path.to_string(),
+                    false => UNMATCHED_METHOD.to_string(),
+                }
+            }
+
+            pub fn observe_call(&self, method: &str, code: &str, elapsed_secs: f64) {
+                self.requests.with_label_values(&[method, code]).inc();
+                self.request_duration
+                    .with_label_values(&[method, code])
+                    .observe(elapsed_secs);
+            }
+
+            // Prometheus text exposition format
+            pub fn render(&self) -> AnyResult<String> {
+                let mut buffer = Vec::new();
+                TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+                Ok(String::from_utf8(buffer)?)
+            }
+
+            // gRPC services do not speak plain HTTP, so the scrape endpoint gets its own port:
+            // answers every request (whatever the path) with render(), until the task is aborted
+            pub async fn spawn_metrics_server(
+                &self,
+                listen_addr: SocketAddr,
+            ) -> AnyResult<(SocketAddr, JoinHandle<()>)> {
+                self.spawn_metrics_server_with_timeout(listen_addr, SCRAPE_TIMEOUT)
+                    .await
+            }
+
+            async fn spawn_metrics_server_with_timeout(
+                &self,
+                listen_addr: SocketAddr,
+                scrape_timeout: Duration,
+            ) -> AnyResult<(SocketAddr, JoinHandle<()>)> {
+                let listener = TcpListener::bind(listen_addr).await?;
+                let local_addr = listener.local_addr()?;
+                println!("Metrics: Serving /metrics on {:?}", local_addr);
+                let metrics = self.clone();
+                let metrics_server = tokio::spawn(async move {
+                    loop {
+                        let (mut stream, _) = match listener.accept().await {
+                            Ok(connection) => connection,
+                            Err(e) => {
+                                println!("Metrics: Failed to accept connection: {:?}", e);
+                                continue;
+                            }
+                        };
+                        let metrics = metrics.clone();
+                        tokio::spawn(async move {
+                            // only the request line matters, the rest is ignored
+                            let mut request = [0u8; 1024];
+                            if timeout(scrape_timeout, stream.read(&mut request))
+                                .await
+                                .is_err()
+                            {
+                                return;
+                            }
+                            let exposition = metrics.render().unwrap_or_default();
+                            let response = format!(
+                                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                                prometheus::TEXT_FORMAT,
+                                exposition.len(),
+                                exposition
+                            );
+                            let _ = timeout(scrape_timeout, async {
+                                stream.write_all(response.as_bytes()).await?;
+                                stream.shutdown().await
+                            })
+                            .await;
+                        });
+                    }
+                });
+                Ok((local_addr, metrics_server))
+            }
+        }
+
+        // tower layer for tonic's Server::builder(), i.e.
+        //      Server::builder()
+        //          .layer(GrpcMetricsLayer::new(grpc_metrics.clone()))
+        //          .add_service(GameServer::with_interceptor(game_service, auth_interceptor))
+        #[derive(Clone)]
+        pub struct GrpcMetricsLayer {
+            metrics: GrpcMetrics,
+        }
+
+        impl GrpcMetricsLayer {
+            pub fn new(metrics: GrpcMetrics) -> Self {
+                GrpcMetricsLayer { metrics }
+            }
+        }
+
+        impl<S> Layer<S> for GrpcMetricsLayer {
+            type Service = GrpcMetricsService<S>;
+
+            fn layer(&self, inner: S) -> Self::Service {
+                GrpcMetricsService {
+                    inner,
+                    metrics: self.metrics.clone(),
+                }
+            }
+        }
+
+        #[derive(Clone)]
+        pub struct GrpcMetricsService<S> {
+            inner: S,
+            metrics: GrpcMetrics,
+        }
+
+        // NOTE: successful calls carry grpc-status in the trailers, which come after the body is
+        // streamed, so a 200 without grpc-status in the headers is counted as OK; failed calls
+        // (Status returned by the handler, or rejected by AuthInterceptor) are "trailers only",
+        // with grpc-status in the headers
+        fn grpc_code_of<B>(response: &http::Response<B>) -> String {
+            match response.headers().get("grpc-status") {
+                Some(grpc_status) => grpc_status.to_str().unwrap_or(UNKNOWN_CODE).to_string(),
+                None if response.status() == http::StatusCode::OK => "0".to_string(),
+                None => UNKNOWN_CODE.to_string(),
+            }
+        }
+
+        impl<S, ReqBody, ResBody> Service<http::Request<ReqBody>> for GrpcMetricsService<S>
+        where
+            S: Service<http::Request<ReqBody>, Response = http::Response<ResBody>>
+                + Clone
+                + Send
+                + 'static,
+            S::Future: Send + 'static,
+            ReqBody: Send + 'static,
+        {
+            type Response = S::Response;
+            type Error = S::Error;
+            #[allow(clippy::type_complexity)]
+            type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+            fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+                self.inner.poll_ready(cx)
+            }
+
+            fn call(&mut self, request: http::Request<ReqBody>) -> Self::Future {
+                // the one that got poll_ready()'ed serves this call, a fresh clone serves the next
+                let clone = self.inner.clone();
+                let mut inner = std::mem::replace(&mut self.inner, clone);
+                let metrics = self.metrics.clone();
+                let method = metrics.method_label(request.uri().path());
+                let started_at = Instant::now();
+                Box::pin(async move {
+                    let result = inner.call(request).await;
+                    let code = match &result {
+                        Ok(response) => grpc_code_of(response),
+                        Err(_) => UNKNOWN_CODE.to_string(),
+                    };
+                    metrics.observe_call(&method, &code, started_at.elapsed().as_secs_f64());
+                    result
+                })
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use std::convert::Infallible;
+
+            // plays a tonic service: answers with the grpc-status of the request's path, if any
+            #[derive(Clone)]
+            struct FakeGrpcService;
+
+            impl Service<http::Request<()>> for FakeGrpcService {
+                type Response = http::Response<()>;
+                type Error = Infallible;
+                type Future = std::future::Ready<Result<Self::Response, Self::Error>>;
+
+                fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+                    Poll::Ready(Ok(()))
+                }
+
+                fn call(&mut self, request: http::Request<()>) -> Self::Future {
+                    let mut response = http::Response::builder().status(200);
+                    if request.uri().path().ends_with("/Unauthenticated") {
+                        response = response.header("grpc-status", "16");
+                    }
+                    std::future::ready(Ok(response.body(()).unwrap()))
+                }
+            }
+
+            async fn call(service: &mut GrpcMetricsService<FakeGrpcService>, path: &str) {
+                let request = http::Request::builder().uri(path).body(()).unwrap();
+                service.call(request).await.unwrap();
+            }
+
+            #[tokio::test]
+            async fn counts_calls_by_method_and_code_with_service_labels() {
+                let metrics = GrpcMetrics::new(
+                    "game",
+                    &["/sudoku.Game/NewGame", "/sudoku.Game/Unauthenticated"],
+                    &[("db_type", "memory")],
+                )
+                .unwrap();
+                let mut service = GrpcMetricsLayer::new(metrics.clone()).layer(FakeGrpcService);
+                call(&mut service, "/sudoku.Game/NewGame").await;
+                call(&mut service, "/sudoku.Game/NewGame").await;
+                call(&mut service, "/sudoku.Game/Unauthenticated").await;
+                // made up by the client
+                call(&mut service, "/sudoku.Game/MadeUp1").await;
+                call(&mut service, "/made.Up/MadeUp2").await;
+
+                let exposition = metrics.render().unwrap();
+                for (name, method, code, expected_count) in [
+                    ("grpc_requests_total", "/sudoku.Game/NewGame", "0", "2"),
+                    (
+                        "grpc_requests_total",
+                        "/sudoku.Game/Unauthenticated",
+                        "16",
+                        "1",
+                    ),
+                    (
+                        "grpc_request_duration_seconds_count",
+                        "/sudoku.Game/NewGame",
+                        "0",
+                        "2",
+                    ),
+                    ("grpc_requests_total", "unmatched", "0", "2"),
+                ] {
+                    let labels = [
+                        format!("method=\"{}\"", method),
+                        format!("code=\"{}\"", code),
+                        "service=\"game\"".to_string(),
+                        "db_type=\"memory\"".to_string(),
+                    ];
+                    assert!(
+                        exposition.lines().any(|line| line.starts_with(name)
+                            && line.ends_with(&format!(" {}", expected_count))
+                            && labels.iter().all(|label| line.contains(label.as_str()))),
+                        "{} {} {}\n{}",
+                        name,
+                        method,
+                        code,
+                        exposition
+                    );
+                }
+                assert!(!exposition.contains("MadeUp"), "{}", exposition);
+            }
+
+            #[tokio::test]
+            async fn metrics_server_serves_exposition() {
+                let metrics =
+                    GrpcMetrics::new("resolver", &["/sudoku.Resolver/Resolve"], &[]).unwrap();
+                metrics.observe_call("/sudoku.Resolver/Resolve", "0", 0.01);
+                let (local_addr, metrics_server) = metrics
+                    .spawn_metrics_server("127.0.0.1:0".parse().unwrap())
+                    .await
+                    .unwrap();
+                let response = reqwest::get(format!("http://{}/metrics", local_addr))
+                    .await
+                    .unwrap();
+                assert_eq!(response.status(), 200);
+                assert!(response
+                    .headers()
+                    .get("content-type")
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .starts_with("text/plain"));
+                let exposition = response.text().await.unwrap();
+                assert!(exposition.contains("grpc_requests_total{"));
+                assert!(exposition.contains("service=\"resolver\""));
+                metrics_server.abort();
+            }
+
+            #[tokio::test]
+            async fn metrics_server_drops_idle_connections() {
+                let metrics = GrpcMetrics::new("resolver", &[], &[]).unwrap();
+                let (local_addr, metrics_server) = metrics
+                    .spawn_metrics_server_with_timeout(
+                        "127.0.0.1:0".parse().unwrap(),
+                        Duration::from_millis(100),
+                    )
+                    .await
+                    .unwrap();
+                // connects, and never sends a thing
+                let mut idle = tokio::net::TcpStream::connect(local_addr).await.unwrap();
+                let mut response = Vec::new();
+                let bytes_read = timeout(Duration::from_secs(5), idle.read_to_end(&mut response))
+                    .await
+                    .expect("idle connection was not dropped")
+                    .unwrap();
+                assert_eq!(bytes_read, 0);
+                metrics_server.abort();
+            }
+        }
+    }
+}
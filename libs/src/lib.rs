@@ -1,4 +1,7 @@
+pub mod auth_client;
+pub mod auth_interceptor;
 pub mod generators;
+pub mod grpc_metrics;
 pub mod models;
 pub mod solvers;
 
@@ -1,3 +1,4 @@
 pub mod block;
 pub mod board;
 pub mod cell;
+pub mod rules;
@@ -1,13 +1,160 @@
-
 pub mod libsudoku {
     pub mod models {
-        use std::collections::HashSet;
-
-        use crate::models::block::{libsudoku::models::Block};
-        
+        // NOTE: Same as the protobuf SudokuMatrix, the board is stored as a flat [81] with stride 9
+        // (row-major), in which None is an empty cell and Some(1..9) is a digit.  Rows, columns and
+        // blocks are just different views (indices) into that same flat array.
+        pub const BOARD_WIDTH: usize = 9;
+        pub const BOARD_SIZE: usize = BOARD_WIDTH * BOARD_WIDTH; // const 81
+        pub const BLOCK_WIDTH: usize = 3;
 
+        #[derive(Clone, Copy, PartialEq, Eq, Debug)]
         pub struct Board {
-            blocks: HashSet<Block>, // Q: can there be chances where two (or more) blocks are identical?
+            cells: [Option<u8>; BOARD_SIZE],
+        }
+        impl Default for Board {
+            fn default() -> Self {
+                Board {
+                    cells: [None; BOARD_SIZE],
+                }
+            }
+        }
+        impl Board {
+            pub fn new() -> Board {
+                Board::default()
+            }
+            pub fn from_cells(cells: [Option<u8>; BOARD_SIZE]) -> Result<Board, String> {
+                let ret = Board { cells };
+                ret.validate_ranges()?;
+                Ok(ret)
+            }
+            // validates ranges only (Some[1..9] or None), duplicate digits are for the validator
+            fn validate_ranges(&self) -> Result<(), String> {
+                match self
+                    .cells
+                    .iter()
+                    .position(|cell| matches!(cell, Some(v) if !(1..=9).contains(v)))
+                {
+                    Some(index) => Err(format!(
+                        "Value {:?} at index {} must be in range 1..9",
+                        self.cells[index], index
+                    )),
+                    None => Ok(()),
+                }
+            }
+
+            pub fn cells(&self) -> &[Option<u8>; BOARD_SIZE] {
+                &self.cells
+            }
+            pub fn get(&self, index: usize) -> Option<u8> {
+                self.cells[index]
+            }
+            pub fn get_at(&self, row: usize, col: usize) -> Option<u8> {
+                self.cells[Self::to_index(row, col)]
+            }
+            pub fn set(&mut self, index: usize, value: Option<u8>) -> Result<(), String> {
+                if index >= BOARD_SIZE {
+                    return Err(format!("Index {} cannot exceed {}", index, BOARD_SIZE - 1));
+                }
+                match value {
+                    Some(v) if !(1..=9).contains(&v) => {
+                        return Err(format!("Value {} must be in range 1..9", v))
+                    }
+                    _ => (),
+                }
+                self.cells[index] = value;
+                Ok(())
+            }
+            pub fn set_at(
+                &mut self,
+                row: usize,
+                col: usize,
+                value: Option<u8>,
+            ) -> Result<(), String> {
+                if row >= BOARD_WIDTH || col >= BOARD_WIDTH {
+                    return Err(format!("Row {} and col {} must be in range 0..8", row, col));
+                }
+                self.set(Self::to_index(row, col), value)
+            }
+
+            pub fn is_filled(&self) -> bool {
+                self.cells.iter().all(|cell| cell.is_some())
+            }
+            pub fn filled_count(&self) -> usize {
+                self.cells.iter().filter(|cell| cell.is_some()).count()
+            }
+
+            pub fn to_index(row: usize, col: usize) -> usize {
+                row * BOARD_WIDTH + col
+            }
+            pub fn to_row_col(index: usize) -> (usize, usize) {
+                (index / BOARD_WIDTH, index % BOARD_WIDTH)
+            }
+            // block is 0..8, left-to-right, top-to-bottom
+            pub fn to_block(index: usize) -> usize {
+                let (row, col) = Self::to_row_col(index);
+                (row / BLOCK_WIDTH) * BLOCK_WIDTH + (col / BLOCK_WIDTH)
+            }
+            pub fn row_indices(row: usize) -> [usize; BOARD_WIDTH] {
+                let mut ret = [0; BOARD_WIDTH];
+                for (col, index) in ret.iter_mut().enumerate() {
+                    *index = Self::to_index(row, col);
+                }
+                ret
+            }
+            pub fn col_indices(col: usize) -> [usize; BOARD_WIDTH] {
+                let mut ret = [0; BOARD_WIDTH];
+                for (row, index) in ret.iter_mut().enumerate() {
+                    *index = Self::to_index(row, col);
+                }
+                ret
+            }
+            pub fn block_indices(block: usize) -> [usize; BOARD_WIDTH] {
+                let top = (block / BLOCK_WIDTH) * BLOCK_WIDTH;
+                let left = (block % BLOCK_WIDTH) * BLOCK_WIDTH;
+                let mut ret = [0; BOARD_WIDTH];
+                for (i, index) in ret.iter_mut().enumerate() {
+                    *index = Self::to_index(top + i / BLOCK_WIDTH, left + i % BLOCK_WIDTH);
+                }
+                ret
+            }
+            // all the indices (excluding itself) that shares the row, column, or block with index
+            pub fn peer_indices(index: usize) -> Vec<usize> {
+                let (row, col) = Self::to_row_col(index);
+                let mut ret: Vec<usize> = Self::row_indices(row)
+                    .iter()
+                    .chain(Self::col_indices(col).iter())
+                    .chain(Self::block_indices(Self::to_block(index)).iter())
+                    .copied()
+                    .filter(|i| *i != index)
+                    .collect();
+                ret.sort_unstable();
+                ret.dedup();
+                ret
+            }
+        }
+        // renders as 9 lines of digits with '.' as empty cells, blocks separated by '|' and '-'
+        impl std::fmt::Display for Board {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                for row in 0..BOARD_WIDTH {
+                    if row > 0 && row % BLOCK_WIDTH == 0 {
+                        writeln!(f, "------+-------+------")?;
+                    }
+                    for col in 0..BOARD_WIDTH {
+                        if col > 0 && col % BLOCK_WIDTH == 0 {
+                            write!(f, "| ")?;
+                        }
+                        match self.get_at(row, col) {
+                            Some(v) => write!(f, "{}", v)?,
+                            None => write!(f, ".")?,
+                        }
+                        if col < BOARD_WIDTH - 1 {
+                            write!(f, " ")?;
+                        }
+                    }
+                    writeln!(f)?;
+                }
+                Ok(())
+            }
         }
     }
 }
@@ -0,0 +1,36 @@
+pub mod libsudoku {
+    pub mod models {
+        // Rust-side mirror of the protobuf `GameRules` (see game.proto), counters are the only
+        // mutable parts once the game has started
+        #[derive(Clone, PartialEq, Eq, Debug, Default)]
+        pub struct GameRules {
+            pub difficulty: i32,
+            pub is_time_based: bool,
+            pub possible_time_limit_submit: Option<i32>, // in seconds
+            pub possible_max_hint_count: Option<i32>,
+            pub possible_max_wrong_submit: Option<i32>,
+            pub hints_offered_so_far: i32,
+            pub wrong_submits_so_far: i32,
+        }
+        impl GameRules {
+            pub fn new(difficulty: i32) -> GameRules {
+                GameRules {
+                    difficulty,
+                    ..Default::default()
+                }
+            }
+            pub fn can_offer_hint(&self) -> bool {
+                match self.possible_max_hint_count {
+                    Some(max) => self.hints_offered_so_far < max,
+                    None => true,
+                }
+            }
+            pub fn can_submit(&self) -> bool {
+                match self.possible_max_wrong_submit {
+                    Some(max) => self.wrong_submits_so_far < max,
+                    None => true,
+                }
+            }
+        }
+    }
+}
@@ -1 +1,2 @@
-pub mod simple;
\ No newline at end of file
+pub mod simple;
+pub mod validator;
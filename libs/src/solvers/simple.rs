@@ -0,0 +1,128 @@
+pub mod libsudoku {
+    pub mod solvers {
+        use crate::{
+            models::board::libsudoku::models::{Board, BOARD_SIZE},
+            solvers::validator::libsudoku::solvers::validate,
+        };
+
+        // bitmask in which bit N (1..9) is set when digit N can still be placed at index
+        fn candidate_mask(board: &Board, index: usize) -> u16 {
+            let used = Board::peer_indices(index)
+                .iter()
+                .filter_map(|peer| board.get(*peer))
+                .fold(0u16, |mask, v| mask | (1 << v));
+            !used & 0b11_1111_1110
+        }
+
+        pub fn candidates(board: &Board, index: usize) -> Vec<u8> {
+            match board.get(index) {
+                Some(_) => Vec::new(),
+                None => {
+                    let mask = candidate_mask(board, index);
+                    (1..=9u8).filter(|v| mask & (1 << v) != 0).collect()
+                }
+            }
+        }
+
+        // the empty cell with the fewest candidates (None if the board is filled)
+        fn most_constrained(board: &Board) -> Option<(usize, u16)> {
+            (0..BOARD_SIZE)
+                .filter(|index| board.get(*index).is_none())
+                .map(|index| (index, candidate_mask(board, index)))
+                .min_by_key(|(_, mask)| mask.count_ones())
+        }
+
+        // Brute-force (backtracking), but always fills the most constrained cell first so that
+        // most puzzles are solved without ever guessing.  Stops as soon as `limit` solutions are found.
+        fn solve_recursive(board: &mut Board, solutions: &mut Vec<Board>, limit: usize) {
+            if solutions.len() >= limit {
+                return;
+            }
+            match most_constrained(board) {
+                None => solutions.push(*board),
+                Some((index, mask)) => {
+                    for v in (1..=9u8).filter(|v| mask & (1 << v) != 0) {
+                        board.set(index, Some(v)).unwrap(); // index and value are always in range
+                        solve_recursive(board, solutions, limit);
+                        if solutions.len() >= limit {
+                            break;
+                        }
+                    }
+                    board.set(index, None).unwrap();
+                }
+            }
+        }
+
+        fn find_solutions(board: &Board, limit: usize) -> Vec<Board> {
+            let mut solutions = Vec::new();
+            // no point searching if there are already duplicates on the board
+            if validate(board).is_valid() {
+                let mut scratch = *board;
+                solve_recursive(&mut scratch, &mut solutions, limit);
+            }
+            solutions
+        }
+
+        pub fn solve(board: &Board) -> Option<Board> {
+            find_solutions(board, 1).into_iter().next()
+        }
+
+        // counts up to `limit` solutions, i.e. count_solutions(board, 2) == 1 means unique solution
+        pub fn count_solutions(board: &Board, limit: usize) -> usize {
+            find_solutions(board, limit).len()
+        }
+
+        // Returns (index, digit) of the next cell the player should fill; prefers the cell with the
+        // fewest candidates (for the player, it is the most "obvious" one).  None if the board is
+        // already filled, or if the current entries lead to no solution.
+        pub fn get_hint(board: &Board) -> Option<(usize, u8)> {
+            let solution = solve(board)?;
+            let (index, _) = most_constrained(board)?;
+            solution.get(index).map(|v| (index, v))
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::solvers::*;
+        use crate::models::board::libsudoku::models::Board;
+
+        // the well-known example puzzle (unique solution, 30 clues)
+        const PUZZLE: &str =
+            "53..7....6..195....98....6.8...6...34..8.3..17...2...6.6....28....419..5....8..79";
+
+        fn parse(s: &str) -> Board {
+            let mut board = Board::new();
+            for (index, c) in s.chars().enumerate() {
+                board.set(index, c.to_digit(10).map(|d| d as u8)).unwrap();
+            }
+            board
+        }
+
+        #[test]
+        fn solves_unique_puzzle() {
+            let board = parse(PUZZLE);
+            let solution = solve(&board).unwrap();
+            assert!(solution.is_filled());
+            assert_eq!(count_solutions(&board, 2), 1);
+            assert_eq!(solution.get_at(0, 2), Some(4));
+        }
+
+        #[test]
+        fn hint_matches_solution() {
+            let board = parse(PUZZLE);
+            let solution = solve(&board).unwrap();
+            let (index, digit) = get_hint(&board).unwrap();
+            assert_eq!(board.get(index), None);
+            assert_eq!(solution.get(index), Some(digit));
+        }
+
+        #[test]
+        fn duplicates_have_no_solution() {
+            let mut board = parse(PUZZLE);
+            board.set_at(0, 2, Some(5)).unwrap(); // 5 is already on row 0
+            assert_eq!(solve(&board), None);
+            assert_eq!(get_hint(&board), None);
+        }
+    }
+}
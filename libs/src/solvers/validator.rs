@@ -0,0 +1,130 @@
+pub mod libsudoku {
+    pub mod solvers {
+        use crate::models::board::libsudoku::models::{Board, BOARD_SIZE, BOARD_WIDTH};
+
+        // See resolver/README.md "Validations": for each row, column and 3x3 block, verify that
+        // there are no duplicate digits, and if all cells are filled, that it totals 45 (rule of 45)
+        #[derive(Clone, PartialEq, Eq, Debug)]
+        pub struct Validation {
+            pub conflicting_indices: Vec<usize>, // every cell that shares a duplicate digit
+            pub digit_counts: [u8; BOARD_WIDTH], // how many of digit N (index N-1) are on the board
+            pub is_filled: bool,
+        }
+        impl Validation {
+            pub fn is_valid(&self) -> bool {
+                self.conflicting_indices.is_empty()
+            }
+            // valid and filled means solved
+            pub fn is_solved(&self) -> bool {
+                self.is_valid() && self.is_filled
+            }
+        }
+
+        fn find_duplicates(board: &Board, group: &[usize; BOARD_WIDTH]) -> Vec<usize> {
+            group
+                .iter()
+                .copied()
+                .filter(|index| match board.get(*index) {
+                    Some(v) => group
+                        .iter()
+                        .any(|other| other != index && board.get(*other) == Some(v)),
+                    None => false,
+                })
+                .collect()
+        }
+
+        pub fn validate(board: &Board) -> Validation {
+            let mut conflicts = [false; BOARD_SIZE];
+            for n in 0..BOARD_WIDTH {
+                let groups = [
+                    Board::row_indices(n),
+                    Board::col_indices(n),
+                    Board::block_indices(n),
+                ];
+                for group in groups.iter() {
+                    for index in find_duplicates(board, group) {
+                        conflicts[index] = true;
+                    }
+                    // rule of 45 can only be applied to filled groups; if there are no duplicates,
+                    // it should always be 45, so this is more of a sanity check
+                    if group.iter().all(|i| board.get(*i).is_some()) {
+                        let sum: u32 = group.iter().map(|i| board.get(*i).unwrap() as u32).sum();
+                        if sum != 45 {
+                            for index in group.iter() {
+                                conflicts[*index] = true;
+                            }
+                        }
+                    }
+                }
+            }
+
+            let mut digit_counts = [0u8; BOARD_WIDTH];
+            for v in board.cells().iter().flatten() {
+                digit_counts[(*v - 1) as usize] += 1;
+            }
+
+            Validation {
+                conflicting_indices: (0..BOARD_SIZE).filter(|i| conflicts[*i]).collect(),
+                digit_counts,
+                is_filled: board.is_filled(),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::solvers::*;
+        use crate::generators::from_file::libsudoku::generators::parse_puzzle;
+
+        // the well-known example puzzle (30 clues), and its solution
+        const PUZZLE: &str =
+            "53..7....6..195....98....6.8...6...34..8.3..17...2...6.6....28....419..5....8..79";
+        const SOLUTION: &str =
+            "534678912672195348198342567859761423426853791713924856961537284287419635345286179";
+
+        #[test]
+        fn partial_board_is_valid_but_not_solved() {
+            let validation = validate(&parse_puzzle(PUZZLE).unwrap());
+            assert!(validation.is_valid());
+            assert!(validation.conflicting_indices.is_empty());
+            assert!(!validation.is_filled);
+            assert!(!validation.is_solved());
+            assert_eq!(validation.digit_counts, [3, 2, 3, 2, 3, 5, 3, 5, 4]);
+        }
+
+        #[test]
+        fn duplicates_flag_only_conflicting_indices() {
+            // each one only shares a row, a column or a block with the clue it duplicates
+            for (row, col, digit, expected_indices) in [
+                (0, 2, 7, vec![2, 4]),  // row 0 already has 7 at (0, 4)
+                (0, 3, 4, vec![3, 66]), // column 3 already has 4 at (7, 3)
+                (0, 2, 6, vec![2, 9]),  // block 0 already has 6 at (1, 0)
+            ] {
+                let mut board = parse_puzzle(PUZZLE).unwrap();
+                board.set_at(row, col, Some(digit)).unwrap();
+                let validation = validate(&board);
+                assert!(!validation.is_valid());
+                assert!(!validation.is_solved());
+                assert_eq!(validation.conflicting_indices, expected_indices);
+            }
+        }
+
+        #[test]
+        fn solved_board() {
+            let validation = validate(&parse_puzzle(SOLUTION).unwrap());
+            assert!(validation.is_valid());
+            assert!(validation.is_filled);
+            assert!(validation.is_solved());
+            assert_eq!(validation.digit_counts, [9; 9]);
+
+            // swapping two digits of a row keeps the rows valid, but not the columns
+            let mut board = parse_puzzle(SOLUTION).unwrap();
+            board.set_at(0, 0, Some(3)).unwrap();
+            board.set_at(0, 1, Some(5)).unwrap();
+            let validation = validate(&board);
+            assert!(validation.is_filled);
+            assert!(!validation.is_solved());
+            assert_eq!(validation.digit_counts, [9; 9]);
+        }
+    }
+}
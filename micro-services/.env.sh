@@ -5,6 +5,9 @@
 # of THIS file and should work just fine.
 set -o nounset
 
+# NOTE: The relay reads the same keys from its (optional) TOML config file and command line flags
+# too, see oauth_relay_service/README.md for which one wins
+
 # NOTE: DO _NOT_ commit this file to the repository IF it contains sensitive information!
 # This file should NEVER be edited, it's here to express/define environment variables
 # needed by Docker-compose, etc, and placed with default value.
@@ -29,24 +32,70 @@ GOOGLE_CLIENT_SECRET=your_google_client_secret
 # dev hosts...  On Docker-compose based, I think it still works as well
 # due to port-forwarding, though I've never been successful with that...
 REST_PORT=8080
+# Where the relay listens (BIND_ADDRESS:REST_PORT), and with how many workers (empty means one per
+# CPU); set both TLS_CERT_PATH and TLS_KEY_PATH (PEM) for it to serve HTTPS itself, in which case
+# every <NAME>_REDIRECT_URI has to be https:// as well (the relay refuses to start otherwise)
+# `kill -HUP` the relay to pick up a renewed certificate; TLS_SELF_SIGNED=true instead of the pair
+# serves a throw-away certificate for localhost (dev only), and HTTP_REDIRECT_PORT (TLS only)
+# answers plain HTTP on that port with a redirect to https://...:REST_PORT
+BIND_ADDRESS=0.0.0.0
+HTTP_WORKERS=
+TLS_CERT_PATH=
+TLS_KEY_PATH=
+TLS_SELF_SIGNED=
+HTTP_REDIRECT_PORT=
 GOOGLE_REDIRECT_URI=http://localhost:${REST_PORT}/auth_callback
 
+# Which OAuth2 providers the player can login with (/login?provider=github), comma separated, in
+# which the FIRST is the default (/login without ?provider=).  Each one needs its own
+# <NAME>_CLIENT_ID, <NAME>_CLIENT_SECRET and <NAME>_REDIRECT_URI (same /auth_callback is fine),
+# and optionally <NAME>_SCOPE; besides google, github and microsoft (MICROSOFT_TENANT, defaults to
+# 'common'), any other name is a generic OIDC provider discovered via <NAME>_ISSUER_URL, i.e.:
+#   export OAUTH2_PROVIDERS="google,github,okta"
+#   export GITHUB_CLIENT_ID="abcdef"
+#   export GITHUB_CLIENT_SECRET="123456"
+#   export GITHUB_REDIRECT_URI=http://localhost:8080/auth_callback
+#   export OKTA_ISSUER_URL="https://my-org.okta.com"
+#   ...
+# GOOGLE_ISSUER_URL (and MICROSOFT_ISSUER_URL) can point google (microsoft) at another issuer,
+# i.e. a local mock provider so that the whole auth-code flow can run without Google (the relay's
+# own tests do just that, see src/providers/mock.rs)
+OAUTH2_PROVIDERS=google
+
 # Database connection information, for PostgreSQL we need host:port but for sqlite, all we need is the path to the file
 # As for username/passwd for SQL services, it should be at the host access level (i.e. in MySQL, it's via I.P. address)
 # I do agree that CIDN-IP-based access is not the most secure, but I do not wish to over-complicate this project (personally
 # I think stuffing password in a static file here is worse!)
 # NOTE: For SQLite, the directory MUST exist (but if file does not exist, it will be created), and at the same
 # time, if it is within the Docker container, it will be created as a volume (so it will persist)
+# DB_CONNECTION is one of 'sqlite', 'postgres', or 'memory' (volatile, for tests)
 DB_CONNECTION=sqlite
 DB_HOST=localhost
 DB_PORT=5432
 DB_STORAGE_PATH=./data/db.sqlite3
+# PostgreSQL only (DB_PASSWORD is optional, if you must, put it in .env.local)
+DB_USER=postgres
+DB_NAME=oauth_relay
+DB_POOL_SIZE=16
+# Tokens are encrypted at rest (sqlite/postgres) with TOKEN_ENCRYPTION_KEYS, which is a comma separated
+# list of '<key_id>:<base64 32 bytes key>' in which the FIRST is the current key (i.e. generate one via
+#   $ echo "TOKEN_ENCRYPTION_KEYS=k1:$(openssl rand -base64 32)" >> .env.local
+# and to rotate, prepend a new key 'k2:...,k1:...', restart (rows get re-encrypted with k2 at startup), then drop k1)
+# Like GOOGLE_CLIENT_SECRET, set it in .env.local (never commit it)
+# Session tokens (JWT handed to the client on login/keepalive) are signed with SESSION_TOKEN_SIGNING_KEY,
+# a base64 Ed25519 private key (PKCS#8 DER), same key on every relay (i.e. generate one via
+#   $ echo "SESSION_TOKEN_SIGNING_KEY=$(openssl genpkey -algorithm ed25519 -outform DER | base64 -w0)" >> .env.local
+# when not set, the relay generates a throw-away key at startup, good enough for local play only)
+# SESSION_TOKEN_KEY_ID is the "kid" on /.well-known/jwks.json (bump it along with the key when rotating)
+SESSION_TOKEN_KEY_ID=relay-1
+SESSION_TOKEN_ISSUER=oauth_relay_service
 
 # Message broker connection information, for RabbitMQ we need host:port but for Redis, all we need is the path to the file
 # Kafka: 9092
 # RabbitMQ (AMQP): 5672
 # Redis: 6379
 # Make sure the hostname (BROKER_HOST) matches whats on Docker-Compose hostname
+# MQ_CONNECTION can also be 'memory' (single instance, for tests)
 MQ_CONNECTION=kafka
 BROKER_HOST=kafka_auth_messenger
 BROKER_PORT=9092
@@ -64,15 +113,29 @@ source .env.local       # nested source'ing...
 GOOGLE_REDIRECT_URI=http://localhost:${REST_PORT}/auth_callback
 
 export REST_PORT=$REST_PORT
+export BIND_ADDRESS=$BIND_ADDRESS
+export HTTP_WORKERS=$HTTP_WORKERS
+export TLS_CERT_PATH=$TLS_CERT_PATH
+export TLS_KEY_PATH=$TLS_KEY_PATH
+export TLS_SELF_SIGNED=$TLS_SELF_SIGNED
+export HTTP_REDIRECT_PORT=$HTTP_REDIRECT_PORT
 
 export GOOGLE_CLIENT_ID=$GOOGLE_CLIENT_ID
 export GOOGLE_CLIENT_SECRET=$GOOGLE_CLIENT_SECRET
 export GOOGLE_REDIRECT_URI=$GOOGLE_REDIRECT_URI
+export OAUTH2_PROVIDERS=$OAUTH2_PROVIDERS
 
 export DB_CONNECTION=$DB_CONNECTION
 export DB_HOST=$DB_HOST
 export DB_PORT=$DB_PORT
 export DB_STORAGE_PATH=$DB_STORAGE_PATH
+export DB_USER=$DB_USER
+export DB_NAME=$DB_NAME
+export DB_POOL_SIZE=$DB_POOL_SIZE
+export TOKEN_ENCRYPTION_KEYS=${TOKEN_ENCRYPTION_KEYS:-}
+export SESSION_TOKEN_SIGNING_KEY=${SESSION_TOKEN_SIGNING_KEY:-}
+export SESSION_TOKEN_KEY_ID=$SESSION_TOKEN_KEY_ID
+export SESSION_TOKEN_ISSUER=$SESSION_TOKEN_ISSUER
 
 export MQ_CONNECTION=$MQ_CONNECTION
 export BROKER_HOST=$BROKER_HOST
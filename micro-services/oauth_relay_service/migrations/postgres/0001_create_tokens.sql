@@ -0,0 +1,14 @@
+-- V1: tokens table, same schema as SQLite's except types are Postgres flavored (BIGSERIAL for
+-- AUTOINCREMENT, BIGINT for epoch times)
+CREATE TABLE IF NOT EXISTS tokens (
+    session_id      BIGSERIAL PRIMARY KEY,
+    state_token     TEXT NOT NULL UNIQUE,
+
+    client_address  TEXT NOT NULL,
+    client_port     INTEGER NOT NULL,
+    client_email    TEXT,
+
+    access_token    TEXT NOT NULL UNIQUE,
+    refresh_token   TEXT,
+    expires_in      BIGINT NOT NULL,
+    expiry_time     BIGINT NOT NULL);
@@ -0,0 +1,3 @@
+-- V2: which OAuth2 provider (see providers.rs) the session logged in with; sessions from before
+-- providers were pluggable are all Google's
+ALTER TABLE tokens ADD COLUMN provider TEXT NOT NULL DEFAULT 'google';
@@ -0,0 +1,14 @@
+-- V1: tokens table (same as what create_table_token() used to create, hence "IF NOT EXISTS" for
+-- databases created before schema_version existed)
+CREATE TABLE IF NOT EXISTS tokens (
+    session_id      INTEGER PRIMARY KEY AUTOINCREMENT,
+    state_token     TEXT NOT NULL UNIQUE,
+
+    client_address  TEXT NOT NULL,
+    client_port     INTEGER NOT NULL,
+    client_email    TEXT,
+
+    access_token    TEXT NOT NULL UNIQUE,
+    refresh_token   TEXT,
+    expires_in      INTEGER NOT NULL,
+    expiry_time     INTEGER NOT NULL);
@@ -0,0 +1,6 @@
+-- V3: logins in progress (from /login until auth_code_callback()), keyed by state_token, with
+-- their PKCE code_verifier (encrypted, same as the tokens); rows are taken (deleted) by the callback
+CREATE TABLE IF NOT EXISTS login_states (
+    state_token     TEXT PRIMARY KEY,
+    code_verifier   TEXT NOT NULL,
+    created_at      INTEGER NOT NULL);
@@ -0,0 +1,3 @@
+-- V4: login_states are no longer deleted by the callback, but marked used (epoch time), so that a
+-- replayed callback can be told apart from an unknown state; rows are purged by the reaper
+ALTER TABLE login_states ADD COLUMN used_at INTEGER;
@@ -0,0 +1,4 @@
+-- V5: which OAuth2 provider (see providers.rs) the login was started with, so that the callback
+-- takes it from here rather than from the (client-supplied) state; logins in flight from before
+-- are all Google's
+ALTER TABLE login_states ADD COLUMN provider TEXT NOT NULL DEFAULT 'google';
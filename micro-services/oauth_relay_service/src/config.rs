@@ -1,13 +1,5 @@
-use dotenvy::dotenv;
 use serde::{Deserialize, Serialize};
-use std::{
-    env,
-    fs::File,
-    io::{self, Read},
-    net::IpAddr,
-    path::Path,
-    str::FromStr,
-};
+use std::{collections::HashMap, env, fs, io, net::IpAddr, path::Path, str::FromStr};
 
 #[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub enum HostType {
@@ -109,7 +101,12 @@ pub enum DBType {
     PostgresSQL {
         host_as_name_or_address: HostType,
         host_port: u16,
+        db_user: String,
+        possible_db_password: Option<String>, // preferably None, and let pg_hba.conf trust the host
+        db_name: String,
+        pool_size: usize,
     },
+    Memory, // volatile, mainly for tests
 }
 
 #[derive(Clone)]
@@ -130,19 +127,191 @@ pub enum MQType {
         host_as_name_or_address: HostType,
         host_port: u16,
     },
+    Memory, // single instance only, mainly for tests
+}
+
+// Which flavor of OAuth2 the provider speaks (see providers.rs), which decides where the endpoints
+// come from (OIDC discovery document, or well-known ones for non-OIDC providers such as GitHub)
+#[derive(Clone, Debug, PartialEq)]
+pub enum OAuth2ProviderKind {
+    Google,
+    GitHub,
+    Microsoft { tenant: String }, // "common", "organizations", "consumers" or a tenant id
+    Oidc { issuer_url: String }, // any OIDC provider, discovered via <issuer_url>/.well-known/openid-configuration
+}
+
+#[derive(Clone)]
+pub struct OAuth2ProviderConfig {
+    pub name: String, // what /login?provider= refers to, and what the session records
+    pub kind: OAuth2ProviderKind,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String, // all providers can share the same /auth_callback
+    pub possible_scope: Option<String>, // None means the kind's default (see providers.rs)
+    // where Google's/Microsoft's discovery document is fetched from instead of the real one (i.e.
+    // a mock provider on localhost, see providers/mock.rs); ignored for GitHub (not OIDC)
+    pub possible_issuer_url: Option<String>,
+}
+
+// What the relay serves HTTPS with (see web/actix/tls.rs)
+#[derive(Clone, Debug, PartialEq)]
+pub enum TlsConfig {
+    // PEM files (i.e. fullchain.pem and privkey.pem from certbot), re-read on SIGHUP
+    Files { cert_path: String, key_path: String },
+    // generated at startup for localhost, local development only (browsers will complain)
+    SelfSigned,
 }
 
 #[derive(Clone)]
 pub struct Config {
+    // where the HTTP server listens, i.e. 0.0.0.0:8080
+    pub bind_address: IpAddr,
     pub rest_port: u16,
+    // None means actix's default (one per physical CPU)
+    pub possible_http_workers: Option<usize>,
+    // None means plain HTTP (i.e. behind a TLS terminating proxy)
+    pub possible_tls: Option<TlsConfig>,
+    // plain HTTP listener (same bind_address) which only redirects to the HTTPS one
+    pub possible_http_redirect_port: Option<u16>,
 
-    pub google_client_id: String,
-    pub google_client_secret: String,
-    pub google_redirect_uri: String,
+    // first one is the default when /login has no ?provider=
+    pub oauth2_providers: Vec<OAuth2ProviderConfig>,
 
     pub db_connection: DBType,
 
     pub mq_connection: MQType,
+
+    // (key_id, base64 encoded 32 bytes key) used to encrypt tokens at rest (see storage/token_cipher.rs);
+    // first one is the current key, the rest are older keys kept only to decrypt (and re-encrypt)
+    pub token_encryption_keys: Vec<(String, String)>,
+
+    // base64 encoded Ed25519 private key (PKCS#8 DER) which signs the session tokens (JWT, see
+    // session_token.rs); None means a throw-away key is generated at startup (dev only)
+    pub possible_session_token_signing_key: Option<String>,
+    // "kid" of the above key in the JWKS, so that verifiers can tell keys apart when rotating
+    pub session_token_key_id: String,
+    // "iss" claim of the session tokens, verifiers SHOULD check it
+    pub session_token_issuer: String,
+}
+
+// One source of settings, keyed the same way as the environment variables (i.e. "REST_PORT"), so
+// that each layer can override any of the others' keys (see Config::load())
+pub type ConfigLayer = HashMap<String, String>;
+
+// where Config::load() looks for the TOML file when neither --config nor CONFIG_FILE says otherwise
+// (optional, unlike the one asked for explicitly)
+const DEFAULT_CONFIG_FILE: &str = "oauth_relay.toml";
+
+// the bottom layer, whatever is not set anywhere else
+const CONFIG_DEFAULTS: &[(&str, &str)] = &[
+    ("BIND_ADDRESS", "0.0.0.0"),
+    ("REST_PORT", "8080"),
+    ("OAUTH2_PROVIDERS", "google"), // Google only, unless told otherwise
+    ("MICROSOFT_TENANT", "common"),
+    ("DB_USER", "postgres"),
+    ("DB_NAME", "oauth_relay"),
+    ("DB_POOL_SIZE", "16"),
+    ("SESSION_TOKEN_KEY_ID", "relay-1"),
+    ("SESSION_TOKEN_ISSUER", "oauth_relay_service"),
+];
+
+// Every missing and invalid key found while loading, so that they can all be fixed in one go
+// rather than one restart at a time
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<String>);
+impl std::fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} configuration error(s):", self.0.len())?;
+        for error in self.0.iter() {
+            write!(f, "\n  - {}", error)?;
+        }
+        Ok(())
+    }
+}
+impl std::error::Error for ConfigErrors {}
+
+// All layers merged (last one wins), and whatever went wrong reading them so far
+struct ConfigReader {
+    settings: ConfigLayer,
+    errors: Vec<String>,
+}
+
+impl ConfigReader {
+    fn new(layers: Vec<ConfigLayer>) -> Self {
+        let mut settings = ConfigLayer::new();
+        for layer in layers {
+            settings.extend(layer);
+        }
+        ConfigReader {
+            settings,
+            errors: Vec::new(),
+        }
+    }
+
+    // empty is the same as not set (i.e. "export DB_PASSWORD=" in .env.sh)
+    fn get(&self, key: &str) -> Option<String> {
+        self.settings
+            .get(key)
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    }
+
+    fn require(&mut self, key: &str) -> String {
+        match self.get(key) {
+            Some(value) => value,
+            None => {
+                self.errors.push(format!("{} must be set", key));
+                String::new()
+            }
+        }
+    }
+
+    fn parse<T: FromStr + Default>(&mut self, key: &str, expected: &str) -> T {
+        match self.get(key) {
+            Some(value) => self.parse_value(key, &value, expected),
+            None => {
+                self.errors.push(format!("{} must be set", key));
+                T::default()
+            }
+        }
+    }
+
+    fn parse_value<T: FromStr + Default>(&mut self, key: &str, value: &str, expected: &str) -> T {
+        match value.parse() {
+            Ok(parsed) => parsed,
+            Err(_) => {
+                self.errors
+                    .push(format!("{} must be {} (got '{}')", key, expected, value));
+                T::default()
+            }
+        }
+    }
+
+    // not set is fine, but if it is, it has to parse
+    fn parse_optional<T: FromStr + Default>(&mut self, key: &str, expected: &str) -> Option<T> {
+        self.get(key)
+            .map(|value| self.parse_value(key, &value, expected))
+    }
+
+    // like parse(), for the types without a Default (the default is in CONFIG_DEFAULTS anyways)
+    fn parse_or<T: FromStr>(&mut self, key: &str, expected: &str, fallback: T) -> T {
+        match self.get(key).map(|value| (value.parse(), value)) {
+            Some((Ok(parsed), _)) => parsed,
+            Some((Err(_), value)) => {
+                self.errors
+                    .push(format!("{} must be {} (got '{}')", key, expected, value));
+                fallback
+            }
+            None => {
+                self.errors.push(format!("{} must be set", key));
+                fallback
+            }
+        }
+    }
+
+    fn invalid(&mut self, error: String) {
+        self.errors.push(error);
+    }
 }
 
 impl Config {
@@ -189,106 +358,737 @@ impl Config {
         ret_resolved
     }
 
-    fn make_db_type(db_type: &str) -> DBType {
-        match db_type {
+    // no point resolving a host that is not set (already reported)
+    fn make_host(reader: &mut ConfigReader, key: &str) -> HostType {
+        match reader.require(key) {
+            host if host.is_empty() => HostType::HostAsName(host),
+            host => Self::parse_to_ip_address(host.as_str()),
+        }
+    }
+
+    fn make_db_type(reader: &mut ConfigReader) -> DBType {
+        match reader.require("DB_CONNECTION").as_str() {
             "sqlite" => DBType::SQLite {
-                db_path: env::var("DB_STORAGE_PATH").expect("DB_STORAGE_PATH must be set"),
+                db_path: reader.require("DB_STORAGE_PATH"),
             },
             "postgres" => DBType::PostgresSQL {
-                host_as_name_or_address: Self::parse_to_ip_address(
-                    env::var("DB_HOST").expect("DB_HOST must be set").as_str(),
-                ),
-                host_port: env::var("DB_PORT")
-                    .expect("DB_PORT must be set")
-                    .parse()
-                    .expect("DB_PORT must be a valid port number"),
+                host_as_name_or_address: Self::make_host(reader, "DB_HOST"),
+                host_port: reader.parse("DB_PORT", "a valid port number"),
+                db_user: reader.require("DB_USER"),
+                possible_db_password: reader.get("DB_PASSWORD"),
+                db_name: reader.require("DB_NAME"),
+                pool_size: reader.parse("DB_POOL_SIZE", "a valid number"),
             },
-            _ => panic!("DB_CONNECTION must be either 'sqlite' or 'postgres'"),
+            "memory" => DBType::Memory,
+            "" => DBType::Memory, // not set, already reported
+            db_connection => {
+                reader.invalid(format!(
+                    "DB_CONNECTION must be either 'sqlite', 'postgres', or 'memory' (got '{}')",
+                    db_connection
+                ));
+                DBType::Memory
+            }
         }
     }
 
-    fn make_mq_type(mq_type: &str) -> MQType {
-        match mq_type {
-            "kafka" => MQType::Kafka {
-                host_as_name_or_address: Self::parse_to_ip_address(
-                    env::var("BROKER_HOST")
-                        .expect("BROKER_HOST must be set")
-                        .as_str(),
-                ),
-                host_port: env::var("BROKER_PORT")
-                    .expect("BROKER_PORT must be set")
-                    .parse()
-                    .expect("BROKER_PORT must be a valid port number"),
-            },
-            "rabbitmq" => MQType::RabbitMQ {
-                host_as_name_or_address: Self::parse_to_ip_address(
-                    env::var("BROKER_HOST")
-                        .expect("BROKER_HOST must be set")
-                        .as_str(),
-                ),
-                host_port: env::var("BROKER_PORT")
-                    .expect("BROKER_PORT must be set")
-                    .parse()
-                    .expect("BROKER_PORT must be a valid port number"),
-            },
-            "redis" => MQType::Redis {
-                host_as_name_or_address: Self::parse_to_ip_address(
-                    env::var("BROKER_HOST")
-                        .expect("BROKER_HOST must be set")
-                        .as_str(),
-                ),
-                host_port: env::var("BROKER_PORT")
-                    .expect("BROKER_PORT must be set")
-                    .parse()
-                    .expect("BROKER_PORT must be a valid port number"),
-            },
-            "mongodb" => MQType::MongoDB {
-                host_as_name_or_address: Self::parse_to_ip_address(
-                    env::var("BROKER_HOST")
-                        .expect("BROKER_HOST must be set")
-                        .as_str(),
-                ),
-                host_port: env::var("BROKER_PORT")
-                    .expect("BROKER_PORT must be set")
-                    .parse()
-                    .expect("BROKER_PORT must be a valid port number"),
-            },
-            _ => panic!("MQ_CONNECTION must be either 'kafka', 'rabbitmq', 'redis', or 'mongodb'"),
+    fn make_mq_type(reader: &mut ConfigReader) -> MQType {
+        let mq_connection = reader.require("MQ_CONNECTION");
+        let broker = |reader: &mut ConfigReader| -> (HostType, u16) {
+            (
+                Self::make_host(reader, "BROKER_HOST"),
+                reader.parse("BROKER_PORT", "a valid port number"),
+            )
+        };
+        match mq_connection.as_str() {
+            "kafka" => {
+                let (host_as_name_or_address, host_port) = broker(reader);
+                MQType::Kafka {
+                    host_as_name_or_address,
+                    host_port,
+                }
+            }
+            "rabbitmq" => {
+                let (host_as_name_or_address, host_port) = broker(reader);
+                MQType::RabbitMQ {
+                    host_as_name_or_address,
+                    host_port,
+                }
+            }
+            "redis" => {
+                let (host_as_name_or_address, host_port) = broker(reader);
+                MQType::Redis {
+                    host_as_name_or_address,
+                    host_port,
+                }
+            }
+            "mongodb" => {
+                let (host_as_name_or_address, host_port) = broker(reader);
+                MQType::MongoDB {
+                    host_as_name_or_address,
+                    host_port,
+                }
+            }
+            "memory" => MQType::Memory,
+            "" => MQType::Memory, // not set, already reported
+            mq_connection => {
+                reader.invalid(format!(
+                    "MQ_CONNECTION must be either 'kafka', 'rabbitmq', 'redis', 'mongodb', or 'memory' (got '{}')",
+                    mq_connection
+                ));
+                MQType::Memory
+            }
+        }
+    }
+
+    // i.e. OAUTH2_PROVIDERS="google,github,microsoft,okta" in which each <NAME> needs:
+    //  - <NAME>_CLIENT_ID, <NAME>_CLIENT_SECRET and <NAME>_REDIRECT_URI (i.e. GOOGLE_CLIENT_ID)
+    //  - optionally <NAME>_SCOPE (space separated)
+    //  - for MICROSOFT, optionally MICROSOFT_TENANT (defaults to "common")
+    //  - for anything other than google/github/microsoft, <NAME>_ISSUER_URL (generic OIDC)
+    //  - for google/microsoft, optionally <NAME>_ISSUER_URL to discover the endpoints elsewhere
+    fn make_oauth2_providers(reader: &mut ConfigReader) -> Vec<OAuth2ProviderConfig> {
+        let provider_names = reader.require("OAUTH2_PROVIDERS");
+        let provider_configs: Vec<OAuth2ProviderConfig> = provider_names
+            .split(',')
+            .map(|s| s.trim().to_lowercase())
+            .filter(|s| !s.is_empty())
+            .map(|name| {
+                let env_prefix = name.to_uppercase().replace('-', "_");
+                let key = |suffix: &str| format!("{}_{}", env_prefix, suffix);
+                let kind = match name.as_str() {
+                    "google" => OAuth2ProviderKind::Google,
+                    "github" => OAuth2ProviderKind::GitHub,
+                    "microsoft" => OAuth2ProviderKind::Microsoft {
+                        tenant: reader.require("MICROSOFT_TENANT"),
+                    },
+                    _ => OAuth2ProviderKind::Oidc {
+                        issuer_url: reader.require(&key("ISSUER_URL")),
+                    },
+                };
+                OAuth2ProviderConfig {
+                    client_id: reader.require(&key("CLIENT_ID")),
+                    client_secret: reader.require(&key("CLIENT_SECRET")),
+                    redirect_uri: reader.require(&key("REDIRECT_URI")),
+                    possible_scope: reader.get(&key("SCOPE")),
+                    possible_issuer_url: reader.get(&key("ISSUER_URL")),
+                    name,
+                    kind,
+                }
+            })
+            .collect();
+        if provider_configs.is_empty() && !provider_names.is_empty() {
+            reader.invalid(
+                "OAUTH2_PROVIDERS must list at least one provider (i.e. 'google')".to_string(),
+            );
+        }
+        provider_configs
+    }
+
+    // both or neither, half a TLS setup is a typo rather than plain HTTP; and either the files or
+    // TLS_SELF_SIGNED=true, not both
+    fn make_tls_config(reader: &mut ConfigReader) -> Option<TlsConfig> {
+        let is_self_signed = reader
+            .parse_optional("TLS_SELF_SIGNED", "either 'true' or 'false'")
+            .unwrap_or(false);
+        match (
+            reader.get("TLS_CERT_PATH"),
+            reader.get("TLS_KEY_PATH"),
+            is_self_signed,
+        ) {
+            (Some(_), _, true) | (_, Some(_), true) => {
+                reader.invalid(
+                    "TLS_SELF_SIGNED cannot be used along with TLS_CERT_PATH and TLS_KEY_PATH"
+                        .to_string(),
+                );
+                None
+            }
+            (Some(cert_path), Some(key_path), false) => Some(TlsConfig::Files {
+                cert_path,
+                key_path,
+            }),
+            (None, None, true) => Some(TlsConfig::SelfSigned),
+            (None, None, false) => None,
+            _ => {
+                reader.invalid("TLS_CERT_PATH and TLS_KEY_PATH must be set together".to_string());
+                None
+            }
+        }
+    }
+
+    // Settings which are fine on their own, but not together (or not with what's on disk), so
+    // that the relay refuses to start rather than fail on the first request
+    fn check_conflicts(config: &Config, reader: &mut ConfigReader) {
+        if config.possible_http_workers == Some(0) {
+            reader.invalid("HTTP_WORKERS must be at least 1".to_string());
+        }
+        match (config.possible_http_redirect_port, &config.possible_tls) {
+            (Some(_), None) => reader.invalid(
+                "HTTP_REDIRECT_PORT needs TLS (TLS_CERT_PATH and TLS_KEY_PATH, or TLS_SELF_SIGNED)"
+                    .to_string(),
+            ),
+            (Some(http_redirect_port), Some(_)) if http_redirect_port == config.rest_port => reader
+                .invalid(format!(
+                    "HTTP_REDIRECT_PORT and REST_PORT cannot both be {}",
+                    config.rest_port
+                )),
+            _ => {}
+        }
+        if let Some(TlsConfig::Files {
+            cert_path,
+            key_path,
+        }) = &config.possible_tls
+        {
+            for (key, path) in [("TLS_CERT_PATH", cert_path), ("TLS_KEY_PATH", key_path)] {
+                if !Path::new(path).is_file() {
+                    reader.invalid(format!("{} '{}' is not a file", key, path));
+                }
+            }
+        }
+        // the directory has to be there, the file gets created (see .env.sh)
+        if let DBType::SQLite { db_path } = &config.db_connection {
+            let db_path = Path::new(db_path);
+            if db_path.is_dir() {
+                reader.invalid(format!(
+                    "DB_STORAGE_PATH '{}' is a directory, not a file",
+                    db_path.display()
+                ));
+            } else if let Some(db_dir) = db_path.parent().filter(|dir| !dir.as_os_str().is_empty())
+            {
+                if !db_dir.is_dir() {
+                    reader.invalid(format!(
+                        "DB_STORAGE_PATH directory '{}' does not exist",
+                        db_dir.display()
+                    ));
+                }
+            }
+        }
+        // the provider redirects the player's browser to us, so on this host it has to be how
+        // we're listening (the rest may well be a proxy in front of us, can't tell)
+        let scheme = match config.possible_tls {
+            Some(_) => "https",
+            None => "http",
+        };
+        for provider_config in config.oauth2_providers.iter() {
+            let key = format!(
+                "{}_REDIRECT_URI",
+                provider_config.name.to_uppercase().replace('-', "_")
+            );
+            if provider_config.redirect_uri.is_empty() {
+                continue; // not set, already reported
+            }
+            let redirect_uri = match reqwest::Url::parse(&provider_config.redirect_uri) {
+                Ok(redirect_uri) => redirect_uri,
+                Err(e) => {
+                    reader.invalid(format!(
+                        "{} must be a URL (got '{}'): {}",
+                        key, provider_config.redirect_uri, e
+                    ));
+                    continue;
+                }
+            };
+            if config.possible_tls.is_some() && redirect_uri.scheme() != "https" {
+                reader.invalid(format!(
+                    "{} must be https:// since the relay serves TLS (got '{}')",
+                    key, provider_config.redirect_uri
+                ));
+            }
+            let is_local = match redirect_uri.host_str() {
+                Some("localhost") => true,
+                Some(host) => host
+                    .trim_matches(|c| c == '[' || c == ']') // IPv6
+                    .parse::<IpAddr>()
+                    .map(|ip| ip.is_loopback())
+                    .unwrap_or(false),
+                None => false,
+            };
+            if is_local
+                && redirect_uri.scheme() == scheme
+                && redirect_uri.port_or_known_default() != Some(config.rest_port)
+            {
+                reader.invalid(format!(
+                    "{} '{}' points at this host, but REST_PORT is {}",
+                    key, provider_config.redirect_uri, config.rest_port
+                ));
+            }
+        }
+    }
+
+    // i.e. TOKEN_ENCRYPTION_KEYS="k2:<base64 key>,k1:<base64 key>" (new key first when rotating)
+    fn make_token_encryption_keys(reader: &mut ConfigReader) -> Vec<(String, String)> {
+        let keys = reader.get("TOKEN_ENCRYPTION_KEYS").unwrap_or_default();
+        keys.split(',')
+            .map(|s| s.trim())
+            .filter(|s| !s.is_empty())
+            .filter_map(|key_id_and_key| match key_id_and_key.split_once(':') {
+                Some((key_id, key)) => Some((key_id.trim().to_string(), key.trim().to_string())),
+                None => {
+                    reader.invalid(
+                        "TOKEN_ENCRYPTION_KEYS must be comma separated '<key_id>:<base64 key>'"
+                            .to_string(),
+                    );
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // Builds the Config out of the layers (last one wins), reporting every missing/invalid key at
+    // once rather than just the first one
+    pub fn from_layers(layers: Vec<ConfigLayer>) -> Result<Self, ConfigErrors> {
+        let mut reader = ConfigReader::new(layers);
+        let config = Config {
+            bind_address: reader.parse_or(
+                "BIND_ADDRESS",
+                "an IP address",
+                IpAddr::from([0, 0, 0, 0]),
+            ),
+            rest_port: reader.parse("REST_PORT", "a valid port number"),
+            possible_http_workers: reader.parse_optional("HTTP_WORKERS", "a number of workers"),
+            possible_tls: Self::make_tls_config(&mut reader),
+            possible_http_redirect_port: reader
+                .parse_optional("HTTP_REDIRECT_PORT", "a valid port number"),
+
+            oauth2_providers: Self::make_oauth2_providers(&mut reader),
+
+            db_connection: Self::make_db_type(&mut reader),
+
+            mq_connection: Self::make_mq_type(&mut reader),
+
+            // optional here since the memory backend has nothing at rest, but SQLite/Postgres
+            // refuse to start without it (see storage::open_token_store_from_config())
+            token_encryption_keys: Self::make_token_encryption_keys(&mut reader),
+
+            // i.e. SESSION_TOKEN_SIGNING_KEY=$(openssl genpkey -algorithm ed25519 -outform DER | base64 -w0)
+            possible_session_token_signing_key: reader.get("SESSION_TOKEN_SIGNING_KEY"),
+            session_token_key_id: reader.require("SESSION_TOKEN_KEY_ID"),
+            session_token_issuer: reader.require("SESSION_TOKEN_ISSUER"),
+        };
+        Self::check_conflicts(&config, &mut reader);
+        match reader.errors.is_empty() {
+            true => Ok(config),
+            false => Err(ConfigErrors(reader.errors)),
+        }
+    }
+
+    // Layered, from the bottom up (each one overrides the ones before it):
+    //  1. CONFIG_DEFAULTS
+    //  2. TOML file (--config <path>, or CONFIG_FILE, or else DEFAULT_CONFIG_FILE if there is one)
+    //  3. '.env' file on the current directory, if there is one
+    //  4. environment variables
+    //  5. command line flags (i.e. --rest-port 8081, see cli_layer())
+    // Meant to be called once (see main()), the rest get it via web::Data<Config>
+    pub fn load() -> Result<Self, ConfigErrors> {
+        let mut errors = Vec::new();
+        let args: Vec<String> = env::args().skip(1).collect();
+        let cli_layer = Self::cli_layer(&args).unwrap_or_else(|e| {
+            errors.push(e);
+            ConfigLayer::new()
+        });
+        let env_layer: ConfigLayer = env::vars().collect();
+
+        // dump current dir so we know which files it loaded:
+        println!("Config: Loading from {:?}", env::current_dir());
+        let possible_config_file = match cli_layer.get("CONFIG").or(env_layer.get("CONFIG_FILE")) {
+            Some(config_file) => Some(config_file.clone()),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Some(DEFAULT_CONFIG_FILE.to_string())
+            }
+            None => None,
+        };
+        let toml_layer = match &possible_config_file {
+            Some(config_file) => fs::read_to_string(config_file)
+                .map_err(|e| format!("Cannot read config file '{}': {}", config_file, e))
+                .and_then(|toml_str| Self::toml_layer(&toml_str)),
+            None => Ok(ConfigLayer::new()),
+        }
+        .unwrap_or_else(|e| {
+            errors.push(e);
+            ConfigLayer::new()
+        });
+        let dotenv_layer = Self::dotenv_layer(Path::new(".env")).unwrap_or_else(|e| {
+            errors.push(e);
+            ConfigLayer::new()
+        });
+
+        let layers = vec![
+            Self::default_layer(),
+            toml_layer,
+            dotenv_layer,
+            env_layer,
+            cli_layer,
+        ];
+        match (Self::from_layers(layers), errors.is_empty()) {
+            (Ok(config), true) => Ok(config),
+            (Ok(_), false) => Err(ConfigErrors(errors)),
+            (Err(ConfigErrors(more_errors)), _) => {
+                errors.extend(more_errors);
+                Err(ConfigErrors(errors))
+            }
+        }
+    }
+
+    pub fn default_layer() -> ConfigLayer {
+        CONFIG_DEFAULTS
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    // Flat keys are the environment variable names in lower case, and tables prefix their keys
+    // (i.e. [google] client_id = "..." is GOOGLE_CLIENT_ID); arrays are comma separated lists:
+    //      rest_port = 8080
+    //      oauth2_providers = ["google", "github"]
+    //      [db]
+    //      connection = "sqlite"
+    //      storage_path = "./data/db.sqlite3"
+    pub fn toml_layer(toml_str: &str) -> Result<ConfigLayer, String> {
+        fn flatten(
+            layer: &mut ConfigLayer,
+            prefix: &str,
+            table: &toml::Table,
+        ) -> Result<(), String> {
+            for (key, value) in table.iter() {
+                let key = match prefix.is_empty() {
+                    true => key.to_uppercase().replace('-', "_"),
+                    false => format!("{}_{}", prefix, key.to_uppercase().replace('-', "_")),
+                };
+                let value = match value {
+                    toml::Value::Table(table) => {
+                        flatten(layer, &key, table)?;
+                        continue;
+                    }
+                    toml::Value::String(s) => s.clone(),
+                    toml::Value::Array(values) => values
+                        .iter()
+                        .map(|value| match value {
+                            toml::Value::String(s) => Ok(s.clone()),
+                            toml::Value::Table(_) | toml::Value::Array(_) => {
+                                Err(format!("{} must be a list of plain values", key))
+                            }
+                            value => Ok(value.to_string()),
+                        })
+                        .collect::<Result<Vec<String>, String>>()?
+                        .join(","),
+                    value => value.to_string(),
+                };
+                layer.insert(key, value);
+            }
+            Ok(())
+        }
+        let table: toml::Table = toml_str
+            .parse()
+            .map_err(|e| format!("Invalid TOML config: {}", e))?;
+        let mut layer = ConfigLayer::new();
+        flatten(&mut layer, "", &table)?;
+        Ok(layer)
+    }
+
+    // same KEY=value file as before, only no longer required, and without touching the environment
+    pub fn dotenv_layer(path: &Path) -> Result<ConfigLayer, String> {
+        if !path.exists() {
+            return Ok(ConfigLayer::new());
         }
+        dotenvy::from_path_iter(path)
+            .and_then(|iter| iter.collect::<Result<ConfigLayer, _>>())
+            .map_err(|e| format!("Invalid '{}' file: {}", path.display(), e))
     }
 
-    // ASSUMES that '.env' file is in the same directory as the executable and/or the
-    // calling host already have set the dir where .evn file resides
-    pub fn from_local_env_file() -> Self {
-        // dump current dir so we know which .env it loaded:
-        println!("Loading '.env' file from {:?}", env::current_dir());
-
-        //dotenv::from_path(absolute_path_with_filename).ok();
-        //dotenv::from_filename(".env").ok();
-        dotenvy::dotenv().unwrap(); // assumes file is ".env" on current directory
-        Config {
-            rest_port: env::var("REST_PORT")
-                .expect("REST_PORT must be set")
-                .parse()
-                .expect("REST_PORT must be a valid port number"),
-
-            google_client_id: env::var("GOOGLE_CLIENT_ID").expect("GOOGLE_CLIENT_ID must be set"),
-            google_client_secret: env::var("GOOGLE_CLIENT_SECRET")
-                .expect("GOOGLE_CLIENT_SECRET must be set"),
-            google_redirect_uri: env::var("GOOGLE_REDIRECT_URI")
-                .expect("GOOGLE_REDIRECT_URI must be set"),
-
-            db_connection: Self::make_db_type(
-                env::var("DB_CONNECTION")
-                    .expect("DB_CONNECTION must be set")
-                    .as_str(),
+    // --<key> <value> or --<key>=<value>, in which key is the environment variable name in kebab
+    // case (i.e. --db-connection memory is DB_CONNECTION=memory), --config being the TOML file
+    pub fn cli_layer(args: &[String]) -> Result<ConfigLayer, String> {
+        let mut layer = ConfigLayer::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let flag = match arg.strip_prefix("--") {
+                Some(flag) if !flag.is_empty() => flag,
+                _ => {
+                    return Err(format!(
+                        "Unexpected argument '{}', expected --<key> <value>",
+                        arg
+                    ))
+                }
+            };
+            let (key, value) = match flag.split_once('=') {
+                Some((key, value)) => (key, value.to_string()),
+                None => match args.next() {
+                    Some(value) => (flag, value.clone()),
+                    None => return Err(format!("Flag '--{}' has no value", flag)),
+                },
+            };
+            layer.insert(key.to_uppercase().replace('-', "_"), value);
+        }
+        Ok(layer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_layer(settings: &[(&str, &str)]) -> ConfigLayer {
+        settings
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn make_memory_layer() -> ConfigLayer {
+        make_layer(&[
+            ("DB_CONNECTION", "memory"),
+            ("MQ_CONNECTION", "memory"),
+            ("GOOGLE_CLIENT_ID", "test_client_id"),
+            ("GOOGLE_CLIENT_SECRET", "test_client_secret"),
+            (
+                "GOOGLE_REDIRECT_URI",
+                "https://relay.example.com/auth_callback",
             ),
+        ])
+    }
+
+    // an empty file is good enough for the checks, it's actix that reads them
+    fn make_temp_file(name: &str) -> String {
+        let path =
+            env::temp_dir().join(format!("oauth_relay_test_{}_{}", std::process::id(), name));
+        fs::write(&path, "").unwrap();
+        path.display().to_string()
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let toml_layer = Config::toml_layer(
+            r#"
+            rest_port = 8081
+            oauth2_providers = ["google", "github"]
+            [github]
+            client_id = "github_client_id"
+            client_secret = "github_client_secret"
+            redirect_uri = "https://relay.example.com/auth_callback"
+            "#,
+        )
+        .unwrap();
+        let dotenv_path =
+            env::temp_dir().join(format!("oauth_relay_test_{}.env", std::process::id()));
+        fs::write(
+            &dotenv_path,
+            "REST_PORT=8082\nSESSION_TOKEN_ISSUER=from_dotenv\n",
+        )
+        .unwrap();
+        let dotenv_layer = Config::dotenv_layer(&dotenv_path).unwrap();
+        fs::remove_file(&dotenv_path).unwrap();
+        let env_layer = make_layer(&[("REST_PORT", "8083"), ("SESSION_TOKEN_KEY_ID", "from_env")]);
+        let cli_layer =
+            Config::cli_layer(&["--rest-port".to_string(), "8084".to_string()]).unwrap();
 
-            mq_connection: Self::make_mq_type(
-                env::var("MQ_CONNECTION")
-                    .expect("MQ_CONNECTION must be set")
-                    .as_str(),
+        let config = Config::from_layers(vec![
+            Config::default_layer(),
+            make_memory_layer(),
+            toml_layer,
+            dotenv_layer,
+            env_layer,
+            cli_layer,
+        ])
+        .unwrap();
+        assert_eq!(config.rest_port, 8084);
+        assert_eq!(config.session_token_key_id, "from_env");
+        assert_eq!(config.session_token_issuer, "from_dotenv");
+        let provider_names: Vec<&str> = config
+            .oauth2_providers
+            .iter()
+            .map(|provider_config| provider_config.name.as_str())
+            .collect();
+        assert_eq!(provider_names, vec!["google", "github"]);
+        assert_eq!(config.oauth2_providers[1].client_id, "github_client_id");
+        assert!(matches!(config.db_connection, DBType::Memory));
+        // no .env is fine, it's just one layer less
+        assert!(Config::dotenv_layer(&dotenv_path).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_every_missing_and_invalid_key_at_once() {
+        let errors = match Config::from_layers(vec![
+            Config::default_layer(),
+            make_layer(&[
+                ("REST_PORT", "eighty"),
+                ("OAUTH2_PROVIDERS", "google,okta"),
+                ("DB_CONNECTION", "postgres"),
+                ("DB_HOST", "127.0.0.1"),
+                ("MQ_CONNECTION", "carrier_pigeon"),
+                ("TOKEN_ENCRYPTION_KEYS", "no_key_id"),
+            ]),
+        ]) {
+            Ok(_) => panic!("should not have loaded"),
+            Err(ConfigErrors(errors)) => errors,
+        };
+        for expected in [
+            "REST_PORT must be a valid port number (got 'eighty')",
+            "GOOGLE_CLIENT_ID must be set",
+            "GOOGLE_CLIENT_SECRET must be set",
+            "GOOGLE_REDIRECT_URI must be set",
+            "OKTA_ISSUER_URL must be set",
+            "OKTA_CLIENT_ID must be set",
+            "DB_PORT must be set",
+            "MQ_CONNECTION must be either 'kafka', 'rabbitmq', 'redis', 'mongodb', or 'memory' (got 'carrier_pigeon')",
+            "TOKEN_ENCRYPTION_KEYS must be comma separated '<key_id>:<base64 key>'",
+        ] {
+            assert!(
+                errors.iter().any(|error| error == expected),
+                "'{}' not in {:?}",
+                expected,
+                errors
+            );
+        }
+        // defaults fill in the rest
+        assert!(!errors.iter().any(|error| error.starts_with("DB_USER")));
+    }
+
+    #[test]
+    fn cli_flags_are_kebab_case_keys() {
+        let args: Vec<String> = [
+            "--db-connection=memory",
+            "--rest-port",
+            "9090",
+            "--config",
+            "relay.toml",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let cli_layer = Config::cli_layer(&args).unwrap();
+        assert_eq!(cli_layer["DB_CONNECTION"], "memory");
+        assert_eq!(cli_layer["REST_PORT"], "9090");
+        assert_eq!(cli_layer["CONFIG"], "relay.toml");
+
+        assert!(Config::cli_layer(&["--rest-port".to_string()]).is_err());
+        assert!(Config::cli_layer(&["8080".to_string()]).is_err());
+        assert!(Config::toml_layer("rest_port = ").is_err());
+        assert!(Config::toml_layer("[[db]]\nconnection = \"memory\"").is_err());
+    }
+
+    #[test]
+    fn server_settings_come_from_config() {
+        let cert_path = make_temp_file("cert.pem");
+        let key_path = make_temp_file("key.pem");
+        let config = Config::from_layers(vec![
+            Config::default_layer(),
+            make_memory_layer(),
+            make_layer(&[
+                ("BIND_ADDRESS", "127.0.0.1"),
+                ("REST_PORT", "8443"),
+                ("HTTP_WORKERS", "2"),
+                ("TLS_CERT_PATH", &cert_path),
+                ("TLS_KEY_PATH", &key_path),
+                (
+                    "GOOGLE_REDIRECT_URI",
+                    "https://localhost:8443/auth_callback",
+                ),
+            ]),
+        ])
+        .unwrap();
+        assert_eq!(config.bind_address, IpAddr::from([127, 0, 0, 1]));
+        assert_eq!(config.rest_port, 8443);
+        assert_eq!(config.possible_http_workers, Some(2));
+        assert_eq!(
+            config.possible_tls,
+            Some(TlsConfig::Files {
+                cert_path: cert_path.clone(),
+                key_path: key_path.clone(),
+            })
+        );
+        assert!(config.possible_http_redirect_port.is_none());
+
+        let config = Config::from_layers(vec![
+            Config::default_layer(),
+            make_memory_layer(),
+            make_layer(&[
+                ("REST_PORT", "8443"),
+                ("TLS_SELF_SIGNED", "true"),
+                ("HTTP_REDIRECT_PORT", "8080"),
+            ]),
+        ])
+        .unwrap();
+        assert_eq!(config.possible_tls, Some(TlsConfig::SelfSigned));
+        assert_eq!(config.possible_http_redirect_port, Some(8080));
+
+        // and plain HTTP on all interfaces with actix's worker count, unless told otherwise
+        let config =
+            Config::from_layers(vec![Config::default_layer(), make_memory_layer()]).unwrap();
+        assert_eq!(config.bind_address, IpAddr::from([0, 0, 0, 0]));
+        assert_eq!(config.rest_port, 8080);
+        assert!(config.possible_http_workers.is_none());
+        assert!(config.possible_tls.is_none());
+        fs::remove_file(cert_path).unwrap();
+        fs::remove_file(key_path).unwrap();
+    }
+
+    #[test]
+    fn refuses_conflicting_server_settings() {
+        let cert_path = make_temp_file("conflicting_cert.pem");
+        for (settings, expected) in [
+            (
+                vec![("TLS_CERT_PATH", cert_path.as_str())],
+                "TLS_CERT_PATH and TLS_KEY_PATH must be set together",
+            ),
+            (
+                vec![
+                    ("TLS_CERT_PATH", cert_path.as_str()),
+                    ("TLS_KEY_PATH", "/nowhere/key.pem"),
+                ],
+                "TLS_KEY_PATH '/nowhere/key.pem' is not a file",
+            ),
+            (
+                vec![
+                    ("TLS_CERT_PATH", cert_path.as_str()),
+                    ("TLS_KEY_PATH", cert_path.as_str()),
+                    ("GOOGLE_REDIRECT_URI", "http://relay.example.com/auth_callback"),
+                ],
+                "GOOGLE_REDIRECT_URI must be https:// since the relay serves TLS (got 'http://relay.example.com/auth_callback')",
+            ),
+            (
+                vec![
+                    ("REST_PORT", "8081"),
+                    ("GOOGLE_REDIRECT_URI", "http://localhost:8080/auth_callback"),
+                ],
+                "GOOGLE_REDIRECT_URI 'http://localhost:8080/auth_callback' points at this host, but REST_PORT is 8081",
+            ),
+            (vec![("HTTP_WORKERS", "0")], "HTTP_WORKERS must be at least 1"),
+            (
+                vec![
+                    ("TLS_SELF_SIGNED", "true"),
+                    ("TLS_CERT_PATH", cert_path.as_str()),
+                    ("TLS_KEY_PATH", cert_path.as_str()),
+                ],
+                "TLS_SELF_SIGNED cannot be used along with TLS_CERT_PATH and TLS_KEY_PATH",
+            ),
+            (
+                vec![("HTTP_REDIRECT_PORT", "8081")],
+                "HTTP_REDIRECT_PORT needs TLS (TLS_CERT_PATH and TLS_KEY_PATH, or TLS_SELF_SIGNED)",
+            ),
+            (
+                vec![("TLS_SELF_SIGNED", "true"), ("HTTP_REDIRECT_PORT", "8080")],
+                "HTTP_REDIRECT_PORT and REST_PORT cannot both be 8080",
+            ),
+            (
+                vec![("BIND_ADDRESS", "localhost")],
+                "BIND_ADDRESS must be an IP address (got 'localhost')",
+            ),
+            (
+                vec![
+                    ("DB_CONNECTION", "sqlite"),
+                    ("DB_STORAGE_PATH", "/nowhere/db.sqlite3"),
+                ],
+                "DB_STORAGE_PATH directory '/nowhere' does not exist",
             ),
+        ] {
+            let errors = match Config::from_layers(vec![
+                Config::default_layer(),
+                make_memory_layer(),
+                make_layer(&settings),
+            ]) {
+                Ok(_) => panic!("{:?} should not have loaded", settings),
+                Err(ConfigErrors(errors)) => errors,
+            };
+            assert_eq!(errors, vec![expected.to_string()], "{:?}", settings);
         }
+        fs::remove_file(cert_path).unwrap();
     }
 }
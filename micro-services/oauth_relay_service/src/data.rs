@@ -1,6 +1,7 @@
 use base64::{engine::general_purpose, Engine};
 use serde::{Deserialize, Serialize};
 use std::{
+    collections::BTreeMap,
     time::{SystemTime, UNIX_EPOCH},
 };
 
@@ -44,6 +45,12 @@ impl SessionIDType {
     }
 }
 
+// Sessions from before providers were pluggable (see providers.rs) are all Google's
+pub const DEFAULT_OAUTH2_PROVIDER: &str = "google";
+fn default_oauth2_provider() -> String {
+    DEFAULT_OAUTH2_PROVIDER.to_string()
+}
+
 // oftentimes, for asyn methods, it's safest to clone before moving that closure to the thread, so
 // we need Clone to make sure it copies the data (not the ref)
 #[derive(Serialize, Deserialize, Clone)]
@@ -59,20 +66,26 @@ pub struct TokenData {
     pub possible_refresh_token: Option<String>,
     pub expires_in: i64,
     expiry_time: SystemTime,
+
+    // name of the OAuth2Provider (i.e. "google", "github") who authenticated the player, which is
+    // also who we go back to for refresh and revocation
+    #[serde(default = "default_oauth2_provider")]
+    pub provider: String,
 }
 // override to_string() so that we won't print access_token and other sensitive data in logs
 impl std::fmt::Display for TokenData {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "TokenData {{ session_id: {:?}, state_token: {}, client_address: {}, client_port: {}, possible_client_email: {:?}, access_token: <hidden>, possible_refresh_token: <hidden>, expires_in: {}, expiry_time: {:?} }}",
+            "TokenData {{ session_id: {:?}, state_token: {}, client_address: {}, client_port: {}, possible_client_email: {:?}, access_token: <hidden>, possible_refresh_token: <hidden>, expires_in: {}, expiry_time: {:?}, provider: {} }}",
             self.session_id,
             self.state_token,
             self.client_address,
             self.client_port,
             self.possible_client_email,
             self.expires_in,
-            self.expiry_time
+            self.expiry_time,
+            self.provider
         )
     }
 }
@@ -120,6 +133,7 @@ impl TokenData {
             possible_refresh_token,
             expires_in,
             expiry_time,
+            provider: default_oauth2_provider(),
         }
     }
 
@@ -206,6 +220,13 @@ impl TokenData {
         format!("{}:{}:{}", self.client_address, self.client_port, email)
     }
 
+    pub fn session_id_type(&self) -> SessionIDType {
+        self.session_id.clone()
+    }
+    // i.e. once persisted, the (temporary) Hash is replaced by the (DB) ID
+    pub fn set_session_id(&mut self, session_id: SessionIDType) {
+        self.session_id = session_id;
+    }
     pub fn session_id(&self) -> Option<u64> {
         match self.session_id {
             SessionIDType::ID(id) => Some(id),
@@ -259,6 +280,12 @@ pub struct OAuth2AuthCodeRequest {
 
     pub possible_include_granted_scopes: Option<bool>, // Enables applications to use incremental authorization to request access to additional scopes in context. If you set this parameter's value to true and the authorization request is granted, then the new access token will also cover any scopes to which the user previously granted the application access
     pub possible_prompt: Option<String>, // A space-delimited, case-sensitive list of prompts to present the user. If you don't specify this parameter, the user will be prompted only the first time your app requests access. Possible values are 'none', 'consent', and 'select_account'.
+
+    // code_challenge: PKCE (RFC 7636), BASE64URL(SHA256(code_verifier)) of a code_verifier which
+    // never leaves the relay until the auth code is traded for tokens (see OAuth2TokenRequest), so
+    // that an intercepted auth code is useless on its own.
+    pub possible_code_challenge: Option<String>,
+    pub possible_code_challenge_method: Option<String>, // always 'S256' (we never send 'plain')
 }
 
 // state: Any string value that your application uses to maintain state between your
@@ -282,6 +309,11 @@ pub struct OAuth2AuthCodeRequestState {
     pub mq_type: String,                               // None, Kafka, etc
     pub possible_mq_address: Option<HostType>, // for PostgresSQL
     pub possible_mq_port: Option<u16>,                 // for PostgresSQL
+
+    // which OAuth2Provider the player picked on /login (?provider=), so that the callback knows
+    // whose token endpoint to trade the auth code with
+    #[serde(default = "default_oauth2_provider")]
+    pub provider: String,
 }
 pub fn encode_state_token(possible_state: Option<OAuth2AuthCodeRequestState>) -> Option<String> {
     match possible_state {
@@ -302,9 +334,13 @@ pub fn decode_state_token(state_token: Option<String>) -> Option<OAuth2AuthCodeR
     match state_token {
         Some(state) => {
             // decode from base64 and deserialize from JSON to struct
-            let decoded_bytes = general_purpose::URL_SAFE_NO_PAD.decode(state).unwrap(); // panic on failure
-            let decoded = String::from_utf8(decoded_bytes).unwrap(); // panic on failure
-            let possible_state = serde_json::from_str(&decoded);
+            // NOTE: it comes back from the outside (auth_code_callback()), so anything malformed
+            // is None rather than a panic
+            let decoded_bytes = match general_purpose::URL_SAFE_NO_PAD.decode(state) {
+                Ok(decoded_bytes) => decoded_bytes,
+                Err(_) => return None,
+            };
+            let possible_state = serde_json::from_slice(&decoded_bytes);
             match possible_state {
                 Ok(state) => Some(state),
                 Err(_) => None,
@@ -314,6 +350,33 @@ pub fn decode_state_token(state_token: Option<String>) -> Option<OAuth2AuthCodeR
     }
 }
 
+// A login in progress (login_states table), issued by /login and taken back by auth_code_callback(),
+// which is how the callback knows that the state it got is one we issued (CSRF), that it is not a
+// replay (possible_used_at) and not too old (see TIMEOUT_FOR_AUTH_CODE_CALLBACK); it is also the
+// only record of the login the callback trusts (i.e. provider), the state it gets back is the client's
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoginState {
+    pub state_token: String,
+    pub code_verifier: String, // PKCE, see OAuth2AuthCodeRequest::possible_code_challenge
+    pub provider: String,      // see providers.rs, the one /login built the auth URL for
+    pub created_at: SystemTime,
+    pub possible_used_at: Option<SystemTime>, // None until the callback took it
+}
+impl LoginState {
+    pub fn new(state_token: &str, code_verifier: &str, provider: &str) -> Self {
+        LoginState {
+            state_token: state_token.to_string(),
+            code_verifier: code_verifier.to_string(),
+            provider: provider.to_string(),
+            created_at: SystemTime::now(),
+            possible_used_at: None,
+        }
+    }
+    pub fn created_at_as_sec_from_epoch(&self) -> u64 {
+        self.created_at.duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+}
+
 // Sample response (to the  redirect_uri via parameter):
 //   https://localhost:8080/authcode_callback?error=access_denied
 //   https://localhost:8080/authcode_callback?code=4/P7q7W91a-oMsCeLvIaQm6bTrgtp7
@@ -331,6 +394,7 @@ pub struct OAuth2AuthCodeResponse {
 //  - code	The authorization code returned from the initial request to https://accounts.google.com/o/oauth2/v2/auth
 //  - grant_type	As defined in the OAuth 2.0 specification, this field's value must be set to authorization_code.
 //  - redirect_uri	One of the redirect URIs listed for your project in the API Console Credentials page for the given client_id.
+//  - code_verifier	(PKCE) The code_verifier whose code_challenge was sent along with the auth code request.
 #[derive(Serialize, Clone)]
 pub struct OAuth2TokenRequest {
     pub state_token: String,
@@ -339,6 +403,7 @@ pub struct OAuth2TokenRequest {
     pub code: String,
     pub redirect_uri: String,
     pub grant_type: String,
+    pub possible_code_verifier: Option<String>,
 }
 
 // Sample response:
@@ -358,16 +423,67 @@ pub struct OAuth2TokenRequest {
 //      "token_type" : "Bearer"
 //   }
 // see: https://developers.google.com/identity/protocols/oauth2/web-server#httprest
+// NOTE: Other providers are not as generous: GitHub has neither expires_in (its tokens do not
+// expire) nor id_token (not OIDC)
 #[derive(Serialize, Deserialize, Clone)]
 pub struct OAuth2TokenResponse {
     pub access_token: String,
+    #[serde(default = "default_expires_in")]
     pub expires_in: i64, // I believe Google uses seconds (i.e. 3599 (6 minutes))
-    pub id_token: String,
+    #[serde(rename = "id_token", default)]
+    pub possible_id_token: Option<String>,
     pub scope: String, // space separated, i.e. "https://www.googleapis.com/auth/userinfo.profile openid https://www.googleapis.com/auth/userinfo.email"
     pub token_type: String,
+    // Google calls it "refresh_token", and only hands it out on first consent (with access_type=offline)
+    #[serde(rename = "refresh_token", default)]
+    pub possible_refresh_token: Option<String>,
+}
+
+// tokens that never expire still get checked on once in a while, as if they were Google's
+fn default_expires_in() -> i64 {
+    3599
+}
+
+// See: https://developers.google.com/identity/protocols/oauth2/web-server#offline
+// HTTP POST request to TOKEN_URL_POST (same endpoint as OAuth2TokenRequest)
+//  - client_id	The client ID obtained from the API Console Credentials page.
+//  - client_secret	The client secret obtained from the API Console Credentials page.
+//  - grant_type	As defined in the OAuth 2.0 specification, this field's value must be set to refresh_token.
+//  - refresh_token	The refresh token returned from the authorization code exchange.
+#[derive(Serialize, Clone)]
+pub struct OAuth2RefreshTokenRequest {
+    pub client_id: String,
+    pub client_secret: String,
+    pub grant_type: String,
+    pub refresh_token: String,
+}
+
+// Sample response (note that there is no refresh_token, unless Google decided to rotate it):
+//  {
+//      "access_token": "1/fFAGRNJru1FTz70BzhT3Zg",
+//      "expires_in": 3920,
+//      "scope": "https://www.googleapis.com/auth/drive.metadata.readonly",
+//      "token_type": "Bearer"
+//  }
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OAuth2RefreshTokenResponse {
+    pub access_token: String,
+    pub expires_in: i64,
+    #[serde(default)]
+    pub scope: String,
+    pub token_type: String,
+    #[serde(rename = "refresh_token", default)]
     pub possible_refresh_token: Option<String>,
 }
 
+// See: https://developers.google.com/identity/protocols/oauth2/web-server#tokenrevoke
+// HTTP POST request to REVOCATION_ENDPOINT_POST
+//  - token	Either access_token or refresh_token (revoking refresh_token also revokes its access_tokens)
+#[derive(Serialize, Clone)]
+pub struct OAuth2RevokeTokenRequest {
+    pub token: String,
+}
+
 #[derive(Deserialize, Clone)]
 pub struct OAuth2UerInfoRequest {
     pub access_token: String,
@@ -417,17 +533,44 @@ pub struct OAuth2UserInfoResponse {
     pub hd: Option<String>,
 }
 
+// All we need out of any provider's userinfo endpoint, which are not shaped alike:
+//  - OIDC (Google's openidconnect, Microsoft Graph, ...): "sub", "email", "email_verified"
+//  - Google's v1 (see OAuth2UserInfoResponse): "id", "email", "verified_email"
+//  - GitHub's /user: "id" (number), "email" which is null if the player keeps it private (see
+//    GitHubUserEmail)
+#[derive(Deserialize, Clone)]
+pub struct OAuth2ProviderUserInfo {
+    #[serde(rename = "email", default)]
+    pub possible_email: Option<String>,
+    #[serde(rename = "email_verified", alias = "verified_email", default)]
+    pub possible_email_verified: Option<bool>,
+}
+
+// One of GitHub's GET /user/emails (needs "user:email" scope)
+//  [ { "email": "octocat@github.com", "verified": true, "primary": true, "visibility": "public" } ]
+#[derive(Deserialize, Clone)]
+pub struct GitHubUserEmail {
+    pub email: String,
+    pub primary: bool,
+    pub verified: bool,
+}
+
 #[derive(Deserialize, Clone)]
 pub struct LoginRequest {
     pub possible_last_session_id: SessionIDType,
     pub possible_last_state_token: Option<String>,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct LoginResponse {
     // Either session info (for next login request/recovery/keeplive) or error message
     pub possible_session_id: Option<u64>,
     pub possible_state_token: Option<String>,
+    // URL the player needs to open (browser) to consent; only set on the first (new) login request
+    pub possible_auth_url: Option<String>,
+    // signed JWT (see SessionTokenClaims) to present to the other services; only set along with session_id
+    #[serde(default)]
+    pub possible_session_token: Option<String>,
 
     pub possible_login_error: Option<String>,
 }
@@ -436,7 +579,7 @@ pub struct LoginResponse {
 pub struct KeepaliveRequest {
     pub last_session_id: String,
 }
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct KeepaliveResponse {
     pub next_expected_time: u64, // absolute time, EPOCH based
     // time-to-live in seconds (problem with relative deltaT is that if round-trip takes long time,
@@ -445,4 +588,63 @@ pub struct KeepaliveResponse {
     pub ttl: u64,
     pub status: String,
     pub message: String,
+    // fresh session token (JWT), since the one handed out on login expires (see SESSION_TOKEN_TTL)
+    #[serde(default)]
+    pub possible_session_token: Option<String>,
+}
+
+// /readyz (see web/actix/health.rs), one per dependency
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DependencyStatus {
+    pub status: String,  // "OK" or "ERROR"
+    pub backend: String, // i.e. "sqlite", "kafka", or the provider's name
+    #[serde(default)]
+    pub possible_error: Option<String>,
+}
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ReadinessResponse {
+    pub status: String, // "OK" only if every dependency is
+    // "db", "mq", and "provider:<name>" for each enabled provider
+    pub dependencies: BTreeMap<String, DependencyStatus>,
+}
+
+// Published on the message bus (see Messenger::post_session_event()) when a session ends, so that
+// other services (i.e. the game service) can drop whatever they hold for that session_id
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionEventType {
+    SessionExpired, // expired with no refresh_token (see reaper.rs)
+    Logout,         // player logged out (see logout.rs)
+}
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SessionEvent {
+    pub event_type: SessionEventType,
+    pub session_id: u64,
+    pub possible_client_email: Option<String>,
+    pub event_time: u64, // absolute time, EPOCH based
+}
+impl SessionEvent {
+    pub fn new(event_type: SessionEventType, token_data: &TokenData) -> Self {
+        Self {
+            event_type,
+            session_id: token_data.session_id().unwrap_or_default(),
+            possible_client_email: token_data.possible_client_email.clone(),
+            event_time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        }
+    }
+}
+
+// Claims of the session token (JWT) the relay signs after login, and re-issues on keepalive, so
+// that the other services (game, generator, resolver) can verify callers offline via the JWKS
+// at /.well-known/jwks.json rather than asking us on every request
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SessionTokenClaims {
+    pub sub: String, // player's email
+    pub sid: u64,    // session_id (same as last_session_id)
+    pub iss: String, // Config::session_token_issuer
+    pub iat: u64,    // EPOCH based
+    pub exp: u64,    // EPOCH based
 }
@@ -0,0 +1,108 @@
+use crate::data::KeepaliveResponse;
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use std::fmt;
+
+// Whatever an HTTP handler can fail with, so that handlers return Result<HttpResponse, RelayError>
+// rather than panic (a bad client should never take a worker down with it)
+// The body is the same KeepaliveResponse shape (status "ERROR") for every route, so that clients
+// only need to deserialize one kind of error
+// NOTE: 502 and 503 carry the cause for the logs, the client only gets its top-level message
+#[derive(Debug)]
+pub enum RelayError {
+    // 400: missing/malformed params, unknown provider, bad state, ...
+    BadRequest(String),
+    // 401: unknown/expired session or denied consent, player has to login (again)
+    Unauthorized(String),
+    // 404: nothing there (i.e. already logged out)
+    NotFound(String),
+    // 502: the OAuth2 provider refused or failed us
+    BadGateway(anyhow::Error),
+    // 503: our own backends (DB, MQ, signing key) failed us
+    Unavailable(anyhow::Error),
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelayError::BadRequest(message)
+            | RelayError::Unauthorized(message)
+            | RelayError::NotFound(message) => write!(f, "{}", message),
+            RelayError::BadGateway(e) => write!(f, "OAuth2 provider failed: {}", e),
+            RelayError::Unavailable(e) => write!(f, "Service unavailable: {}", e),
+        }
+    }
+}
+
+// storage, messenger and session_token all speak anyhow, and when they fail it's on us
+impl From<anyhow::Error> for RelayError {
+    fn from(e: anyhow::Error) -> Self {
+        RelayError::Unavailable(e)
+    }
+}
+
+impl ResponseError for RelayError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            RelayError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            RelayError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            RelayError::NotFound(_) => StatusCode::NOT_FOUND,
+            RelayError::BadGateway(_) => StatusCode::BAD_GATEWAY,
+            RelayError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code()).json(KeepaliveResponse {
+            next_expected_time: 0,
+            ttl: 0,
+            status: "ERROR".to_string(),
+            message: self.to_string(),
+            possible_session_token: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::to_bytes;
+
+    #[actix_web::test]
+    async fn maps_to_status_and_json_body() {
+        for (relay_error, expected_status, expected_message) in [
+            (
+                RelayError::BadRequest("last_session_id not found".to_string()),
+                StatusCode::BAD_REQUEST,
+                "last_session_id not found",
+            ),
+            (
+                RelayError::Unauthorized("Unknown session_id '42', please login".to_string()),
+                StatusCode::UNAUTHORIZED,
+                "Unknown session_id '42', please login",
+            ),
+            (
+                RelayError::NotFound("Unknown session_id '42'".to_string()),
+                StatusCode::NOT_FOUND,
+                "Unknown session_id '42'",
+            ),
+            (
+                RelayError::BadGateway(anyhow::anyhow!("invalid_grant")),
+                StatusCode::BAD_GATEWAY,
+                "OAuth2 provider failed: invalid_grant",
+            ),
+            (
+                anyhow::anyhow!("database is locked").into(),
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Service unavailable: database is locked",
+            ),
+        ] {
+            let response = relay_error.error_response();
+            assert_eq!(response.status(), expected_status);
+            let keepalive_response: KeepaliveResponse =
+                serde_json::from_slice(&to_bytes(response.into_body()).await.unwrap()).unwrap();
+            assert_eq!(keepalive_response.status, "ERROR");
+            assert_eq!(keepalive_response.message, expected_message);
+            assert_eq!(keepalive_response.ttl, 0);
+        }
+    }
+}
@@ -1,19 +1,50 @@
 //#include modules:
 pub mod config;
 pub mod data;
+pub mod error;
 pub mod messenger;
+pub mod metrics;
+pub mod providers;
+pub mod session_token;
 pub mod storage;
 pub mod web;
 
-use std::{env, path::Path};
-
 use config::Config;
-use storage::sqlite::main_sqlite::sqlite_actix_main;
+use session_token::SessionTokenIssuer;
+use web::actix::app;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let config = Config::from_local_env_file();
-    let db_connection = storage::open_db_connection_from_config(&config).await;
-    let (mq_producer, mq_consumer) = messenger::open_mq_connections_from_config(&config).await;
-    sqlite_actix_main(&config, &db_connection, &mq_producer, &mq_consumer)
+    // loaded once, everything else gets it handed down (or via web::Data<Config>)
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Config: {}", e);
+            std::process::exit(2);
+        }
+    };
+    // same as an invalid key: a DB/MQ we cannot open or a malformed signing key is reported, and
+    // we exit rather than panic
+    let token_store = match storage::open_token_store_from_config(&config).await {
+        Ok(token_store) => token_store,
+        Err(e) => {
+            println!("Storage: {:#}", e);
+            std::process::exit(2);
+        }
+    };
+    let messenger = match messenger::open_messenger_from_config(&config).await {
+        Ok(messenger) => messenger,
+        Err(e) => {
+            println!("Messenger: {:#}", e);
+            std::process::exit(2);
+        }
+    };
+    let session_token_issuer = match SessionTokenIssuer::from_config(&config) {
+        Ok(session_token_issuer) => session_token_issuer,
+        Err(e) => {
+            println!("SessionToken: {:#}", e);
+            std::process::exit(2);
+        }
+    };
+    app::run_http_server(&config, &token_store, &messenger, session_token_issuer).await
 }
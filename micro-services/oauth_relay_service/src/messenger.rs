@@ -1,148 +1,69 @@
 //#include
 pub mod kafka;
+pub mod memory;
 
+use crate::{
+    config::*,
+    data::{SessionEvent, TokenData},
+};
 use anyhow::Result as AnyResult;
-use crate::{config::*, data::TokenData};
+use async_trait::async_trait;
+use std::sync::Arc;
 
-pub type TMQProducer = kafka::TMQProducerKafka; // Currently using Kafka as the MQ
-pub type TMQProducerLock = kafka::TMQProducerLockKafka;
-pub type TMQConsumer = kafka::TMQConsumerKafka; // Currently using Kafka as the MQ
-pub type TMQConsumerLock = kafka::TMQConsumerLockKafka;
-
-pub async fn open_mq_connections_from_config(config: &Config) -> (TMQProducerLock, TMQConsumerLock) {
-    match &config.mq_connection {
-        MQType::Kafka {
-            host_as_name_or_address,
-            host_port,
-        } => kafka::open_mq_connections_kafka(&host_as_name_or_address, host_port),
-        MQType::MongoDB {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => panic!("MongoDB not yet implemented"),
-        MQType::RabbitMQ {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => panic!("RabbitMQ not yet implemented"),
-        MQType::Redis {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => panic!("Redis not yet implemented"),
+// Same idea as storage::TokenStore, each message broker (Kafka, in-memory, ...) implements this,
+// and which one is used is decided ONCE at startup (see open_messenger_from_config())
+#[async_trait]
+pub trait Messenger: Send + Sync {
+    // i.e. "kafka", "memory" (mainly for logging and OAuth2AuthCodeRequestState)
+    fn mq_type(&self) -> String;
+    fn mq_address(&self) -> Option<HostType> {
+        None
     }
-}
-
-pub(crate) fn get_mq_type(config: &Config) -> String {
-    match &config.mq_connection {
-        MQType::Kafka {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => "kafka".to_string(),
-        MQType::MongoDB {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => "mongodb".to_string(),
-        MQType::RabbitMQ {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => "rabbitmq".to_string(),
-        MQType::Redis {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => "redis".to_string(),
+    fn mq_port(&self) -> Option<u16> {
+        None
     }
-}
 
-pub(crate) fn get_mq_address(config: &Config) -> Option<HostType> {
-    match &config.mq_connection {
-        MQType::Kafka {
-            host_as_name_or_address,
-            host_port: _,
-        } => Some(host_as_name_or_address.clone()),
-        MQType::MongoDB {
-            host_as_name_or_address,
-            host_port: _,
-        } => Some(host_as_name_or_address.clone()),
-        MQType::RabbitMQ {
-            host_as_name_or_address,
-            host_port: _,
-        } => Some(host_as_name_or_address.clone()),
-        MQType::Redis {
-            host_as_name_or_address,
-            host_port: _,
-        } => Some(host_as_name_or_address.clone()),
-    }
-}
+    // Ok(()) if both the producer and the consumer reach the broker, see /readyz
+    async fn ping(&self) -> AnyResult<()>;
 
-pub(crate) fn get_mq_port(config: &Config) -> Option<u16> {
-    match &config.mq_connection {
-        MQType::Kafka {
-            host_as_name_or_address: _,
-            host_port,
-        } => Some(host_port.clone()),
-        MQType::MongoDB {
-            host_as_name_or_address: _,
-            host_port,
-        } => Some(host_port.clone()),
-        MQType::RabbitMQ {
-            host_as_name_or_address: _,
-            host_port,
-        } => Some(host_port.clone()),
-        MQType::Redis {
-            host_as_name_or_address: _,
-            host_port,
-        } => Some(host_port.clone()),
-    }
-}
+    // (producer) Signal/notify/message/publish that we have a new session_id (new login) for any
+    // services who cares for that event (including /login waiting for it)
+    async fn post_new_login(&self, token_data: &TokenData) -> AnyResult<()>;
 
-pub(crate) fn get_token<TFn>(
-    config: &Config,
-    mq_lock: &TMQProducerLock,
-    lhs_state_token: &str,
-    fn_equ_op: TFn,
-) -> Option<TokenData>
-where
-    TFn: Fn(&str /*lhs_state_token*/, TokenData /*rhs*/) -> bool, // compare lhs_state_token == rhs:TokenData.state_token
-{
-    match &config.mq_connection {
-        MQType::Kafka {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => kafka::get_token_kafka(mq_lock, lhs_state_token, fn_equ_op),
-        MQType::MongoDB {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => None,
-        MQType::RabbitMQ {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => None,
-        MQType::Redis {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => None,
-    }
+    // (consumer) Ok(Some) if a new login with matching state_token has been posted
+    async fn get_token(&self, state_token: &str) -> AnyResult<Option<TokenData>>;
+
+    // (producer) Publish that a session has ended (i.e. expired), for any services who cares for
+    // that event (i.e. game service dropping its state for that session_id)
+    async fn post_session_event(&self, session_event: &SessionEvent) -> AnyResult<()>;
 }
 
-pub(crate) async fn post_new_login(
-    config: &Config,
-    db_connection: &TMQProducerLock,
-    token_data: &TokenData,
-) -> AnyResult<()> {
-    match &config.mq_connection {
+// cloning an Arc<T> just means incrementing the reference count, so it's cheap to share with handlers
+pub type TMessenger = Arc<dyn Messenger>;
+
+pub async fn open_messenger_from_config(config: &Config) -> AnyResult<TMessenger> {
+    let messenger: TMessenger = match &config.mq_connection {
         MQType::Kafka {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => kafka::post_new_login_kafka(db_connection, token_data).await,
+            host_as_name_or_address,
+            host_port,
+        } => Arc::new(kafka::KafkaMessenger::open(
+            host_as_name_or_address,
+            host_port,
+        )?),
+        MQType::Memory => Arc::new(memory::MemoryMessenger::new()),
         MQType::MongoDB {
             host_as_name_or_address: _,
             host_port: _,
-        } => panic!("MongoDB not yet implemented"),
+        } => anyhow::bail!("MongoDB not yet implemented"),
         MQType::RabbitMQ {
             host_as_name_or_address: _,
             host_port: _,
-        } => panic!("RabbitMQ not yet implemented"),
+        } => anyhow::bail!("RabbitMQ not yet implemented"),
         MQType::Redis {
             host_as_name_or_address: _,
             host_port: _,
-        } => panic!("Redis not yet implemented"),
-    }
+        } => anyhow::bail!("Redis not yet implemented"),
+    };
+    println!("Messenger: Using '{}' messenger", messenger.mq_type());
+    Ok(messenger)
 }
@@ -1,8 +1,10 @@
 pub mod data_kafka;
 pub mod main_kafka;
 
+use super::{HostType, Messenger};
 use crate::data::*;
-use super::HostType;
+use anyhow::Result as AnyResult;
+use async_trait::async_trait;
 //use clap::{value_t, App, Arg};
 use futures::{stream::FuturesUnordered, StreamExt, TryStreamExt};
 //use log::info;
@@ -25,10 +27,59 @@ pub type TMQProducerLockKafka = Arc<Mutex<TMQProducerKafka>>;
 pub type TMQConsumerKafka = StreamConsumer; // Currently using Kafka as the MQ
 pub type TMQConsumerLockKafka = Arc<Mutex<TMQConsumerKafka>>;
 
+pub struct KafkaMessenger {
+    host_as_name_or_address: HostType,
+    host_port: u16,
+    mq_producer: TMQProducerLockKafka,
+    mq_consumer: TMQConsumerLockKafka,
+}
+impl KafkaMessenger {
+    pub fn open(host_as_name_or_address: &HostType, host_port: &u16) -> AnyResult<Self> {
+        let (mq_producer, mq_consumer) =
+            open_mq_connections_kafka(host_as_name_or_address, host_port)?;
+        Ok(KafkaMessenger {
+            host_as_name_or_address: host_as_name_or_address.clone(),
+            host_port: *host_port,
+            mq_producer,
+            mq_consumer,
+        })
+    }
+}
+#[async_trait]
+impl Messenger for KafkaMessenger {
+    fn mq_type(&self) -> String {
+        "kafka".to_string()
+    }
+    fn mq_address(&self) -> Option<HostType> {
+        Some(self.host_as_name_or_address.clone())
+    }
+    fn mq_port(&self) -> Option<u16> {
+        Some(self.host_port)
+    }
+    async fn ping(&self) -> AnyResult<()> {
+        ping_kafka(&self.mq_producer, &self.mq_consumer).await
+    }
+    async fn post_new_login(&self, token_data: &TokenData) -> AnyResult<()> {
+        post_new_login_kafka(&self.mq_producer, token_data).await
+    }
+    async fn post_session_event(&self, session_event: &SessionEvent) -> AnyResult<()> {
+        post_session_event_kafka(&self.mq_producer, session_event).await
+    }
+    async fn get_token(&self, state_token: &str) -> AnyResult<Option<TokenData>> {
+        get_token_kafka(
+            &self.mq_consumer,
+            state_token,
+            |lhs_state_token: &str, token_data: TokenData| {
+                lhs_state_token == token_data.state_token.as_str()
+            },
+        )
+    }
+}
+
 pub(crate) fn open_mq_connections_kafka(
     hostas_name_or_address: &HostType,
     host_port: &u16,
-) -> (TMQProducerLockKafka, TMQConsumerLockKafka) {
+) -> AnyResult<(TMQProducerLockKafka, TMQConsumerLockKafka)> {
     // define lamba to return ClientConfig so that I can deal with late bindings
     //let get_kafka_client_config = || {
     //    ClientConfig::new()
@@ -75,11 +126,11 @@ pub(crate) fn open_mq_connections_kafka(
     
     
     )
-            .expect("Failed to create Kafka consumer");
+            .map_err(|e| anyhow::anyhow!("Failed to create Kafka consumer: {}", e))?;
     let topic = "auth";
     stream_consumer
-        .subscribe(&[topic.clone()])
-        .expect(format!("Failed to subscribe to Kafka topic '{}'", topic.clone()).as_str());
+        .subscribe(&[topic])
+        .map_err(|e| anyhow::anyhow!("Failed to subscribe to Kafka topic '{}': {}", topic, e))?;
     //        // Create the `FutureProducer` to produce asynchronously.
     //        let producer: FutureProducer = ClientConfig::new()
     //            .set("bootstrap.servers", &brokers)
@@ -101,29 +152,83 @@ pub(crate) fn open_mq_connections_kafka(
             .set("enable.auto.offset.store", "true")
        
 )
-        .expect("Failed to create Kafka producer");
-    (
+        .map_err(|e| anyhow::anyhow!("Failed to create Kafka producer: {}", e))?;
+    Ok((
         Arc::new(Mutex::new(future_producer)),
         Arc::new(Mutex::new(stream_consumer)),
-    )
+    ))
 }
 
+// NOTE: new logins over Kafka are not implemented yet; both ends answer Err() (which the handlers
+// turn into a 503) rather than panicking the worker, use MQ_CONNECTION=memory in the meantime
 pub(crate) fn get_token_kafka<TFn>(
-    mq_lock: &TMQProducerLockKafka,
+    mq_lock: &TMQConsumerLockKafka,
     lhs_state_token: &str,
     fn_equ_op: TFn,
-) -> Option<TokenData>
+) -> AnyResult<Option<TokenData>>
 where
     TFn: Fn(&str /*lhs_state_token*/, TokenData /*rhs*/) -> bool, // compare lhs_state_token == rhs:TokenData.state_token
 {
-    todo!()
+    anyhow::bail!("Kafka: Consuming new logins is not implemented")
 }
 
 pub(crate) async fn post_new_login_kafka(
     db_connection: &TMQProducerLockKafka,
     token_data: &TokenData,
 ) -> Result<(), anyhow::Error> {
-    todo!()
+    anyhow::bail!("Kafka: Posting new logins is not implemented")
+}
+
+// Both ends ask the broker for its metadata, which librdkafka does blocking (hence spawn_blocking)
+const PING_TIMEOUT: Duration = Duration::from_secs(3);
+pub(crate) async fn ping_kafka(
+    mq_producer: &TMQProducerLockKafka,
+    mq_consumer: &TMQConsumerLockKafka,
+) -> Result<(), anyhow::Error> {
+    let (mq_producer, mq_consumer) = (mq_producer.clone(), mq_consumer.clone());
+    tokio::task::spawn_blocking(move || {
+        mq_producer
+            .blocking_lock()
+            .client()
+            .fetch_metadata(None, PING_TIMEOUT)
+            .map_err(|e| anyhow::anyhow!("Kafka producer cannot reach the broker: {}", e))?;
+        mq_consumer
+            .blocking_lock()
+            .client()
+            .fetch_metadata(None, PING_TIMEOUT)
+            .map_err(|e| anyhow::anyhow!("Kafka consumer cannot reach the broker: {}", e))?;
+        Ok(())
+    })
+    .await?
+}
+
+// Session events go on their own topic (the "auth" consumer above only cares for new logins),
+// keyed by session_id so that events of the same session stay in order (same partition)
+const TOPIC_SESSION_EVENTS: &str = "session_events";
+pub(crate) async fn post_session_event_kafka(
+    mq_producer: &TMQProducerLockKafka,
+    session_event: &SessionEvent,
+) -> Result<(), anyhow::Error> {
+    let payload = serde_json::to_string(session_event)?;
+    let key = session_event.session_id.to_string();
+    let producer = mq_producer.lock().await;
+    match producer
+        .send(
+            FutureRecord::to(TOPIC_SESSION_EVENTS)
+                .key(&key)
+                .payload(&payload),
+            Duration::from_secs(5),
+        )
+        .await
+    {
+        Ok(_) => Ok(()),
+        Err((e, _)) => anyhow::bail!(
+            "Failed to post {:?} for session_id {} to Kafka: {}",
+            session_event.event_type,
+            session_event.session_id,
+            e
+        ),
+    }
 }
 
 //mod temp {
@@ -0,0 +1,52 @@
+use super::Messenger;
+use crate::data::{SessionEvent, TokenData};
+use anyhow::Result as AnyResult;
+use async_trait::async_trait;
+use std::sync::Mutex;
+
+// Single-instance (in-process) messenger, mainly for tests and for running the relay without a
+// broker.  Posted logins are queued until /login consumes them (by state_token).
+#[derive(Default)]
+pub struct MemoryMessenger {
+    // std Mutex rather than tokio's, since it is never held across an .await
+    new_logins: Mutex<Vec<TokenData>>,
+    session_events: Mutex<Vec<SessionEvent>>,
+}
+impl MemoryMessenger {
+    pub fn new() -> Self {
+        MemoryMessenger::default()
+    }
+    // there is no consumer for these (yet), so it's mainly for tests to see what was published
+    pub fn session_events(&self) -> Vec<SessionEvent> {
+        self.session_events.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Messenger for MemoryMessenger {
+    fn mq_type(&self) -> String {
+        "memory".to_string()
+    }
+    async fn ping(&self) -> AnyResult<()> {
+        Ok(())
+    }
+    async fn post_new_login(&self, token_data: &TokenData) -> AnyResult<()> {
+        self.new_logins.lock().unwrap().push(token_data.clone());
+        Ok(())
+    }
+    async fn get_token(&self, state_token: &str) -> AnyResult<Option<TokenData>> {
+        let mut new_logins = self.new_logins.lock().unwrap();
+        // consume it, same as a consumer would commit the offset
+        Ok(new_logins
+            .iter()
+            .position(|token_data| token_data.state_token == state_token)
+            .map(|index| new_logins.remove(index)))
+    }
+    async fn post_session_event(&self, session_event: &SessionEvent) -> AnyResult<()> {
+        self.session_events
+            .lock()
+            .unwrap()
+            .push(session_event.clone());
+        Ok(())
+    }
+}
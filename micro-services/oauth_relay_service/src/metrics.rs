@@ -0,0 +1,196 @@
+use crate::{
+    config::HostType,
+    data::{SessionEvent, TokenData},
+    messenger::{Messenger, TMessenger},
+};
+use anyhow::Result as AnyResult;
+use async_trait::async_trait;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::{collections::HashMap, time::Duration};
+
+// Prometheus metrics of the relay, scraped via /metrics (see web/actix/metrics.rs):
+//  - relay_http_requests_total{route, method, status} and relay_http_request_duration_seconds
+//    {route, status}, for every request (see app::make_app()), i.e. login success rate is
+//    /auth_callback by status, callback latency is its histogram, keepalive volume is /keepalive
+//  - relay_mq_publishes_total{event, outcome}, for every message posted (see MeteredMessenger)
+// Every metric also carries db_type and mq_type (TokenStore::db_type(), Messenger::mq_type()),
+// so that relays on different backends can be told apart on the same dashboard
+// NOTE: route is the matched pattern (i.e. "/keepalive"), never the raw path, so that whatever
+// clients make up does not blow up the number of series
+#[derive(Clone)]
+pub struct RelayMetrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    mq_publishes: IntCounterVec,
+}
+
+impl RelayMetrics {
+    pub fn new(db_type: &str, mq_type: &str) -> AnyResult<Self> {
+        let backend_labels = HashMap::from([
+            ("db_type".to_string(), db_type.to_string()),
+            ("mq_type".to_string(), mq_type.to_string()),
+        ]);
+        // own registry (rather than prometheus' global one), one per App
+        let registry = Registry::new_custom(None, Some(backend_labels))?;
+        let http_requests = IntCounterVec::new(
+            Opts::new("relay_http_requests_total", "HTTP requests served"),
+            &["route", "method", "status"],
+        )?;
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "relay_http_request_duration_seconds",
+                "HTTP request latency (including OAuth2 provider round trips)",
+            ),
+            &["route", "status"],
+        )?;
+        let mq_publishes = IntCounterVec::new(
+            Opts::new("relay_mq_publishes_total", "Messages posted to the MQ"),
+            &["event", "outcome"],
+        )?;
+        registry.register(Box::new(http_requests.clone()))?;
+        registry.register(Box::new(http_request_duration.clone()))?;
+        registry.register(Box::new(mq_publishes.clone()))?;
+        Ok(RelayMetrics {
+            registry,
+            http_requests,
+            http_request_duration,
+            mq_publishes,
+        })
+    }
+
+    pub fn observe_http_request(&self, route: &str, method: &str, status: u16, elapsed: Duration) {
+        let status = status.to_string();
+        self.http_requests
+            .with_label_values(&[route, method, status.as_str()])
+            .inc();
+        self.http_request_duration
+            .with_label_values(&[route, status.as_str()])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_mq_publish(&self, event: &str, is_posted: bool) {
+        let outcome = match is_posted {
+            true => "ok",
+            false => "error",
+        };
+        self.mq_publishes.with_label_values(&[event, outcome]).inc();
+    }
+
+    // Prometheus text exposition format
+    pub fn render(&self) -> AnyResult<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+// Messenger which counts every post (see relay_mq_publishes_total) of the one it wraps, so that
+// each backend (Kafka, ...) does not have to
+pub struct MeteredMessenger {
+    messenger: TMessenger,
+    metrics: RelayMetrics,
+}
+
+impl MeteredMessenger {
+    pub fn new(messenger: TMessenger, metrics: RelayMetrics) -> Self {
+        MeteredMessenger { messenger, metrics }
+    }
+}
+
+#[async_trait]
+impl Messenger for MeteredMessenger {
+    fn mq_type(&self) -> String {
+        self.messenger.mq_type()
+    }
+    fn mq_address(&self) -> Option<HostType> {
+        self.messenger.mq_address()
+    }
+    fn mq_port(&self) -> Option<u16> {
+        self.messenger.mq_port()
+    }
+    async fn ping(&self) -> AnyResult<()> {
+        self.messenger.ping().await
+    }
+    async fn post_new_login(&self, token_data: &TokenData) -> AnyResult<()> {
+        let result = self.messenger.post_new_login(token_data).await;
+        self.metrics.observe_mq_publish("new_login", result.is_ok());
+        result
+    }
+    async fn get_token(&self, state_token: &str) -> AnyResult<Option<TokenData>> {
+        self.messenger.get_token(state_token).await
+    }
+    async fn post_session_event(&self, session_event: &SessionEvent) -> AnyResult<()> {
+        let result = self.messenger.post_session_event(session_event).await;
+        // same name as on the wire, i.e. "logout"
+        let event = serde_json::to_value(session_event.event_type)?;
+        self.metrics
+            .observe_mq_publish(event.as_str().unwrap_or_default(), result.is_ok());
+        result
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::{
+        data::{SessionEventType, SessionIDType},
+        messenger::memory::MemoryMessenger,
+        storage::tests::make_token_data,
+    };
+    use std::sync::Arc;
+
+    // value of the sample of `name` carrying (at least) these labels, whatever order the encoder
+    // put them in (const labels come in no particular order)
+    pub(crate) fn find_sample(
+        exposition: &str,
+        name: &str,
+        labels: &[(&str, &str)],
+    ) -> Option<f64> {
+        exposition.lines().find_map(|line| {
+            let (series, value) = line.rsplit_once(' ')?;
+            let series_labels = series.strip_prefix(name)?.strip_prefix('{')?;
+            labels
+                .iter()
+                .all(|(label, label_value)| {
+                    series_labels.contains(&format!("{}=\"{}\"", label, label_value))
+                })
+                .then(|| value.parse().unwrap())
+        })
+    }
+
+    #[tokio::test]
+    async fn counts_posts_by_event_with_backend_labels() {
+        let metrics = RelayMetrics::new("sqlite", "memory").unwrap();
+        let messenger = MeteredMessenger::new(Arc::new(MemoryMessenger::new()), metrics.clone());
+        let token_data = make_token_data(SessionIDType::ID(42), "state_token", "access_token");
+        messenger.post_new_login(&token_data).await.unwrap();
+        messenger.post_new_login(&token_data).await.unwrap();
+        messenger
+            .post_session_event(&SessionEvent::new(SessionEventType::Logout, &token_data))
+            .await
+            .unwrap();
+        assert_eq!(messenger.mq_type(), "memory");
+
+        let exposition = metrics.render().unwrap();
+        for (event, expected_count) in [("new_login", 2.0), ("logout", 1.0)] {
+            assert_eq!(
+                find_sample(
+                    &exposition,
+                    "relay_mq_publishes_total",
+                    &[
+                        ("db_type", "sqlite"),
+                        ("mq_type", "memory"),
+                        ("event", event),
+                        ("outcome", "ok"),
+                    ],
+                ),
+                Some(expected_count),
+                "{}",
+                exposition
+            );
+        }
+    }
+}
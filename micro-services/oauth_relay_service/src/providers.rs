@@ -0,0 +1,573 @@
+//#include
+#[cfg(test)]
+pub(crate) mod mock;
+
+use crate::{
+    config::{OAuth2ProviderConfig, OAuth2ProviderKind},
+    data::{GitHubUserEmail, OAuth2AuthCodeRequest, OAuth2ProviderUserInfo, OAuth2TokenResponse},
+    web::web_consts::*,
+};
+use anyhow::Result as AnyResult;
+use serde::Deserialize;
+use std::sync::Arc;
+
+// The relay used to be Google only; now any of the following can authenticate the player, picked
+// via /login?provider=<name> (see Config::oauth2_providers for which ones are enabled):
+//  - Google, Microsoft (Entra ID) and any other OIDC provider: endpoints come from the discovery
+//    document (<issuer>/.well-known/openid-configuration), so we do not have to keep up with them
+//  - GitHub: not OIDC (no discovery, no id_token, no expiry, no revocation via token alone), so
+//    its endpoints are well-known ones, and email may need a second request (see request_email())
+// Everything after the callback (refresh, revoke, keepalive) goes back to whichever provider the
+// session was created with (see TokenData::provider)
+
+const GOOGLE_ISSUER_URL: &str = "https://accounts.google.com";
+const MICROSOFT_ISSUER_URL: &str = "https://login.microsoftonline.com";
+const GITHUB_AUTHORIZATION_ENDPOINT_GET: &str = "https://github.com/login/oauth/authorize";
+const GITHUB_TOKEN_ENDPOINT_POST: &str = "https://github.com/login/oauth/access_token";
+const GITHUB_USERINFO_ENDPOINT_GET: &str = "https://api.github.com/user";
+const GITHUB_EMAILS_ENDPOINT_GET: &str = "https://api.github.com/user/emails";
+
+// GitHub's API refuses requests without User-Agent
+const USER_AGENT: &str = "oauth_relay_service";
+
+// The bits of the OIDC discovery document we care for
+// see: https://openid.net/specs/openid-connect-discovery-1_0.html#ProviderMetadata
+#[derive(Deserialize, Clone)]
+pub struct OidcDiscoveryDocument {
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    #[serde(rename = "userinfo_endpoint", default)]
+    pub possible_userinfo_endpoint: Option<String>,
+    #[serde(rename = "revocation_endpoint", default)]
+    pub possible_revocation_endpoint: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OAuth2Endpoints {
+    pub authorization_endpoint: String, // GET (player's browser)
+    pub token_endpoint: String,         // POST (auth code and refresh_token)
+    pub userinfo_endpoint: String,      // GET (email)
+    pub possible_revocation_endpoint: Option<String>, // POST, None if provider has none
+    pub possible_emails_endpoint: Option<String>, // GET, GitHub only (private emails)
+}
+
+impl OAuth2Endpoints {
+    // what Google's discovery document says anyways (see web_consts.rs), in case it's unreachable
+    pub fn google() -> Self {
+        OAuth2Endpoints {
+            authorization_endpoint: AUTHORIZATION_ENDPOINT_GET.to_string(),
+            token_endpoint: TOKEN_ENDPOINT_POST.to_string(),
+            userinfo_endpoint: USERINFO_ENDPOINT_GET.to_string(),
+            possible_revocation_endpoint: Some(REVOCATION_ENDPOINT_POST.to_string()),
+            possible_emails_endpoint: None,
+        }
+    }
+
+    // NOTE: GitHub OAuth apps can only revoke via basic auth on the app's own API, which we do not
+    // bother with; its tokens are dropped on our side only
+    pub fn github() -> Self {
+        OAuth2Endpoints {
+            authorization_endpoint: GITHUB_AUTHORIZATION_ENDPOINT_GET.to_string(),
+            token_endpoint: GITHUB_TOKEN_ENDPOINT_POST.to_string(),
+            userinfo_endpoint: GITHUB_USERINFO_ENDPOINT_GET.to_string(),
+            possible_revocation_endpoint: None,
+            possible_emails_endpoint: Some(GITHUB_EMAILS_ENDPOINT_GET.to_string()),
+        }
+    }
+
+    pub fn from_discovery_document(
+        issuer_url: &str,
+        discovery_document: OidcDiscoveryDocument,
+    ) -> AnyResult<Self> {
+        let userinfo_endpoint = match discovery_document.possible_userinfo_endpoint {
+            Some(userinfo_endpoint) => userinfo_endpoint,
+            None => anyhow::bail!("'{}' has no userinfo_endpoint", issuer_url),
+        };
+        Ok(OAuth2Endpoints {
+            authorization_endpoint: discovery_document.authorization_endpoint,
+            token_endpoint: discovery_document.token_endpoint,
+            userinfo_endpoint,
+            possible_revocation_endpoint: discovery_document.possible_revocation_endpoint,
+            possible_emails_endpoint: None,
+        })
+    }
+
+    // GET <issuer_url>/.well-known/openid-configuration
+    pub async fn discover(http_client: &reqwest::Client, issuer_url: &str) -> AnyResult<Self> {
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            issuer_url.trim_end_matches('/')
+        );
+        let response = http_client
+            .get(discovery_url.as_str())
+            .header("Accept", "application/json")
+            .header("User-Agent", USER_AGENT)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!("'{}' responded with {}", discovery_url, status);
+        }
+        Self::from_discovery_document(issuer_url, response.json::<OidcDiscoveryDocument>().await?)
+    }
+}
+
+#[derive(Clone)]
+pub struct OAuth2Provider {
+    pub name: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub redirect_uri: String,
+    pub scope: String, // space separated
+    // Google specific bits of the auth URL (see OAuth2AuthCodeRequest), None for everybody else
+    pub possible_access_type: Option<String>,
+    pub possible_include_granted_scopes: Option<bool>,
+    pub possible_prompt: Option<String>,
+    pub endpoints: OAuth2Endpoints,
+}
+
+impl OAuth2Provider {
+    pub fn new(config: &OAuth2ProviderConfig, endpoints: OAuth2Endpoints) -> Self {
+        let is_google = config.kind == OAuth2ProviderKind::Google;
+        let default_scope = match config.kind {
+            // refresh_token comes from access_type=offline, rather than a scope
+            OAuth2ProviderKind::Google => "email profile https://www.googleapis.com/auth/drive",
+            OAuth2ProviderKind::GitHub => "read:user user:email",
+            // offline_access is what gets us a refresh_token
+            OAuth2ProviderKind::Microsoft { .. } => "openid email profile offline_access",
+            OAuth2ProviderKind::Oidc { .. } => "openid email profile",
+        };
+        OAuth2Provider {
+            name: config.name.clone(),
+            client_id: config.client_id.clone(),
+            client_secret: config.client_secret.clone(),
+            redirect_uri: config.redirect_uri.clone(),
+            scope: config
+                .possible_scope
+                .clone()
+                .unwrap_or_else(|| default_scope.to_string()),
+            possible_access_type: is_google.then(|| "offline".to_string()),
+            possible_include_granted_scopes: is_google.then_some(true),
+            possible_prompt: is_google.then(|| "consent select_account".to_string()),
+            endpoints,
+        }
+    }
+
+    // Resolves the endpoints of the provider (network, for the OIDC ones)
+    pub async fn discover(
+        http_client: &reqwest::Client,
+        config: &OAuth2ProviderConfig,
+    ) -> AnyResult<Self> {
+        let endpoints = match (&config.kind, &config.possible_issuer_url) {
+            // overridden (i.e. mock provider), no falling back on the real thing
+            (OAuth2ProviderKind::Google, Some(issuer_url))
+            | (OAuth2ProviderKind::Microsoft { .. }, Some(issuer_url)) => {
+                OAuth2Endpoints::discover(http_client, issuer_url).await?
+            }
+            (OAuth2ProviderKind::Google, None) => {
+                match OAuth2Endpoints::discover(http_client, GOOGLE_ISSUER_URL).await {
+                    Ok(endpoints) => endpoints,
+                    Err(e) => {
+                        println!(
+                            "Providers: Failed to discover Google endpoints, using the well-known ones: {:?}",
+                            e
+                        );
+                        OAuth2Endpoints::google()
+                    }
+                }
+            }
+            (OAuth2ProviderKind::GitHub, _) => OAuth2Endpoints::github(),
+            (OAuth2ProviderKind::Microsoft { tenant }, None) => {
+                OAuth2Endpoints::discover(
+                    http_client,
+                    format!("{}/{}/v2.0", MICROSOFT_ISSUER_URL, tenant).as_str(),
+                )
+                .await?
+            }
+            (OAuth2ProviderKind::Oidc { issuer_url }, _) => {
+                OAuth2Endpoints::discover(http_client, issuer_url).await?
+            }
+        };
+        println!(
+            "Providers: '{}' authorizes via {}",
+            config.name, endpoints.authorization_endpoint
+        );
+        Ok(Self::new(config, endpoints))
+    }
+
+    // what login() hands the player's browser (see login::make_auth_url())
+    // possible_code_challenge is the S256 PKCE code_challenge (see login::make_code_challenge())
+    pub fn make_auth_code_request(
+        &self,
+        possible_state: Option<String>,
+        possible_code_challenge: Option<String>,
+    ) -> OAuth2AuthCodeRequest {
+        OAuth2AuthCodeRequest {
+            client_id: self.client_id.clone(),
+            redirect_uri: self.redirect_uri.clone(),
+            response_type: "code".to_string(),
+            scope: self.scope.clone(),
+            possible_access_type: self.possible_access_type.clone(),
+            possible_state,
+            possible_include_granted_scopes: self.possible_include_granted_scopes,
+            possible_prompt: self.possible_prompt.clone(),
+            possible_code_challenge_method: possible_code_challenge
+                .as_ref()
+                .map(|_| "S256".to_string()),
+            possible_code_challenge,
+        }
+    }
+
+    // Player's (verified) email address, via the userinfo endpoint; for GitHub, in which the email
+    // on /user is null when kept private, falls back on the primary verified one of /user/emails
+    pub async fn request_email(
+        &self,
+        http_client: &reqwest::Client,
+        token_response: &OAuth2TokenResponse,
+    ) -> AnyResult<String> {
+        let authorization = format!(
+            "{} {}", // some providers say "bearer", which is still fine
+            token_response.token_type, token_response.access_token
+        );
+        let user_info: OAuth2ProviderUserInfo = self
+            .get_json(
+                http_client,
+                self.endpoints.userinfo_endpoint.as_str(),
+                authorization.as_str(),
+            )
+            .await?;
+        if user_info.possible_email_verified == Some(false) {
+            anyhow::bail!("'{}' says the email has not been verified", self.name);
+        }
+        if let Some(email) = user_info.possible_email {
+            return Ok(email);
+        }
+        let emails_endpoint = match &self.endpoints.possible_emails_endpoint {
+            Some(emails_endpoint) => emails_endpoint,
+            None => anyhow::bail!("'{}' did not hand out an email", self.name),
+        };
+        let user_emails: Vec<GitHubUserEmail> = self
+            .get_json(http_client, emails_endpoint, authorization.as_str())
+            .await?;
+        match user_emails
+            .into_iter()
+            .find(|user_email| user_email.primary && user_email.verified)
+        {
+            Some(user_email) => Ok(user_email.email),
+            None => anyhow::bail!("'{}' has no primary verified email", self.name),
+        }
+    }
+
+    // Ok(()) if the host of every endpoint the relay itself calls resolves (the authorization
+    // endpoint is the player's browser's business), see /readyz
+    pub async fn resolve_endpoints(&self) -> AnyResult<()> {
+        let endpoints = [
+            Some(&self.endpoints.token_endpoint),
+            Some(&self.endpoints.userinfo_endpoint),
+            self.endpoints.possible_revocation_endpoint.as_ref(),
+            self.endpoints.possible_emails_endpoint.as_ref(),
+        ];
+        for endpoint in endpoints.into_iter().flatten() {
+            let url = reqwest::Url::parse(endpoint)?;
+            let (host, port) = match (url.host_str(), url.port_or_known_default()) {
+                (Some(host), Some(port)) => (host, port),
+                _ => anyhow::bail!("'{}' has no host", endpoint),
+            };
+            // [::1] is what host_str() says, lookup_host() wants it bare
+            let host = host.trim_start_matches('[').trim_end_matches(']');
+            if tokio::net::lookup_host((host, port))
+                .await?
+                .next()
+                .is_none()
+            {
+                anyhow::bail!("'{}' does not resolve", host);
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_json<T: serde::de::DeserializeOwned>(
+        &self,
+        http_client: &reqwest::Client,
+        endpoint: &str,
+        authorization: &str,
+    ) -> AnyResult<T> {
+        let response = http_client
+            .get(endpoint)
+            .header("Authorization", authorization)
+            .header("Accept", "application/json")
+            .header("User-Agent", USER_AGENT)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            anyhow::bail!(
+                "'{}' refused {}: {} {}",
+                self.name,
+                endpoint,
+                status,
+                response.text().await.unwrap_or_default()
+            );
+        }
+        Ok(response.json::<T>().await?)
+    }
+}
+
+// All enabled providers, the first one being the default (/login without ?provider=)
+pub struct OAuth2Providers {
+    providers: Vec<OAuth2Provider>,
+}
+pub type TOAuth2Providers = Arc<OAuth2Providers>;
+
+impl OAuth2Providers {
+    pub fn new(providers: Vec<OAuth2Provider>) -> AnyResult<Self> {
+        if providers.is_empty() {
+            anyhow::bail!("At least one OAuth2 provider is required");
+        }
+        Ok(OAuth2Providers { providers })
+    }
+
+    // refuses to start on the first provider which cannot be discovered, rather than finding out
+    // when a player picks it
+    pub async fn discover(provider_configs: &[OAuth2ProviderConfig]) -> AnyResult<Self> {
+        let http_client = reqwest::Client::new();
+        let mut providers = Vec::new();
+        for provider_config in provider_configs {
+            providers.push(
+                OAuth2Provider::discover(&http_client, provider_config)
+                    .await
+                    .map_err(|e| {
+                        anyhow::anyhow!("OAuth2 provider '{}': {}", provider_config.name, e)
+                    })?,
+            );
+        }
+        Self::new(providers)
+    }
+
+    pub fn default_provider(&self) -> &OAuth2Provider {
+        &self.providers[0]
+    }
+
+    // None (no ?provider=) is the default provider, unknown ones are None
+    pub fn get(&self, possible_name: Option<&str>) -> Option<&OAuth2Provider> {
+        match possible_name {
+            Some(name) => self
+                .providers
+                .iter()
+                .find(|provider| provider.name.eq_ignore_ascii_case(name)),
+            None => Some(self.default_provider()),
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &OAuth2Provider> {
+        self.providers.iter()
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.providers
+            .iter()
+            .map(|provider| provider.name.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use actix_web::{
+        body::BoxBody,
+        dev::{ServiceFactory, ServiceRequest, ServiceResponse},
+        web, App, HttpRequest, HttpResponse, HttpServer,
+    };
+
+    pub(crate) fn make_provider_config(
+        name: &str,
+        kind: OAuth2ProviderKind,
+    ) -> OAuth2ProviderConfig {
+        OAuth2ProviderConfig {
+            name: name.to_string(),
+            kind,
+            client_id: "test_client_id".to_string(),
+            client_secret: "test_client_secret".to_string(),
+            redirect_uri: "http://localhost:8080/auth_callback".to_string(),
+            possible_scope: None,
+            possible_issuer_url: None,
+        }
+    }
+
+    // "google" provider whose endpoints all live on base_url (i.e. a mock server of the test)
+    pub(crate) fn make_providers(base_url: &str) -> TOAuth2Providers {
+        let endpoints = OAuth2Endpoints {
+            authorization_endpoint: format!("{}/authorize", base_url),
+            token_endpoint: format!("{}/token", base_url),
+            userinfo_endpoint: format!("{}/userinfo", base_url),
+            possible_revocation_endpoint: Some(format!("{}/revoke", base_url)),
+            possible_emails_endpoint: None,
+        };
+        Arc::new(
+            OAuth2Providers::new(vec![OAuth2Provider::new(
+                &make_provider_config("google", OAuth2ProviderKind::Google),
+                endpoints,
+            )])
+            .unwrap(),
+        )
+    }
+
+    // serves app_factory's App on a free local port, and returns its base_url
+    pub(crate) fn start_mock_server<F, T>(app_factory: F) -> String
+    where
+        F: Fn() -> App<T> + Send + Clone + 'static,
+        T: ServiceFactory<
+                ServiceRequest,
+                Config = (),
+                Response = ServiceResponse<BoxBody>,
+                Error = actix_web::Error,
+                InitError = (),
+            > + 'static,
+    {
+        let http_server = HttpServer::new(app_factory)
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let base_url = format!("http://{}", http_server.addrs()[0]);
+        actix_web::rt::spawn(http_server.run());
+        base_url
+    }
+
+    // plays an OIDC provider's discovery document, and GitHub's /user and /user/emails
+    fn start_mock_provider() -> String {
+        start_mock_server(|| {
+            App::new()
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to(|request: HttpRequest| async move {
+                        let base_url = format!("http://{}", request.connection_info().host());
+                        HttpResponse::Ok().json(serde_json::json!({
+                            "issuer": base_url,
+                            "authorization_endpoint": format!("{}/authorize", base_url),
+                            "token_endpoint": format!("{}/token", base_url),
+                            "userinfo_endpoint": format!("{}/userinfo", base_url),
+                            "jwks_uri": format!("{}/jwks", base_url),
+                        }))
+                    }),
+                )
+                .route(
+                    "/user",
+                    web::get().to(|request: HttpRequest| async move {
+                        assert!(request.headers().contains_key("User-Agent"));
+                        HttpResponse::Ok().json(serde_json::json!({"id": 1, "email": null}))
+                    }),
+                )
+                .route(
+                    "/user/emails",
+                    web::get().to(|| async {
+                        HttpResponse::Ok().json(serde_json::json!([
+                            {"email": "other@example.com", "primary": false, "verified": true},
+                            {"email": "unverified@example.com", "primary": true, "verified": false},
+                            {"email": "player@example.com", "primary": true, "verified": true},
+                        ]))
+                    }),
+                )
+                .route(
+                    "/unverified",
+                    web::get().to(|| async {
+                        HttpResponse::Ok().json(serde_json::json!({
+                            "sub": "1",
+                            "email": "player@example.com",
+                            "email_verified": false
+                        }))
+                    }),
+                )
+        })
+    }
+
+    fn make_token_response() -> OAuth2TokenResponse {
+        serde_json::from_value(serde_json::json!({
+            "access_token": "access_token",
+            "scope": "read:user,user:email",
+            "token_type": "bearer"
+        }))
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn discovers_oidc_endpoints() {
+        let base_url = start_mock_provider();
+        let provider = OAuth2Provider::discover(
+            &reqwest::Client::new(),
+            &make_provider_config(
+                "okta",
+                OAuth2ProviderKind::Oidc {
+                    issuer_url: format!("{}/", base_url),
+                },
+            ),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            provider.endpoints.token_endpoint,
+            format!("{}/token", base_url)
+        );
+        assert_eq!(provider.endpoints.possible_revocation_endpoint, None);
+        assert_eq!(provider.scope, "openid email profile");
+        assert!(provider.possible_access_type.is_none());
+
+        // nobody home
+        assert!(OAuth2Providers::discover(&[make_provider_config(
+            "okta",
+            OAuth2ProviderKind::Oidc {
+                issuer_url: "http://127.0.0.1:1".to_string(),
+            },
+        )])
+        .await
+        .is_err());
+    }
+
+    #[actix_web::test]
+    async fn github_email_falls_back_to_primary_verified_one() {
+        let base_url = start_mock_provider();
+        let mut endpoints = OAuth2Endpoints::github();
+        endpoints.userinfo_endpoint = format!("{}/user", base_url);
+        endpoints.possible_emails_endpoint = Some(format!("{}/user/emails", base_url));
+        let provider = OAuth2Provider::new(
+            &make_provider_config("github", OAuth2ProviderKind::GitHub),
+            endpoints,
+        );
+        let email = provider
+            .request_email(&reqwest::Client::new(), &make_token_response())
+            .await
+            .unwrap();
+        assert_eq!(email, "player@example.com");
+    }
+
+    #[actix_web::test]
+    async fn rejects_unverified_email() {
+        let base_url = start_mock_provider();
+        let mut provider = make_providers(&base_url).default_provider().clone();
+        provider.endpoints.userinfo_endpoint = format!("{}/unverified", base_url);
+        assert!(provider
+            .request_email(&reqwest::Client::new(), &make_token_response())
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn first_provider_is_the_default() {
+        let providers = OAuth2Providers::new(vec![
+            OAuth2Provider::new(
+                &make_provider_config("github", OAuth2ProviderKind::GitHub),
+                OAuth2Endpoints::github(),
+            ),
+            OAuth2Provider::new(
+                &make_provider_config("google", OAuth2ProviderKind::Google),
+                OAuth2Endpoints::google(),
+            ),
+        ])
+        .unwrap();
+        assert_eq!(providers.get(None).unwrap().name, "github");
+        assert_eq!(providers.get(Some("Google")).unwrap().name, "google");
+        assert!(providers.get(Some("myspace")).is_none());
+        assert_eq!(providers.names(), vec!["github", "google"]);
+        assert!(OAuth2Providers::new(Vec::new()).is_err());
+    }
+}
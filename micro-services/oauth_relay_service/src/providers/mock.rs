@@ -0,0 +1,334 @@
+use crate::web::actix::login::make_code_challenge;
+use actix_web::{http::header, web, App, HttpRequest, HttpResponse, HttpServer};
+use std::{
+    collections::HashMap,
+    net::TcpListener,
+    sync::{Arc, Mutex},
+};
+
+// Mock OIDC provider (authorize, token, userinfo, revoke and the discovery document) on
+// 127.0.0.1, so that the whole auth-code flow of sequence_flow.puml runs without Google nor a
+// browser: point a provider at it via OAuth2ProviderConfig::possible_issuer_url (GOOGLE_ISSUER_URL)
+// and "consent" by GET'ing the auth URL, which redirects straight back to the relay's callback.
+// Users are picked via login_hint (first user if not set), and set_failure() makes it misbehave
+// the way real providers do (denied consent, refused code, userinfo down, ...)
+// PKCE is enforced the way Google does it: an auth code requested with a code_challenge can only
+// be traded along with its code_verifier
+
+pub(crate) const MOCK_CLIENT_ID: &str = "test_client_id";
+pub(crate) const MOCK_CLIENT_SECRET: &str = "test_client_secret";
+const MOCK_EXPIRES_IN: i64 = 3599;
+
+#[derive(Clone, Debug)]
+pub(crate) struct MockUser {
+    pub email: String,
+    pub email_verified: bool,
+}
+impl MockUser {
+    pub fn new(email: &str) -> Self {
+        MockUser {
+            email: email.to_string(),
+            email_verified: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum MockFailure {
+    #[default]
+    None,
+    DenyConsent,    // authorize redirects back with error=access_denied
+    RejectCode,     // token endpoint answers 400 invalid_grant to auth codes
+    RejectRefresh,  // token endpoint answers 400 invalid_grant to refresh_tokens
+    NoRefreshToken, // token endpoint hands out no refresh_token (i.e. not the first consent)
+    UserinfoDown,   // userinfo endpoint answers 503
+}
+
+struct MockAuthCode {
+    user_index: usize,
+    redirect_uri: String,
+    possible_code_challenge: Option<String>, // S256, PKCE
+}
+
+#[derive(Default)]
+struct MockProviderState {
+    users: Vec<MockUser>,
+    failure: MockFailure,
+    codes: HashMap<String, MockAuthCode>,   // single use
+    access_tokens: HashMap<String, usize>,  // -> user index
+    refresh_tokens: HashMap<String, usize>, // -> user index
+    revoked_tokens: Vec<String>,
+    next_id: u64,
+}
+impl MockProviderState {
+    fn make_id(&mut self, prefix: &str) -> String {
+        self.next_id += 1;
+        format!("{}_{}", prefix, self.next_id)
+    }
+}
+type TMockProviderState = Arc<Mutex<MockProviderState>>;
+
+pub(crate) struct MockProvider {
+    issuer_url: String,
+    state: TMockProviderState,
+}
+
+impl MockProvider {
+    // runs until the test's runtime goes away
+    pub fn start(users: Vec<MockUser>) -> Self {
+        let state = TMockProviderState::new(Mutex::new(MockProviderState {
+            users,
+            ..Default::default()
+        }));
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let issuer_url = format!("http://{}", listener.local_addr().unwrap());
+        let app_state = state.clone();
+        let app_issuer_url = issuer_url.clone();
+        let http_server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(app_state.clone()))
+                .app_data(web::Data::new(app_issuer_url.clone()))
+                .route(
+                    "/.well-known/openid-configuration",
+                    web::get().to(discovery_document),
+                )
+                .route("/authorize", web::get().to(authorize))
+                .route("/token", web::post().to(token))
+                .route("/userinfo", web::get().to(userinfo))
+                .route("/revoke", web::post().to(revoke))
+        })
+        .workers(1)
+        .listen(listener)
+        .unwrap();
+        actix_web::rt::spawn(http_server.run());
+        MockProvider { issuer_url, state }
+    }
+
+    pub fn issuer_url(&self) -> &str {
+        self.issuer_url.as_str()
+    }
+
+    pub fn set_failure(&self, failure: MockFailure) {
+        self.state.lock().unwrap().failure = failure;
+    }
+
+    pub fn revoked_tokens(&self) -> Vec<String> {
+        self.state.lock().unwrap().revoked_tokens.clone()
+    }
+}
+
+async fn discovery_document(issuer_url: web::Data<String>) -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({
+        "issuer": issuer_url.as_str(),
+        "authorization_endpoint": format!("{}/authorize", issuer_url.as_str()),
+        "token_endpoint": format!("{}/token", issuer_url.as_str()),
+        "userinfo_endpoint": format!("{}/userinfo", issuer_url.as_str()),
+        "revocation_endpoint": format!("{}/revoke", issuer_url.as_str()),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code", "refresh_token"],
+    }))
+}
+
+fn redirect_back(redirect_uri: &str, params: &[(&str, String)]) -> HttpResponse {
+    match reqwest::Url::parse_with_params(redirect_uri, params) {
+        Ok(location) => HttpResponse::Found()
+            .insert_header((header::LOCATION, location.to_string()))
+            .finish(),
+        Err(e) => HttpResponse::BadRequest().body(format!("bad redirect_uri: {}", e)),
+    }
+}
+
+fn oauth2_error(error: &str) -> HttpResponse {
+    HttpResponse::BadRequest().json(serde_json::json!({ "error": error }))
+}
+
+// GET /authorize?client_id=&redirect_uri=&response_type=code&state=[&login_hint=]
+// the player "consents" right away, and gets redirected back to the relay's callback
+async fn authorize(
+    query: web::Query<HashMap<String, String>>,
+    state: web::Data<TMockProviderState>,
+) -> HttpResponse {
+    let (redirect_uri, state_param) = match (query.get("redirect_uri"), query.get("state")) {
+        (Some(redirect_uri), Some(state_param)) => (redirect_uri.clone(), state_param.clone()),
+        _ => return HttpResponse::BadRequest().body("redirect_uri and state are required"),
+    };
+    if query.get("client_id").map(|s| s.as_str()) != Some(MOCK_CLIENT_ID)
+        || query.get("response_type").map(|s| s.as_str()) != Some("code")
+    {
+        return redirect_back(
+            &redirect_uri,
+            &[
+                ("error", "unauthorized_client".to_string()),
+                ("state", state_param),
+            ],
+        );
+    }
+    let mut state = state.lock().unwrap();
+    if state.failure == MockFailure::DenyConsent {
+        return redirect_back(
+            &redirect_uri,
+            &[
+                ("error", "access_denied".to_string()),
+                ("state", state_param),
+            ],
+        );
+    }
+    let possible_user_index = match query.get("login_hint") {
+        Some(login_hint) => state
+            .users
+            .iter()
+            .position(|user| user.email == *login_hint),
+        None if !state.users.is_empty() => Some(0),
+        None => None,
+    };
+    let user_index = match possible_user_index {
+        Some(user_index) => user_index,
+        None => {
+            return redirect_back(
+                &redirect_uri,
+                &[
+                    ("error", "access_denied".to_string()),
+                    ("state", state_param),
+                ],
+            )
+        }
+    };
+    // same as Google, plain is allowed by the RFC but we only do S256
+    let possible_code_challenge = match (
+        query.get("code_challenge"),
+        query.get("code_challenge_method").map(|s| s.as_str()),
+    ) {
+        (Some(code_challenge), Some("S256")) => Some(code_challenge.clone()),
+        (None, None) => None,
+        _ => {
+            return redirect_back(
+                &redirect_uri,
+                &[
+                    ("error", "invalid_request".to_string()),
+                    ("state", state_param),
+                ],
+            )
+        }
+    };
+    let code = state.make_id("code");
+    state.codes.insert(
+        code.clone(),
+        MockAuthCode {
+            user_index,
+            redirect_uri: redirect_uri.clone(),
+            possible_code_challenge,
+        },
+    );
+    redirect_back(&redirect_uri, &[("code", code), ("state", state_param)])
+}
+
+// POST /token (grant_type=authorization_code or refresh_token), x-www-form-urlencoded
+async fn token(
+    form: web::Form<HashMap<String, String>>,
+    state: web::Data<TMockProviderState>,
+) -> HttpResponse {
+    if form.get("client_id").map(|s| s.as_str()) != Some(MOCK_CLIENT_ID)
+        || form.get("client_secret").map(|s| s.as_str()) != Some(MOCK_CLIENT_SECRET)
+    {
+        return HttpResponse::Unauthorized().json(serde_json::json!({"error": "invalid_client"}));
+    }
+    let mut state = state.lock().unwrap();
+    let (user_index, is_refresh) = match form.get("grant_type").map(|s| s.as_str()) {
+        Some("authorization_code") => {
+            if state.failure == MockFailure::RejectCode {
+                return oauth2_error("invalid_grant");
+            }
+            // single use, whether it works out or not
+            let possible_code = form.get("code").and_then(|code| state.codes.remove(code));
+            match possible_code {
+                Some(auth_code)
+                    if form.get("redirect_uri") == Some(&auth_code.redirect_uri)
+                        && auth_code.possible_code_challenge.as_deref()
+                            == form
+                                .get("code_verifier")
+                                .map(|code_verifier| make_code_challenge(code_verifier))
+                                .as_deref() =>
+                {
+                    (auth_code.user_index, false)
+                }
+                _ => return oauth2_error("invalid_grant"),
+            }
+        }
+        Some("refresh_token") => {
+            if state.failure == MockFailure::RejectRefresh {
+                return oauth2_error("invalid_grant");
+            }
+            match form
+                .get("refresh_token")
+                .and_then(|refresh_token| state.refresh_tokens.get(refresh_token))
+            {
+                Some(user_index) => (*user_index, true),
+                None => return oauth2_error("invalid_grant"),
+            }
+        }
+        _ => return oauth2_error("unsupported_grant_type"),
+    };
+    let access_token = state.make_id("mock_access");
+    state.access_tokens.insert(access_token.clone(), user_index);
+    // same as Google: refresh_token only on (first) consent, never on refresh
+    let possible_refresh_token = match is_refresh || state.failure == MockFailure::NoRefreshToken {
+        true => None,
+        false => {
+            let refresh_token = state.make_id("mock_refresh");
+            state
+                .refresh_tokens
+                .insert(refresh_token.clone(), user_index);
+            Some(refresh_token)
+        }
+    };
+    HttpResponse::Ok().json(serde_json::json!({
+        "access_token": access_token,
+        "expires_in": MOCK_EXPIRES_IN,
+        "id_token": "mock.id.token",
+        "scope": "openid email profile",
+        "token_type": "Bearer",
+        "refresh_token": possible_refresh_token,
+    }))
+}
+
+// GET /userinfo with Authorization: Bearer <access_token>
+async fn userinfo(request: HttpRequest, state: web::Data<TMockProviderState>) -> HttpResponse {
+    let state = state.lock().unwrap();
+    if state.failure == MockFailure::UserinfoDown {
+        return HttpResponse::ServiceUnavailable().finish();
+    }
+    let possible_user = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .and_then(|access_token| state.access_tokens.get(access_token))
+        .map(|user_index| state.users[*user_index].clone());
+    match possible_user {
+        Some(user) => HttpResponse::Ok().json(serde_json::json!({
+            "sub": user.email,
+            "email": user.email,
+            "email_verified": user.email_verified,
+        })),
+        None => HttpResponse::Unauthorized().json(serde_json::json!({"error": "invalid_token"})),
+    }
+}
+
+// POST /revoke (token=...), refresh_token takes nothing else with it here, it's a mock...
+async fn revoke(
+    form: web::Form<HashMap<String, String>>,
+    state: web::Data<TMockProviderState>,
+) -> HttpResponse {
+    let mut state = state.lock().unwrap();
+    let token = match form.get("token") {
+        Some(token) => token.clone(),
+        None => return oauth2_error("invalid_request"),
+    };
+    let was_known = state.access_tokens.remove(&token).is_some()
+        | state.refresh_tokens.remove(&token).is_some();
+    if !was_known {
+        return oauth2_error("invalid_token");
+    }
+    state.revoked_tokens.push(token);
+    HttpResponse::Ok().finish()
+}
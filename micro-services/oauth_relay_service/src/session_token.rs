@@ -0,0 +1,249 @@
+use crate::{
+    config::Config,
+    data::{SessionTokenClaims, TokenData},
+    web::web_consts::SESSION_TOKEN_TTL,
+};
+use anyhow::Result as AnyResult;
+use base64::{engine::general_purpose, Engine};
+use jsonwebtoken::{
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Signs session tokens (JWT, EdDSA/Ed25519) handed to the client on login and keepalive; claims
+// are SessionTokenClaims (sub=email, sid=session_id, iss, iat, exp).
+// Only the public half ever leaves the relay, via the JWKS (see jwks()), which is what the other
+// services use to verify callers offline.
+// NOTE: Without SESSION_TOKEN_SIGNING_KEY, a throw-away key is generated at startup, which is fine
+// for a single (dev) relay, but tokens do not survive a restart, and with more than one relay,
+// each would have its own key; so set it (same key on every relay) for anything else.
+pub struct SessionTokenIssuer {
+    key_id: String,
+    issuer: String,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    public_key: Vec<u8>, // raw 32 bytes Ed25519 public key
+}
+
+impl SessionTokenIssuer {
+    // private_key_pkcs8 is the DER encoded PKCS#8 Ed25519 private key
+    pub fn new(key_id: &str, issuer: &str, private_key_pkcs8: &[u8]) -> AnyResult<Self> {
+        if key_id.is_empty() {
+            anyhow::bail!("Session token key id must not be empty");
+        }
+        let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(private_key_pkcs8)
+            .map_err(|e| anyhow::anyhow!("Session token signing key '{}': {}", key_id, e))?;
+        let public_key = key_pair.public_key().as_ref().to_vec();
+        Ok(SessionTokenIssuer {
+            key_id: key_id.to_string(),
+            issuer: issuer.to_string(),
+            encoding_key: EncodingKey::from_ed_der(private_key_pkcs8),
+            decoding_key: DecodingKey::from_ed_der(&public_key),
+            public_key,
+        })
+    }
+
+    pub fn generate(key_id: &str, issuer: &str) -> AnyResult<Self> {
+        let private_key_pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .map_err(|e| anyhow::anyhow!("Failed to generate session token signing key: {}", e))?;
+        Self::new(key_id, issuer, private_key_pkcs8.as_ref())
+    }
+
+    pub fn from_config(config: &Config) -> AnyResult<Self> {
+        match &config.possible_session_token_signing_key {
+            Some(key_base64) => {
+                let private_key_pkcs8 = general_purpose::STANDARD
+                    .decode(key_base64.trim())
+                    .map_err(|e| anyhow::anyhow!("SESSION_TOKEN_SIGNING_KEY: {}", e))?;
+                Self::new(
+                    config.session_token_key_id.as_str(),
+                    config.session_token_issuer.as_str(),
+                    &private_key_pkcs8,
+                )
+            }
+            None => {
+                println!("SessionToken: SESSION_TOKEN_SIGNING_KEY is not set, generating a throw-away key (dev only!)");
+                Self::generate(
+                    config.session_token_key_id.as_str(),
+                    config.session_token_issuer.as_str(),
+                )
+            }
+        }
+    }
+
+    pub fn key_id(&self) -> &str {
+        self.key_id.as_str()
+    }
+
+    // Signs a session token for the (logged in) session, valid for SESSION_TOKEN_TTL
+    pub fn issue(&self, token_data: &TokenData) -> AnyResult<String> {
+        let session_id = match token_data.session_id() {
+            Some(session_id) => session_id,
+            None => anyhow::bail!("Cannot issue a session token without session_id"),
+        };
+        let email = match &token_data.possible_client_email {
+            Some(email) => email.clone(),
+            None => anyhow::bail!(
+                "Cannot issue a session token without email for session_id: {}",
+                session_id
+            ),
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        // sessions without refresh_token are over once expired, the session token should not outlive them
+        let mut exp = now + SESSION_TOKEN_TTL.as_secs();
+        if token_data.possible_refresh_token.is_none() {
+            exp = exp.min(now.saturating_add_signed(token_data.expiry_time_as_sec_from_now()));
+        }
+        let claims = SessionTokenClaims {
+            sub: email,
+            sid: session_id,
+            iss: self.issuer.clone(),
+            iat: now,
+            exp,
+        };
+        let mut header = Header::new(Algorithm::EdDSA);
+        header.kid = Some(self.key_id.clone());
+        Ok(jsonwebtoken::encode(&header, &claims, &self.encoding_key)?)
+    }
+
+    // Same checks the other services are expected to do (signature, exp, iss)
+    pub fn verify(&self, session_token: &str) -> AnyResult<SessionTokenClaims> {
+        let mut validation = Validation::new(Algorithm::EdDSA);
+        validation.set_issuer(&[self.issuer.as_str()]);
+        validation.set_required_spec_claims(&["exp", "iss", "sub"]);
+        Ok(jsonwebtoken::decode::<SessionTokenClaims>(
+            session_token,
+            &self.decoding_key,
+            &validation,
+        )?
+        .claims)
+    }
+
+    // JWK Set (RFC 7517) with our public key, served as /.well-known/jwks.json
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: vec![Jwk {
+                common: CommonParameters {
+                    public_key_use: Some(PublicKeyUse::Signature),
+                    key_algorithm: Some(KeyAlgorithm::EdDSA),
+                    key_id: Some(self.key_id.clone()),
+                    ..Default::default()
+                },
+                algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x: general_purpose::URL_SAFE_NO_PAD.encode(&self.public_key),
+                }),
+            }],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data::SessionIDType, storage::tests::make_token_data};
+
+    #[test]
+    fn issue_then_verify() {
+        let issuer = SessionTokenIssuer::generate("k1", "test_relay").unwrap();
+        let token_data = make_token_data(SessionIDType::ID(42), "state_token", "access_token");
+        let session_token = issuer.issue(&token_data).unwrap();
+        let claims = issuer.verify(&session_token).unwrap();
+        assert_eq!(claims.sub, "player@example.com");
+        assert_eq!(claims.sid, 42);
+        assert_eq!(claims.iss, "test_relay");
+        assert_eq!(claims.exp - claims.iat, SESSION_TOKEN_TTL.as_secs());
+        assert_eq!(
+            jsonwebtoken::decode_header(&session_token).unwrap().kid,
+            Some("k1".to_string())
+        );
+    }
+
+    #[test]
+    fn verify_rejects_other_keys_and_issuers() {
+        let issuer = SessionTokenIssuer::generate("k1", "test_relay").unwrap();
+        let token_data = make_token_data(SessionIDType::ID(42), "state_token", "access_token");
+        let session_token = issuer.issue(&token_data).unwrap();
+        let other_key = SessionTokenIssuer::generate("k1", "test_relay").unwrap();
+        assert!(other_key.verify(&session_token).is_err());
+        let other_issuer = SessionTokenIssuer::generate("k1", "someone_else").unwrap();
+        let forged = other_issuer.issue(&token_data).unwrap();
+        assert!(issuer.verify(&forged).is_err());
+
+        let mut tampered = session_token.clone();
+        tampered.insert(session_token.find('.').unwrap() + 1, 'x');
+        assert!(issuer.verify(&tampered).is_err());
+    }
+
+    #[test]
+    fn session_token_does_not_outlive_session_without_refresh_token() {
+        let issuer = SessionTokenIssuer::generate("k1", "test_relay").unwrap();
+        let mut token_data = make_token_data(SessionIDType::ID(42), "state_token", "access_token");
+        token_data.possible_refresh_token = None;
+        token_data.set_expiry_time_from_now(600);
+        let claims = issuer.verify(&issuer.issue(&token_data).unwrap()).unwrap();
+        assert!(claims.exp - claims.iat <= 600);
+    }
+
+    #[test]
+    fn issue_requires_session_id_and_email() {
+        let issuer = SessionTokenIssuer::generate("k1", "test_relay").unwrap();
+        let mut token_data = make_token_data(SessionIDType::ID(42), "state_token", "access_token");
+        token_data.possible_client_email = None;
+        assert!(issuer.issue(&token_data).is_err());
+        token_data = make_token_data(SessionIDType::ID(42), "state_token", "access_token");
+        token_data.set_session_id(SessionIDType::Undefined(None));
+        assert!(issuer.issue(&token_data).is_err());
+    }
+
+    #[test]
+    fn jwks_verifies_issued_tokens() {
+        let issuer = SessionTokenIssuer::generate("k1", "test_relay").unwrap();
+        let token_data = make_token_data(SessionIDType::ID(42), "state_token", "access_token");
+        let session_token = issuer.issue(&token_data).unwrap();
+        // round-trip through JSON, as a verifier fetching /.well-known/jwks.json would
+        let jwks_json = serde_json::to_value(issuer.jwks()).unwrap();
+        assert_eq!(jwks_json["keys"][0]["kty"], "OKP");
+        assert_eq!(jwks_json["keys"][0]["crv"], "Ed25519");
+        assert_eq!(jwks_json["keys"][0]["alg"], "EdDSA");
+        assert_eq!(jwks_json["keys"][0]["kid"], "k1");
+        let jwks: JwkSet = serde_json::from_value(jwks_json).unwrap();
+        let jwk = jwks.find("k1").unwrap();
+        let mut validation = Validation::new(Algorithm::EdDSA);
+        validation.set_issuer(&["test_relay"]);
+        let claims = jsonwebtoken::decode::<SessionTokenClaims>(
+            &session_token,
+            &DecodingKey::from_jwk(jwk).unwrap(),
+            &validation,
+        )
+        .unwrap()
+        .claims;
+        assert_eq!(claims.sid, 42);
+    }
+
+    #[test]
+    fn signing_key_from_config_round_trips() {
+        let private_key_pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_base64 = general_purpose::STANDARD.encode(private_key_pkcs8.as_ref());
+        let a = SessionTokenIssuer::new("k1", "test_relay", private_key_pkcs8.as_ref()).unwrap();
+        let b = SessionTokenIssuer::new(
+            "k1",
+            "test_relay",
+            &general_purpose::STANDARD.decode(key_base64).unwrap(),
+        )
+        .unwrap();
+        let token_data = make_token_data(SessionIDType::ID(7), "state_token", "access_token");
+        let session_token = a.issue(&token_data).unwrap();
+        assert_eq!(b.verify(&session_token).unwrap().sid, 7);
+        assert!(SessionTokenIssuer::new("k1", "test_relay", b"not a key").is_err());
+    }
+}
@@ -1,112 +1,420 @@
 //#include
+pub(crate) mod memory;
+pub(crate) mod migrations;
+pub(crate) mod postgres;
 pub(crate) mod sqlite;
+pub(crate) mod token_cipher;
 
 use crate::{
     config::{HostType, *},
-    data::TokenData,
+    data::{LoginState, TokenData},
 };
 use anyhow::Result as AnyResult;
-use core::panic;
-use std::sync::Arc;
-use tokio::sync::Mutex;
+use async_trait::async_trait;
+use std::{sync::Arc, time::SystemTime};
+use token_cipher::TokenCipher;
 
-pub type TDBConnection = sqlite::TDBConnection_sqlite; // currently using tokio-rusqlite
-pub type TDBConnectionLock = sqlite::TDBConnectionLock_sqlite;
+// Each backend (SQLite, Postgres, in-memory) implements this, and which one is used is decided
+// ONCE at startup (see open_token_store_from_config()), so handlers (and tests) only deal with
+// `dyn TokenStore` and never match on DBType themselves
+#[async_trait]
+pub trait TokenStore: Send + Sync {
+    // i.e. "sqlite", "postgres", "memory" (mainly for logging and OAuth2AuthCodeRequestState)
+    fn db_type(&self) -> String;
+    fn db_address(&self) -> Option<HostType> {
+        None
+    }
+    fn db_port(&self) -> Option<u16> {
+        None
+    }
+    fn db_path(&self) -> Option<String> {
+        None
+    }
+
+    // create/upgrade DB table(s) to the latest schema version (see migrations.rs), returns that
+    // version, or Err() if the DB is newer than this binary (refuse to start)
+    // Also re-encrypts tokens which are not encrypted with the current key (i.e. plain-text rows
+    // from before encryption, or rows encrypted with a rotated out key, see token_cipher.rs)
+    async fn migrate(&self) -> AnyResult<i64>;
+
+    // Ok(()) if the DB answers (a round trip, not just an open connection), see /readyz
+    async fn ping(&self) -> AnyResult<()>;
+
+    // Ok(None) if session does not exist (or last_session_id is not a valid session_id), Err() on DB errors
+    async fn get_token_by_session_id(
+        &self,
+        last_session_id: &Option<String>,
+    ) -> AnyResult<Option<TokenData>>;
+
+    // Inserts new session (login) or updates existing session (refreshed token and/or expiry), returns session_id
+    // If TokenData already has a (DB) session_id, it is an update of an existing session, else it
+    // is a new login which is inserted (or updated if state_token exists)
+    async fn upsert_token_data(&self, token_data: &TokenData) -> AnyResult<u64>;
+
+    // Sessions whose token expires (or has expired) before expiry_time, with or without refresh_token
+    // (i.e. for the refresh sweeper, see web/actix/refresh.rs)
+    async fn get_tokens_expiring_before(
+        &self,
+        expiry_time: SystemTime,
+    ) -> AnyResult<Vec<TokenData>>;
 
-fn new_connection(db_connection: TDBConnection) -> TDBConnectionLock {
-    Arc::new(Mutex::new(db_connection))
+    // Ok(false) if session does not exist (i.e. already deleted), Err() on DB errors
+    async fn delete_token_by_session_id(&self, session_id: u64) -> AnyResult<bool>;
+
+    // Login in progress (see LoginState), stored on /login next to its state_token, since the
+    // callback may well land on another relay instance
+    async fn insert_login_state(&self, login_state: &LoginState) -> AnyResult<()>;
+
+    // Marks the login state used, and hands it back as it was BEFORE that (so possible_used_at is
+    // Some if it had already been taken, i.e. replayed callback); Ok(None) if state_token is
+    // unknown (or purged), Err() on DB errors
+    async fn take_login_state(&self, state_token: &str) -> AnyResult<Option<LoginState>>;
+
+    // Whether state_token is one /login issued (taken or not), without taking it (i.e. for the
+    // /login long-poll); Ok(false) if unknown (or purged), Err() on DB errors
+    async fn has_login_state(&self, state_token: &str) -> AnyResult<bool>;
+
+    // Purges login states created before created_before, used or not (see reaper.rs); Returns
+    // number of login states deleted
+    async fn delete_login_states_created_before(
+        &self,
+        created_before: SystemTime,
+    ) -> AnyResult<u64>;
 }
 
-pub async fn open_db_connection_from_config(config: &Config) -> TDBConnectionLock {
-    match &config.db_connection {
-        DBType::SQLite { db_path } => sqlite::open_db_connection_rusqlite(db_path.as_str())
-            .await
-            .unwrap(),
-        DBType::PostgresSQL {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => panic!("PostgreSQL not yet implemented"),
-        //_ => panic!("Unsupported DBType"),
+// cloning an Arc<T> just means incrementing the reference count, so it's cheap to share with handlers
+pub type TTokenStore = Arc<dyn TokenStore>;
+
+// Tokens persisted on disk (SQLite, Postgres) are encrypted, so refuse to start without key(s)
+fn make_token_cipher(config: &Config) -> AnyResult<TokenCipher> {
+    if config.token_encryption_keys.is_empty() {
+        anyhow::bail!(
+            "TOKEN_ENCRYPTION_KEYS must be set for sqlite/postgres (i.e. \"k1:$(openssl rand -base64 32)\")"
+        );
     }
+    TokenCipher::new(&config.token_encryption_keys)
 }
 
-pub(crate) fn get_db_port(config: &Config) -> Option<u16> {
-    match &config.db_connection {
-        DBType::SQLite { db_path: _ } => None,
+pub async fn open_token_store_from_config(config: &Config) -> AnyResult<TTokenStore> {
+    let token_store: TTokenStore = match &config.db_connection {
+        DBType::SQLite { db_path } => {
+            Arc::new(sqlite::SQLiteTokenStore::open(db_path, make_token_cipher(config)?).await?)
+        }
         DBType::PostgresSQL {
-            host_as_name_or_address: _,
+            host_as_name_or_address,
             host_port,
-        } => Some(host_port.clone()),
-    }
+            db_user,
+            possible_db_password,
+            db_name,
+            pool_size,
+        } => Arc::new(
+            postgres::PostgresTokenStore::open(
+                host_as_name_or_address,
+                *host_port,
+                db_user,
+                possible_db_password,
+                db_name,
+                *pool_size,
+                make_token_cipher(config)?,
+            )
+            .await?,
+        ),
+        DBType::Memory => Arc::new(memory::MemoryTokenStore::new()),
+    };
+    println!(
+        "Storage: Using '{}' token store (token encryption key: {:?})",
+        token_store.db_type(),
+        config
+            .token_encryption_keys
+            .first()
+            .map(|(key_id, _)| key_id)
+    );
+    Ok(token_store)
 }
 
-pub(crate) fn get_db_address(config: &Config) -> Option<HostType> {
-    match &config.db_connection {
-        DBType::SQLite { db_path: _ } => None,
-        DBType::PostgresSQL {
-            host_as_name_or_address,
-            host_port: _,
-        } => Some(host_as_name_or_address.clone()),
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::data::{SessionIDType, DEFAULT_OAUTH2_PROVIDER};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    // state_token and access_token are UNIQUE, so make them unique per call (in case the DB
+    // outlives the test, i.e. Postgres)
+    pub(crate) fn make_unique(prefix: &str) -> String {
+        format!(
+            "{}_{}",
+            prefix,
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        )
     }
-}
 
-pub(crate) fn get_db_path(config: &Config) -> Option<String> {
-    match &config.db_connection {
-        DBType::SQLite { db_path } => Some(db_path.clone()),
-        DBType::PostgresSQL {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => None,
+    pub(crate) fn make_token_data(
+        session_id: SessionIDType,
+        state_token: &str,
+        access_token: &str,
+    ) -> TokenData {
+        TokenData::new(
+            session_id,
+            state_token.to_string(),
+            "192.168.1.2".into(),
+            12345,
+            Some("player@example.com".to_string()),
+            access_token.to_string(),
+            Some("refresh_token".to_string()),
+            3599,
+            SystemTime::now() + Duration::from_secs(3599),
+        )
     }
-}
 
-pub(crate) fn get_db_type(config: &Config) -> String {
-    match &config.db_connection {
-        DBType::SQLite { db_path: _ } => "sqlite".to_string(),
-        DBType::PostgresSQL {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => "postgres".to_string(),
+    // token named after name, expiring expires_in seconds from now (negative: already expired)
+    pub(crate) async fn store_token(
+        token_store: &TTokenStore,
+        name: &str,
+        possible_refresh_token: Option<&str>,
+        expires_in: i64,
+    ) -> u64 {
+        let mut token_data = make_token_data(
+            SessionIDType::make_hash(name),
+            name,
+            format!("access_{}", name).as_str(),
+        );
+        token_data.possible_refresh_token = possible_refresh_token.map(|s| s.to_string());
+        token_data.set_expiry_time_from_now(expires_in);
+        token_store.upsert_token_data(&token_data).await.unwrap()
     }
-}
 
-pub(crate) async fn get_token_by_session_id(
-    config: &Config,
-    db_connection: &TDBConnectionLock,
-    last_session_id: &Option<String>,
-) -> Option<TokenData> {
-    //    db_connection
-    //        .lock()
-    //        .await
-    //        .call(move |conn| {
-    //            conn.query_row(
-    //                "SELECT COUNT(*) FROM tokens WHERE session_id = ?",
-    //                params![last_session_id],
-    //                |row| row.get(0),
-    //            )
-    //            .map_err(|e| tokio_rusqlite::Error::from(e))
-    //        })
-    match &config.db_connection {
-        DBType::SQLite { db_path: _ } => {
-            sqlite::get_token_by_session_id(db_connection, last_session_id).await
+    pub(crate) fn make_test_token_cipher() -> TokenCipher {
+        TokenCipher::new(&[token_cipher::tests::make_key("test", 7)]).unwrap()
+    }
+
+    // Same expectations for every backend
+    pub(crate) async fn verify_upsert_then_update(token_store: &dyn TokenStore) {
+        token_store.migrate().await.unwrap();
+        token_store.ping().await.unwrap(); // what /readyz asks
+        let state_token = make_unique("state");
+
+        // new login
+        let mut new_login = make_token_data(
+            SessionIDType::make_hash(&state_token),
+            &state_token,
+            &make_unique("access"),
+        );
+        new_login.provider = "github".to_string();
+        let session_id = token_store.upsert_token_data(&new_login).await.unwrap();
+        let stored = token_store
+            .get_token_by_session_id(&Some(session_id.to_string()))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.access_token, new_login.access_token);
+        assert_eq!(stored.state_token, state_token);
+        assert_eq!(stored.client_port, 12345);
+        assert_eq!(stored.session_id(), Some(session_id));
+        assert_eq!(stored.provider, "github");
+        assert_eq!(
+            stored.expiry_time_as_sec_from_epoch(),
+            new_login.expiry_time_as_sec_from_epoch()
+        );
+
+        // same login again (same state_token) updates rather than inserts
+        let relogin = make_token_data(
+            SessionIDType::make_hash(&state_token),
+            &state_token,
+            &make_unique("access"),
+        );
+        assert_eq!(
+            token_store.upsert_token_data(&relogin).await.unwrap(),
+            session_id
+        );
+
+        // refreshed token (no new refresh_token, so the old one is kept)
+        let mut refreshed = make_token_data(
+            SessionIDType::ID(session_id),
+            &state_token,
+            &make_unique("access"),
+        );
+        refreshed.possible_refresh_token = None;
+        assert_eq!(
+            token_store.upsert_token_data(&refreshed).await.unwrap(),
+            session_id
+        );
+        let stored = token_store
+            .get_token_by_session_id(&Some(session_id.to_string()))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.access_token, refreshed.access_token);
+        assert_eq!(
+            stored.possible_refresh_token,
+            Some("refresh_token".to_string())
+        );
+
+        // unknown/invalid sessions are not errors, just None
+        assert!(token_store
+            .get_token_by_session_id(&Some(u32::MAX.to_string()))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(token_store
+            .get_token_by_session_id(&Some("abc".to_string()))
+            .await
+            .unwrap()
+            .is_none());
+        // but updating a session that does not exist is
+        let unknown = make_token_data(
+            SessionIDType::ID(u32::MAX as u64),
+            &make_unique("state"),
+            &make_unique("access"),
+        );
+        assert!(token_store.upsert_token_data(&unknown).await.is_err());
+    }
+
+    pub(crate) async fn verify_get_tokens_expiring_before(token_store: &dyn TokenStore) {
+        token_store.migrate().await.unwrap();
+        let mut session_ids = Vec::new();
+        for expires_in in [-60, 10, 3600] {
+            let state_token = make_unique("state");
+            let mut token_data = make_token_data(
+                SessionIDType::make_hash(&state_token),
+                &state_token,
+                &make_unique("access"),
+            );
+            token_data.set_expiry_time_from_now(expires_in);
+            session_ids.push(token_store.upsert_token_data(&token_data).await.unwrap());
         }
-        DBType::PostgresSQL {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => panic!("PostgreSQL not yet implemented"),
+        // the DB may be shared with other tests (i.e. Postgres), so only look at ours
+        let expiring: Vec<u64> = token_store
+            .get_tokens_expiring_before(SystemTime::now() + Duration::from_secs(60))
+            .await
+            .unwrap()
+            .iter()
+            .filter_map(|token_data| token_data.session_id())
+            .filter(|session_id| session_ids.contains(session_id))
+            .collect();
+        assert_eq!(expiring, session_ids[0..2].to_vec());
+        // and it's decrypted, same as get_token_by_session_id()
+        let expiring = token_store
+            .get_tokens_expiring_before(SystemTime::now())
+            .await
+            .unwrap();
+        let expired = expiring
+            .iter()
+            .find(|token_data| token_data.session_id() == Some(session_ids[0]))
+            .unwrap();
+        assert_eq!(
+            expired.possible_refresh_token,
+            Some("refresh_token".to_string())
+        );
+        assert_eq!(expired.provider, DEFAULT_OAUTH2_PROVIDER);
     }
-}
 
-pub(crate) async fn upsert_token_data(
-    config: &Config,
-    db_connection: &TDBConnectionLock,
-    token_data: &TokenData,
-) -> AnyResult<()> {
-    match &config.db_connection {
-        DBType::SQLite { db_path: _ } => sqlite::upsert_token_data(db_connection, token_data).await,
-        DBType::PostgresSQL {
-            host_as_name_or_address: _,
-            host_port: _,
-        } => panic!("PostgreSQL not yet implemented"),
+    pub(crate) async fn verify_take_login_state(token_store: &dyn TokenStore) {
+        token_store.migrate().await.unwrap();
+        let login_state = LoginState::new(&make_unique("state"), "code_verifier", "github");
+        token_store.insert_login_state(&login_state).await.unwrap();
+        // same state_token twice is refused
+        assert!(token_store.insert_login_state(&login_state).await.is_err());
+        assert!(token_store
+            .has_login_state(&login_state.state_token)
+            .await
+            .unwrap());
+
+        let taken = token_store
+            .take_login_state(&login_state.state_token)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(taken.code_verifier, "code_verifier");
+        assert_eq!(taken.provider, "github");
+        assert_eq!(
+            taken.created_at_as_sec_from_epoch(),
+            login_state.created_at_as_sec_from_epoch()
+        );
+        assert!(taken.possible_used_at.is_none());
+        // already taken (i.e. replayed callback)
+        let replayed = token_store
+            .take_login_state(&login_state.state_token)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(replayed.possible_used_at.is_some());
+        // still there once taken (i.e. client polling /login while the callback got through)
+        assert!(token_store
+            .has_login_state(&login_state.state_token)
+            .await
+            .unwrap());
+        assert!(token_store
+            .take_login_state(&make_unique("state"))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(!token_store
+            .has_login_state(&make_unique("state"))
+            .await
+            .unwrap());
+    }
+
+    pub(crate) async fn verify_delete_login_states_created_before(token_store: &dyn TokenStore) {
+        token_store.migrate().await.unwrap();
+        let mut old_login_state = LoginState::new(&make_unique("state"), "old", "google");
+        old_login_state.created_at = SystemTime::now() - Duration::from_secs(3600);
+        token_store
+            .insert_login_state(&old_login_state)
+            .await
+            .unwrap();
+        let new_login_state = LoginState::new(&make_unique("state"), "new", "google");
+        token_store
+            .insert_login_state(&new_login_state)
+            .await
+            .unwrap();
+
+        // the DB may be shared with other tests (i.e. Postgres), so at least ours
+        assert!(
+            token_store
+                .delete_login_states_created_before(SystemTime::now() - Duration::from_secs(60))
+                .await
+                .unwrap()
+                >= 1
+        );
+        assert!(token_store
+            .take_login_state(&old_login_state.state_token)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(token_store
+            .take_login_state(&new_login_state.state_token)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    pub(crate) async fn verify_delete_token(token_store: &dyn TokenStore) {
+        token_store.migrate().await.unwrap();
+        let state_token = make_unique("state");
+        let session_id = token_store
+            .upsert_token_data(&make_token_data(
+                SessionIDType::make_hash(&state_token),
+                &state_token,
+                &make_unique("access"),
+            ))
+            .await
+            .unwrap();
+        assert!(token_store
+            .delete_token_by_session_id(session_id)
+            .await
+            .unwrap());
+        assert!(token_store
+            .get_token_by_session_id(&Some(session_id.to_string()))
+            .await
+            .unwrap()
+            .is_none());
+        // already gone
+        assert!(!token_store
+            .delete_token_by_session_id(session_id)
+            .await
+            .unwrap());
     }
 }
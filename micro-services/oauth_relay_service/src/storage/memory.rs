@@ -0,0 +1,204 @@
+use super::{migrations, TokenStore};
+use crate::data::{LoginState, SessionIDType, TokenData};
+use anyhow::Result as AnyResult;
+use async_trait::async_trait;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+// Volatile (lost on restart), single-instance token store, mainly for tests and for running the
+// relay without any DB.  Mimics the SQL backends, including the UNIQUE constraints.
+#[derive(Default)]
+struct MemoryTokens {
+    last_session_id: u64, // same as AUTOINCREMENT, session_ids are never reused
+    tokens: BTreeMap<u64, TokenData>,
+    login_states: HashMap<String, LoginState>, // by state_token
+}
+
+#[derive(Default)]
+pub struct MemoryTokenStore {
+    // std Mutex rather than tokio's, since it is never held across an .await
+    tokens: Mutex<MemoryTokens>,
+}
+impl MemoryTokenStore {
+    pub fn new() -> Self {
+        MemoryTokenStore::default()
+    }
+}
+
+// session_id is always the key it is stored under
+fn with_session_id(session_id: u64, token_data: &TokenData) -> TokenData {
+    let mut ret = token_data.clone();
+    ret.set_session_id(SessionIDType::ID(session_id));
+    ret
+}
+
+#[async_trait]
+impl TokenStore for MemoryTokenStore {
+    fn db_type(&self) -> String {
+        "memory".to_string()
+    }
+    async fn migrate(&self) -> AnyResult<i64> {
+        // nothing to migrate, but claim the same version as the SQL backends
+        Ok(migrations::latest_version(migrations::MIGRATIONS_SQLITE))
+    }
+    async fn ping(&self) -> AnyResult<()> {
+        Ok(())
+    }
+    async fn get_token_by_session_id(
+        &self,
+        last_session_id: &Option<String>,
+    ) -> AnyResult<Option<TokenData>> {
+        let session_id = match last_session_id
+            .as_ref()
+            .and_then(|s| s.trim().parse::<u64>().ok())
+        {
+            Some(session_id) => session_id,
+            None => return Ok(None),
+        };
+        let memory_tokens = self.tokens.lock().unwrap();
+        Ok(memory_tokens.tokens.get(&session_id).cloned())
+    }
+    async fn upsert_token_data(&self, token_data: &TokenData) -> AnyResult<u64> {
+        let mut memory_tokens = self.tokens.lock().unwrap();
+        let possible_existing_session_id = match token_data.session_id_type() {
+            SessionIDType::ID(session_id) => match memory_tokens.tokens.contains_key(&session_id) {
+                true => Some(session_id),
+                false => anyhow::bail!("Session_id {} does not exist", session_id),
+            },
+            _ => memory_tokens
+                .tokens
+                .iter()
+                .find(|(_, stored)| stored.state_token == token_data.state_token)
+                .map(|(session_id, _)| *session_id),
+        };
+        // access_token is UNIQUE on the SQL backends as well
+        if memory_tokens.tokens.iter().any(|(session_id, stored)| {
+            Some(*session_id) != possible_existing_session_id
+                && stored.access_token == token_data.access_token
+        }) {
+            anyhow::bail!("Failed to store token: access_token already exists");
+        }
+
+        match possible_existing_session_id {
+            Some(session_id) => {
+                let stored = memory_tokens.tokens.get_mut(&session_id).unwrap();
+                let mut updated = with_session_id(session_id, token_data);
+                // same as COALESCE() on the SQL backends, keep what we had if new one is None
+                if updated.possible_refresh_token.is_none() {
+                    updated.possible_refresh_token = stored.possible_refresh_token.clone();
+                }
+                if updated.possible_client_email.is_none() {
+                    updated.possible_client_email = stored.possible_client_email.clone();
+                }
+                // update path (by session_id) never changes state_token
+                updated.state_token = stored.state_token.clone();
+                *stored = updated;
+                Ok(session_id)
+            }
+            None => {
+                memory_tokens.last_session_id += 1;
+                let session_id = memory_tokens.last_session_id;
+                memory_tokens
+                    .tokens
+                    .insert(session_id, with_session_id(session_id, token_data));
+                Ok(session_id)
+            }
+        }
+    }
+    async fn get_tokens_expiring_before(
+        &self,
+        expiry_time: SystemTime,
+    ) -> AnyResult<Vec<TokenData>> {
+        let memory_tokens = self.tokens.lock().unwrap();
+        Ok(memory_tokens
+            .tokens
+            .values()
+            .filter(|token_data| token_data.expiry_time() < expiry_time)
+            .cloned()
+            .collect())
+    }
+    async fn delete_token_by_session_id(&self, session_id: u64) -> AnyResult<bool> {
+        let mut memory_tokens = self.tokens.lock().unwrap();
+        Ok(memory_tokens.tokens.remove(&session_id).is_some())
+    }
+    async fn insert_login_state(&self, login_state: &LoginState) -> AnyResult<()> {
+        let mut memory_tokens = self.tokens.lock().unwrap();
+        // state_token is the PRIMARY KEY of login_states on the SQL backends
+        if memory_tokens
+            .login_states
+            .contains_key(&login_state.state_token)
+        {
+            anyhow::bail!("Failed to store login state: state_token already exists");
+        }
+        memory_tokens
+            .login_states
+            .insert(login_state.state_token.clone(), login_state.clone());
+        Ok(())
+    }
+    async fn take_login_state(&self, state_token: &str) -> AnyResult<Option<LoginState>> {
+        let mut memory_tokens = self.tokens.lock().unwrap();
+        Ok(memory_tokens
+            .login_states
+            .get_mut(state_token)
+            .map(|login_state| {
+                let previous = login_state.clone();
+                login_state
+                    .possible_used_at
+                    .get_or_insert(SystemTime::now());
+                previous
+            }))
+    }
+    async fn has_login_state(&self, state_token: &str) -> AnyResult<bool> {
+        let memory_tokens = self.tokens.lock().unwrap();
+        Ok(memory_tokens.login_states.contains_key(state_token))
+    }
+    async fn delete_login_states_created_before(
+        &self,
+        created_before: SystemTime,
+    ) -> AnyResult<u64> {
+        let mut memory_tokens = self.tokens.lock().unwrap();
+        let count_before = memory_tokens.login_states.len();
+        memory_tokens
+            .login_states
+            .retain(|_, login_state| login_state.created_at >= created_before);
+        Ok((count_before - memory_tokens.login_states.len()) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_upsert_then_update_refreshed_token() {
+        let token_store = MemoryTokenStore::new();
+        crate::storage::tests::verify_upsert_then_update(&token_store).await;
+    }
+
+    #[tokio::test]
+    async fn memory_get_tokens_expiring_before() {
+        let token_store = MemoryTokenStore::new();
+        crate::storage::tests::verify_get_tokens_expiring_before(&token_store).await;
+    }
+
+    #[tokio::test]
+    async fn memory_take_login_state() {
+        let token_store = MemoryTokenStore::new();
+        crate::storage::tests::verify_take_login_state(&token_store).await;
+    }
+
+    #[tokio::test]
+    async fn memory_delete_login_states_created_before() {
+        let token_store = MemoryTokenStore::new();
+        crate::storage::tests::verify_delete_login_states_created_before(&token_store).await;
+    }
+
+    #[tokio::test]
+    async fn memory_delete_token() {
+        let token_store = MemoryTokenStore::new();
+        crate::storage::tests::verify_delete_token(&token_store).await;
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::Result as AnyResult;
+
+// Schema migrations are embedded into the binary (include_str!) and applied in order at startup
+// (see TokenStore::migrate()).  Each backend records the versions it has applied in its own
+// schema_version table, so:
+//  - new DB: all migrations are applied
+//  - older DB: only the ones it has not seen yet are applied
+//  - newer DB (i.e. rolled back binary): we refuse to start rather than corrupt it
+// NOTE: Migrations are append-only, NEVER edit one that has been released, add a new one instead,
+// and keep both backends at the same version
+pub(crate) struct Migration {
+    pub version: i64, // 1'based, contiguous
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+pub(crate) const MIGRATIONS_SQLITE: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create tokens table",
+        sql: include_str!("../../migrations/sqlite/0001_create_tokens.sql"),
+    },
+    Migration {
+        version: 2,
+        description: "add provider to tokens",
+        sql: include_str!("../../migrations/sqlite/0002_add_provider.sql"),
+    },
+    Migration {
+        version: 3,
+        description: "create login_states table",
+        sql: include_str!("../../migrations/sqlite/0003_create_login_states.sql"),
+    },
+    Migration {
+        version: 4,
+        description: "add used_at to login_states",
+        sql: include_str!("../../migrations/sqlite/0004_add_used_at_to_login_states.sql"),
+    },
+    Migration {
+        version: 5,
+        description: "add provider to login_states",
+        sql: include_str!("../../migrations/sqlite/0005_add_provider_to_login_states.sql"),
+    },
+];
+
+pub(crate) const MIGRATIONS_POSTGRES: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create tokens table",
+        sql: include_str!("../../migrations/postgres/0001_create_tokens.sql"),
+    },
+    Migration {
+        version: 2,
+        description: "add provider to tokens",
+        sql: include_str!("../../migrations/postgres/0002_add_provider.sql"),
+    },
+    Migration {
+        version: 3,
+        description: "create login_states table",
+        sql: include_str!("../../migrations/postgres/0003_create_login_states.sql"),
+    },
+    Migration {
+        version: 4,
+        description: "add used_at to login_states",
+        sql: include_str!("../../migrations/postgres/0004_add_used_at_to_login_states.sql"),
+    },
+    Migration {
+        version: 5,
+        description: "add provider to login_states",
+        sql: include_str!("../../migrations/postgres/0005_add_provider_to_login_states.sql"),
+    },
+];
+
+pub(crate) fn latest_version(migrations: &[Migration]) -> i64 {
+    migrations.last().map(|m| m.version).unwrap_or(0)
+}
+
+// Migrations not yet applied to a DB at current_version, or Err() if the DB is newer than us
+pub(crate) fn pending_migrations(
+    migrations: &'static [Migration],
+    current_version: i64,
+) -> AnyResult<&'static [Migration]> {
+    let latest = latest_version(migrations);
+    if current_version > latest {
+        anyhow::bail!(
+            "Database schema version {} is newer than the latest version {} this binary knows of, refusing to start (upgrade oauth_relay_service)",
+            current_version,
+            latest
+        );
+    }
+    Ok(&migrations[current_version.max(0) as usize..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_are_contiguous_and_in_sync() {
+        for migrations in [MIGRATIONS_SQLITE, MIGRATIONS_POSTGRES] {
+            for (index, migration) in migrations.iter().enumerate() {
+                assert_eq!(migration.version, index as i64 + 1);
+            }
+        }
+        assert_eq!(
+            latest_version(MIGRATIONS_SQLITE),
+            latest_version(MIGRATIONS_POSTGRES)
+        );
+    }
+
+    #[test]
+    fn refuses_newer_schema() {
+        let latest = latest_version(MIGRATIONS_SQLITE);
+        assert_eq!(
+            pending_migrations(MIGRATIONS_SQLITE, 0).unwrap().len() as i64,
+            latest
+        );
+        assert!(pending_migrations(MIGRATIONS_SQLITE, latest)
+            .unwrap()
+            .is_empty());
+        assert!(pending_migrations(MIGRATIONS_SQLITE, latest + 1).is_err());
+    }
+}
@@ -0,0 +1,367 @@
+//#include
+pub mod storage_postgres;
+
+use super::{
+    token_cipher::{TokenCipher, COLUMN_CODE_VERIFIER},
+    TokenStore,
+};
+use crate::{
+    config::HostType,
+    data::{LoginState, SessionIDType, TokenData},
+};
+use anyhow::Result as AnyResult;
+use async_trait::async_trait;
+use deadpool_postgres::{Manager, ManagerConfig, Pool, RecyclingMethod};
+use std::time::SystemTime;
+use tokio_postgres::NoTls;
+
+// Unlike SQLite (single file, single connection behind a Mutex), Postgres is shared by all the
+// relay instances and the handlers are concurrent, hence pooled connections
+pub type TDBConnectionPoolPostgres = Pool;
+
+pub async fn open_db_connection_pool_postgres(
+    host_as_name_or_address: &HostType,
+    host_port: u16,
+    db_user: &str,
+    possible_db_password: &Option<String>,
+    db_name: &str,
+    pool_size: usize,
+) -> AnyResult<TDBConnectionPoolPostgres> {
+    let mut pg_config = tokio_postgres::Config::new();
+    pg_config
+        .host(host_as_name_or_address.to_string().as_str())
+        .port(host_port)
+        .user(db_user)
+        .dbname(db_name);
+    if let Some(db_password) = possible_db_password {
+        pg_config.password(db_password.as_str());
+    }
+    let manager = Manager::from_config(
+        pg_config,
+        NoTls,
+        ManagerConfig {
+            recycling_method: RecyclingMethod::Fast,
+        },
+    );
+    let pool = Pool::builder(manager).max_size(pool_size).build()?;
+
+    // pool connects lazily, so grab one now so that misconfigurations fail at startup rather
+    // than on the first /keepalive
+    match pool.get().await {
+        Ok(_) => Ok(pool),
+        Err(e) => anyhow::bail!(
+            "Error connecting to database '{}' at {}:{}: '{}'",
+            db_name,
+            host_as_name_or_address,
+            host_port,
+            e
+        ),
+    }
+}
+
+// Ok(None) when session_id is either not a number or does not exist, Err() only on DB errors
+pub(crate) async fn get_token_by_session_id(
+    db_pool: &TDBConnectionPoolPostgres,
+    last_session_id: &Option<String>,
+) -> AnyResult<Option<TokenData>> {
+    let session_id = match last_session_id
+        .as_ref()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+    {
+        Some(session_id) => session_id,
+        None => return Ok(None),
+    };
+    storage_postgres::get_token_by_session_id(db_pool, session_id)
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to get token for session_id {}: {}", session_id, e))
+}
+
+// Same semantics as sqlite::upsert_token_data()
+pub(crate) async fn upsert_token_data(
+    db_pool: &TDBConnectionPoolPostgres,
+    token_data: &TokenData,
+) -> AnyResult<u64> {
+    match token_data.session_id_type() {
+        SessionIDType::ID(session_id) => {
+            match storage_postgres::update_token(db_pool, session_id, token_data).await {
+                Ok(0) => anyhow::bail!("Session_id {} does not exist", session_id),
+                Ok(_) => Ok(session_id),
+                Err(e) => Err(anyhow::anyhow!(
+                    "Failed to update token for session_id {}: {}",
+                    session_id,
+                    e
+                )),
+            }
+        }
+        _ => storage_postgres::upsert_token(db_pool, token_data)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to store token: {}", e)),
+    }
+}
+
+// Same as SQLiteTokenStore, tokens are encrypted/decrypted here
+pub struct PostgresTokenStore {
+    host_as_name_or_address: HostType,
+    host_port: u16,
+    db_pool: TDBConnectionPoolPostgres,
+    token_cipher: TokenCipher,
+}
+impl PostgresTokenStore {
+    pub async fn open(
+        host_as_name_or_address: &HostType,
+        host_port: u16,
+        db_user: &str,
+        possible_db_password: &Option<String>,
+        db_name: &str,
+        pool_size: usize,
+        token_cipher: TokenCipher,
+    ) -> AnyResult<Self> {
+        Ok(PostgresTokenStore {
+            host_as_name_or_address: host_as_name_or_address.clone(),
+            host_port,
+            db_pool: open_db_connection_pool_postgres(
+                host_as_name_or_address,
+                host_port,
+                db_user,
+                possible_db_password,
+                db_name,
+                pool_size,
+            )
+            .await?,
+            token_cipher,
+        })
+    }
+}
+#[async_trait]
+impl TokenStore for PostgresTokenStore {
+    fn db_type(&self) -> String {
+        "postgres".to_string()
+    }
+    fn db_address(&self) -> Option<HostType> {
+        Some(self.host_as_name_or_address.clone())
+    }
+    fn db_port(&self) -> Option<u16> {
+        Some(self.host_port)
+    }
+    async fn migrate(&self) -> AnyResult<i64> {
+        let version = storage_postgres::migrate(&self.db_pool).await?;
+        let rows_reencrypted =
+            storage_postgres::reencrypt_tokens(&self.db_pool, &self.token_cipher).await?;
+        if rows_reencrypted > 0 {
+            println!(
+                "Storage: Re-encrypted {} session(s) with token encryption key '{}'",
+                rows_reencrypted,
+                self.token_cipher.current_key_id()
+            );
+        }
+        Ok(version)
+    }
+    async fn ping(&self) -> AnyResult<()> {
+        storage_postgres::ping(&self.db_pool).await
+    }
+    async fn get_token_by_session_id(
+        &self,
+        last_session_id: &Option<String>,
+    ) -> AnyResult<Option<TokenData>> {
+        match get_token_by_session_id(&self.db_pool, last_session_id).await? {
+            Some(token_data) => Ok(Some(self.token_cipher.decrypt_token_data(&token_data)?)),
+            None => Ok(None),
+        }
+    }
+    async fn upsert_token_data(&self, token_data: &TokenData) -> AnyResult<u64> {
+        upsert_token_data(
+            &self.db_pool,
+            &self.token_cipher.encrypt_token_data(token_data)?,
+        )
+        .await
+    }
+    async fn get_tokens_expiring_before(
+        &self,
+        expiry_time: SystemTime,
+    ) -> AnyResult<Vec<TokenData>> {
+        storage_postgres::get_tokens_expiring_before(&self.db_pool, expiry_time)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to get expiring tokens: {}", e))?
+            .iter()
+            .map(|token_data| self.token_cipher.decrypt_token_data(token_data))
+            .collect()
+    }
+    async fn delete_token_by_session_id(&self, session_id: u64) -> AnyResult<bool> {
+        storage_postgres::delete_token(&self.db_pool, session_id)
+            .await
+            .map(|rows_deleted| rows_deleted > 0)
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "Failed to delete token for session_id {}: {}",
+                    session_id,
+                    e
+                )
+            })
+    }
+    async fn insert_login_state(&self, login_state: &LoginState) -> AnyResult<()> {
+        let mut encrypted = login_state.clone();
+        encrypted.code_verifier = self
+            .token_cipher
+            .encrypt(COLUMN_CODE_VERIFIER, &login_state.code_verifier)?;
+        storage_postgres::insert_login_state(&self.db_pool, &encrypted)
+            .await
+            .map(|_| ())
+            .map_err(|e| anyhow::anyhow!("Failed to store login state: {}", e))
+    }
+    async fn take_login_state(&self, state_token: &str) -> AnyResult<Option<LoginState>> {
+        match storage_postgres::take_login_state(&self.db_pool, state_token)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to take login state: {}", e))?
+        {
+            Some(mut login_state) => {
+                login_state.code_verifier = self
+                    .token_cipher
+                    .decrypt(COLUMN_CODE_VERIFIER, &login_state.code_verifier)?;
+                Ok(Some(login_state))
+            }
+            None => Ok(None),
+        }
+    }
+    async fn has_login_state(&self, state_token: &str) -> AnyResult<bool> {
+        storage_postgres::has_login_state(&self.db_pool, state_token)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to look up login state: {}", e))
+    }
+    async fn delete_login_states_created_before(
+        &self,
+        created_before: SystemTime,
+    ) -> AnyResult<u64> {
+        storage_postgres::delete_login_states_created_before(&self.db_pool, created_before)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to delete login states: {}", e))
+    }
+}
+
+// These need a running Postgres, hence #[ignore]; see test_postgres.sh which starts one (either in
+// a container or as a temporary local instance) and runs `cargo test -- --ignored postgres`
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::tests::{
+        make_test_token_cipher, make_token_data, make_unique,
+        verify_delete_login_states_created_before, verify_delete_token,
+        verify_get_tokens_expiring_before, verify_take_login_state, verify_upsert_then_update,
+    };
+    use std::env;
+
+    async fn open_test_store() -> PostgresTokenStore {
+        let host: HostType = env::var("TEST_DB_HOST")
+            .unwrap_or_else(|_| "localhost".to_string())
+            .into();
+        let port = env::var("TEST_DB_PORT")
+            .map(|s| s.parse().unwrap())
+            .unwrap_or(5432);
+        let user = env::var("TEST_DB_USER").unwrap_or_else(|_| "postgres".to_string());
+        let db_name = env::var("TEST_DB_NAME").unwrap_or_else(|_| "postgres".to_string());
+        let token_store = PostgresTokenStore::open(
+            &host,
+            port,
+            &user,
+            &env::var("TEST_DB_PASSWORD").ok(),
+            &db_name,
+            4,
+            make_test_token_cipher(),
+        )
+        .await
+        .unwrap();
+        token_store.migrate().await.unwrap();
+        token_store
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see test_postgres.sh"]
+    async fn postgres_upsert_then_update_refreshed_token() {
+        let token_store = open_test_store().await;
+        verify_upsert_then_update(&token_store).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see test_postgres.sh"]
+    async fn postgres_get_tokens_expiring_before() {
+        let token_store = open_test_store().await;
+        verify_get_tokens_expiring_before(&token_store).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see test_postgres.sh"]
+    async fn postgres_delete_token() {
+        let token_store = open_test_store().await;
+        verify_delete_token(&token_store).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see test_postgres.sh"]
+    async fn postgres_take_login_state() {
+        let token_store = open_test_store().await;
+        verify_take_login_state(&token_store).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see test_postgres.sh"]
+    async fn postgres_delete_login_states_created_before() {
+        let token_store = open_test_store().await;
+        verify_delete_login_states_created_before(&token_store).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see test_postgres.sh"]
+    async fn postgres_malformed_row_fails_the_query_not_the_store() {
+        let token_store = open_test_store().await;
+        // i.e. written by hand, or by some other tool: client_port does not fit in a u16
+        // (expiry_time far ahead, so that other tests sharing the DB never get to see it)
+        let state_token = make_unique("malformed");
+        let session_id: i64 = token_store
+            .db_pool
+            .get()
+            .await
+            .unwrap()
+            .query_one(
+                "INSERT INTO tokens (state_token, client_address, client_port, access_token, expires_in, expiry_time) VALUES ($1, '192.168.1.2', 70000, $1, 0, 4102444800) RETURNING session_id",
+                &[&state_token],
+            )
+            .await
+            .unwrap()
+            .get(0);
+
+        assert!(token_store
+            .get_token_by_session_id(&Some(session_id.to_string()))
+            .await
+            .is_err());
+        assert!(token_store
+            .delete_token_by_session_id(session_id as u64)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs Postgres, see test_postgres.sh"]
+    async fn postgres_concurrent_logins_share_pool() {
+        let token_store: crate::storage::TTokenStore = std::sync::Arc::new(open_test_store().await);
+        let handles: Vec<_> = (0..16)
+            .map(|_| {
+                let token_store = token_store.clone();
+                tokio::spawn(async move {
+                    let state_token = make_unique("state");
+                    let token_data = make_token_data(
+                        SessionIDType::make_hash(&state_token),
+                        &state_token,
+                        &make_unique("access"),
+                    );
+                    token_store.upsert_token_data(&token_data).await.unwrap()
+                })
+            })
+            .collect();
+        let mut session_ids = Vec::new();
+        for handle in handles {
+            session_ids.push(handle.await.unwrap());
+        }
+        session_ids.sort_unstable();
+        session_ids.dedup();
+        assert_eq!(session_ids.len(), 16);
+    }
+}
@@ -0,0 +1,407 @@
+use crate::data::{LoginState, SessionIDType, TokenData};
+use crate::storage::migrations::{self, MIGRATIONS_POSTGRES};
+use crate::storage::token_cipher::{TokenCipher, COLUMN_ACCESS_TOKEN, COLUMN_REFRESH_TOKEN};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_postgres::Row;
+
+use super::TDBConnectionPoolPostgres;
+
+// Round trip to the DB (see /readyz), which also tells whether the pool can (re)connect
+pub(crate) async fn ping(db_pool: &TDBConnectionPoolPostgres) -> anyhow::Result<()> {
+    let client = db_pool.get().await?;
+    client.query_one("SELECT 1", &[]).await?;
+    Ok(())
+}
+
+// schema_version is created here (not via migrations) since it's what tracks the migrations
+const CREATE_SCHEMA_VERSION: &str = r#"
+CREATE TABLE IF NOT EXISTS schema_version (
+    version         BIGINT PRIMARY KEY,
+    description     TEXT NOT NULL,
+    applied_at      BIGINT NOT NULL)
+"#;
+const SELECT_SCHEMA_VERSION: &str = r#"
+SELECT COALESCE(MAX(version), 0) FROM schema_version
+"#;
+// $1: version (BIGINT)
+// $2: description (TEXT)
+// $3: applied_at (BIGINT (epoch time))
+const INSERT_SCHEMA_VERSION: &str = r#"
+INSERT INTO schema_version (version, description, applied_at) VALUES ($1, $2, $3)
+"#;
+// Unlike SQLite, multiple relay instances may start at the same time against the same DB, so
+// they take turns via a (transaction scoped) advisory lock; the key is arbitrary but fixed
+const MIGRATION_LOCK_KEY: i64 = 0x0a07_4e1a_7000_0001;
+// Brings the DB up to the latest schema version (all in one transaction, Postgres has transactional
+// DDL), and returns that version; Err() if the DB is newer than what we know of
+pub(crate) async fn migrate(db_pool: &TDBConnectionPoolPostgres) -> anyhow::Result<i64> {
+    let mut client = db_pool.get().await?;
+    let transaction = client.transaction().await?;
+    transaction
+        .execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK_KEY])
+        .await?;
+    transaction.batch_execute(CREATE_SCHEMA_VERSION).await?;
+    let current_version: i64 = transaction
+        .query_one(SELECT_SCHEMA_VERSION, &[])
+        .await?
+        .try_get(0)?;
+    for migration in migrations::pending_migrations(MIGRATIONS_POSTGRES, current_version)? {
+        transaction.batch_execute(migration.sql).await?;
+        transaction
+            .execute(
+                INSERT_SCHEMA_VERSION,
+                &[
+                    &migration.version,
+                    &migration.description,
+                    &(SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs() as i64),
+                ],
+            )
+            .await?;
+        println!(
+            "Storage: Migrating Postgres schema to version {} ({})",
+            migration.version, migration.description
+        );
+    }
+    transaction.commit().await?;
+    Ok(migrations::latest_version(MIGRATIONS_POSTGRES))
+}
+
+// Unlike SQLite, Postgres can hand back the session_id via RETURNING (on both INSERT and UPDATE paths)
+// $1: state_token (TEXT UNIQUE)
+// $2: client_address (TEXT)
+// $3: client_port (INTEGER)
+// $4: client_email (optional TEXT)
+// $5: access_token (TEXT (from server))
+// $6: refresh_token (optional TEXT, NULL keeps the old one on update)
+// $7: expires_in: (BIGINT (from server))
+// $8: expiry_time (BIGINT (epoch time))
+// $9: provider (TEXT)
+const UPSERT_TOKEN: &str = r#"
+INSERT INTO tokens (
+        state_token,
+        client_address, client_port, client_email,
+        access_token, refresh_token, expires_in, expiry_time, provider)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+    ON CONFLICT(state_token) DO UPDATE SET
+        client_address = excluded.client_address,
+        client_port = excluded.client_port,
+        client_email = COALESCE(excluded.client_email, tokens.client_email),
+        access_token = excluded.access_token,
+        refresh_token = COALESCE(excluded.refresh_token, tokens.refresh_token),
+        expires_in = excluded.expires_in,
+        expiry_time = excluded.expiry_time,
+        provider = excluded.provider
+    RETURNING session_id
+"#;
+pub(crate) async fn upsert_token(
+    db_pool: &TDBConnectionPoolPostgres,
+    token_data: &TokenData,
+) -> anyhow::Result<u64> {
+    let client = db_pool.get().await?;
+    let row = client
+        .query_one(
+            UPSERT_TOKEN,
+            &[
+                &token_data.state_token,                              // 1
+                &token_data.client_address.to_string(),               // 2
+                &(token_data.client_port as i32),                     // 3
+                &token_data.possible_client_email,                    // 4
+                &token_data.access_token,                             // 5
+                &token_data.possible_refresh_token,                   // 6
+                &token_data.expires_in,                               // 7
+                &(token_data.expiry_time_as_sec_from_epoch() as i64), // 8
+                &token_data.provider,                                 // 9
+            ],
+        )
+        .await?;
+    let session_id: i64 = row.try_get(0)?;
+    Ok(u64::try_from(session_id)?)
+}
+
+// Update path for refreshed tokens, returns number of rows updated (0 or 1)
+// $1: session_id (BIGINT)
+// $2: access_token (TEXT)
+// $3: refresh_token (optional TEXT, NULL keeps the old one)
+// $4: expires_in (BIGINT)
+// $5: expiry_time (BIGINT (epoch time))
+const UPDATE_TOKEN: &str = r#"
+UPDATE tokens SET
+        access_token = $2,
+        refresh_token = COALESCE($3, refresh_token),
+        expires_in = $4,
+        expiry_time = $5
+    WHERE session_id = $1
+"#;
+pub(crate) async fn update_token(
+    db_pool: &TDBConnectionPoolPostgres,
+    session_id: u64,
+    token_data: &TokenData,
+) -> anyhow::Result<u64> {
+    let client = db_pool.get().await?;
+    let rows_updated = client
+        .execute(
+            UPDATE_TOKEN,
+            &[
+                &(session_id as i64),                                 // 1
+                &token_data.access_token,                             // 2
+                &token_data.possible_refresh_token,                   // 3
+                &token_data.expires_in,                               // 4
+                &(token_data.expiry_time_as_sec_from_epoch() as i64), // 5
+            ],
+        )
+        .await?;
+    Ok(rows_updated)
+}
+
+// $1: session_id (BIGINT)
+const SELECT_TOKEN: &str = r#"
+SELECT state_token,
+        client_address, client_port, client_email,
+        access_token, refresh_token, expires_in, expiry_time, provider
+    FROM tokens WHERE session_id = $1
+"#;
+pub(crate) async fn get_token_by_session_id(
+    db_pool: &TDBConnectionPoolPostgres,
+    session_id: u64,
+) -> anyhow::Result<Option<TokenData>> {
+    let client = db_pool.get().await?;
+    let possible_row = client
+        .query_opt(SELECT_TOKEN, &[&(session_id as i64)])
+        .await?;
+    possible_row
+        .map(|row| to_token_data(session_id, &row))
+        .transpose()
+}
+
+// $1: expiry_time (BIGINT (epoch time))
+const SELECT_TOKENS_EXPIRING_BEFORE: &str = r#"
+SELECT state_token,
+        client_address, client_port, client_email,
+        access_token, refresh_token, expires_in, expiry_time, provider, session_id
+    FROM tokens WHERE expiry_time < $1 ORDER BY session_id
+"#;
+pub(crate) async fn get_tokens_expiring_before(
+    db_pool: &TDBConnectionPoolPostgres,
+    expiry_time: SystemTime,
+) -> anyhow::Result<Vec<TokenData>> {
+    let client = db_pool.get().await?;
+    let expiry_time_as_sec_from_epoch =
+        expiry_time.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let rows = client
+        .query(
+            SELECT_TOKENS_EXPIRING_BEFORE,
+            &[&expiry_time_as_sec_from_epoch],
+        )
+        .await?;
+    // session_id is last, so that the rest of the columns are same as SELECT_TOKEN
+    rows.iter()
+        .map(|row| to_token_data(u64::try_from(row.try_get::<usize, i64>(9)?)?, row))
+        .collect()
+}
+
+// $1: session_id (BIGINT)
+const DELETE_TOKEN: &str = r#"
+DELETE FROM tokens WHERE session_id = $1
+"#;
+// Returns number of rows deleted (0 or 1)
+pub(crate) async fn delete_token(
+    db_pool: &TDBConnectionPoolPostgres,
+    session_id: u64,
+) -> anyhow::Result<u64> {
+    let client = db_pool.get().await?;
+    Ok(client
+        .execute(DELETE_TOKEN, &[&(session_id as i64)])
+        .await?)
+}
+
+// $1: state_token (TEXT PRIMARY KEY)
+// $2: code_verifier (TEXT (encrypted))
+// $3: created_at (BIGINT (epoch time))
+// $4: provider (TEXT)
+const INSERT_LOGIN_STATE: &str = r#"
+INSERT INTO login_states (state_token, code_verifier, created_at, provider) VALUES ($1, $2, $3, $4)
+"#;
+pub(crate) async fn insert_login_state(
+    db_pool: &TDBConnectionPoolPostgres,
+    login_state: &LoginState,
+) -> anyhow::Result<u64> {
+    let client = db_pool.get().await?;
+    Ok(client
+        .execute(
+            INSERT_LOGIN_STATE,
+            &[
+                &login_state.state_token,                             // 1
+                &login_state.code_verifier,                           // 2
+                &(login_state.created_at_as_sec_from_epoch() as i64), // 3
+                &login_state.provider,                                // 4
+            ],
+        )
+        .await?)
+}
+
+// Marks it used and hands back the used_at from BEFORE (the sub-select), all in one statement; the
+// row lock (FOR UPDATE) makes concurrent callbacks (i.e. two relay instances) take turns, so only
+// one of them ever sees it unused
+// $1: state_token (TEXT PRIMARY KEY)
+// $2: used_at (BIGINT (epoch time))
+const UPDATE_LOGIN_STATE_USED: &str = r#"
+UPDATE login_states SET used_at = COALESCE(login_states.used_at, $2)
+    FROM (SELECT state_token, used_at FROM login_states WHERE state_token = $1 FOR UPDATE) AS previous
+    WHERE login_states.state_token = previous.state_token
+    RETURNING login_states.code_verifier, login_states.created_at, previous.used_at,
+        login_states.provider
+"#;
+pub(crate) async fn take_login_state(
+    db_pool: &TDBConnectionPoolPostgres,
+    state_token: &str,
+) -> anyhow::Result<Option<LoginState>> {
+    let client = db_pool.get().await?;
+    let possible_row = client
+        .query_opt(
+            UPDATE_LOGIN_STATE_USED,
+            &[
+                &state_token, // 1
+                &(SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs() as i64), // 2
+            ],
+        )
+        .await?;
+    possible_row
+        .map(|row| {
+            Ok(LoginState {
+                state_token: state_token.to_string(),
+                code_verifier: row.try_get(0)?,
+                provider: row.try_get(3)?,
+                created_at: from_epoch_secs(row.try_get(1)?)?,
+                possible_used_at: row
+                    .try_get::<usize, Option<i64>>(2)?
+                    .map(from_epoch_secs)
+                    .transpose()?,
+            })
+        })
+        .transpose()
+}
+
+// $1: state_token (TEXT PRIMARY KEY)
+const SELECT_LOGIN_STATE_EXISTS: &str = r#"
+SELECT 1 FROM login_states WHERE state_token = $1
+"#;
+pub(crate) async fn has_login_state(
+    db_pool: &TDBConnectionPoolPostgres,
+    state_token: &str,
+) -> anyhow::Result<bool> {
+    let client = db_pool.get().await?;
+    Ok(client
+        .query_opt(SELECT_LOGIN_STATE_EXISTS, &[&state_token])
+        .await?
+        .is_some())
+}
+
+// $1: created_at (BIGINT (epoch time))
+const DELETE_LOGIN_STATES_CREATED_BEFORE: &str = r#"
+DELETE FROM login_states WHERE created_at < $1
+"#;
+// Returns number of rows deleted
+pub(crate) async fn delete_login_states_created_before(
+    db_pool: &TDBConnectionPoolPostgres,
+    created_before: SystemTime,
+) -> anyhow::Result<u64> {
+    let client = db_pool.get().await?;
+    let created_before_as_sec_from_epoch =
+        created_before.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    Ok(client
+        .execute(
+            DELETE_LOGIN_STATES_CREATED_BEFORE,
+            &[&created_before_as_sec_from_epoch],
+        )
+        .await?)
+}
+
+// epoch time (BIGINT) as SystemTime, Err() rather than a panic if it's out of range (i.e. negative)
+fn from_epoch_secs(epoch_secs: i64) -> anyhow::Result<SystemTime> {
+    UNIX_EPOCH
+        .checked_add(Duration::from_secs(u64::try_from(epoch_secs)?))
+        .ok_or_else(|| anyhow::anyhow!("Epoch time {} is out of range", epoch_secs))
+}
+
+// unlike rusqlite, Row::get() is 0'based AND so are the columns...
+// NOTE: try_get() rather than get() (which panics), so that a malformed row fails the request (503)
+// rather than the worker
+fn to_token_data(session_id: u64, row: &Row) -> anyhow::Result<TokenData> {
+    let mut token_data = TokenData::new(
+        SessionIDType::ID(session_id),
+        row.try_get(0)?,
+        row.try_get::<usize, String>(1)?.into(),
+        u16::try_from(row.try_get::<usize, i32>(2)?)?,
+        row.try_get(3)?,
+        row.try_get(4)?,
+        row.try_get(5)?,
+        row.try_get(6)?,
+        from_epoch_secs(row.try_get(7)?)?,
+    );
+    token_data.provider = row.try_get(8)?;
+    Ok(token_data)
+}
+
+// Same as SQLite, rows not (yet) encrypted with the current key (i.e. "enc:k2:"), locked FOR UPDATE
+// $1: current key prefix (TEXT)
+const SELECT_TOKENS_TO_REENCRYPT: &str = r#"
+SELECT session_id, access_token, refresh_token
+    FROM tokens
+    WHERE substr(access_token, 1, length($1)) <> $1
+        OR (refresh_token IS NOT NULL AND substr(refresh_token, 1, length($1)) <> $1)
+    FOR UPDATE
+"#;
+// $1: session_id (BIGINT)
+// $2: access_token (TEXT)
+// $3: refresh_token (optional TEXT)
+const UPDATE_REENCRYPTED_TOKENS: &str = r#"
+UPDATE tokens SET access_token = $2, refresh_token = $3 WHERE session_id = $1
+"#;
+// Re-encrypts with the current key (all in one transaction, under the same advisory lock as
+// migrate() so that relay instances starting together do not do it twice), and returns number of
+// rows re-encrypted
+pub(crate) async fn reencrypt_tokens(
+    db_pool: &TDBConnectionPoolPostgres,
+    token_cipher: &TokenCipher,
+) -> anyhow::Result<u64> {
+    let mut client = db_pool.get().await?;
+    let transaction = client.transaction().await?;
+    transaction
+        .execute("SELECT pg_advisory_xact_lock($1)", &[&MIGRATION_LOCK_KEY])
+        .await?;
+    let rows = transaction
+        .query(
+            SELECT_TOKENS_TO_REENCRYPT,
+            &[&token_cipher.current_prefix()],
+        )
+        .await?;
+    for row in rows.iter() {
+        let session_id: i64 = row.try_get(0)?;
+        let access_token: String = row.try_get(1)?;
+        let possible_refresh_token: Option<String> = row.try_get(2)?;
+        let reencrypted_refresh_token = match possible_refresh_token {
+            Some(refresh_token) => {
+                Some(token_cipher.reencrypt(COLUMN_REFRESH_TOKEN, &refresh_token)?)
+            }
+            None => None,
+        };
+        transaction
+            .execute(
+                UPDATE_REENCRYPTED_TOKENS,
+                &[
+                    &session_id,                                                  // 1
+                    &token_cipher.reencrypt(COLUMN_ACCESS_TOKEN, &access_token)?, // 2
+                    &reencrypted_refresh_token,                                   // 3
+                ],
+            )
+            .await?;
+    }
+    transaction.commit().await?;
+    Ok(rows.len() as u64)
+}
@@ -1,11 +1,16 @@
 //#include
 pub mod data_sqlite;
 pub mod handlers_sqlite;
-pub mod main_sqlite;
 pub mod storage_sqlite;
 
+use super::{
+    token_cipher::{TokenCipher, COLUMN_CODE_VERIFIER},
+    TokenStore,
+};
+use crate::data::{LoginState, SessionIDType, TokenData};
 use anyhow::Result as AnyResult;
-use std::sync::Arc;
+use async_trait::async_trait;
+use std::{sync::Arc, time::SystemTime};
 use tokio::sync::Mutex;
 
 pub type TDBConnection_sqlite = tokio_rusqlite::Connection; // currently using tokio-rusqlite
@@ -15,7 +20,7 @@ pub async fn open_db_connection_rusqlite(db_path: &str) -> AnyResult<TDBConnecti
     let result = tokio_rusqlite::Connection::open(db_path).await;
     match result {
         Ok(db_connection) => Ok(new_connection_sqlite(db_connection)),
-        Err(e) => panic!("Error connecting to database: '{}'", e),
+        Err(e) => anyhow::bail!("Error connecting to database '{}': {}", db_path, e),
     }
 }
 
@@ -23,18 +28,51 @@ fn new_connection_sqlite(db_connection: tokio_rusqlite::Connection) -> TDBConnec
     Arc::new(Mutex::new(db_connection))
 }
 
+// Ok(None) when session_id is either not a number or does not exist, Err() only on DB errors
 pub(crate) async fn get_token_by_session_id(
     db_connection: &TDBConnectionLock_sqlite,
     last_session_id: &Option<String>,
-) -> Option<crate::data::TokenData> {
-    todo!()
+) -> AnyResult<Option<TokenData>> {
+    let session_id = match last_session_id
+        .as_ref()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+    {
+        Some(session_id) => session_id,
+        None => return Ok(None),
+    };
+    match storage_sqlite::get_token_by_session_id(db_connection, session_id).await {
+        Ok(possible_token_data) => Ok(possible_token_data),
+        Err(e) => Err(anyhow::anyhow!(
+            "Failed to get token for session_id {}: {}",
+            session_id,
+            e
+        )),
+    }
 }
 
+// If TokenData already has a (DB) session_id, it is an update of an existing session (i.e. refreshed
+// token and/or expiry), else it is a new login which is inserted (or updated if state_token exists)
+// Returns the session_id of the row
 pub(crate) async fn upsert_token_data(
     db_connection: &TDBConnectionLock_sqlite,
-    token_data: &crate::data::TokenData,
-) -> Result<(), anyhow::Error> {
-    todo!()
+    token_data: &TokenData,
+) -> AnyResult<u64> {
+    match token_data.session_id_type() {
+        SessionIDType::ID(session_id) => {
+            match storage_sqlite::update_token(db_connection, session_id, token_data).await {
+                Ok(0) => anyhow::bail!("Session_id {} does not exist", session_id),
+                Ok(_) => Ok(session_id),
+                Err(e) => Err(anyhow::anyhow!(
+                    "Failed to update token for session_id {}: {}",
+                    session_id,
+                    e
+                )),
+            }
+        }
+        _ => storage_sqlite::upsert_token(db_connection, token_data)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to store token: {}", e)),
+    }
 }
 
 //async fn open_db_connection_from_config_sqlite(config: Config) -> TDBConnectionLock_sqlite {
@@ -43,3 +81,335 @@ pub(crate) async fn upsert_token_data(
 //        _ => panic!("Unsupported DBType"),
 //    }
 //}
+
+// access_token and refresh_token are encrypted/decrypted here, so storage_sqlite only sees ciphertext
+pub struct SQLiteTokenStore {
+    db_path: String,
+    db_connection: TDBConnectionLock_sqlite,
+    token_cipher: TokenCipher,
+}
+impl SQLiteTokenStore {
+    pub async fn open(db_path: &str, token_cipher: TokenCipher) -> AnyResult<Self> {
+        Ok(SQLiteTokenStore {
+            db_path: db_path.to_string(),
+            db_connection: open_db_connection_rusqlite(db_path).await?,
+            token_cipher,
+        })
+    }
+}
+#[async_trait]
+impl TokenStore for SQLiteTokenStore {
+    fn db_type(&self) -> String {
+        "sqlite".to_string()
+    }
+    fn db_path(&self) -> Option<String> {
+        Some(self.db_path.clone())
+    }
+    async fn migrate(&self) -> AnyResult<i64> {
+        let version = storage_sqlite::migrate(&self.db_connection).await?;
+        let rows_reencrypted =
+            storage_sqlite::reencrypt_tokens(&self.db_connection, &self.token_cipher).await?;
+        if rows_reencrypted > 0 {
+            println!(
+                "Storage: Re-encrypted {} session(s) with token encryption key '{}'",
+                rows_reencrypted,
+                self.token_cipher.current_key_id()
+            );
+        }
+        Ok(version)
+    }
+    async fn ping(&self) -> AnyResult<()> {
+        storage_sqlite::ping(&self.db_connection).await?;
+        Ok(())
+    }
+    async fn get_token_by_session_id(
+        &self,
+        last_session_id: &Option<String>,
+    ) -> AnyResult<Option<TokenData>> {
+        match get_token_by_session_id(&self.db_connection, last_session_id).await? {
+            Some(token_data) => Ok(Some(self.token_cipher.decrypt_token_data(&token_data)?)),
+            None => Ok(None),
+        }
+    }
+    async fn upsert_token_data(&self, token_data: &TokenData) -> AnyResult<u64> {
+        upsert_token_data(
+            &self.db_connection,
+            &self.token_cipher.encrypt_token_data(token_data)?,
+        )
+        .await
+    }
+    async fn get_tokens_expiring_before(
+        &self,
+        expiry_time: SystemTime,
+    ) -> AnyResult<Vec<TokenData>> {
+        storage_sqlite::get_tokens_expiring_before(&self.db_connection, expiry_time)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to get expiring tokens: {}", e))?
+            .iter()
+            .map(|token_data| self.token_cipher.decrypt_token_data(token_data))
+            .collect()
+    }
+    async fn delete_token_by_session_id(&self, session_id: u64) -> AnyResult<bool> {
+        match storage_sqlite::delete_token(&self.db_connection, session_id).await {
+            Ok(rows_deleted) => Ok(rows_deleted > 0),
+            Err(e) => Err(anyhow::anyhow!(
+                "Failed to delete token for session_id {}: {}",
+                session_id,
+                e
+            )),
+        }
+    }
+    async fn insert_login_state(&self, login_state: &LoginState) -> AnyResult<()> {
+        let mut encrypted = login_state.clone();
+        encrypted.code_verifier = self
+            .token_cipher
+            .encrypt(COLUMN_CODE_VERIFIER, &login_state.code_verifier)?;
+        storage_sqlite::insert_login_state(&self.db_connection, &encrypted)
+            .await
+            .map(|_| ())
+            .map_err(|e| anyhow::anyhow!("Failed to store login state: {}", e))
+    }
+    async fn take_login_state(&self, state_token: &str) -> AnyResult<Option<LoginState>> {
+        match storage_sqlite::take_login_state(&self.db_connection, state_token)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to take login state: {}", e))?
+        {
+            Some(mut login_state) => {
+                login_state.code_verifier = self
+                    .token_cipher
+                    .decrypt(COLUMN_CODE_VERIFIER, &login_state.code_verifier)?;
+                Ok(Some(login_state))
+            }
+            None => Ok(None),
+        }
+    }
+    async fn has_login_state(&self, state_token: &str) -> AnyResult<bool> {
+        storage_sqlite::has_login_state(&self.db_connection, state_token)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to look up login state: {}", e))
+    }
+    async fn delete_login_states_created_before(
+        &self,
+        created_before: SystemTime,
+    ) -> AnyResult<u64> {
+        storage_sqlite::delete_login_states_created_before(&self.db_connection, created_before)
+            .await
+            .map(|rows_deleted| rows_deleted as u64)
+            .map_err(|e| anyhow::anyhow!("Failed to delete login states: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{
+        tests::{make_test_token_cipher, make_token_data, make_unique},
+        token_cipher::tests::make_key,
+    };
+
+    #[tokio::test]
+    async fn upsert_then_update_refreshed_token() {
+        let token_store = SQLiteTokenStore::open(":memory:", make_test_token_cipher())
+            .await
+            .unwrap();
+        crate::storage::tests::verify_upsert_then_update(&token_store).await;
+    }
+
+    #[tokio::test]
+    async fn open_reports_unopenable_db() {
+        let result =
+            SQLiteTokenStore::open("/nonexistent/dir/tokens.db", make_test_token_cipher()).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn get_tokens_expiring_before() {
+        let token_store = SQLiteTokenStore::open(":memory:", make_test_token_cipher())
+            .await
+            .unwrap();
+        crate::storage::tests::verify_get_tokens_expiring_before(&token_store).await;
+    }
+
+    #[tokio::test]
+    async fn malformed_row_fails_the_query_not_the_store() {
+        let token_store = SQLiteTokenStore::open(":memory:", make_test_token_cipher())
+            .await
+            .unwrap();
+        token_store.migrate().await.unwrap();
+        // i.e. written by hand, or by some other tool: client_address is not even TEXT
+        let session_id = token_store
+            .db_connection
+            .lock()
+            .await
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO tokens (state_token, client_address, client_port, access_token, expires_in, expiry_time) VALUES ('malformed', X'FF', 0, 'malformed', 0, 0)",
+                    [],
+                )?;
+                Ok(conn.last_insert_rowid() as u64)
+            })
+            .await
+            .unwrap();
+
+        assert!(token_store
+            .get_tokens_expiring_before(SystemTime::now())
+            .await
+            .is_err());
+        assert!(token_store
+            .get_token_by_session_id(&Some(session_id.to_string()))
+            .await
+            .is_err());
+        // the connection survived it
+        assert!(token_store
+            .delete_token_by_session_id(session_id)
+            .await
+            .unwrap());
+        assert!(token_store
+            .get_tokens_expiring_before(SystemTime::now())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn delete_token() {
+        let token_store = SQLiteTokenStore::open(":memory:", make_test_token_cipher())
+            .await
+            .unwrap();
+        crate::storage::tests::verify_delete_token(&token_store).await;
+    }
+
+    #[tokio::test]
+    async fn take_login_state() {
+        let token_store = SQLiteTokenStore::open(":memory:", make_test_token_cipher())
+            .await
+            .unwrap();
+        crate::storage::tests::verify_take_login_state(&token_store).await;
+    }
+
+    #[tokio::test]
+    async fn delete_login_states_created_before() {
+        let token_store = SQLiteTokenStore::open(":memory:", make_test_token_cipher())
+            .await
+            .unwrap();
+        crate::storage::tests::verify_delete_login_states_created_before(&token_store).await;
+    }
+
+    #[tokio::test]
+    async fn migrate_is_idempotent_and_refuses_newer_schema() {
+        let token_store = SQLiteTokenStore::open(":memory:", make_test_token_cipher())
+            .await
+            .unwrap();
+        let latest = token_store.migrate().await.unwrap();
+        assert_eq!(token_store.migrate().await.unwrap(), latest);
+
+        // i.e. DB migrated by a newer relay, and then this (older) binary was rolled back
+        token_store
+            .db_connection
+            .lock()
+            .await
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, 'from the future', 0)",
+                    [latest + 1],
+                )?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert!(token_store.migrate().await.is_err());
+    }
+
+    // what's actually on disk
+    async fn read_raw_tokens(
+        token_store: &SQLiteTokenStore,
+        session_id: u64,
+    ) -> (String, Option<String>) {
+        token_store
+            .db_connection
+            .lock()
+            .await
+            .call(move |conn| {
+                Ok(conn.query_row(
+                    "SELECT access_token, refresh_token FROM tokens WHERE session_id = ?1",
+                    [session_id],
+                    |row| Ok((row.get(0)?, row.get(1)?)),
+                )?)
+            })
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn tokens_encrypted_at_rest_and_reencrypted_on_rotation() {
+        let db_path = std::env::temp_dir().join(format!("{}.db", make_unique("tokens")));
+        let db_path = db_path.to_str().unwrap();
+        let old_store =
+            SQLiteTokenStore::open(db_path, TokenCipher::new(&[make_key("k1", 1)]).unwrap())
+                .await
+                .unwrap();
+        old_store.migrate().await.unwrap();
+        let state_token = make_unique("state");
+        let token_data = make_token_data(
+            SessionIDType::make_hash(&state_token),
+            &state_token,
+            &make_unique("access"),
+        );
+        let session_id = old_store.upsert_token_data(&token_data).await.unwrap();
+        let (access_token, possible_refresh_token) = read_raw_tokens(&old_store, session_id).await;
+        assert!(access_token.starts_with("enc:k1:"));
+        assert!(!access_token.contains(&token_data.access_token));
+        assert!(possible_refresh_token.unwrap().starts_with("enc:k1:"));
+
+        // i.e. row stored before encryption was introduced (bypassing the cipher)
+        let legacy_state_token = make_unique("state");
+        let legacy_token_data = make_token_data(
+            SessionIDType::make_hash(&legacy_state_token),
+            &legacy_state_token,
+            "ya29.legacy",
+        );
+        let legacy_session_id = upsert_token_data(&old_store.db_connection, &legacy_token_data)
+            .await
+            .unwrap();
+
+        // rotate: k2 is current, k1 kept to decrypt until migrate() re-encrypted everything
+        let new_store = SQLiteTokenStore::open(
+            db_path,
+            TokenCipher::new(&[make_key("k2", 2), make_key("k1", 1)]).unwrap(),
+        )
+        .await
+        .unwrap();
+        new_store.migrate().await.unwrap();
+        for (session_id, expected_access_token) in [
+            (session_id, token_data.access_token.as_str()),
+            (legacy_session_id, "ya29.legacy"),
+        ] {
+            let (access_token, possible_refresh_token) =
+                read_raw_tokens(&new_store, session_id).await;
+            assert!(access_token.starts_with("enc:k2:"));
+            assert!(possible_refresh_token.unwrap().starts_with("enc:k2:"));
+            let stored = new_store
+                .get_token_by_session_id(&Some(session_id.to_string()))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(stored.access_token, expected_access_token);
+            assert_eq!(
+                stored.possible_refresh_token,
+                Some("refresh_token".to_string())
+            );
+        }
+
+        // now k1 can be dropped
+        let k2_only_store =
+            SQLiteTokenStore::open(db_path, TokenCipher::new(&[make_key("k2", 2)]).unwrap())
+                .await
+                .unwrap();
+        assert!(k2_only_store
+            .get_token_by_session_id(&Some(session_id.to_string()))
+            .await
+            .unwrap()
+            .is_some());
+        std::fs::remove_file(db_path).ok();
+    }
+}
@@ -1,39 +1,300 @@
-use crate::data::{SessionIDType, TokenData};
+use crate::config::HostType;
+use crate::data::{LoginState, SessionIDType, TokenData};
 //use anyhow::Result as AnyResult;
-use std::time::UNIX_EPOCH;
-use tokio_rusqlite::{self, params};
+use crate::storage::migrations::{self, MIGRATIONS_SQLITE};
+use crate::storage::token_cipher::{TokenCipher, COLUMN_ACCESS_TOKEN, COLUMN_REFRESH_TOKEN};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_rusqlite::{
+    self, params,
+    types::{FromSql, FromSqlError, FromSqlResult, ValueRef},
+    OptionalExtension,
+};
 
-use super::{TDBConnectionLock_sqlite};
+use super::TDBConnectionLock_sqlite;
 
-// Note that we use "IF NOT EXISTS" so that create_table_token() can be
-// safely called multiple times without failing...
-const CREATE_TABLE: &str = r#"
-CREATE TABLE IF NOT EXISTS tokens (
-    session_id      INTEGER PRIMARY KEY AUTOINCREMENT,
-    state_token     TEXT NOT NULL UNIQUE,
+// client_address (TEXT) read via row.get(), so that a malformed one fails the row like any other
+// column (FromSqlConversionFailure) rather than panicking the connection's thread, which would take
+// the whole store down with it
+impl FromSql for HostType {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+// Round trip to the DB (see /readyz)
+pub(crate) async fn ping(db_connection: &TDBConnectionLock_sqlite) -> tokio_rusqlite::Result<i64> {
+    let conn = db_connection.lock().await;
+    conn.call(|conn| Ok(conn.query_row("SELECT 1", [], |row| row.get(0))?))
+        .await
+}
+
+// schema_version is created here (not via migrations) since it's what tracks the migrations
+const CREATE_SCHEMA_VERSION: &str = r#"
+CREATE TABLE IF NOT EXISTS schema_version (
+    version         INTEGER PRIMARY KEY,
+    description     TEXT NOT NULL,
+    applied_at      INTEGER NOT NULL)
+"#;
+const SELECT_SCHEMA_VERSION: &str = r#"
+SELECT COALESCE(MAX(version), 0) FROM schema_version
+"#;
+// IN1: version (INTEGER)
+// IN2: description (TEXT)
+// IN3: applied_at (INTEGER (epoch time))
+const INSERT_SCHEMA_VERSION: &str = r#"
+INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)
+"#;
+// Brings the DB up to the latest schema version (each migration in its own transaction), and
+// returns that version; Err() if the DB is newer than what we know of
+pub(crate) async fn migrate(db_connection: &TDBConnectionLock_sqlite) -> anyhow::Result<i64> {
+    let conn = db_connection.lock().await;
+    let current_version: i64 = conn
+        .call(|conn| {
+            conn.execute(CREATE_SCHEMA_VERSION, [])?;
+            Ok(conn.query_row(SELECT_SCHEMA_VERSION, [], |row| row.get(0))?)
+        })
+        .await?;
+    for migration in migrations::pending_migrations(MIGRATIONS_SQLITE, current_version)? {
+        conn.call(move |conn| {
+            let transaction = conn.transaction()?;
+            transaction.execute_batch(migration.sql)?;
+            transaction.execute(
+                INSERT_SCHEMA_VERSION,
+                params![
+                    migration.version,
+                    migration.description,
+                    SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs() as i64
+                ],
+            )?;
+            transaction.commit()?;
+            Ok(())
+        })
+        .await?;
+        println!(
+            "Storage: Migrated SQLite schema to version {} ({})",
+            migration.version, migration.description
+        );
+    }
+    Ok(migrations::latest_version(MIGRATIONS_SQLITE))
+}
+
+// IN1: session_id (INTEGER)
+const SELECT_TOKEN: &str = r#"
+SELECT state_token, 
+        client_address, client_port, client_email, 
+        access_token, refresh_token, expires_in, expiry_time, provider 
+    FROM tokens WHERE session_id = ?1
+"#;
+pub(crate) async fn get_token_by_session_id(
+    db_connection: &TDBConnectionLock_sqlite,
+    session_id: u64,
+) -> tokio_rusqlite::Result<Option<TokenData>> {
+    let conn = db_connection.lock().await;
+    conn.call(move |conn| {
+        let mut stmt = conn.prepare(SELECT_TOKEN)?;
+        // in parm1: session_id
+        // NOTE: optional() maps QueryReturnedNoRows to Ok(None), so only DB errors are Err()
+        let possible_token_data = stmt
+            .query_row(params![session_id], |row| {
+                let mut token_data = TokenData::new(
+                    SessionIDType::ID(session_id),
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                    UNIX_EPOCH + Duration::from_secs(row.get(7)?),
+                );
+                token_data.provider = row.get(8)?;
+                Ok(token_data)
+            })
+            .optional()?;
+        Ok(possible_token_data)
+    })
+    .await
+}
+
+// IN1: expiry_time (INTEGER (epoch time))
+const SELECT_TOKENS_EXPIRING_BEFORE: &str = r#"
+SELECT session_id, state_token,
+        client_address, client_port, client_email,
+        access_token, refresh_token, expires_in, expiry_time, provider
+    FROM tokens WHERE expiry_time < ?1 ORDER BY session_id
+"#;
+pub(crate) async fn get_tokens_expiring_before(
+    db_connection: &TDBConnectionLock_sqlite,
+    expiry_time: SystemTime,
+) -> tokio_rusqlite::Result<Vec<TokenData>> {
+    let conn = db_connection.lock().await;
+    let expiry_time_as_sec_from_epoch =
+        expiry_time.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    conn.call(move |conn| {
+        let mut stmt = conn.prepare(SELECT_TOKENS_EXPIRING_BEFORE)?;
+        let tokens = stmt
+            .query_map(params![expiry_time_as_sec_from_epoch], |row| {
+                // same as SELECT_TOKEN, but shifted by one (session_id)
+                let mut token_data = TokenData::new(
+                    SessionIDType::ID(row.get(0)?),
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                    row.get(5)?,
+                    row.get(6)?,
+                    row.get(7)?,
+                    UNIX_EPOCH + Duration::from_secs(row.get(8)?),
+                );
+                token_data.provider = row.get(9)?;
+                Ok(token_data)
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(tokens)
+    })
+    .await
+}
+
+// IN1: session_id (INTEGER)
+const DELETE_TOKEN: &str = r#"
+DELETE FROM tokens WHERE session_id = ?1
+"#;
+// Returns number of rows deleted (0 or 1)
+pub(crate) async fn delete_token(
+    db_connection: &TDBConnectionLock_sqlite,
+    session_id: u64,
+) -> tokio_rusqlite::Result<usize> {
+    let conn = db_connection.lock().await;
+    conn.call(move |conn| Ok(conn.execute(DELETE_TOKEN, params![session_id])?))
+        .await
+}
+
+// IN1: state_token (TEXT PRIMARY KEY)
+// IN2: code_verifier (TEXT (encrypted))
+// IN3: created_at (INTEGER (epoch time))
+// IN4: provider (TEXT)
+const INSERT_LOGIN_STATE: &str = r#"
+INSERT INTO login_states (state_token, code_verifier, created_at, provider) VALUES (?1, ?2, ?3, ?4)
+"#;
+pub(crate) async fn insert_login_state(
+    db_connection: &TDBConnectionLock_sqlite,
+    login_state_ref: &LoginState,
+) -> tokio_rusqlite::Result<usize> {
+    let conn = db_connection.lock().await;
+    let login_state = login_state_ref.clone();
+    conn.call(move |conn| {
+        Ok(conn.execute(
+            INSERT_LOGIN_STATE,
+            params![
+                login_state.state_token,                             // 1
+                login_state.code_verifier,                           // 2
+                (login_state.created_at_as_sec_from_epoch() as i64), // 3
+                login_state.provider,                                // 4
+            ],
+        )?)
+    })
+    .await
+}
 
-    client_address  TEXT NOT NULL,
-    client_port     INTEGER NOT NULL,
-    client_email    TEXT,
+// IN1: state_token (TEXT PRIMARY KEY)
+const SELECT_LOGIN_STATE: &str = r#"
+SELECT code_verifier, created_at, used_at, provider FROM login_states WHERE state_token = ?1
+"#;
+// IN1: state_token (TEXT PRIMARY KEY)
+// IN2: used_at (INTEGER (epoch time))
+const UPDATE_LOGIN_STATE_USED: &str = r#"
+UPDATE login_states SET used_at = ?2 WHERE state_token = ?1 AND used_at IS NULL
+"#;
+// SELECT and UPDATE in one transaction (rather than UPDATE ... RETURNING, which needs SQLite 3.35+,
+// and would hand back the new used_at rather than the old one), so that only one caller ever gets
+// it back unused
+pub(crate) async fn take_login_state(
+    db_connection: &TDBConnectionLock_sqlite,
+    state_token: &str,
+) -> tokio_rusqlite::Result<Option<LoginState>> {
+    let conn = db_connection.lock().await;
+    let state_token = state_token.to_string();
+    conn.call(move |conn| {
+        let transaction = conn.transaction()?;
+        let possible_login_state = transaction
+            .query_row(SELECT_LOGIN_STATE, params![state_token], |row| {
+                Ok(LoginState {
+                    state_token: state_token.clone(),
+                    code_verifier: row.get(0)?,
+                    provider: row.get(3)?,
+                    created_at: UNIX_EPOCH + Duration::from_secs(row.get(1)?),
+                    possible_used_at: row
+                        .get::<usize, Option<u64>>(2)?
+                        .map(|used_at| UNIX_EPOCH + Duration::from_secs(used_at)),
+                })
+            })
+            .optional()?;
+        transaction.execute(
+            UPDATE_LOGIN_STATE_USED,
+            params![
+                state_token,
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs() as i64
+            ],
+        )?;
+        transaction.commit()?;
+        Ok(possible_login_state)
+    })
+    .await
+}
+
+// IN1: state_token (TEXT PRIMARY KEY)
+const SELECT_LOGIN_STATE_EXISTS: &str = r#"
+SELECT 1 FROM login_states WHERE state_token = ?1
+"#;
+pub(crate) async fn has_login_state(
+    db_connection: &TDBConnectionLock_sqlite,
+    state_token: &str,
+) -> tokio_rusqlite::Result<bool> {
+    let conn = db_connection.lock().await;
+    let state_token = state_token.to_string();
+    conn.call(move |conn| {
+        Ok(conn
+            .query_row(SELECT_LOGIN_STATE_EXISTS, params![state_token], |_| Ok(()))
+            .optional()?
+            .is_some())
+    })
+    .await
+}
 
-    access_token    TEXT NOT NULL UNIQUE,
-    refresh_token   TEXT,
-    expires_in      INTEGER NOT NULL,
-    expiry_time     INTEGER NOT NULL)
+// IN1: created_at (INTEGER (epoch time))
+const DELETE_LOGIN_STATES_CREATED_BEFORE: &str = r#"
+DELETE FROM login_states WHERE created_at < ?1
 "#;
-pub(crate) async fn create_table_token(
+// Returns number of rows deleted
+pub(crate) async fn delete_login_states_created_before(
     db_connection: &TDBConnectionLock_sqlite,
-) -> tokio_rusqlite::Result<()> {
+    created_before: SystemTime,
+) -> tokio_rusqlite::Result<usize> {
     let conn = db_connection.lock().await;
-    conn.call(|conn| match conn.execute(CREATE_TABLE, []) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(tokio_rusqlite::Error::Rusqlite(e)),
+    let created_before_as_sec_from_epoch =
+        created_before.duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    conn.call(move |conn| {
+        Ok(conn.execute(
+            DELETE_LOGIN_STATES_CREATED_BEFORE,
+            params![created_before_as_sec_from_epoch],
+        )?)
     })
     .await
 }
 
-// Note that session_id is primary key and should be returned to caller via conn.last_insert_rowid()
-// It's weird that sqlite::rowdata is 0'based (i.e. col1=row.get(0)?;), yet statements are 1'based...
+// Inserts the token, or updates the row if state_token already exists (i.e. same login that got
+// re-authenticated/refreshed), and returns the session_id of the inserted/updated row.
+// If new refresh_token is NULL, we keep the old one (Google only hands out refresh_token on first consent)
+// NOTE: session_id is the primary key, assigned by SQLite on insert
 // IN1: state_token (TEXT UNIQUE)
 // IN2: client_address (TEXT)
 // IN3: client_port (INTEGER)
@@ -42,68 +303,158 @@ pub(crate) async fn create_table_token(
 // IN6: refresh_token (optional TEXT)
 // IN7: expires_in: (INTEGER (from server))
 // IN8: expiry_time (INTEGER (epoch time))
-const INSERT_TOKEN: &str = r#"
+// IN9: provider (TEXT)
+const UPSERT_TOKEN: &str = r#"
 INSERT INTO tokens (
         state_token,
-        client_address, client_port, client_email, 
-        access_token, refresh_token, expires_in, expiry_time) 
-    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+        client_address, client_port, client_email,
+        access_token, refresh_token, expires_in, expiry_time, provider)
+    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+    ON CONFLICT(state_token) DO UPDATE SET
+        client_address = excluded.client_address,
+        client_port = excluded.client_port,
+        client_email = COALESCE(excluded.client_email, tokens.client_email),
+        access_token = excluded.access_token,
+        refresh_token = COALESCE(excluded.refresh_token, tokens.refresh_token),
+        expires_in = excluded.expires_in,
+        expiry_time = excluded.expiry_time,
+        provider = excluded.provider
+"#;
+// IN1: state_token (TEXT UNIQUE)
+const SELECT_SESSION_ID: &str = r#"
+SELECT session_id FROM tokens WHERE state_token = ?1
 "#;
-pub(crate) async fn store_token(
+pub(crate) async fn upsert_token(
     db_connection: &TDBConnectionLock_sqlite,
-    token_data_no_session_id_ref: &TokenData,
-) -> tokio_rusqlite::Result<i64> {
+    token_data_ref: &TokenData,
+) -> tokio_rusqlite::Result<u64> {
     let conn = db_connection.lock().await;
-    let token_data_no_session_id = token_data_no_session_id_ref.clone();
+    let token_data = token_data_ref.clone();
     conn.call(move |conn| {
         conn.execute(
-            INSERT_TOKEN,
+            UPSERT_TOKEN,
             params![
-                token_data_no_session_id.state_token,                      // 1
-                token_data_no_session_id.client_address.to_string(),       // 2
-                token_data_no_session_id.client_port,                      // 3
-                token_data_no_session_id.possible_client_email,            // 4
-                token_data_no_session_id.access_token,                     // 5
-                token_data_no_session_id.possible_refresh_token,           // 6
-                token_data_no_session_id.expires_in,                       // 7
-                (token_data_no_session_id.expiry_time_as_sec_from_epoch() as i64), // 8
+                token_data.state_token,                              // 1
+                token_data.client_address.to_string(),               // 2
+                token_data.client_port,                              // 3
+                token_data.possible_client_email,                    // 4
+                token_data.access_token,                             // 5
+                token_data.possible_refresh_token,                   // 6
+                token_data.expires_in,                               // 7
+                (token_data.expiry_time_as_sec_from_epoch() as i64), // 8
+                token_data.provider,                                 // 9
             ],
         )?;
-        let session_id = conn.last_insert_rowid();
-        Ok(session_id)
+        // last_insert_rowid() is not updated on the "DO UPDATE" path, so look it up by state_token
+        let session_id: i64 =
+            conn.query_row(SELECT_SESSION_ID, params![token_data.state_token], |row| {
+                row.get(0)
+            })?;
+        Ok(session_id as u64)
     })
     .await
 }
 
+// Update path for refreshed tokens (i.e. grant_type=refresh_token), in which the session_id is
+// already known and only the token and its expiry changes.  Returns number of rows updated (0 or 1)
 // IN1: session_id (INTEGER)
-const SELECT_TOKEN: &str = r#"
-SELECT state_token, 
-        client_address, client_port, client_email, 
-        access_token, refresh_token, expires_in, expiry_time 
-    FROM tokens WHERE session_id = ?1
+// IN2: access_token (TEXT)
+// IN3: refresh_token (optional TEXT, NULL keeps the old one)
+// IN4: expires_in (INTEGER)
+// IN5: expiry_time (INTEGER (epoch time))
+const UPDATE_TOKEN: &str = r#"
+UPDATE tokens SET
+        access_token = ?2,
+        refresh_token = COALESCE(?3, refresh_token),
+        expires_in = ?4,
+        expiry_time = ?5
+    WHERE session_id = ?1
 "#;
-pub(crate) async fn get_token_by_session_id(
+pub(crate) async fn update_token(
     db_connection: &TDBConnectionLock_sqlite,
     session_id: u64,
-) -> tokio_rusqlite::Result<TokenData> {
+    token_data_ref: &TokenData,
+) -> tokio_rusqlite::Result<usize> {
     let conn = db_connection.lock().await;
+    let token_data = token_data_ref.clone();
     conn.call(move |conn| {
-        let mut stmt = conn.prepare(SELECT_TOKEN)?;
-        // in parm1: session_id
-        let token_data = stmt.query_row(params![session_id], |row| {
-            Ok(TokenData::new(
-                SessionIDType::ID(session_id),
-                row.get(0)?,
-                row.get::<usize, String>(1)?.to_string().parse().unwrap(),
-                row.get(2)?,
-                row.get(3)?,
-                row.get(4)?,
-                row.get(5)?,
-                row.get(6)?,
-                UNIX_EPOCH + std::time::Duration::new(row.get(7)?, 10),
-            ))
-        })?;
-        Ok(token_data)
+        let rows_updated = conn.execute(
+            UPDATE_TOKEN,
+            params![
+                session_id,                                          // 1
+                token_data.access_token,                             // 2
+                token_data.possible_refresh_token,                   // 3
+                token_data.expires_in,                               // 4
+                (token_data.expiry_time_as_sec_from_epoch() as i64), // 5
+            ],
+        )?;
+        Ok(rows_updated)
     })
     .await
 }
+
+// Rows with access_token and/or refresh_token NOT prefixed with the current key (i.e. "enc:k2:"),
+// which is either plain-text (from before encryption) or encrypted with an older key
+// NOTE: substr() rather than LIKE, since LIKE is case-insensitive and treats '_' as wildcard
+// IN1: current key prefix (TEXT)
+const SELECT_TOKENS_TO_REENCRYPT: &str = r#"
+SELECT session_id, access_token, refresh_token
+    FROM tokens
+    WHERE substr(access_token, 1, length(?1)) <> ?1
+        OR (refresh_token IS NOT NULL AND substr(refresh_token, 1, length(?1)) <> ?1)
+"#;
+// IN1: session_id (INTEGER)
+// IN2: access_token (TEXT)
+// IN3: refresh_token (optional TEXT)
+const UPDATE_REENCRYPTED_TOKENS: &str = r#"
+UPDATE tokens SET access_token = ?2, refresh_token = ?3 WHERE session_id = ?1
+"#;
+// Re-encrypts with the current key (all in one transaction), and returns number of rows re-encrypted
+pub(crate) async fn reencrypt_tokens(
+    db_connection: &TDBConnectionLock_sqlite,
+    token_cipher: &TokenCipher,
+) -> anyhow::Result<usize> {
+    // hold the lock across the SELECT and the UPDATEs so that nobody writes in between
+    let conn = db_connection.lock().await;
+    let current_prefix = token_cipher.current_prefix();
+    let rows: Vec<(i64, String, Option<String>)> = conn
+        .call(move |conn| {
+            let mut stmt = conn.prepare(SELECT_TOKENS_TO_REENCRYPT)?;
+            let rows = stmt
+                .query_map(params![current_prefix], |row| {
+                    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+                })?
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(rows)
+        })
+        .await?;
+    let mut reencrypted_rows = Vec::with_capacity(rows.len());
+    for (session_id, access_token, possible_refresh_token) in rows {
+        reencrypted_rows.push((
+            session_id,
+            token_cipher.reencrypt(COLUMN_ACCESS_TOKEN, &access_token)?,
+            match possible_refresh_token {
+                Some(refresh_token) => {
+                    Some(token_cipher.reencrypt(COLUMN_REFRESH_TOKEN, &refresh_token)?)
+                }
+                None => None,
+            },
+        ));
+    }
+    let rows_reencrypted = reencrypted_rows.len();
+    if rows_reencrypted > 0 {
+        conn.call(move |conn| {
+            let transaction = conn.transaction()?;
+            for (session_id, access_token, possible_refresh_token) in reencrypted_rows {
+                transaction.execute(
+                    UPDATE_REENCRYPTED_TOKENS,
+                    params![session_id, access_token, possible_refresh_token],
+                )?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await?;
+    }
+    Ok(rows_reencrypted)
+}
@@ -0,0 +1,240 @@
+use crate::data::TokenData;
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Key, Nonce,
+};
+use anyhow::Result as AnyResult;
+use base64::{engine::general_purpose, Engine};
+use std::collections::HashMap;
+
+// Encrypts access_token and refresh_token at rest (AES-256-GCM), stored as:
+//      "enc:<key_id>:<base64(nonce || ciphertext+tag)>"
+// The key_id prefix is what makes key rotation possible: new rows are always encrypted with the
+// current (first) key, older keys are only kept around to decrypt, and at startup, rows that are
+// not encrypted with the current key (including plain-text rows from before encryption) are
+// re-encrypted (see TokenStore::migrate()), after which the old key can be dropped.
+// The column name is bound as AAD, so that an access_token cannot be swapped with a refresh_token.
+const ENCRYPTED_PREFIX: &str = "enc:";
+const KEY_SIZE: usize = 32; // AES-256
+const NONCE_SIZE: usize = 12; // 96-bits, as recommended for GCM
+
+pub const COLUMN_ACCESS_TOKEN: &str = "access_token";
+pub const COLUMN_REFRESH_TOKEN: &str = "refresh_token";
+pub const COLUMN_CODE_VERIFIER: &str = "code_verifier"; // login_states, see TokenStore::insert_login_state()
+
+pub struct TokenCipher {
+    current_key_id: String,
+    ciphers: HashMap<String, Aes256Gcm>,
+}
+
+impl TokenCipher {
+    // keys are (key_id, base64 encoded 32 bytes key), first one is the current key
+    pub fn new(keys: &[(String, String)]) -> AnyResult<Self> {
+        let current_key_id = match keys.first() {
+            Some((key_id, _)) => key_id.clone(),
+            None => anyhow::bail!("At least one token encryption key is required"),
+        };
+        let mut ciphers = HashMap::new();
+        for (key_id, key_base64) in keys {
+            if key_id.is_empty()
+                || !key_id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-')
+            {
+                anyhow::bail!(
+                    "Token encryption key id '{}' must be non-empty alphanumeric (and '-')",
+                    key_id
+                );
+            }
+            let key_bytes = general_purpose::STANDARD
+                .decode(key_base64.trim())
+                .map_err(|e| anyhow::anyhow!("Token encryption key '{}': {}", key_id, e))?;
+            if key_bytes.len() != KEY_SIZE {
+                anyhow::bail!(
+                    "Token encryption key '{}' must be {} bytes (base64 encoded), got {} bytes",
+                    key_id,
+                    KEY_SIZE,
+                    key_bytes.len()
+                );
+            }
+            let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key_bytes));
+            if ciphers.insert(key_id.clone(), cipher).is_some() {
+                anyhow::bail!("Token encryption key id '{}' is defined twice", key_id);
+            }
+        }
+        Ok(TokenCipher {
+            current_key_id,
+            ciphers,
+        })
+    }
+
+    pub fn current_key_id(&self) -> &str {
+        self.current_key_id.as_str()
+    }
+    // i.e. "enc:<current_key_id>:", rows which do not start with it needs re-encryption
+    pub fn current_prefix(&self) -> String {
+        format!("{}{}:", ENCRYPTED_PREFIX, self.current_key_id)
+    }
+    pub fn is_current(&self, stored: &str) -> bool {
+        stored.starts_with(self.current_prefix().as_str())
+    }
+
+    pub fn encrypt(&self, column: &str, plaintext: &str) -> AnyResult<String> {
+        let cipher = &self.ciphers[&self.current_key_id];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: column.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt {}", column))?;
+        let mut nonce_and_ciphertext = nonce.to_vec();
+        nonce_and_ciphertext.extend_from_slice(&ciphertext);
+        Ok(format!(
+            "{}{}",
+            self.current_prefix(),
+            general_purpose::STANDARD.encode(nonce_and_ciphertext)
+        ))
+    }
+
+    // plain-text (not yet migrated) values are returned as is
+    pub fn decrypt(&self, column: &str, stored: &str) -> AnyResult<String> {
+        let key_id_and_payload = match stored.strip_prefix(ENCRYPTED_PREFIX) {
+            Some(s) => s,
+            None => return Ok(stored.to_string()),
+        };
+        let (key_id, payload) = match key_id_and_payload.split_once(':') {
+            Some(key_id_and_payload) => key_id_and_payload,
+            None => anyhow::bail!("Malformed encrypted {}", column),
+        };
+        let cipher = match self.ciphers.get(key_id) {
+            Some(cipher) => cipher,
+            None => anyhow::bail!(
+                "Unknown token encryption key id '{}' for {} (was the key dropped before rows were re-encrypted?)",
+                key_id,
+                column
+            ),
+        };
+        let nonce_and_ciphertext = general_purpose::STANDARD
+            .decode(payload)
+            .map_err(|e| anyhow::anyhow!("Malformed encrypted {}: {}", column, e))?;
+        if nonce_and_ciphertext.len() < NONCE_SIZE {
+            anyhow::bail!("Malformed encrypted {}", column);
+        }
+        let (nonce, ciphertext) = nonce_and_ciphertext.split_at(NONCE_SIZE);
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: column.as_bytes(),
+                },
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to decrypt {} (wrong key or tampered)", column))?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+
+    // decrypt with whichever key it was encrypted with (if at all), and encrypt with current key
+    // (as is, if it already is encrypted with the current key)
+    pub fn reencrypt(&self, column: &str, stored: &str) -> AnyResult<String> {
+        if self.is_current(stored) {
+            return Ok(stored.to_string());
+        }
+        self.encrypt(column, self.decrypt(column, stored)?.as_str())
+    }
+
+    pub fn encrypt_token_data(&self, token_data: &TokenData) -> AnyResult<TokenData> {
+        let mut ret = token_data.clone();
+        ret.access_token = self.encrypt(COLUMN_ACCESS_TOKEN, &token_data.access_token)?;
+        ret.possible_refresh_token = match &token_data.possible_refresh_token {
+            Some(refresh_token) => Some(self.encrypt(COLUMN_REFRESH_TOKEN, refresh_token)?),
+            None => None,
+        };
+        Ok(ret)
+    }
+    pub fn decrypt_token_data(&self, token_data: &TokenData) -> AnyResult<TokenData> {
+        let mut ret = token_data.clone();
+        ret.access_token = self.decrypt(COLUMN_ACCESS_TOKEN, &token_data.access_token)?;
+        ret.possible_refresh_token = match &token_data.possible_refresh_token {
+            Some(refresh_token) => Some(self.decrypt(COLUMN_REFRESH_TOKEN, refresh_token)?),
+            None => None,
+        };
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // i.e. `openssl rand -base64 32`
+    pub(crate) fn make_key(key_id: &str, byte: u8) -> (String, String) {
+        (
+            key_id.to_string(),
+            general_purpose::STANDARD.encode([byte; KEY_SIZE]),
+        )
+    }
+
+    #[test]
+    fn roundtrip_and_rotation() {
+        let old_cipher = TokenCipher::new(&[make_key("k1", 1)]).unwrap();
+        let stored = old_cipher
+            .encrypt(COLUMN_ACCESS_TOKEN, "ya29.secret")
+            .unwrap();
+        assert!(stored.starts_with("enc:k1:"));
+        assert!(!stored.contains("ya29.secret"));
+        assert_eq!(
+            old_cipher.decrypt(COLUMN_ACCESS_TOKEN, &stored).unwrap(),
+            "ya29.secret"
+        );
+        // same plaintext, different nonce
+        assert_ne!(
+            stored,
+            old_cipher
+                .encrypt(COLUMN_ACCESS_TOKEN, "ya29.secret")
+                .unwrap()
+        );
+
+        // rotated: k2 is current, k1 still decrypts
+        let new_cipher = TokenCipher::new(&[make_key("k2", 2), make_key("k1", 1)]).unwrap();
+        assert!(!new_cipher.is_current(&stored));
+        let reencrypted = new_cipher.reencrypt(COLUMN_ACCESS_TOKEN, &stored).unwrap();
+        assert!(new_cipher.is_current(&reencrypted));
+        assert_eq!(
+            new_cipher
+                .decrypt(COLUMN_ACCESS_TOKEN, &reencrypted)
+                .unwrap(),
+            "ya29.secret"
+        );
+        // once k1 is dropped, rows not re-encrypted can no longer be read
+        assert!(TokenCipher::new(&[make_key("k2", 2)])
+            .unwrap()
+            .decrypt(COLUMN_ACCESS_TOKEN, &stored)
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_tampering_swapping_and_bad_keys() {
+        let cipher = TokenCipher::new(&[make_key("k1", 1)]).unwrap();
+        let stored = cipher.encrypt(COLUMN_ACCESS_TOKEN, "ya29.secret").unwrap();
+        // access_token cannot be passed off as refresh_token
+        assert!(cipher.decrypt(COLUMN_REFRESH_TOKEN, &stored).is_err());
+        let mut tampered = stored.clone();
+        tampered.pop();
+        tampered.push(if stored.ends_with('A') { 'B' } else { 'A' });
+        assert!(cipher.decrypt(COLUMN_ACCESS_TOKEN, &tampered).is_err());
+        // legacy plain-text passes through (until migrated)
+        assert_eq!(
+            cipher.decrypt(COLUMN_ACCESS_TOKEN, "ya29.legacy").unwrap(),
+            "ya29.legacy"
+        );
+
+        assert!(TokenCipher::new(&[]).is_err());
+        assert!(TokenCipher::new(&[("k1".to_string(), "dG9vc2hvcnQ=".to_string())]).is_err());
+        assert!(TokenCipher::new(&[make_key("k:1", 1)]).is_err());
+        assert!(TokenCipher::new(&[make_key("k1", 1), make_key("k1", 2)]).is_err());
+    }
+}
@@ -1,2 +1,1202 @@
 pub mod login;
-pub mod keepalive;
\ No newline at end of file
+pub mod app;
+pub mod health;
+pub mod jwks;
+pub mod keepalive;
+pub mod logout;
+pub mod metrics;
+pub mod reaper;
+pub mod refresh;
+pub mod revoke;
+pub mod session_auth;
+pub mod tls;
+
+// Offline (DB_CONNECTION=memory, MQ_CONNECTION=memory) flows of /login and /keepalive, in which
+// Google's part (consent and auth_code_callback()'s token requests) is played by the test itself
+#[cfg(test)]
+mod tests {
+    use super::{
+        app::{make_app, AppState},
+        *,
+    };
+    use crate::{
+        config::{Config, DBType, MQType, OAuth2ProviderKind},
+        data::{
+            decode_state_token, encode_state_token, KeepaliveResponse, LoginResponse, LoginState,
+            OAuth2AuthCodeRequestState, ReadinessResponse, SessionEvent, SessionIDType, TokenData,
+        },
+        messenger::{self, TMessenger},
+        metrics::{tests::find_sample, RelayMetrics},
+        providers::{
+            mock::{MockFailure, MockProvider, MockUser},
+            tests::{make_provider_config, make_providers},
+            OAuth2Providers, TOAuth2Providers,
+        },
+        session_token::SessionTokenIssuer,
+        storage::{self, TTokenStore},
+        web::web_consts::TIMEOUT_FOR_AUTH_CODE_CALLBACK,
+    };
+    use actix_web::{
+        http::{header, StatusCode},
+        test, web, HttpServer,
+    };
+    use std::{
+        net::TcpListener,
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    const CLIENT_ADDR: &str = "192.168.1.2:12345";
+
+    fn make_memory_config() -> Config {
+        Config {
+            bind_address: [127, 0, 0, 1].into(),
+            rest_port: 8080,
+            possible_http_workers: Some(1),
+            possible_tls: None,
+            possible_http_redirect_port: None,
+            oauth2_providers: Vec::new(), // see make_test_providers()
+            db_connection: DBType::Memory,
+            mq_connection: MQType::Memory,
+            token_encryption_keys: Vec::new(),
+            possible_session_token_signing_key: None,
+            session_token_key_id: "test-key".to_string(),
+            session_token_issuer: "test_relay".to_string(),
+        }
+    }
+
+    // nobody listening, none of these tests get as far as talking to the provider
+    fn make_test_providers() -> TOAuth2Providers {
+        make_providers("http://127.0.0.1:1")
+    }
+
+    // what the relay serves (app::make_app()), on the given backends
+    fn make_app_state(
+        config: &Config,
+        token_store: &TTokenStore,
+        messenger: &TMessenger,
+        providers: TOAuth2Providers,
+    ) -> AppState {
+        AppState::new(
+            config,
+            token_store,
+            messenger,
+            providers,
+            SessionTokenIssuer::from_config(config).unwrap(),
+            RelayMetrics::new(&token_store.db_type(), &messenger.mq_type()).unwrap(),
+        )
+    }
+
+    async fn open_backends(config: &Config) -> (TTokenStore, TMessenger) {
+        let token_store = storage::open_token_store_from_config(config).await.unwrap();
+        token_store.migrate().await.unwrap();
+        let messenger = messenger::open_messenger_from_config(config).await.unwrap();
+        (token_store, messenger)
+    }
+
+    #[actix_web::test]
+    async fn app_serves_every_route() {
+        let config = make_memory_config();
+        let (token_store, messenger) = open_backends(&config).await;
+        let app = test::init_service(make_app(make_app_state(
+            &config,
+            &token_store,
+            &messenger,
+            make_test_providers(),
+        )))
+        .await;
+
+        // routed (whatever the handler makes of the empty request), trailing slash or not
+        for (method, uri, expected_status) in [
+            ("GET", "/login", StatusCode::OK),
+            ("GET", "/login/", StatusCode::OK),
+            ("GET", "/auth_callback", StatusCode::BAD_REQUEST),
+            ("GET", "/keepalive", StatusCode::BAD_REQUEST),
+            ("GET", "/healthz", StatusCode::OK),
+            ("GET", "/readyz", StatusCode::OK),
+            ("POST", "/logout", StatusCode::BAD_REQUEST),
+            ("GET", "/.well-known/jwks.json", StatusCode::OK),
+            ("GET", "/metrics", StatusCode::OK),
+            ("GET", "/nowhere", StatusCode::NOT_FOUND),
+        ] {
+            let request = match method {
+                "POST" => test::TestRequest::post(),
+                _ => test::TestRequest::get(),
+            }
+            .uri(uri)
+            .peer_addr(CLIENT_ADDR.parse().unwrap())
+            .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), expected_status, "{} {}", method, uri);
+            // session ids/tokens are never cached, the public key is
+            let cache_control = response.headers().get(header::CACHE_CONTROL).unwrap();
+            match uri {
+                "/.well-known/jwks.json" => assert_eq!(cache_control, "public, max-age=300"),
+                _ => assert_eq!(cache_control, "no-store"),
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn metrics_count_requests_by_route_status_and_backend() {
+        let config = make_memory_config();
+        let (token_store, messenger) = open_backends(&config).await;
+        let app = test::init_service(make_app(make_app_state(
+            &config,
+            &token_store,
+            &messenger,
+            make_test_providers(),
+        )))
+        .await;
+
+        for uri in [
+            "/keepalive?last_session_id=42",
+            "/keepalive?last_session_id=43",
+            "/keepalive",
+            "/login",
+            "/nowhere/42",
+        ] {
+            let request = test::TestRequest::get()
+                .uri(uri)
+                .peer_addr(CLIENT_ADDR.parse().unwrap())
+                .to_request();
+            test::call_service(&app, request).await;
+        }
+
+        let request = test::TestRequest::get().uri("/metrics").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain"));
+        let exposition = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+        for (name, route, status, expected_count) in [
+            ("relay_http_requests_total", "/keepalive", "401", 2.0),
+            ("relay_http_requests_total", "/keepalive", "400", 1.0),
+            ("relay_http_requests_total", "/login", "200", 1.0),
+            // whatever the path, so that made up ones do not each get their own series
+            ("relay_http_requests_total", "unmatched", "404", 1.0),
+            (
+                "relay_http_request_duration_seconds_count",
+                "/keepalive",
+                "401",
+                2.0,
+            ),
+        ] {
+            assert_eq!(
+                find_sample(
+                    &exposition,
+                    name,
+                    &[
+                        ("route", route),
+                        ("status", status),
+                        ("db_type", "memory"),
+                        ("mq_type", "memory"),
+                    ],
+                ),
+                Some(expected_count),
+                "{} {} {}\n{}",
+                name,
+                route,
+                status,
+                exposition
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn readyz_reports_every_dependency() {
+        let config = make_memory_config();
+        let (token_store, messenger) = open_backends(&config).await;
+        let app = test::init_service(make_app(make_app_state(
+            &config,
+            &token_store,
+            &messenger,
+            make_test_providers(),
+        )))
+        .await;
+
+        let request = test::TestRequest::get().uri("/readyz").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let readiness_response: ReadinessResponse = test::read_body_json(response).await;
+        assert_eq!(readiness_response.status, "OK");
+        let dependencies: Vec<(&str, &str, &str)> = readiness_response
+            .dependencies
+            .iter()
+            .map(|(dependency, dependency_status)| {
+                (
+                    dependency.as_str(),
+                    dependency_status.backend.as_str(),
+                    dependency_status.status.as_str(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            dependencies,
+            vec![
+                ("db", "memory", "OK"),
+                ("mq", "memory", "OK"),
+                ("provider:google", "google", "OK"),
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn readyz_fails_on_unresolvable_provider() {
+        let config = make_memory_config();
+        let (token_store, messenger) = open_backends(&config).await;
+        // .invalid never resolves (RFC 6761)
+        let app = test::init_service(make_app(make_app_state(
+            &config,
+            &token_store,
+            &messenger,
+            make_providers("https://oauth2.example.invalid"),
+        )))
+        .await;
+
+        let request = test::TestRequest::get().uri("/readyz").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let readiness_response: ReadinessResponse = test::read_body_json(response).await;
+        assert_eq!(readiness_response.status, "ERROR");
+        assert_eq!(readiness_response.dependencies["db"].status, "OK");
+        assert_eq!(readiness_response.dependencies["mq"].status, "OK");
+        let provider_status = &readiness_response.dependencies["provider:google"];
+        assert_eq!(provider_status.status, "ERROR");
+        assert!(provider_status.possible_error.is_some());
+
+        // still alive though, nothing to restart
+        let request = test::TestRequest::get().uri("/healthz").to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    // what auth_code_callback() would have built from Google's responses
+    fn make_consented_token_data(state_token: &str) -> TokenData {
+        TokenData::new(
+            SessionIDType::make_hash(state_token),
+            state_token.to_string(),
+            "192.168.1.2".into(),
+            12345,
+            Some("player@example.com".to_string()),
+            "access_token".to_string(),
+            Some("refresh_token".to_string()),
+            3599,
+            SystemTime::now() + Duration::from_secs(3599),
+        )
+    }
+
+    #[actix_web::test]
+    async fn login_then_keepalive() {
+        let config = make_memory_config();
+        let (token_store, messenger) = open_backends(&config).await;
+        let app_state = make_app_state(&config, &token_store, &messenger, make_test_providers());
+        let session_token_issuer = app_state.session_token_issuer.clone();
+        let app = test::init_service(make_app(app_state)).await;
+
+        // 1. new login, relay hands back the auth URL and state token right away
+        let request = test::TestRequest::get()
+            .uri("/login")
+            .peer_addr(CLIENT_ADDR.parse().unwrap())
+            .to_request();
+        let login_response: LoginResponse = test::call_and_read_body_json(&app, request).await;
+        let state_token = login_response.possible_state_token.unwrap();
+        let auth_url = login_response.possible_auth_url.unwrap();
+        assert!(auth_url.starts_with("http://127.0.0.1:1/authorize?"));
+        assert!(auth_url.contains("client_id=test_client_id"));
+        assert!(auth_url.contains("access_type=offline"));
+        assert!(login_response.possible_session_id.is_none());
+
+        // 2. player consents, and auth_code_callback() stores and posts the new login
+        let session_id = login::store_and_post_new_login(
+            &token_store,
+            &messenger,
+            make_consented_token_data(&state_token),
+        )
+        .await
+        .unwrap();
+
+        // 3. client polls with the state token, and gets the session_id
+        let request = test::TestRequest::get()
+            .uri(&format!("/login?last_state_token={}", state_token))
+            .peer_addr(CLIENT_ADDR.parse().unwrap())
+            .to_request();
+        let login_response: LoginResponse = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(login_response.possible_session_id, Some(session_id));
+        assert_eq!(login_response.possible_state_token, Some(state_token));
+        assert!(login_response.possible_login_error.is_none());
+        let session_token = login_response.possible_session_token.unwrap();
+        let claims = session_token_issuer.verify(&session_token).unwrap();
+        assert_eq!(claims.sid, session_id);
+        assert_eq!(claims.sub, "player@example.com");
+        assert_eq!(claims.iss, "test_relay");
+
+        // 4. and keeps the session alive, with the session token it got
+        let request = test::TestRequest::get()
+            .uri(&format!("/keepalive?last_session_id={}", session_id))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", session_token)))
+            .peer_addr(CLIENT_ADDR.parse().unwrap())
+            .to_request();
+        let keepalive_response: KeepaliveResponse =
+            test::call_and_read_body_json(&app, request).await;
+        assert_eq!(keepalive_response.status, "OK");
+        assert!(keepalive_response.ttl > 0);
+        let claims = session_token_issuer
+            .verify(&keepalive_response.possible_session_token.unwrap())
+            .unwrap();
+        assert_eq!(claims.sid, session_id);
+
+        // 5. other services fetch the public key to verify session tokens offline
+        let request = test::TestRequest::get()
+            .uri("/.well-known/jwks.json")
+            .to_request();
+        let jwks: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(jwks["keys"][0]["kid"], "test-key");
+        assert_eq!(jwks["keys"][0]["alg"], "EdDSA");
+    }
+
+    #[actix_web::test]
+    async fn keepalive_rejects_unknown_and_missing_session() {
+        let config = make_memory_config();
+        let (token_store, messenger) = open_backends(&config).await;
+        let app = test::init_service(make_app(make_app_state(
+            &config,
+            &token_store,
+            &messenger,
+            make_test_providers(),
+        )))
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri("/keepalive?last_session_id=42")
+            .peer_addr(CLIENT_ADDR.parse().unwrap())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let request = test::TestRequest::get()
+            .uri("/keepalive")
+            .peer_addr(CLIENT_ADDR.parse().unwrap())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    // logged in session (as auth_code_callback() stores it), and the session token /login hands out
+    async fn make_logged_in_session(
+        token_store: &TTokenStore,
+        messenger: &TMessenger,
+        session_token_issuer: &SessionTokenIssuer,
+        state_token: &str,
+    ) -> (u64, String) {
+        let mut token_data = make_consented_token_data(state_token);
+        token_data.access_token = format!("access_token_{}", state_token); // unique per session
+        let session_id =
+            login::store_and_post_new_login(token_store, messenger, token_data.clone())
+                .await
+                .unwrap();
+        token_data.set_session_id(SessionIDType::ID(session_id));
+        (session_id, session_token_issuer.issue(&token_data).unwrap())
+    }
+
+    #[actix_web::test]
+    async fn keepalive_rejects_guessed_and_foreign_session_ids() {
+        let config = make_memory_config();
+        let (token_store, messenger) = open_backends(&config).await;
+        let app_state = make_app_state(&config, &token_store, &messenger, make_test_providers());
+        let (session_id_a, session_token_a) = make_logged_in_session(
+            &token_store,
+            &messenger,
+            &app_state.session_token_issuer,
+            "state_token_a",
+        )
+        .await;
+        let (session_id_b, _) = make_logged_in_session(
+            &token_store,
+            &messenger,
+            &app_state.session_token_issuer,
+            "state_token_b",
+        )
+        .await;
+        // right claims, wrong key
+        let (session_id_c, forged_session_token) = make_logged_in_session(
+            &token_store,
+            &messenger,
+            &SessionTokenIssuer::generate("test-key", "test_relay").unwrap(),
+            "state_token_c",
+        )
+        .await;
+        let app = test::init_service(make_app(app_state)).await;
+
+        for (session_id, possible_session_token) in [
+            (session_id_b, None),                          // guessed id, no credential
+            (session_id_b, Some(session_token_a.clone())), // someone else's session
+            (session_id_c, Some(forged_session_token)),    // not signed by us
+            (session_id_a, Some("not a jwt".to_string())),
+        ] {
+            let mut request = test::TestRequest::get()
+                .uri(&format!("/keepalive?last_session_id={}", session_id))
+                .peer_addr(CLIENT_ADDR.parse().unwrap());
+            if let Some(session_token) = possible_session_token {
+                request = request
+                    .insert_header((header::AUTHORIZATION, format!("Bearer {}", session_token)));
+            }
+            let response = test::call_service(&app, request.to_request()).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            let keepalive_response: KeepaliveResponse =
+                serde_json::from_slice(&test::read_body(response).await).unwrap();
+            assert!(keepalive_response.possible_session_token.is_none());
+        }
+
+        // while the owner still gets through
+        let request = test::TestRequest::get()
+            .uri(&format!("/keepalive?last_session_id={}", session_id_a))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", session_token_a)))
+            .peer_addr(CLIENT_ADDR.parse().unwrap())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn keepalive_rejects_expired_session_without_refresh_token() {
+        let config = make_memory_config();
+        let (token_store, messenger) = open_backends(&config).await;
+        let mut token_data = make_consented_token_data("expired_state_token");
+        token_data.possible_refresh_token = None;
+        token_data.set_expiry_time_from_now(-10);
+        let session_id =
+            login::store_and_post_new_login(&token_store, &messenger, token_data.clone())
+                .await
+                .unwrap();
+        let app_state = make_app_state(&config, &token_store, &messenger, make_test_providers());
+        // a session token which is still good (as if issued while the session could be refreshed)
+        token_data.set_session_id(SessionIDType::ID(session_id));
+        token_data.possible_refresh_token = Some("refresh_token".to_string());
+        let session_token = app_state.session_token_issuer.issue(&token_data).unwrap();
+        let app = test::init_service(make_app(app_state)).await;
+
+        // cannot be refreshed, so the player has to login again
+        let request = test::TestRequest::get()
+            .uri(&format!("/keepalive?last_session_id={}", session_id))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", session_token)))
+            .peer_addr(CLIENT_ADDR.parse().unwrap())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let keepalive_response: KeepaliveResponse =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(keepalive_response.status, "ERROR");
+    }
+
+    #[actix_web::test]
+    async fn login_rejects_unknown_provider() {
+        let config = make_memory_config();
+        let (token_store, messenger) = open_backends(&config).await;
+        let app = test::init_service(make_app(make_app_state(
+            &config,
+            &token_store,
+            &messenger,
+            make_test_providers(),
+        )))
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri("/login?provider=myspace")
+            .peer_addr(CLIENT_ADDR.parse().unwrap())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let error_response: KeepaliveResponse =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(error_response.status, "ERROR");
+        assert!(error_response.message.contains("google"));
+
+        // known ones are case insensitive
+        let request = test::TestRequest::get()
+            .uri("/login?provider=Google")
+            .peer_addr(CLIENT_ADDR.parse().unwrap())
+            .to_request();
+        let login_response: LoginResponse = test::call_and_read_body_json(&app, request).await;
+        assert!(login_response.possible_auth_url.is_some());
+    }
+
+    #[actix_web::test]
+    async fn login_poll_rejects_unknown_or_purged_state_token() {
+        let config = make_memory_config();
+        let (token_store, messenger) = open_backends(&config).await;
+        let app = test::init_service(make_app(make_app_state(
+            &config,
+            &token_store,
+            &messenger,
+            make_test_providers(),
+        )))
+        .await;
+        let request = test::TestRequest::get()
+            .uri("/login")
+            .peer_addr(CLIENT_ADDR.parse().unwrap())
+            .to_request();
+        let login_response: LoginResponse = test::call_and_read_body_json(&app, request).await;
+        let state_token = login_response.possible_state_token.unwrap();
+
+        // right away, rather than after TIMEOUT_FOR_AUTH_CODE_CALLBACK
+        let start_time = SystemTime::now();
+        token_store
+            .delete_login_states_created_before(SystemTime::now() + Duration::from_secs(1))
+            .await
+            .unwrap();
+        for state_token in ["made_up_state_token", state_token.as_str()] {
+            let request = test::TestRequest::get()
+                .uri(&format!("/login?last_state_token={}", state_token))
+                .peer_addr(CLIENT_ADDR.parse().unwrap())
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(
+                response.status(),
+                StatusCode::BAD_REQUEST,
+                "{}",
+                state_token
+            );
+            let error_response: KeepaliveResponse =
+                serde_json::from_slice(&test::read_body(response).await).unwrap();
+            assert_eq!(error_response.status, "ERROR");
+            assert!(error_response.message.contains("Unknown state"));
+        }
+        assert!(start_time.elapsed().unwrap() < TIMEOUT_FOR_AUTH_CODE_CALLBACK);
+    }
+
+    #[actix_web::test]
+    async fn logout_rejects_unknown_and_missing_session() {
+        let config = make_memory_config();
+        let (token_store, messenger) = open_backends(&config).await;
+        let app_state = make_app_state(&config, &token_store, &messenger, make_test_providers());
+        // a genuine session token, for a session which is not there (anymore)
+        let mut token_data = make_consented_token_data("logged_out_state_token");
+        token_data.set_session_id(SessionIDType::ID(42));
+        let session_token = app_state.session_token_issuer.issue(&token_data).unwrap();
+        let app = test::init_service(make_app(app_state)).await;
+
+        // already logged out (or never logged in)
+        let request = test::TestRequest::post()
+            .uri("/logout?last_session_id=42")
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", session_token)))
+            .peer_addr(CLIENT_ADDR.parse().unwrap())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let logout_response: KeepaliveResponse =
+            serde_json::from_slice(&test::read_body(response).await).unwrap();
+        assert_eq!(logout_response.status, "ERROR");
+
+        let request = test::TestRequest::post()
+            .uri("/logout")
+            .peer_addr(CLIENT_ADDR.parse().unwrap())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn logout_rejects_guessed_and_foreign_session_ids() {
+        let config = make_memory_config();
+        let (token_store, messenger) = open_backends(&config).await;
+        let app_state = make_app_state(&config, &token_store, &messenger, make_test_providers());
+        let (session_id_a, session_token_a) = make_logged_in_session(
+            &token_store,
+            &messenger,
+            &app_state.session_token_issuer,
+            "state_token_a",
+        )
+        .await;
+        let (session_id_b, session_token_b) = make_logged_in_session(
+            &token_store,
+            &messenger,
+            &app_state.session_token_issuer,
+            "state_token_b",
+        )
+        .await;
+        let app = test::init_service(make_app(app_state)).await;
+
+        for possible_session_token in [None, Some(session_token_a.clone())] {
+            let mut request = test::TestRequest::post()
+                .uri(&format!("/logout?last_session_id={}", session_id_b))
+                .peer_addr(CLIENT_ADDR.parse().unwrap());
+            if let Some(session_token) = possible_session_token {
+                request = request
+                    .insert_header((header::AUTHORIZATION, format!("Bearer {}", session_token)));
+            }
+            let response = test::call_service(&app, request.to_request()).await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        // nobody else got to end it
+        assert!(token_store
+            .get_token_by_session_id(&Some(session_id_b.to_string()))
+            .await
+            .unwrap()
+            .is_some());
+
+        // but its owner does (the provider is unreachable, which does not stop the logout)
+        let request = test::TestRequest::post()
+            .uri(&format!("/logout?last_session_id={}", session_id_b))
+            .insert_header((header::AUTHORIZATION, format!("Bearer {}", session_token_b)))
+            .peer_addr(CLIENT_ADDR.parse().unwrap())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(token_store
+            .get_token_by_session_id(&Some(session_id_b.to_string()))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(token_store
+            .get_token_by_session_id(&Some(session_id_a.to_string()))
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[actix_web::test]
+    async fn run_http_server_returns_startup_errors() {
+        let mut config = make_memory_config();
+        let (token_store, messenger) = open_backends(&config).await;
+
+        // nobody answers discovery
+        let mut provider_config = make_provider_config("google", OAuth2ProviderKind::Google);
+        provider_config.possible_issuer_url = Some("http://127.0.0.1:1".to_string());
+        config.oauth2_providers = vec![provider_config.clone()];
+        let e = app::run_http_server(
+            &config,
+            &token_store,
+            &messenger,
+            SessionTokenIssuer::from_config(&config).unwrap(),
+        )
+        .await
+        .unwrap_err();
+        assert!(e.to_string().starts_with("Providers: "), "{}", e);
+
+        // someone else is on REST_PORT
+        let mock_provider = MockProvider::start(vec![MockUser::new("player@example.com")]);
+        provider_config.possible_issuer_url = Some(mock_provider.issuer_url().to_string());
+        config.oauth2_providers = vec![provider_config];
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        config.rest_port = listener.local_addr().unwrap().port();
+        let e = app::run_http_server(
+            &config,
+            &token_store,
+            &messenger,
+            SessionTokenIssuer::from_config(&config).unwrap(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::AddrInUse);
+    }
+
+    // what login() hands out (encoded) as state on the auth URL
+    fn make_encoded_state(state_token: &str) -> String {
+        encode_state_token(Some(OAuth2AuthCodeRequestState {
+            login_client_ip: "192.168.1.2".into(),
+            login_client_port: 12345,
+            session_id: SessionIDType::Undefined(None),
+            state_token: state_token.to_string(),
+            db_type: "memory".to_string(),
+            possible_db_address: None,
+            possible_db_port: None,
+            possible_db_path: None,
+            mq_type: "memory".to_string(),
+            possible_mq_address: None,
+            possible_mq_port: None,
+            provider: "google".to_string(),
+        }))
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn auth_callback_rejects_unknown_replayed_and_expired_state() {
+        let config = make_memory_config();
+        let (token_store, messenger) = open_backends(&config).await;
+        // i.e. player took too long to consent
+        let mut expired = LoginState::new("expired_state_token", "code_verifier", "google");
+        expired.created_at = SystemTime::now() - TIMEOUT_FOR_AUTH_CODE_CALLBACK * 2;
+        token_store.insert_login_state(&expired).await.unwrap();
+        // i.e. callback already came (and went)
+        let replayed = LoginState::new("replayed_state_token", "code_verifier", "google");
+        token_store.insert_login_state(&replayed).await.unwrap();
+        token_store
+            .take_login_state(&replayed.state_token)
+            .await
+            .unwrap();
+        let app = test::init_service(make_app(make_app_state(
+            &config,
+            &token_store,
+            &messenger,
+            make_test_providers(),
+        )))
+        .await;
+
+        for (query_string, expected_message) in [
+            ("code=auth_code".to_string(), "Missing or malformed state"),
+            (
+                "code=auth_code&state=not-base64!".to_string(),
+                "Missing or malformed state",
+            ),
+            (
+                format!("code=auth_code&state={}", make_encoded_state("forged")),
+                "Unknown state",
+            ),
+            (
+                format!(
+                    "code=auth_code&state={}",
+                    make_encoded_state(&expired.state_token)
+                ),
+                "State has expired",
+            ),
+            (
+                format!(
+                    "code=auth_code&state={}",
+                    make_encoded_state(&replayed.state_token)
+                ),
+                "State has already been used",
+            ),
+            // an expired one is used up as well
+            (
+                format!(
+                    "error=access_denied&state={}",
+                    make_encoded_state(&expired.state_token)
+                ),
+                "State has already been used",
+            ),
+        ] {
+            let request = test::TestRequest::get()
+                .uri(&format!("/auth_callback?{}", query_string))
+                .peer_addr(CLIENT_ADDR.parse().unwrap())
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+            let error_response: KeepaliveResponse =
+                serde_json::from_slice(&test::read_body(response).await).unwrap();
+            assert_eq!(error_response.status, "ERROR");
+            assert!(
+                error_response.message.starts_with(expected_message),
+                "{}: {}",
+                query_string,
+                error_response.message
+            );
+        }
+    }
+
+    // The real thing (sequence_flow.puml), end to end over HTTP: relay (HttpServer, memory backends)
+    // whose "google" provider is the mock one, and the test plays both the client and the player
+    struct EndToEnd {
+        relay_url: String,
+        mock_provider: MockProvider,
+        messenger: TMessenger,
+        session_token_issuer: web::Data<SessionTokenIssuer>,
+        http_client: reqwest::Client,
+    }
+
+    async fn start_end_to_end(users: Vec<MockUser>) -> EndToEnd {
+        let config = make_memory_config();
+        let (_, messenger) = open_backends(&config).await;
+        start_end_to_end_with_messenger(users, messenger).await
+    }
+
+    async fn start_end_to_end_with_messenger(
+        users: Vec<MockUser>,
+        messenger: TMessenger,
+    ) -> EndToEnd {
+        let config = make_memory_config();
+        let (token_store, _) = open_backends(&config).await;
+        let mock_provider = MockProvider::start(users);
+        // bind first, the relay's redirect_uri has to be known before discovery
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let relay_url = format!("http://{}", listener.local_addr().unwrap());
+        let mut provider_config = make_provider_config("google", OAuth2ProviderKind::Google);
+        provider_config.redirect_uri = format!("{}/auth_callback", relay_url);
+        provider_config.possible_issuer_url = Some(mock_provider.issuer_url().to_string());
+        let providers: TOAuth2Providers =
+            Arc::new(OAuth2Providers::discover(&[provider_config]).await.unwrap());
+
+        let app_state = make_app_state(&config, &token_store, &messenger, providers);
+        let session_token_issuer = app_state.session_token_issuer.clone();
+        let http_server = HttpServer::new(move || make_app(app_state.clone()))
+            .workers(1)
+            .listen(listener)
+            .unwrap();
+        actix_web::rt::spawn(http_server.run());
+        EndToEnd {
+            relay_url,
+            mock_provider,
+            messenger,
+            session_token_issuer,
+            http_client: reqwest::Client::new(),
+        }
+    }
+
+    impl EndToEnd {
+        // 1. client asks for a new login, and gets the auth URL and state token back
+        async fn begin_login(&self) -> (String, String) {
+            let login_response: LoginResponse = self
+                .http_client
+                .get(format!("{}/login", self.relay_url))
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            (
+                login_response.possible_state_token.unwrap(),
+                login_response.possible_auth_url.unwrap(),
+            )
+        }
+
+        // 2. player "consents" on the browser: mock provider redirects to the relay's callback,
+        // hence the status is the one of auth_code_callback()
+        async fn consent(&self, auth_url: &str) -> reqwest::StatusCode {
+            self.http_client
+                .get(auth_url)
+                .send()
+                .await
+                .unwrap()
+                .status()
+        }
+    }
+
+    #[actix_web::test]
+    async fn end_to_end_login_keepalive_logout() {
+        let end_to_end = start_end_to_end(vec![
+            MockUser::new("player@example.com"),
+            MockUser::new("other@example.com"),
+        ])
+        .await;
+        let (state_token, auth_url) = end_to_end.begin_login().await;
+        assert!(auth_url.starts_with(&format!(
+            "{}/authorize?",
+            end_to_end.mock_provider.issuer_url()
+        )));
+        // pick the other player via login_hint
+        let auth_url = format!("{}&login_hint=other%40example.com", auth_url);
+        assert_eq!(end_to_end.consent(&auth_url).await, reqwest::StatusCode::OK);
+
+        // 3. client polls with the state token, and gets the session
+        let login_response: LoginResponse = end_to_end
+            .http_client
+            .get(format!(
+                "{}/login?last_state_token={}",
+                end_to_end.relay_url, state_token
+            ))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(login_response.possible_login_error.is_none());
+        let session_id = login_response.possible_session_id.unwrap();
+        let session_token = login_response.possible_session_token.unwrap();
+        let claims = end_to_end
+            .session_token_issuer
+            .verify(&session_token)
+            .unwrap();
+        assert_eq!(claims.sid, session_id);
+        assert_eq!(claims.sub, "other@example.com");
+
+        // 4. keeps the session alive
+        let keepalive_url = format!(
+            "{}/keepalive?last_session_id={}",
+            end_to_end.relay_url, session_id
+        );
+        let response = end_to_end
+            .http_client
+            .get(&keepalive_url)
+            .bearer_auth(&session_token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let keepalive_response: KeepaliveResponse = response.json().await.unwrap();
+        assert_eq!(keepalive_response.status, "OK");
+
+        // 5. logs out, which revokes the refresh_token at the provider
+        let response = end_to_end
+            .http_client
+            .post(format!(
+                "{}/logout?last_session_id={}",
+                end_to_end.relay_url, session_id
+            ))
+            .bearer_auth(&session_token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let revoked_tokens = end_to_end.mock_provider.revoked_tokens();
+        assert_eq!(revoked_tokens.len(), 1);
+        assert!(revoked_tokens[0].starts_with("mock_refresh_"));
+        let response = end_to_end
+            .http_client
+            .get(&keepalive_url)
+            .bearer_auth(&session_token)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn end_to_end_login_fails_with_misbehaving_provider() {
+        let mut unverified = MockUser::new("unverified@example.com");
+        unverified.email_verified = false;
+        let end_to_end =
+            start_end_to_end(vec![MockUser::new("player@example.com"), unverified]).await;
+
+        for (failure, possible_login_hint, expected_status) in [
+            (
+                MockFailure::DenyConsent,
+                None,
+                reqwest::StatusCode::UNAUTHORIZED,
+            ),
+            (
+                MockFailure::RejectCode,
+                None,
+                reqwest::StatusCode::BAD_GATEWAY,
+            ),
+            (
+                MockFailure::UserinfoDown,
+                None,
+                reqwest::StatusCode::BAD_GATEWAY,
+            ),
+            (
+                MockFailure::None,
+                Some("unverified%40example.com"),
+                reqwest::StatusCode::BAD_GATEWAY,
+            ),
+            (
+                MockFailure::None,
+                Some("nobody%40example.com"),
+                reqwest::StatusCode::UNAUTHORIZED,
+            ),
+        ] {
+            end_to_end.mock_provider.set_failure(failure);
+            let (state_token, auth_url) = end_to_end.begin_login().await;
+            let auth_url = match possible_login_hint {
+                Some(login_hint) => format!("{}&login_hint={}", auth_url, login_hint),
+                None => auth_url,
+            };
+            assert_eq!(
+                end_to_end.consent(&auth_url).await,
+                expected_status,
+                "{:?} {:?}",
+                failure,
+                possible_login_hint
+            );
+            // no login was posted, so the client would time out waiting for it
+            assert!(end_to_end
+                .messenger
+                .get_token(&state_token)
+                .await
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[actix_web::test]
+    async fn end_to_end_intercepted_auth_code_is_useless() {
+        let end_to_end = start_end_to_end(vec![MockUser::new("player@example.com")]).await;
+        let (state_token, auth_url) = end_to_end.begin_login().await;
+        assert!(auth_url.contains("code_challenge_method=S256"));
+        assert!(auth_url.contains("code_challenge="));
+
+        // stop at the redirect, i.e. whoever sniffed the redirect_uri on its way to the relay
+        let no_redirect_client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let response = no_redirect_client.get(&auth_url).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::FOUND);
+        let callback_url = reqwest::Url::parse(
+            response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .unwrap()
+                .to_str()
+                .unwrap(),
+        )
+        .unwrap();
+        let auth_code = callback_url
+            .query_pairs()
+            .find(|(name, _)| name == "code")
+            .unwrap()
+            .1
+            .to_string();
+
+        // has the auth code (and even the client secret), but not the code_verifier
+        let response = end_to_end
+            .http_client
+            .post(format!("{}/token", end_to_end.mock_provider.issuer_url()))
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", auth_code.as_str()),
+                ("client_id", "test_client_id"),
+                ("client_secret", "test_client_secret"),
+                (
+                    "redirect_uri",
+                    format!("{}/auth_callback", end_to_end.relay_url).as_str(),
+                ),
+            ])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+        // and auth codes are single use, whether it worked out or not
+        assert_eq!(
+            end_to_end
+                .http_client
+                .get(callback_url.as_str())
+                .send()
+                .await
+                .unwrap()
+                .status(),
+            reqwest::StatusCode::BAD_GATEWAY
+        );
+        assert!(end_to_end
+            .messenger
+            .get_token(&state_token)
+            .await
+            .unwrap()
+            .is_none());
+
+        // the relay's own callback has it, but only once
+        let (_, auth_url) = end_to_end.begin_login().await;
+        let response = no_redirect_client.get(&auth_url).send().await.unwrap();
+        let callback_url = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            end_to_end.consent(&callback_url).await,
+            reqwest::StatusCode::OK
+        );
+        assert_eq!(
+            end_to_end.consent(&callback_url).await,
+            reqwest::StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    async fn end_to_end_callback_ignores_provider_of_tampered_state() {
+        let end_to_end = start_end_to_end(vec![MockUser::new("player@example.com")]).await;
+        let (state_token, auth_url) = end_to_end.begin_login().await;
+        let no_redirect_client = reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let response = no_redirect_client.get(&auth_url).send().await.unwrap();
+        let mut callback_url = reqwest::Url::parse(
+            response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .unwrap()
+                .to_str()
+                .unwrap(),
+        )
+        .unwrap();
+
+        // the state is the client's to tamper with, i.e. pointed at some other provider
+        let query_pairs: Vec<(String, String)> = callback_url
+            .query_pairs()
+            .map(|(name, value)| match name.as_ref() {
+                "state" => {
+                    let mut state = decode_state_token(Some(value.to_string())).unwrap();
+                    state.provider = "forged".to_string();
+                    (name.to_string(), encode_state_token(Some(state)).unwrap())
+                }
+                _ => (name.to_string(), value.to_string()),
+            })
+            .collect();
+        callback_url
+            .query_pairs_mut()
+            .clear()
+            .extend_pairs(query_pairs);
+        assert_eq!(
+            end_to_end.consent(callback_url.as_str()).await,
+            reqwest::StatusCode::OK
+        );
+        // logged in with the provider /login started it with
+        let token_data = end_to_end
+            .messenger
+            .get_token(&state_token)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(token_data.provider, "google");
+    }
+
+    // broker that is up (ping) but neither takes nor hands back new logins, i.e. Kafka until
+    // get_token_kafka() and post_new_login_kafka() are implemented
+    struct FailingMessenger;
+    #[async_trait::async_trait]
+    impl messenger::Messenger for FailingMessenger {
+        fn mq_type(&self) -> String {
+            "failing".to_string()
+        }
+        async fn ping(&self) -> anyhow::Result<()> {
+            Ok(())
+        }
+        async fn post_new_login(&self, _token_data: &TokenData) -> anyhow::Result<()> {
+            anyhow::bail!("post_new_login failed")
+        }
+        async fn get_token(&self, _state_token: &str) -> anyhow::Result<Option<TokenData>> {
+            anyhow::bail!("get_token failed")
+        }
+        async fn post_session_event(&self, _session_event: &SessionEvent) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[actix_web::test]
+    async fn login_poll_is_unavailable_when_messenger_fails() {
+        let config = make_memory_config();
+        let (token_store, _) = open_backends(&config).await;
+        let messenger: TMessenger = Arc::new(FailingMessenger);
+        token_store
+            .insert_login_state(&LoginState::new("state_token", "code_verifier", "google"))
+            .await
+            .unwrap();
+        let app = test::init_service(make_app(make_app_state(
+            &config,
+            &token_store,
+            &messenger,
+            make_test_providers(),
+        )))
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri("/login?last_state_token=state_token")
+            .peer_addr(CLIENT_ADDR.parse().unwrap())
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_web::test]
+    async fn end_to_end_callback_is_unavailable_when_messenger_fails() {
+        let end_to_end = start_end_to_end_with_messenger(
+            vec![MockUser::new("player@example.com")],
+            Arc::new(FailingMessenger),
+        )
+        .await;
+        let (_, auth_url) = end_to_end.begin_login().await;
+        assert_eq!(
+            end_to_end.consent(&auth_url).await,
+            reqwest::StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
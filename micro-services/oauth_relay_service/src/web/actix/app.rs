@@ -0,0 +1,223 @@
+use crate::{
+    config::{Config, TlsConfig},
+    messenger::TMessenger,
+    metrics::{MeteredMessenger, RelayMetrics},
+    providers::{OAuth2Providers, TOAuth2Providers},
+    session_token::SessionTokenIssuer,
+    storage::TTokenStore,
+    web::actix::{health, jwks, keepalive, login, logout, metrics, reaper, refresh, tls},
+};
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceFactory, ServiceRequest, ServiceResponse},
+    http::header,
+    middleware, web, App, HttpServer,
+};
+use std::{io, sync::Arc, time::Instant};
+
+// The relay's actix App, whatever TokenStore/Messenger it runs on: make_app() is the one place
+// routes, middleware and shared state (web::Data) come together, so that run_http_server() and
+// the tests (test::init_service(make_app(...)), or HttpServer::new() for end to end) serve the
+// exact same thing
+
+// Everything the handlers take as web::Data<T>; cloning it just clones the Arcs
+#[derive(Clone)]
+pub struct AppState {
+    pub config: web::Data<Config>,
+    pub token_store: web::Data<TTokenStore>,
+    pub messenger: web::Data<TMessenger>,
+    pub providers: web::Data<TOAuth2Providers>,
+    pub session_token_issuer: web::Data<SessionTokenIssuer>,
+    pub metrics: web::Data<RelayMetrics>,
+}
+
+impl AppState {
+    pub fn new(
+        config: &Config,
+        token_store: &TTokenStore,
+        messenger: &TMessenger,
+        providers: TOAuth2Providers,
+        session_token_issuer: SessionTokenIssuer,
+        metrics: RelayMetrics,
+    ) -> Self {
+        AppState {
+            config: web::Data::new(config.clone()),
+            token_store: web::Data::new(token_store.clone()), // cloning an Arc<T> just means incrementing the reference count
+            messenger: web::Data::new(messenger.clone()),
+            providers: web::Data::new(providers),
+            session_token_issuer: web::Data::new(session_token_issuer),
+            metrics: web::Data::new(metrics),
+        }
+    }
+}
+
+// Every route the relay serves, with its middleware:
+//  - responses carry session ids/tokens, so nothing gets cached unless the route says otherwise
+//    (i.e. jwks), see RFC 6749 5.1
+//  - "/login/" is "/login" (clients build these URLs by hand)
+//  - every request is counted and timed by route and status (see metrics.rs)
+pub fn make_app(
+    app_state: AppState,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    let request_metrics = app_state.metrics.clone();
+    App::new()
+        .app_data(app_state.config)
+        .app_data(app_state.token_store)
+        .app_data(app_state.messenger)
+        .app_data(app_state.providers)
+        .app_data(app_state.session_token_issuer)
+        .app_data(app_state.metrics)
+        .wrap_fn(move |request, service| {
+            let request_metrics = request_metrics.clone();
+            let method = request.method().to_string();
+            let started_at = Instant::now();
+            let response = service.call(request);
+            async move {
+                let response = response.await?;
+                let route = response
+                    .request()
+                    .match_pattern()
+                    .unwrap_or_else(|| "unmatched".to_string());
+                request_metrics.observe_http_request(
+                    &route,
+                    &method,
+                    response.status().as_u16(),
+                    started_at.elapsed(),
+                );
+                Ok(response)
+            }
+        })
+        .wrap(middleware::DefaultHeaders::new().add((header::CACHE_CONTROL, "no-store")))
+        .wrap(middleware::NormalizePath::trim())
+        .service(login::login)
+        .service(login::auth_code_callback)
+        .service(keepalive::keepalive)
+        .service(health::healthz)
+        .service(health::readyz)
+        .service(logout::logout)
+        .service(jwks::jwks)
+        .service(metrics::metrics)
+}
+
+// The relay itself: migrate, discover providers, start the background sweepers, then serve
+// make_app() until stopped
+// NOTE: Whatever stops it from starting (or serving) is returned rather than panicked on, so that
+// main() exits non-zero with the cause (and no backtrace)
+pub async fn run_http_server(
+    config: &Config,
+    token_store: &TTokenStore, // any TokenStore (see storage::open_token_store_from_config())
+    messenger: &TMessenger,
+    session_token_issuer: SessionTokenIssuer,
+) -> io::Result<()> {
+    // create/upgrade DB tables, and refuse to start if the DB is newer than us
+    let schema_version = token_store
+        .migrate()
+        .await
+        .map_err(|e| io::Error::other(format!("Storage: Failed to migrate: {:#}", e)))?;
+    println!("Storage: Schema version {}", schema_version);
+
+    // resolve every enabled provider's endpoints up front (OIDC discovery documents), and refuse
+    // to start if any cannot be
+    let providers: TOAuth2Providers = Arc::new(
+        OAuth2Providers::discover(&config.oauth2_providers)
+            .await
+            .map_err(|e| io::Error::other(format!("Providers: {:#}", e)))?,
+    );
+    println!(
+        "Providers: Enabled {:?} (default '{}')",
+        providers.names(),
+        providers.default_provider().name
+    );
+
+    // every post (including the reaper's) is counted, whatever the MQ is
+    let metrics = RelayMetrics::new(&token_store.db_type(), &messenger.mq_type())
+        .map_err(|e| io::Error::other(format!("Metrics: {:#}", e)))?;
+    let messenger: TMessenger = Arc::new(MeteredMessenger::new(messenger.clone(), metrics.clone()));
+
+    // refresh tokens ahead of their expiry, in the background, for as long as we're up
+    let _refresh_sweeper = refresh::spawn_refresh_sweeper(token_store.clone(), providers.clone());
+    // and get rid of the ones that expired and cannot be refreshed
+    let _session_reaper =
+        reaper::spawn_session_reaper(token_store.clone(), messenger.clone(), providers.clone());
+
+    println!(
+        "SessionToken: Signing session tokens with key id '{}'",
+        session_token_issuer.key_id()
+    );
+    let app_state = AppState::new(
+        config,
+        token_store,
+        &messenger,
+        providers,
+        session_token_issuer,
+        metrics,
+    );
+    // everything about where and how we listen comes from Config (see Config::check_conflicts())
+    let listen_addr = (config.bind_address, config.rest_port);
+    let mut http_server = HttpServer::new(move || make_app(app_state.clone()));
+    if let Some(http_workers) = config.possible_http_workers {
+        http_server = http_server.workers(http_workers);
+    }
+    let http_server = match &config.possible_tls {
+        Some(tls_config) => {
+            let tls_certificates = tls::TlsCertificates::load(tls_config)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e)))?;
+            let ssl_acceptor_builder = tls_certificates
+                .make_ssl_acceptor_builder()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", e)))?;
+            if *tls_config == TlsConfig::SelfSigned {
+                println!(
+                    "TLS: Serving a self-signed certificate for localhost, NOT for production"
+                );
+            }
+            // kill -HUP picks up a renewed certificate without a restart
+            #[cfg(unix)]
+            let _tls_reloader = tls::spawn_tls_reloader(tls_certificates);
+            http_server.bind_openssl(listen_addr, ssl_acceptor_builder)?
+        }
+        None => http_server.bind(listen_addr)?,
+    };
+    // plain HTTP only ever redirects to the HTTPS port above
+    if let Some(http_redirect_port) = config.possible_http_redirect_port {
+        let https_port_as_data = web::Data::new(config.rest_port);
+        let redirect_server = HttpServer::new(move || {
+            App::new()
+                .app_data(https_port_as_data.clone())
+                .default_service(web::to(tls::redirect_to_https))
+        })
+        .workers(1)
+        .bind((config.bind_address, http_redirect_port))?;
+        println!(
+            "HTTP server redirecting {:?} to https",
+            redirect_server.addrs()
+        );
+        actix_web::rt::spawn(redirect_server.run());
+    }
+    println!(
+        "HTTP server listening on {:?} ({})",
+        http_server.addrs(),
+        match config.possible_tls {
+            Some(_) => "https",
+            None => "http",
+        }
+    );
+
+    match http_server.run().await {
+        Ok(_) => {
+            println!("HTTP server stopped");
+            Ok(())
+        }
+        Err(e) => {
+            println!("Error running HTTP server: {:?}", e);
+            Err(e)
+        }
+    }
+}
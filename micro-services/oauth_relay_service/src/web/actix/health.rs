@@ -0,0 +1,89 @@
+use crate::{
+    data::{DependencyStatus, ReadinessResponse},
+    error::RelayError,
+    messenger::TMessenger,
+    providers::TOAuth2Providers,
+    storage::TTokenStore,
+    web::web_consts::READINESS_CHECK_TIMEOUT,
+};
+use actix_web::{web, HttpResponse};
+use anyhow::Result as AnyResult;
+use futures::{
+    future::{join_all, BoxFuture},
+    FutureExt,
+};
+use std::collections::BTreeMap;
+
+/// Liveness route - the process is up and serving, nothing else is checked (a DB outage should not
+/// get the relay restarted, that's what readyz() is for)
+/// HTTP verb: GET
+#[actix_web::get("/healthz")]
+pub async fn healthz() -> Result<HttpResponse, RelayError> {
+    Ok(HttpResponse::Ok().json(serde_json::json!({"status": "OK"})))
+}
+
+/// Readiness route - whether a login would go through right now: DB answers, MQ producer and
+/// consumer reach the broker, and every enabled provider's endpoints resolve
+/// 200 if all of them are OK, 503 otherwise, with the status of each dependency either way
+/// HTTP verb: GET
+#[actix_web::get("/readyz")]
+pub async fn readyz(
+    token_store: web::Data<TTokenStore>,
+    messenger: web::Data<TMessenger>,
+    providers: web::Data<TOAuth2Providers>,
+) -> Result<HttpResponse, RelayError> {
+    // (dependency, backend, check), all checked at the same time
+    let mut checks: Vec<(String, String, BoxFuture<AnyResult<()>>)> = vec![
+        ("db".to_string(), token_store.db_type(), token_store.ping()),
+        ("mq".to_string(), messenger.mq_type(), messenger.ping()),
+    ];
+    for provider in providers.iter() {
+        checks.push((
+            format!("provider:{}", provider.name),
+            provider.name.clone(),
+            provider.resolve_endpoints().boxed(),
+        ));
+    }
+    let dependencies: BTreeMap<String, DependencyStatus> = join_all(checks.into_iter().map(
+        |(dependency, backend, check)| async move {
+            let possible_error = match tokio::time::timeout(READINESS_CHECK_TIMEOUT, check).await {
+                Ok(Ok(_)) => None,
+                Ok(Err(e)) => Some(e.to_string()),
+                Err(_) => Some(format!("No answer within {:?}", READINESS_CHECK_TIMEOUT)),
+            };
+            if let Some(error) = &possible_error {
+                println!(
+                    "Readiness: '{}' ({}) is not ready: {}",
+                    dependency, backend, error
+                );
+            }
+            let dependency_status = DependencyStatus {
+                status: match possible_error {
+                    Some(_) => "ERROR".to_string(),
+                    None => "OK".to_string(),
+                },
+                backend,
+                possible_error,
+            };
+            (dependency, dependency_status)
+        },
+    ))
+    .await
+    .into_iter()
+    .collect();
+
+    let is_ready = dependencies
+        .values()
+        .all(|dependency_status| dependency_status.possible_error.is_none());
+    let readiness_response = ReadinessResponse {
+        status: match is_ready {
+            true => "OK".to_string(),
+            false => "ERROR".to_string(),
+        },
+        dependencies,
+    };
+    match is_ready {
+        true => Ok(HttpResponse::Ok().json(readiness_response)),
+        false => Ok(HttpResponse::ServiceUnavailable().json(readiness_response)),
+    }
+}
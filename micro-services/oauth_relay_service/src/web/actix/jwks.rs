@@ -0,0 +1,14 @@
+use crate::{error::RelayError, session_token::SessionTokenIssuer};
+use actix_web::{http::header, web, HttpResponse};
+
+/// JWKS route - public key(s) the session tokens (JWT) are signed with, so that the other services
+/// can verify callers offline (see session_token.rs)
+/// HTTP verb: GET
+#[actix_web::get("/.well-known/jwks.json")]
+pub async fn jwks(
+    session_token_issuer: web::Data<SessionTokenIssuer>,
+) -> Result<HttpResponse, RelayError> {
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CACHE_CONTROL, "public, max-age=300"))
+        .json(session_token_issuer.jwks()))
+}
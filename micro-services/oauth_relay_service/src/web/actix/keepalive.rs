@@ -1,140 +1,182 @@
 use crate::{
-    config::Config,
     data::{KeepaliveRequest, KeepaliveResponse, TokenData},
-    storage::{self, TDBConnectionLock},
-    web::web_consts::*,
+    error::RelayError,
+    providers::TOAuth2Providers,
+    session_token::SessionTokenIssuer,
+    storage::TTokenStore,
+    web::{
+        actix::{refresh, session_auth},
+        web_consts::*,
+    },
 };
 use actix_web::{web, HttpRequest, HttpResponse};
-use serde_json;
 use serde_urlencoded;
 use std::{
     collections::HashMap,
-    path::Path,
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 /// Keep-alive route - Check if the client is authenticated and handle keep-alive
 /// HTTP verb: GET
 /// params: last_session_id
+/// headers: Authorization: Bearer <session token from /login or the last /keepalive>
 #[actix_web::get("/keepalive")]
 pub async fn keepalive(
     client_http_request: HttpRequest,
-    db_connection: web::Data<TDBConnectionLock>,
-) -> HttpResponse {
-    let current_dir = std::env::current_dir().unwrap();
-    let env_file_path = current_dir.join("build/.env");
-    let config = Config::from_local_env_file();
+    token_store: web::Data<TTokenStore>,
+    providers: web::Data<TOAuth2Providers>,
+    session_token_issuer: web::Data<SessionTokenIssuer>,
+) -> Result<HttpResponse, RelayError> {
     let query_string = client_http_request.query_string();
     let query_params: HashMap<String, String> =
         serde_urlencoded::from_str(query_string).unwrap_or_else(|_| HashMap::new());
-    let keep_alive_request = KeepaliveRequest {
-        last_session_id: query_params
-            .get("last_session_id")
-            .expect("last_session_id not found")
-            .clone(),
+    let keep_alive_request = match query_params.get("last_session_id") {
+        Some(last_session_id) => KeepaliveRequest {
+            last_session_id: last_session_id.clone(),
+        },
+        None => {
+            return Err(RelayError::BadRequest(
+                "last_session_id not found".to_string(),
+            ))
+        }
     };
 
-    // grab some data we want to setup TokenData
-    let client_ip = client_http_request
-        .peer_addr()
-        .map(|addr| addr.ip())
-        .unwrap();
-    let client_port = client_http_request
-        .peer_addr()
-        .map(|addr| addr.port())
-        .unwrap();
+    // the caller has to prove it's their session before we refresh or sign anything for it
+    session_auth::authenticate_session(
+        &client_http_request,
+        session_token_issuer.as_ref(),
+        &keep_alive_request.last_session_id,
+    )?;
 
-    // first check if sessionID is valid (exists in DB)
-    let possible_session_data: Option<TokenData> = storage::get_token_by_session_id(
-        &config,
-        db_connection.as_ref(),
-        &Some(keep_alive_request.last_session_id),
-    )
-    .await;
-    match possible_session_data {
-        Some(token_data) => {
-            // if result row-set exists, count SHOULD be 1 (cannot have more than 1 UNIQUE key)
+    // then check if sessionID is valid (exists in DB)
+    let possible_session_data = token_store
+        .get_token_by_session_id(&Some(keep_alive_request.last_session_id.clone()))
+        .await;
+    let token_data: TokenData = match possible_session_data {
+        Ok(Some(token_data)) => token_data,
+        Ok(None) => {
+            // REJECT/DROP this undesired client ASAP...
+            println!(
+                "Keep-alive: Unknown session_id: {}",
+                keep_alive_request.last_session_id
+            );
+            return Err(RelayError::Unauthorized(format!(
+                "Unknown session_id '{}', please login",
+                keep_alive_request.last_session_id
+            )));
+        }
+        Err(e) => {
+            println!(
+                "Keep-alive: Failed to get session data for session_id: {} with error: {:?}",
+                keep_alive_request.last_session_id, e
+            );
+            return Err(RelayError::Unavailable(e));
+        }
+    };
+    // if result row-set exists, count SHOULD be 1 (cannot have more than 1 UNIQUE key)
+    println!(
+        "Keep-alive: Found session data for session_id: {:?}",
+        token_data.session_id()
+    );
+    // refresh (via refresh_token) if the token has expired, or is about to
+    let token_data = match refresh::needs_refresh(&token_data) {
+        false => {
+            println!(
+                "Keep-alive: Token is not expired for session_id: {:?}",
+                token_data.session_id()
+            );
+            token_data
+        }
+        true if token_data.possible_refresh_token.is_none() => {
+            println!(
+                "Keep-alive: Token is expiring and cannot be refreshed for session_id: {:?}",
+                token_data.session_id()
+            );
+            if token_data.is_expired() {
+                return Err(RelayError::Unauthorized(format!(
+                    "Session '{}' has expired, please login",
+                    keep_alive_request.last_session_id
+                )));
+            }
+            token_data
+        }
+        true => {
             println!(
-                "Keep-alive: Found session data for session_id: {:?}",
+                "Keep-alive: Token is expiring, refreshing for session_id: {:?}",
                 token_data.session_id()
             );
-            let update =
-                storage::upsert_token_data(&config, db_connection.as_ref(), &token_data).await;
-            match update {
-                Ok(_) => {
+            let http_client = reqwest::Client::new();
+            let provider = match providers.get(Some(token_data.provider.as_str())) {
+                Some(provider) => provider,
+                None => {
+                    // provider got disabled since, nobody left to refresh with
+                    return Err(RelayError::Unauthorized(format!(
+                        "Provider '{}' of session '{}' is no longer enabled, please login",
+                        token_data.provider, keep_alive_request.last_session_id
+                    )));
+                }
+            };
+            match refresh::refresh_token_data(
+                token_store.as_ref(),
+                &http_client,
+                provider,
+                &token_data,
+            )
+            .await
+            {
+                Ok(refreshed) => refreshed,
+                Err(e) => {
                     println!(
-                        "Keep-alive: Updated last_accessed for session_id: {:?}",
-                        token_data.session_id()
+                        "Keep-alive: Failed to refresh token for session_id: {:?} with error: {:?}",
+                        token_data.session_id(),
+                        e
                     );
-                    let next_update = SystemTime::now()
-                        .checked_add(TOKEN_REFRESH_INTERVAL)
-                        .unwrap()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap()
-                        .as_secs();
-                    match token_data.is_expired() {
-                        false => {
-                            println!(
-                                "Keep-alive: Token is not expired for session_id: {:?}",
-                                token_data.session_id()
-                            );
-                            let response_body = serde_json::to_string(&KeepaliveResponse {
-                                next_expected_time: next_update,
-                                ttl: TOKEN_REFRESH_INTERVAL.as_secs(),
-                                status: "OK".to_string(),
-                                message: "Keep-alive successful".to_string(),
-                            })
-                            .unwrap();
-                            return HttpResponse::Ok().body(response_body);
-                        }
-                        true => {
-                            println!(
-                                "Keep-alive: Token is expired for session_id: {:?}",
-                                token_data.session_id()
-                            );
-                            todo!("Handle expired token");
-                        }
+                    // still usable until it actually expires, the sweeper will retry
+                    if token_data.is_expired() {
+                        return Err(RelayError::Unauthorized(format!(
+                            "Session '{}' has expired and could not be refreshed, please login",
+                            keep_alive_request.last_session_id
+                        )));
                     }
-                }
-                Err(e) => {
-                    println!("Keep-alive: Failed to update last_accessed for session_id: {:?} with error: {:?}", token_data.session_id(), e);
+                    token_data
                 }
             }
         }
-        _ => {
-            // REJECT/DROP this undesired client ASAP...
-            panic!("Keep-alive: Could not get client IP/port");
-        }
+    };
+    if let Err(e) = token_store.upsert_token_data(&token_data).await {
+        println!(
+            "Keep-alive: Failed to update session_id: {:?} with error: {:?}",
+            token_data.session_id(),
+            e
+        );
+        return Err(RelayError::Unavailable(e));
     }
-    let ttl_in_seconds = Duration::from_secs(60);
-    let next_expected_time_in_epoch = SystemTime::now()
-        .checked_add(ttl_in_seconds)
+
+    // hand out a fresh session token, the one from login (or last keepalive) is about to expire
+    let session_token = match session_token_issuer.issue(&token_data) {
+        Ok(session_token) => session_token,
+        Err(e) => {
+            println!(
+                "Keep-alive: Failed to issue session token for session_id: {:?} with error: {:?}",
+                token_data.session_id(),
+                e
+            );
+            return Err(RelayError::Unavailable(e));
+        }
+    };
+
+    let next_update = SystemTime::now()
+        .checked_add(TOKEN_REFRESH_INTERVAL)
         .unwrap()
         .duration_since(UNIX_EPOCH)
         .unwrap()
         .as_secs();
-    let response_body = serde_json::to_string(&KeepaliveResponse {
-        next_expected_time: next_expected_time_in_epoch,
-        ttl: ttl_in_seconds.as_secs(),
+    // errors are the same KeepaliveResponse shape (see RelayError::error_response())
+    Ok(HttpResponse::Ok().json(KeepaliveResponse {
+        next_expected_time: next_update,
+        ttl: TOKEN_REFRESH_INTERVAL.as_secs(),
         status: "OK".to_string(),
         message: "Keep-alive successful".to_string(),
-    })
-    .unwrap();
-
-    HttpResponse::Ok().body(response_body)
-}
-
-#[actix_web::get("/test/keepalive")]
-async fn health_checker_handler() -> HttpResponse {
-    const MESSAGE1: &str = "OK";
-    let duration = std::time::Duration::new(30, 0);
-    let next_time = std::time::SystemTime::now()
-        .checked_add(duration)
-        .expect("Failed to calculate next time;")
-        .duration_since(std::time::UNIX_EPOCH)
-        .expect("Time went backwards");
-    let next_expected = format!("{}", next_time.as_secs());
-
-    HttpResponse::Ok()
-        .json(serde_json::json!({"status": "success", "ack": MESSAGE1, "next": next_expected}))
+        possible_session_token: Some(session_token),
+    }))
 }
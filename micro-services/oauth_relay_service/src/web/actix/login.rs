@@ -1,14 +1,18 @@
 use crate::{
-    config::{Config, HostType},
+    config::HostType,
     data::*,
-    messenger::{self, TMQConsumerLock, TMQProducerLock},
-    storage::{self, TDBConnectionLock},
+    error::RelayError,
+    messenger::TMessenger,
+    providers::{OAuth2Provider, TOAuth2Providers},
+    session_token::SessionTokenIssuer,
+    storage::TTokenStore,
     web::web_consts::*,
 };
 use actix_web::{web, HttpRequest, HttpResponse};
 use anyhow::Result as AnyResult;
 use base64::{engine::general_purpose, Engine};
 use rand::RngCore;
+use ring::digest;
 use serde_urlencoded;
 use std::{
     collections::HashMap,
@@ -17,70 +21,18 @@ use std::{
 };
 use tokio::time::sleep;
 
-// HTTP GET to request Google OAuth2 authorization code (AUTH_URL_GET) via OAuth2AuthCodeRequest
-// sample request:
-//   https://accounts.google.com/o/oauth2/v2/auth?response_type=code
-//      &client_id=my_client_id
-//      &redirect_uri=my_redirect_uri
-//      &scope=scope&state=my_state
-//      &access_type=my_access_type
-//      &include_granted_scopes=my_include_granted_scopes
-//      &prompt=select_account%20consent
-async fn request_auth_code_trigger_callback(
-    query_get_auth_request: web::Query<OAuth2AuthCodeRequest>,
-    http_client: &web::Data<reqwest::Client>,
-) -> Result<reqwest::Response, reqwest::Error> {
-    let response = http_client
-        .get(AUTHORIZATION_ENDPOINT_GET)
-        .query(&[
-            &format!("client_id={}", query_get_auth_request.client_id),
-            &format!("redirect_uri={}", query_get_auth_request.redirect_uri),
-            &format!("response_type={}", query_get_auth_request.response_type),
-            &format!("scope={}", query_get_auth_request.scope),
-            &format!(
-                "access_type={}",
-                match query_get_auth_request.possible_access_type.clone() {
-                    Some(access_type) => access_type,
-                    None => "online".to_string(),
-                }
-            ),
-            &format!(
-                "state={}",
-                match query_get_auth_request.possible_state.clone() {
-                    Some(state) => state,
-                    None => "".to_string(),
-                }
-            ),
-            &format!(
-                "include_granted_scopes={}",
-                query_get_auth_request
-                    .possible_include_granted_scopes
-                    .unwrap_or_default()
-            ),
-            &format!(
-                "prompt={}",
-                match query_get_auth_request.possible_prompt.clone() {
-                    Some(prompt) => prompt,
-                    None => "consent".to_string(),
-                }
-            ),
-        ])
-        .send()
-        .await;
-    // Should be almost immediate, this request is to trigger callback in which
-    // the user needs to consent/authorize, and once it's all good, we'll get a callback
-    response
-}
-
 // This callback is triggered based off of the service requesting AuthorizationEndpoint (AUTH_URL_GET)
 // sample CALLBACK response from request to AUTH_URL_GET
 //     https://hostname.mydomain.tld/auth_callback?error=access_denied
 //     https://hostname.mydomain.tld/auth_callback?code=4/P7q7W91a-oMsCeLvIaQm6bTrgtp7
 // NOTE: See OAuth2AuthCodeRequest.possible_state, in which we can pass a state token (see build_possible_state_for_callback())
-#[actix_web::get("/auth_callback")] // routing paths MUST match Config::google_redirect_uri! (actually it's GOOGLE_REDIRECT_URI in .env file)
+#[actix_web::get("/auth_callback")] // routing paths MUST match OAuth2ProviderConfig::redirect_uri! (actually it's <PROVIDER>_REDIRECT_URI in .env file)
 pub async fn auth_code_callback(
-    client_http_request: HttpRequest, // unfortunately, for callback from external, we only get this and body (most likely empty)
-) -> HttpResponse {
+    client_http_request: HttpRequest, // from external (Google), so the query string is all we get from the caller
+    token_store: web::Data<TTokenStore>, // the rest is our own (shared) app data
+    messenger: web::Data<TMessenger>,
+    providers: web::Data<TOAuth2Providers>,
+) -> Result<HttpResponse, RelayError> {
     let query_string = client_http_request.query_string();
     let query_params: HashMap<String, String> =
         serde_urlencoded::from_str(query_string).unwrap_or_else(|_| HashMap::new());
@@ -88,104 +40,140 @@ pub async fn auth_code_callback(
         possible_error: query_params.get("error").map(|s| s.to_string()),
         possible_code: query_params.get("code").map(|s| s.to_string()),
     };
-    let possible_state = decode_state_token(query_params.get("state").map(|s| s.to_string()));
 
     // we don't really need IP of Google Service, but for debugging/logging purposes, we'll keep it
     // NOTE: Don't log query_params, for it contains auth code...
-    let client_ip = client_http_request
-        .peer_addr()
-        .map(|addr| HostType::HostAsIP(addr.ip()))
-        .unwrap();
-    let client_port = client_http_request
-        .peer_addr()
-        .map(|addr| addr.port())
-        .unwrap();
+    let (client_ip, client_port) = get_peer_addr(&client_http_request)?;
     println!(
         "AuthCodeCallback: Client (Google Cloud) IP={} (port={})",
         client_ip, client_port
     );
 
-    match auth_code_response.possible_error {
-        Some(_) => {
-            // Error occurred, let's log it and return 500
-            println!(
-                "AuthCodeCallback: Error={}",
-                auth_code_response.possible_error.unwrap()
-            );
-            HttpResponse::InternalServerError().finish()
-        }
+    // CSRF: before anything else (errors included), the state has to be one we issued on /login,
+    // not used yet, and not older than TIMEOUT_FOR_AUTH_CODE_CALLBACK (see take_login_state())
+    let state = match decode_state_token(query_params.get("state").map(|s| s.to_string())) {
+        Some(state) => state,
         None => {
-            let current_dir = std::env::current_dir().unwrap();
-            let env_file_path = current_dir.join("build/.env");
-            let config = Config::from_local_env_file();
-            let db_connection = storage::open_db_connection_from_config(&config).await;
-            let (mq_producer, mq_consumer) =
-                messenger::open_mq_connections_from_config(&config).await;
-
+            println!("AuthCodeCallback: Missing or malformed state");
+            return Err(RelayError::BadRequest(
+                "Missing or malformed state, please login again".to_string(),
+            ));
+        }
+    };
+    let login_state = take_login_state(token_store.as_ref(), &state.state_token).await?;
+
+    match (
+        auth_code_response.possible_error,
+        auth_code_response.possible_code,
+    ) {
+        (Some(error), _) => {
+            // i.e. access_denied, player did not consent (or the provider would not let them)
+            println!("AuthCodeCallback: Error={}", error);
+            Err(RelayError::Unauthorized(format!(
+                "Authorization failed ({}), please login again",
+                error
+            )))
+        }
+        (None, None) => {
+            println!("AuthCodeCallback: Neither code nor error");
+            Err(RelayError::BadRequest(
+                "Missing code, please login again".to_string(),
+            ))
+        }
+        (None, Some(auth_code)) => {
             // First, if it is NOT an error, let's go ahead and request OAuth2Token from Google
-            let auth_code = auth_code_response.possible_code.unwrap(); // should panic if code is not present!
 
-            let state_token = match possible_state {
-                Some(s) => s.state_token,
-                None => make_state_token(), // we should panic, but if we've come this far, we should take it
+            // whoever the player picked on /login, as we recorded it (state.provider is only what
+            // the client, or whoever forged the state, says it is)
+            let provider = match providers.get(Some(login_state.provider.as_str())) {
+                Some(provider) => provider,
+                None => {
+                    println!(
+                        "AuthCodeCallback: Unknown provider: {}",
+                        login_state.provider
+                    );
+                    return Err(RelayError::BadRequest(format!(
+                        "Unknown provider '{}', please login again",
+                        login_state.provider
+                    )));
+                }
             };
+            let state_token = state.state_token;
 
-            // since ther are no way to record the auth_code as persisted data (because we don't have the DB connection)
-            // we will have to do as much work as possible on this callback-thread (which is not ideal)
+            // rather than persisting the auth_code and handing it off, we do as much work as possible
+            // on this callback-thread (which is not ideal)
             // 5. POST a request to TOKEN_URL_POST to get back access_token, refresh_token, expires_in, etc.
             let http_client = web::Data::new(reqwest::Client::new());
             let token_request = OAuth2TokenRequest {
                 state_token: state_token.clone(),
-                client_id: config.google_client_id.clone(),
-                client_secret: config.google_client_secret.clone(),
+                client_id: provider.client_id.clone(),
+                client_secret: provider.client_secret.clone(),
                 code: auth_code,
-                redirect_uri: config.google_redirect_uri.clone(),
+                redirect_uri: provider.redirect_uri.clone(),
                 grant_type: "authorization_code".to_string(),
+                // PKCE: the code_verifier login() kept next to the state token, without it the
+                // auth code is worthless, so whoever intercepted it gets nothing either
+                possible_code_verifier: Some(login_state.code_verifier),
             };
             let query_post_token_request: web::Query<OAuth2TokenRequest> =
                 web::Query(token_request);
-            let possible_token_response =
-                request_access_token(&query_post_token_request, &http_client).await;
+            let possible_token_response = request_access_token(
+                &query_post_token_request,
+                &http_client,
+                provider.endpoints.token_endpoint.as_str(),
+            )
+            .await;
 
             match possible_token_response {
                 Ok(oauth_token_response) => {
                     //let token_data: TokenData = serde_json::from_str( std::str::from_utf8(token_response_json_body.body().into().as_bytes) .unwrap(),) .unwrap();
                     // 6. GET user's email address from Google
-                    let user_info_result =
-                        request_userinfo(&http_client, oauth_token_response.clone()).await;
-                    match user_info_result {
-                        Ok(user_info_response) => {
+                    let email_result =
+                        request_userinfo(&http_client, provider, &oauth_token_response).await;
+                    match email_result {
+                        Ok(email) => {
+                            // Google OAuth2 token expires_in is in seconds
                             let next_expected_time = SystemTime::now()
-                                .checked_add(Duration::from_secs(
-                                    oauth_token_response.expires_in.clone() as u64,
-                                )) // Google OAuth2 token expires_in is in seconds
-                                .unwrap();
+                                + Duration::from_secs(oauth_token_response.expires_in.max(0) as u64);
                             // Now that we've got the user's email address, we can now build TokenData!
-                            let token_data = TokenData::new(
+                            let mut token_data = TokenData::new(
                                 SessionIDType::make_hash(state_token.clone().as_str()),
                                 state_token.clone(),
                                 client_ip,
                                 client_port,
-                                Some(user_info_response.email),
+                                Some(email),
                                 oauth_token_response.access_token,
                                 oauth_token_response.possible_refresh_token,
                                 oauth_token_response.expires_in,
                                 next_expected_time,
                             );
-
-                            // save/persist it
-                            storage::upsert_token_data(&config, &db_connection, &token_data);
-
-                            // 7. Signal/notify/message/publish that we have a new session_id (new login) for any services who cares for that event...
-                            messenger::post_new_login(&config, &mq_producer, &token_data);
-
-                            // the end...
-                            HttpResponse::Ok().finish()
+                            token_data.provider = provider.name.clone();
+
+                            match store_and_post_new_login(
+                                token_store.as_ref(),
+                                messenger.as_ref(),
+                                token_data,
+                            )
+                            .await
+                            {
+                                // the end...
+                                Ok(_) => Ok(HttpResponse::Ok().finish()),
+                                Err(e) => {
+                                    println!("AuthCodeCallback: {:?}", e);
+                                    Err(RelayError::Unavailable(e))
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            println!("AuthCodeCallback: {:?}", e);
+                            Err(RelayError::BadGateway(e))
                         }
-                        Err(_) => HttpResponse::InternalServerError().finish(),
                     }
                 }
-                Err(e) => HttpResponse::InternalServerError().finish(),
+                Err(e) => {
+                    println!("AuthCodeCallback: {:?}", e);
+                    Err(RelayError::BadGateway(e))
+                }
             }
 
             //HttpResponse::Ok().finish()
@@ -193,6 +181,67 @@ pub async fn auth_code_callback(
     }
 }
 
+// The LoginState /login issued for state_token, as long as it has not been used yet (i.e. replayed
+// callback) and is not older than TIMEOUT_FOR_AUTH_CODE_CALLBACK (it is marked used either way);
+// Err() is what auth_code_callback() should reject the callback with
+async fn take_login_state(
+    token_store: &TTokenStore,
+    state_token: &str,
+) -> Result<LoginState, RelayError> {
+    let rejection = match token_store.take_login_state(state_token).await {
+        Ok(Some(login_state)) if login_state.possible_used_at.is_some() => {
+            "State has already been used"
+        }
+        Ok(Some(login_state))
+            if login_state.created_at + TIMEOUT_FOR_AUTH_CODE_CALLBACK < SystemTime::now() =>
+        {
+            "State has expired"
+        }
+        Ok(Some(login_state)) => return Ok(login_state),
+        Ok(None) => "Unknown state",
+        Err(e) => {
+            println!("AuthCodeCallback: Failed to take login state: {:?}", e);
+            return Err(RelayError::Unavailable(e));
+        }
+    };
+    println!("AuthCodeCallback: Rejected: {}", rejection);
+    Err(RelayError::BadRequest(format!(
+        "{}, please login again",
+        rejection
+    )))
+}
+
+// IP and port of whoever is calling; only ever missing if actix is not listening on TCP (i.e. unit
+// tests without peer_addr), which is the caller's problem rather than a reason to panic
+fn get_peer_addr(client_http_request: &HttpRequest) -> Result<(HostType, u16), RelayError> {
+    match client_http_request.peer_addr() {
+        Some(addr) => Ok((HostType::HostAsIP(addr.ip()), addr.port())),
+        None => Err(RelayError::BadRequest(
+            "Cannot tell client address".to_string(),
+        )),
+    }
+}
+
+// Last steps of auth_code_callback(), once we've got the tokens and email from Google:
+//  - save/persist it (session_id is now the DB one, rather than the hash of state_token)
+//  - 7. Signal/notify/message/publish that we have a new session_id (new login) for any services
+//    who cares for that event (i.e. /login waiting for the consent)
+// Returns the session_id
+pub(crate) async fn store_and_post_new_login(
+    token_store: &TTokenStore,
+    messenger: &TMessenger,
+    mut token_data: TokenData,
+) -> AnyResult<u64> {
+    let session_id = token_store.upsert_token_data(&token_data).await?;
+    println!(
+        "AuthCodeCallback: Stored token for session_id: {}",
+        session_id
+    );
+    token_data.set_session_id(SessionIDType::ID(session_id));
+    messenger.post_new_login(&token_data).await?;
+    Ok(session_id)
+}
+
 // HTTP POST to request Google OAuth2 token (TOKEN_URL_POST) via OAuth2TokenRequest
 // this method is called from auth_code_callback() to get the token from Google
 // Sample request:
@@ -205,10 +254,12 @@ pub async fn auth_code_callback(
 //          "client_secret": "client_secret_from_google_api_console",
 //          "code": "auth_code_obtained_from_auth_callback",
 //          "redirect_uri": "http://localhost:8080/auth_callback",
-//          "grant_type": "authorization_code"
+//          "grant_type": "authorization_code",
+//          "code_verifier": "code_verifier_whose_code_challenge_was_on_the_auth_url"
 //      }
 // Note that the request is blocked until we get a response from Google OAuth2 (unlike
 // auth_code_callback() which is a callback from Google OAuth2)
+// NOTE: GitHub answers in x-www-form-urlencoded unless asked for JSON, hence the Accept header
 // Sample response:
 //  {
 //      "access_token" : "ya29.a0AXooCgs2qcHYleg5gD_Qrm...BFQ3NfyrB61-171",
@@ -220,8 +271,9 @@ pub async fn auth_code_callback(
 async fn request_access_token(
     query_post_token_request: &web::Query<OAuth2TokenRequest>,
     http_client: &web::Data<reqwest::Client>,
+    token_endpoint: &str,
 ) -> AnyResult<OAuth2TokenResponse> {
-    let params = [
+    let mut params = vec![
         ("code", query_post_token_request.code.clone()),
         ("client_id", query_post_token_request.client_id.clone()),
         (
@@ -234,76 +286,57 @@ async fn request_access_token(
         ),
         ("grant_type", query_post_token_request.grant_type.clone()),
     ];
+    if let Some(code_verifier) = &query_post_token_request.possible_code_verifier {
+        params.push(("code_verifier", code_verifier.clone()));
+    }
 
     let response = http_client
-        .post(TOKEN_ENDPOINT_POST)
+        .post(token_endpoint)
+        .header("Accept", "application/json")
         .form(&params)
         .send()
         .await;
     match response {
-        Ok(resp) => {
+        Ok(resp) if resp.status().is_success() => {
             // deserialize the response from Google OAuth2
-            let oauth2_token_response: OAuth2TokenResponse = resp.json().await.unwrap();
+            let oauth2_token_response: OAuth2TokenResponse = resp.json().await?;
             Ok(oauth2_token_response)
         }
+        Ok(resp) => anyhow::bail!(
+            "OAuth2 provider refused to hand out token: {}",
+            resp.status()
+        ),
         Err(_) => anyhow::bail!("Failed to get token from Google OAuth2"),
     }
 }
 
 // this method is indirectly called from auth_code_callback() (chained call when qurest_and_store_google_oauth2 succeeds)
-// get the user's email address from Google
+// get the user's email address from the provider (make sure Google API was setup with email priv enabled)
+// $curl -X GET "https://openidconnect.googleapis.com/v1/userinfo" -H"Authorization: Bearer accessTokenHere"
+// (see OAuth2Provider::request_email() for GitHub and the rest)
 async fn request_userinfo(
     http_client: &web::Data<reqwest::Client>,
-    token_response: OAuth2TokenResponse,
-) -> AnyResult<OAuth2UserInfoResponse> {
-    // once we got OK/200 from Google OAuth2, get e-mail address (make sure Google API was setup with email priv enabled)
-    // $curl -X GET "https://www.googleapis.com/oauth2/v1/userinfo?alt=json" -H"Authorization: Bearer accessTokenHere"
-    // JavaScript example:
-    //    var xhr = new XMLHttpRequest();
-    //    xhr.open('POST', 'https://vision.googleapis.com/v1/images:annotate');
-    //    xhr.setRequestHeader('Authorization', 'Bearer ' + token);
-    //    xhr.setRequestHeader('Content-Type', 'application/json; charset=utf-8');
-    //    xhr.setRequestHeader('x-content-type-options', 'nosniff'); // Add x-content-type-options header
-    //    xhr.onload = function () {...}
-    let req = OAuth2UerInfoRequest {
-        access_token: token_response.access_token.clone(),
-        // i.e. "Autorization": "Bearer <access_token_here>"
-        header_authorization: format!(
-            "{} {}", // note that because this is header, we don't need to use '%20' for space
-            token_response.token_type.clone(),
-            token_response.access_token.clone()
-        ),
-    };
-    let user_info_http_response = http_client
-        .get(USERINFO_ENDPOINT_GET)
-        .header("Authorization", req.header_authorization)
-        .send()
+    provider: &OAuth2Provider,
+    token_response: &OAuth2TokenResponse,
+) -> AnyResult<String> {
+    provider
+        .request_email(http_client.as_ref(), token_response)
         .await
-        .unwrap();
-
-    // wrap LoginResponse in a Result (Body)
-    //let possible_user_info_response = httpresponse_body_to_json::<OAuth2UserInfoResponse>(user_info_response).await;
-    let possible_user_info_response = user_info_http_response
-        .json::<OAuth2UserInfoResponse>()
-        .await;
-    match possible_user_info_response {
-        Ok(user_info) => Ok(user_info),
-        Err(_) => anyhow::bail!("Failed to get user info from Google OAuth2"),
-    }
 }
 
-/// Login route - either authenticate or re-authenticate the client against Google OAuth2
+/// Login route - either authenticate or re-authenticate the client against Google OAuth2 (or any
+/// other enabled OAuth2Provider)
 /// HTTP verb: GET
-/// params: last_session_id (optional)
+/// params: last_session_id (optional), provider (optional, i.e. "github"; default provider if not set)
 /// see: https://developers.google.com/static/identity/protocols/oauth2/images/flows/authorization-code.png
 #[actix_web::get("/login")]
 pub async fn login(
     client_http_request: HttpRequest,
-    db_connection: web::Data<TDBConnectionLock>,
-    mq_producer: web::Data<TMQProducerLock>,
-    mq_consumer: web::Data<TMQConsumerLock>,
-    config: web::Data<Config>,
-) -> HttpResponse {
+    token_store: web::Data<TTokenStore>,
+    messenger: web::Data<TMessenger>,
+    providers: web::Data<TOAuth2Providers>,
+    session_token_issuer: web::Data<SessionTokenIssuer>,
+) -> Result<HttpResponse, RelayError> {
     //let db_connection = storage::open_db_connection_from_config(config.clone()).await;
     //let mq_connection = messenger::open_mq_connection_from_config(config.clone()).await;
     let query_string = client_http_request.query_string();
@@ -325,17 +358,22 @@ pub async fn login(
         ),
     };
 
-    // grab some data we want to setup TokenData (we SHOULD panic if we cannot get client IP/port)
-    let client_ip = client_http_request
-        .peer_addr()
-        .map(|addr| HostType::HostAsIP(addr.ip()))
-        .unwrap();
-    let client_port = client_http_request
-        .peer_addr()
-        .map(|addr| addr.port())
-        .unwrap();
+    // grab some data we want to setup TokenData
+    let (client_ip, client_port) = get_peer_addr(&client_http_request)?;
     println!("Login: Client IP={} (port={})", client_ip, client_port);
 
+    let possible_provider_name = query_params.get("provider").map(|s| s.as_str());
+    let provider = match providers.get(possible_provider_name) {
+        Some(provider) => provider,
+        None => {
+            return Err(RelayError::BadRequest(format!(
+                "Unknown provider '{}', expected one of: {}",
+                possible_provider_name.unwrap_or_default(),
+                providers.names().join(", ")
+            )))
+        }
+    };
+
     let state = OAuth2AuthCodeRequestState {
         login_client_ip: client_ip,
         login_client_port: client_port,
@@ -346,82 +384,180 @@ pub async fn login(
             None => make_state_token(),
         },
 
-        db_type: storage::get_db_type(config.as_ref()),
-        possible_db_address: storage::get_db_address(config.as_ref()),
-        possible_db_port: storage::get_db_port(config.as_ref()),
-        possible_db_path: storage::get_db_path(config.as_ref()),
+        db_type: token_store.db_type(),
+        possible_db_address: token_store.db_address(),
+        possible_db_port: token_store.db_port(),
+        possible_db_path: token_store.db_path(),
 
-        mq_type: messenger::get_mq_type(config.as_ref()),
-        possible_mq_address: messenger::get_mq_address(config.as_ref()),
-        possible_mq_port: messenger::get_mq_port(config.as_ref()),
-    };
+        mq_type: messenger.mq_type(),
+        possible_mq_address: messenger.mq_address(),
+        possible_mq_port: messenger.mq_port(),
 
-    // reqwest Google to give us an AuthCode
-    let auth_request = OAuth2AuthCodeRequest {
-        client_id: config.google_client_id.clone(),
-        redirect_uri: config.google_redirect_uri.clone(),
-        response_type: "code".to_string(), // "code" for Authorization Code flow, valid: "code", "token", "id_token", "code token", "code id_token", "token id_token", "code token id_token"
-        scope: "email%20profile%20https://www.googleapis.com/auth/drive".to_string(),
-        possible_access_type: Some("offline".to_string()),
-        possible_state: encode_state_token(Some(state.clone())),
-        possible_include_granted_scopes: Some(true),
-        possible_prompt: Some("consent%20select_account".to_string()), // space delimited
-    };
-    let auth_request_as_json = serde_json::to_string(&auth_request).unwrap();
-    let http_client = web::Data::new(reqwest::Client::new());
-    let query_get_auth_request = web::Query::from_query(auth_request_as_json.as_str()).unwrap();
-    let schedule_auth = request_auth_code_trigger_callback(query_get_auth_request, &http_client);
-
-    // wait for player/client to consent
-    //loop{}
-
-    let login_response = LoginResponse {
-        possible_login_error: Some("Unexpected (unhandled) Error".to_string()),
-        possible_session_id: None,
-        possible_state_token: None,
+        provider: provider.name.clone(),
     };
+
+    // New login (client has no state token yet): hand the auth URL back right away so that the
+    // client can open it for the player to consent, and poll us again with the state token
+    if !query_params.contains_key("last_state_token") {
+        // PKCE: only the code_challenge goes out on the auth URL, the code_verifier stays with us
+        // (next to the state token) until auth_code_callback() trades the auth code for tokens
+        // (it's also what makes the callback accept this state, see take_login_state())
+        let code_verifier = make_code_verifier();
+        if let Err(e) = token_store
+            .insert_login_state(&LoginState::new(
+                &state.state_token,
+                &code_verifier,
+                &provider.name,
+            ))
+            .await
+        {
+            println!("Login: Failed to store login state: {:?}", e);
+            return Err(RelayError::Unavailable(e));
+        }
+        // reqwest Google to give us an AuthCode
+        let auth_request = provider.make_auth_code_request(
+            encode_state_token(Some(state.clone())),
+            Some(make_code_challenge(&code_verifier)),
+        );
+        let auth_url = make_auth_url(
+            provider.endpoints.authorization_endpoint.as_str(),
+            &auth_request,
+        )
+        .map_err(RelayError::BadGateway)?;
+        return Ok(HttpResponse::Ok().json(LoginResponse {
+            possible_login_error: None,
+            possible_session_id: None,
+            possible_state_token: Some(state.state_token.clone()),
+            possible_auth_url: Some(auth_url),
+            possible_session_token: None,
+        }));
+    }
+
+    // only poll for state tokens /login handed out (and not purged yet), otherwise any string
+    // would hold the request open for TIMEOUT_FOR_AUTH_CODE_CALLBACK
+    match token_store.has_login_state(&state.state_token).await {
+        Ok(true) => (),
+        Ok(false) => {
+            println!("Login: Rejected: Unknown state");
+            return Err(RelayError::BadRequest("Unknown state".to_string()));
+        }
+        Err(e) => {
+            println!("Login: Failed to look up login state: {:?}", e);
+            return Err(RelayError::Unavailable(e));
+        }
+    }
+
+    // wait for player/client to consent (on their browser, via the auth URL handed back above)
+
     // Block and wait for the signal that I've got a session_id...
-    let mut possible_token_response: Option<TokenData> = None;
     let start_time = SystemTime::now();
     let timeout = TIMEOUT_FOR_AUTH_CODE_CALLBACK;
-    loop {
+    let possible_token_response: Option<TokenData> = loop {
         // check if we have a session_id from messenger
-        possible_token_response = messenger::get_token(
-            &config,
-            &mq_producer,
-            &state.state_token.as_str(),
-            |lhs_state_token: &str, token_data: TokenData| {
-                lhs_state_token == token_data.state_token.as_str()
-            },
-        );
+        let possible_token_data = match messenger.get_token(state.state_token.as_str()).await {
+            Ok(possible_token_data) => possible_token_data,
+            Err(e) => {
+                // no point holding the request against a broker that errors, the client polls
+                // again with the same state token anyway
+                println!("Login: Failed to get token from messenger: {:?}", e);
+                return Err(RelayError::Unavailable(e));
+            }
+        };
 
-        // yield
-        sleep(Duration::from_secs(1));
-        if start_time.elapsed().unwrap() > timeout {
-            break;
+        if possible_token_data.is_some() || start_time.elapsed().unwrap_or_default() > timeout {
+            break possible_token_data;
         }
-    }
+        // yield
+        sleep(Duration::from_secs(1)).await;
+    };
 
     // Now, query for user's email client_address
     match possible_token_response {
         Some(resp) => {
+            // sign the session token the client presents to the other services
+            let session_token = match session_token_issuer.issue(&resp) {
+                Ok(session_token) => session_token,
+                Err(e) => {
+                    println!("Login: Failed to issue session token: {:?}", e);
+                    return Err(RelayError::Unavailable(e));
+                }
+            };
             // deserialize the response from Google OAuth2
             // wrap LoginResponse in a Result (Body)
-            let response_body = serde_json::to_string(&LoginResponse {
+            Ok(HttpResponse::Ok().json(LoginResponse {
                 possible_login_error: None,
                 possible_session_id: resp.session_id(),
                 possible_state_token: Some(resp.state_token),
-            })
-            .unwrap();
-            HttpResponse::Ok().body(response_body)
+                possible_auth_url: None,
+                possible_session_token: Some(session_token),
+            }))
         }
-        None => HttpResponse::InternalServerError().finish(),
-    };
-
-    // TODO: signal (via kafka) of new session_id (new login) for any services who cares for that event...
+        None => {
+            // player has not consented (yet), client can retry with the same state token
+            // NOTE: not a RelayError, it's the LoginResponse (with state token) the client polls with
+            Ok(HttpResponse::RequestTimeout().json(LoginResponse {
+                possible_login_error: Some("Timed out waiting for consent".to_string()),
+                possible_session_id: None,
+                possible_state_token: Some(state.state_token.clone()),
+                possible_auth_url: None,
+                possible_session_token: None,
+            }))
+        }
+    }
+}
 
-    let response_body = serde_json::to_string(&login_response).unwrap();
-    HttpResponse::Ok().body(response_body)
+// Request for the provider's authorization code (AuthorizationEndpoint via OAuth2AuthCodeRequest),
+// as a URL for the player's browser: the consent page has to be rendered there, not fetched by us
+// sample URL (Google):
+//   https://accounts.google.com/o/oauth2/v2/auth?response_type=code
+//      &client_id=my_client_id
+//      &redirect_uri=my_redirect_uri
+//      &scope=scope&state=my_state
+//      &access_type=my_access_type
+//      &include_granted_scopes=my_include_granted_scopes
+//      &prompt=select_account%20consent
+// NOTE: The Google specific ones (access_type, include_granted_scopes, prompt) are left out when
+// not set, other providers would rather not see them
+fn make_auth_url(
+    authorization_endpoint: &str,
+    auth_request: &OAuth2AuthCodeRequest,
+) -> AnyResult<String> {
+    // scope and prompt may be pre-encoded ("%20") for the GET request, url::Url wants them raw
+    let mut params = vec![
+        ("client_id", auth_request.client_id.clone()),
+        ("redirect_uri", auth_request.redirect_uri.clone()),
+        ("response_type", auth_request.response_type.clone()),
+        ("scope", auth_request.scope.replace("%20", " ")),
+        (
+            "state",
+            auth_request.possible_state.clone().unwrap_or_default(),
+        ),
+    ];
+    if let Some(access_type) = &auth_request.possible_access_type {
+        params.push(("access_type", access_type.clone()));
+    }
+    if let Some(include_granted_scopes) = auth_request.possible_include_granted_scopes {
+        params.push(("include_granted_scopes", include_granted_scopes.to_string()));
+    }
+    if let Some(prompt) = &auth_request.possible_prompt {
+        params.push(("prompt", prompt.replace("%20", " ")));
+    }
+    if let (Some(code_challenge), Some(code_challenge_method)) = (
+        &auth_request.possible_code_challenge,
+        &auth_request.possible_code_challenge_method,
+    ) {
+        params.push(("code_challenge", code_challenge.clone()));
+        params.push(("code_challenge_method", code_challenge_method.clone()));
+    }
+    match reqwest::Url::parse_with_params(authorization_endpoint, &params) {
+        Ok(auth_url) => Ok(auth_url.to_string()),
+        // endpoints are checked at startup (see OAuth2Providers::discover()), should never fail
+        Err(e) => anyhow::bail!(
+            "Bad authorization endpoint '{}': {}",
+            authorization_endpoint,
+            e
+        ),
+    }
 }
 
 // Create an unique SessionToken (aka state) as mentioned in 'https://developers.google.com/identity/openid-connect/openid-connect'
@@ -446,3 +582,34 @@ fn make_state_token() -> String {
     println!("Random base64-encoded string: {}", sanitized_encoded);
     sanitized_encoded
 }
+
+// PKCE (RFC 7636) code_verifier: 32 random bytes as base64url, which makes it 43 characters (the
+// least the RFC allows) of unreserved characters only, so no sanitizing as in make_state_token()
+fn make_code_verifier() -> String {
+    let mut random_bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut random_bytes);
+    general_purpose::URL_SAFE_NO_PAD.encode(random_bytes)
+}
+
+// S256 code_challenge: BASE64URL(SHA256(ASCII(code_verifier)))
+pub(crate) fn make_code_challenge(code_verifier: &str) -> String {
+    general_purpose::URL_SAFE_NO_PAD
+        .encode(digest::digest(&digest::SHA256, code_verifier.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_challenge_is_s256_of_code_verifier() {
+        // RFC 7636, Appendix B
+        assert_eq!(
+            make_code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+        let code_verifier = make_code_verifier();
+        assert_eq!(code_verifier.len(), 43);
+        assert_ne!(code_verifier, make_code_verifier());
+    }
+}
@@ -0,0 +1,99 @@
+use crate::{
+    data::{KeepaliveRequest, KeepaliveResponse, SessionEventType},
+    error::RelayError,
+    messenger::TMessenger,
+    providers::TOAuth2Providers,
+    session_token::SessionTokenIssuer,
+    storage::TTokenStore,
+    web::actix::{revoke, session_auth},
+};
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde_urlencoded;
+use std::collections::HashMap;
+
+/// Logout route - revoke the token at Google, and end the session (see revoke::end_session())
+/// HTTP verb: POST
+/// params: last_session_id
+/// headers: Authorization: Bearer <session token from /login or the last /keepalive>
+/// Response is the same shape as /keepalive (KeepaliveResponse), with ttl of 0 since there is
+/// nothing to keep alive anymore
+#[actix_web::post("/logout")]
+pub async fn logout(
+    client_http_request: HttpRequest,
+    token_store: web::Data<TTokenStore>,
+    messenger: web::Data<TMessenger>,
+    providers: web::Data<TOAuth2Providers>,
+    session_token_issuer: web::Data<SessionTokenIssuer>,
+) -> Result<HttpResponse, RelayError> {
+    let query_string = client_http_request.query_string();
+    let query_params: HashMap<String, String> =
+        serde_urlencoded::from_str(query_string).unwrap_or_else(|_| HashMap::new());
+    let logout_request = match query_params.get("last_session_id") {
+        Some(last_session_id) => KeepaliveRequest {
+            last_session_id: last_session_id.clone(),
+        },
+        None => {
+            return Err(RelayError::BadRequest(
+                "last_session_id not found".to_string(),
+            ))
+        }
+    };
+
+    // only the owner gets to end the session (see session_auth::authenticate_session())
+    session_auth::authenticate_session(
+        &client_http_request,
+        session_token_issuer.as_ref(),
+        &logout_request.last_session_id,
+    )?;
+
+    let token_data = match token_store
+        .get_token_by_session_id(&Some(logout_request.last_session_id.clone()))
+        .await
+    {
+        Ok(Some(token_data)) => token_data,
+        Ok(None) => {
+            // already logged out (or reaped), nothing to do
+            println!(
+                "Logout: Unknown session_id: {}",
+                logout_request.last_session_id
+            );
+            return Err(RelayError::NotFound(format!(
+                "Unknown session_id '{}'",
+                logout_request.last_session_id
+            )));
+        }
+        Err(e) => {
+            println!(
+                "Logout: Failed to get session data for session_id: {} with error: {:?}",
+                logout_request.last_session_id, e
+            );
+            return Err(RelayError::Unavailable(e));
+        }
+    };
+
+    let http_client = reqwest::Client::new();
+    if let Err(e) = revoke::end_session(
+        token_store.as_ref(),
+        messenger.as_ref(),
+        &http_client,
+        providers.as_ref(),
+        &token_data,
+        SessionEventType::Logout,
+    )
+    .await
+    {
+        println!(
+            "Logout: Failed to end session_id: {} with error: {:?}",
+            logout_request.last_session_id, e
+        );
+        return Err(RelayError::Unavailable(e));
+    }
+
+    Ok(HttpResponse::Ok().json(KeepaliveResponse {
+        next_expected_time: 0,
+        ttl: 0,
+        status: "OK".to_string(),
+        message: "Logged out".to_string(),
+        possible_session_token: None,
+    }))
+}
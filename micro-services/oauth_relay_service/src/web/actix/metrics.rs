@@ -0,0 +1,11 @@
+use crate::{error::RelayError, metrics::RelayMetrics};
+use actix_web::{http::header, web, HttpResponse};
+
+/// Metrics route - Prometheus scrape endpoint (see metrics.rs for what is measured)
+/// HTTP verb: GET
+#[actix_web::get("/metrics")]
+pub async fn metrics(metrics: web::Data<RelayMetrics>) -> Result<HttpResponse, RelayError> {
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, prometheus::TEXT_FORMAT))
+        .body(metrics.render()?))
+}
@@ -0,0 +1,233 @@
+use crate::{
+    data::SessionEventType,
+    messenger::TMessenger,
+    providers::TOAuth2Providers,
+    storage::TTokenStore,
+    web::{actix::revoke, web_consts::*},
+};
+use anyhow::Result as AnyResult;
+use std::time::SystemTime;
+use tokio::time::sleep;
+
+// Sessions with refresh_token are kept alive by the refresh sweeper (see refresh.rs), but the ones
+// without it are dead once expired (keepalive() rejects them), yet they'd stay in the DB forever.
+// So are the ones whose refresh keeps failing (i.e. revoked refresh_token), which the sweeper would
+// otherwise retry on every pass: once expired for longer than SESSION_REFRESH_GRACE, they're dead too.
+// The reaper periodically ends those (see revoke::end_session()), which publishes "session_expired"
+// so that the game service can drop whatever it holds for that session_id
+// It also purges the login states (see LoginState) older than LOGIN_STATE_RETENTION, which would
+// otherwise pile up for every login that never made it to the callback
+
+// One pass of the reaper; Returns number of sessions reaped
+pub(crate) async fn reap_expired_sessions(
+    token_store: &TTokenStore,
+    messenger: &TMessenger,
+    http_client: &reqwest::Client,
+    providers: &TOAuth2Providers,
+) -> AnyResult<usize> {
+    let now = SystemTime::now();
+    let expired_tokens = token_store.get_tokens_expiring_before(now).await?;
+    let mut sessions_reaped = 0;
+    for token_data in expired_tokens.iter().filter(|token_data| {
+        token_data.possible_refresh_token.is_none()
+            || token_data.expiry_time() < now - SESSION_REFRESH_GRACE
+    }) {
+        match revoke::end_session(
+            token_store,
+            messenger,
+            http_client,
+            providers,
+            token_data,
+            SessionEventType::SessionExpired,
+        )
+        .await
+        {
+            Ok(_) => sessions_reaped += 1,
+            Err(e) => println!(
+                "Reaper: Failed to reap session_id: {:?} with error: {:?}",
+                token_data.session_id(),
+                e
+            ),
+        }
+    }
+    Ok(sessions_reaped)
+}
+
+// Returns number of login states purged
+pub(crate) async fn purge_login_states(token_store: &TTokenStore) -> AnyResult<u64> {
+    token_store
+        .delete_login_states_created_before(SystemTime::now() - LOGIN_STATE_RETENTION)
+        .await
+}
+
+// Background reaper, runs every SESSION_REAP_INTERVAL for as long as the relay is up
+pub fn spawn_session_reaper(
+    token_store: TTokenStore,
+    messenger: TMessenger,
+    providers: TOAuth2Providers,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let http_client = reqwest::Client::new();
+        loop {
+            sleep(SESSION_REAP_INTERVAL).await;
+            match reap_expired_sessions(&token_store, &messenger, &http_client, &providers).await {
+                Ok(0) => {}
+                Ok(sessions_reaped) => {
+                    println!("Reaper: Reaped {} expired session(s)", sessions_reaped)
+                }
+                Err(e) => println!("Reaper: Reap failed with error: {:?}", e),
+            }
+            match purge_login_states(&token_store).await {
+                Ok(0) => {}
+                Ok(login_states_purged) => {
+                    println!("Reaper: Purged {} login state(s)", login_states_purged)
+                }
+                Err(e) => println!("Reaper: Purge failed with error: {:?}", e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        data::{LoginState, TokenData},
+        messenger::memory::MemoryMessenger,
+        providers::tests::{make_providers, start_mock_server},
+        storage::{memory::MemoryTokenStore, tests::store_token},
+    };
+    use actix_web::{web, App, HttpResponse};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    type TRevokedTokens = Arc<Mutex<Vec<String>>>;
+
+    // plays Google's REVOCATION_ENDPOINT_POST, and remembers what got revoked
+    async fn mock_revocation_endpoint(
+        form: web::Form<HashMap<String, String>>,
+        revoked_tokens: web::Data<TRevokedTokens>,
+    ) -> HttpResponse {
+        revoked_tokens.lock().unwrap().push(form["token"].clone());
+        HttpResponse::Ok().finish()
+    }
+
+    fn start_mock_revocation_endpoint(revoked_tokens: TRevokedTokens) -> String {
+        start_mock_server(move || {
+            App::new()
+                .app_data(web::Data::new(revoked_tokens.clone()))
+                .route("/revoke", web::post().to(mock_revocation_endpoint))
+        })
+    }
+
+    async fn get_token(token_store: &TTokenStore, session_id: u64) -> Option<TokenData> {
+        token_store
+            .get_token_by_session_id(&Some(session_id.to_string()))
+            .await
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn reaps_expired_sessions_without_refresh_token_or_past_grace() {
+        let revoked_tokens = TRevokedTokens::default();
+        let providers = make_providers(&start_mock_revocation_endpoint(revoked_tokens.clone()));
+        let token_store: TTokenStore = Arc::new(MemoryTokenStore::new());
+        let memory_messenger = Arc::new(MemoryMessenger::new());
+        let messenger: TMessenger = memory_messenger.clone();
+        let expired = store_token(&token_store, "expired", None, -10).await;
+        let refreshable = store_token(&token_store, "refreshable", Some("refresh"), -10).await;
+        // i.e. refresh_token revoked, every refresh since has been an invalid_grant
+        let unrefreshable = store_token(
+            &token_store,
+            "unrefreshable",
+            Some("revoked"),
+            -(SESSION_REFRESH_GRACE.as_secs() as i64) - 10,
+        )
+        .await;
+        let fresh = store_token(&token_store, "fresh", None, 3599).await;
+
+        let sessions_reaped = reap_expired_sessions(
+            &token_store,
+            &messenger,
+            &reqwest::Client::new(),
+            &providers,
+        )
+        .await
+        .unwrap();
+        assert_eq!(sessions_reaped, 2);
+        assert!(get_token(&token_store, expired).await.is_none());
+        assert!(get_token(&token_store, unrefreshable).await.is_none());
+        assert!(get_token(&token_store, refreshable).await.is_some());
+        assert!(get_token(&token_store, fresh).await.is_some());
+        // refresh_token if there's one, same as logout
+        let mut revoked_tokens = revoked_tokens.lock().unwrap().clone();
+        revoked_tokens.sort();
+        assert_eq!(revoked_tokens, vec!["access_expired", "revoked"]);
+
+        let session_events = memory_messenger.session_events();
+        assert_eq!(session_events.len(), 2);
+        for session_event in &session_events {
+            assert_eq!(session_event.event_type, SessionEventType::SessionExpired);
+        }
+        let mut reaped_session_ids: Vec<u64> = session_events
+            .iter()
+            .map(|session_event| session_event.session_id)
+            .collect();
+        reaped_session_ids.sort();
+        let mut expected_session_ids = vec![expired, unrefreshable];
+        expected_session_ids.sort();
+        assert_eq!(reaped_session_ids, expected_session_ids);
+        assert_eq!(
+            serde_json::to_value(&session_events[0]).unwrap()["event_type"],
+            "session_expired"
+        );
+    }
+
+    #[actix_web::test]
+    async fn reaps_even_if_revocation_fails() {
+        let token_store: TTokenStore = Arc::new(MemoryTokenStore::new());
+        let memory_messenger = Arc::new(MemoryMessenger::new());
+        let messenger: TMessenger = memory_messenger.clone();
+        let expired = store_token(&token_store, "expired", None, -10).await;
+
+        let sessions_reaped = reap_expired_sessions(
+            &token_store,
+            &messenger,
+            &reqwest::Client::new(),
+            &make_providers("http://127.0.0.1:1"), // nobody listening
+        )
+        .await
+        .unwrap();
+        assert_eq!(sessions_reaped, 1);
+        assert!(get_token(&token_store, expired).await.is_none());
+        assert_eq!(memory_messenger.session_events().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn purges_only_login_states_past_retention() {
+        let token_store: TTokenStore = Arc::new(MemoryTokenStore::new());
+        let mut forgotten = LoginState::new("forgotten", "code_verifier", "google");
+        forgotten.created_at = SystemTime::now() - LOGIN_STATE_RETENTION - Duration::from_secs(1);
+        // expired, but still kept so that a late callback is told it's expired
+        let mut late = LoginState::new("late", "code_verifier", "google");
+        late.created_at = SystemTime::now() - TIMEOUT_FOR_AUTH_CODE_CALLBACK * 2;
+        for login_state in [&forgotten, &late] {
+            token_store.insert_login_state(login_state).await.unwrap();
+        }
+
+        assert_eq!(purge_login_states(&token_store).await.unwrap(), 1);
+        assert!(token_store
+            .take_login_state("forgotten")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(token_store
+            .take_login_state("late")
+            .await
+            .unwrap()
+            .is_some());
+    }
+}
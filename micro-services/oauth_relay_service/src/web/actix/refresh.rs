@@ -0,0 +1,258 @@
+use crate::{
+    data::{OAuth2RefreshTokenRequest, OAuth2RefreshTokenResponse, TokenData},
+    providers::{OAuth2Provider, TOAuth2Providers},
+    storage::TTokenStore,
+    web::web_consts::*,
+};
+use anyhow::Result as AnyResult;
+use std::time::SystemTime;
+use tokio::time::sleep;
+
+// Access tokens from Google expire (expires_in, usually 3599 seconds), so as long as we have a
+// refresh_token (access_type=offline), we trade it for a new access_token either:
+//  - on demand, in keepalive() when the token has expired (or is about to), or
+//  - ahead of time, via the background sweeper (see spawn_refresh_sweeper()), which refreshes
+//    tokens before they get within TOKEN_REFRESH_INTERVAL_MARGIN of expiring
+// Sessions without refresh_token cannot be refreshed, and the player will have to login again
+// NOTE: Same goes for the other providers (see providers.rs), each session is refreshed by the
+// provider it logged in with (TokenData::provider)
+
+// true if token has expired or will expire within TOKEN_REFRESH_INTERVAL_MARGIN
+pub(crate) fn needs_refresh(token_data: &TokenData) -> bool {
+    token_data.expiry_time_as_sec_from_now() <= TOKEN_REFRESH_INTERVAL_MARGIN.as_secs() as i64
+}
+
+// HTTP POST to TOKEN_ENDPOINT_POST via OAuth2RefreshTokenRequest
+// Sample request:
+//      POST /token HTTP/1.1
+//      Host: oauth2.googleapis.com
+//      Content-Type: application/x-www-form-urlencoded
+//
+//      client_id=your_client_id&
+//      client_secret=your_client_secret&
+//      refresh_token=refresh_token&
+//      grant_type=refresh_token
+// Google responds with 400 {"error": "invalid_grant", ...} if the refresh_token was revoked (i.e.
+// the player removed access to the app) or has expired
+async fn request_refresh_token(
+    http_client: &reqwest::Client,
+    token_endpoint: &str,
+    refresh_token_request: &OAuth2RefreshTokenRequest,
+) -> AnyResult<OAuth2RefreshTokenResponse> {
+    let response = http_client
+        .post(token_endpoint)
+        .header("Accept", "application/json")
+        .form(refresh_token_request)
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        anyhow::bail!(
+            "Google OAuth2 refused to refresh token: {} {}",
+            status,
+            response.text().await.unwrap_or_default()
+        );
+    }
+    Ok(response.json::<OAuth2RefreshTokenResponse>().await?)
+}
+
+// Trades refresh_token for a new access_token, and persists the new access_token and expiry
+// (and refresh_token, if Google rotated it); Returns the refreshed TokenData
+pub(crate) async fn refresh_token_data(
+    token_store: &TTokenStore,
+    http_client: &reqwest::Client,
+    provider: &OAuth2Provider,
+    token_data: &TokenData,
+) -> AnyResult<TokenData> {
+    let refresh_token = match &token_data.possible_refresh_token {
+        Some(refresh_token) => refresh_token.clone(),
+        None => anyhow::bail!(
+            "Session_id {:?} has no refresh_token",
+            token_data.session_id()
+        ),
+    };
+    let refresh_token_response = request_refresh_token(
+        http_client,
+        provider.endpoints.token_endpoint.as_str(),
+        &OAuth2RefreshTokenRequest {
+            client_id: provider.client_id.clone(),
+            client_secret: provider.client_secret.clone(),
+            grant_type: "refresh_token".to_string(),
+            refresh_token,
+        },
+    )
+    .await?;
+
+    let mut refreshed = token_data.clone();
+    refreshed.access_token = refresh_token_response.access_token;
+    refreshed.expires_in = refresh_token_response.expires_in;
+    refreshed.set_expiry_time_from_now(refresh_token_response.expires_in);
+    if refresh_token_response.possible_refresh_token.is_some() {
+        refreshed.possible_refresh_token = refresh_token_response.possible_refresh_token;
+    }
+    token_store.upsert_token_data(&refreshed).await?;
+    println!(
+        "Refresh: Refreshed token for session_id: {:?} (expires in {} seconds)",
+        refreshed.session_id(),
+        refreshed.expires_in
+    );
+    Ok(refreshed)
+}
+
+// One pass of the sweeper: refresh every token (with refresh_token) that would otherwise get within
+// TOKEN_REFRESH_INTERVAL_MARGIN of expiring before the next pass; Returns number of tokens refreshed
+// NOTE: Sessions without refresh_token are left alone (they just expire)
+pub(crate) async fn refresh_expiring_tokens(
+    token_store: &TTokenStore,
+    http_client: &reqwest::Client,
+    providers: &TOAuth2Providers,
+) -> AnyResult<usize> {
+    let expiring_tokens = token_store
+        .get_tokens_expiring_before(
+            SystemTime::now() + TOKEN_REFRESH_INTERVAL_MARGIN + TOKEN_REFRESH_SWEEP_INTERVAL,
+        )
+        .await?;
+    let mut tokens_refreshed = 0;
+    for token_data in expiring_tokens
+        .iter()
+        .filter(|token_data| token_data.possible_refresh_token.is_some())
+    {
+        // one bad session (i.e. revoked refresh_token) should not stop the rest from refreshing
+        let provider = match providers.get(Some(token_data.provider.as_str())) {
+            Some(provider) => provider,
+            None => {
+                println!(
+                    "Refresh: Provider '{}' is no longer enabled for session_id: {:?}",
+                    token_data.provider,
+                    token_data.session_id()
+                );
+                continue;
+            }
+        };
+        match refresh_token_data(token_store, http_client, provider, token_data).await {
+            Ok(_) => tokens_refreshed += 1,
+            Err(e) => println!(
+                "Refresh: Failed to refresh token for session_id: {:?} with error: {:?}",
+                token_data.session_id(),
+                e
+            ),
+        }
+    }
+    Ok(tokens_refreshed)
+}
+
+// Background sweeper, runs every TOKEN_REFRESH_SWEEP_INTERVAL for as long as the relay is up
+pub fn spawn_refresh_sweeper(
+    token_store: TTokenStore,
+    providers: TOAuth2Providers,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let http_client = reqwest::Client::new();
+        loop {
+            sleep(TOKEN_REFRESH_SWEEP_INTERVAL).await;
+            match refresh_expiring_tokens(&token_store, &http_client, &providers).await {
+                Ok(0) => {}
+                Ok(tokens_refreshed) => {
+                    println!("Refresh: Refreshed {} token(s)", tokens_refreshed)
+                }
+                Err(e) => println!("Refresh: Sweep failed with error: {:?}", e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        providers::tests::{make_providers, start_mock_server},
+        storage::{memory::MemoryTokenStore, tests::store_token},
+    };
+    use actix_web::{web, App, HttpResponse};
+    use std::{collections::HashMap, sync::Arc};
+
+    // plays Google's TOKEN_ENDPOINT_POST: "revoked" refresh_token is refused, the rest get a new
+    // access_token named after the refresh_token
+    async fn mock_token_endpoint(form: web::Form<HashMap<String, String>>) -> HttpResponse {
+        assert_eq!(form["grant_type"], "refresh_token");
+        assert_eq!(form["client_id"], "test_client_id");
+        assert_eq!(form["client_secret"], "test_client_secret");
+        match form["refresh_token"].as_str() {
+            "revoked" => {
+                HttpResponse::BadRequest().json(serde_json::json!({"error": "invalid_grant"}))
+            }
+            refresh_token => HttpResponse::Ok().json(serde_json::json!({
+                "access_token": format!("refreshed_with_{}", refresh_token),
+                "expires_in": 3599,
+                "scope": "openid email",
+                "token_type": "Bearer"
+            })),
+        }
+    }
+
+    async fn get_token(token_store: &TTokenStore, session_id: u64) -> TokenData {
+        token_store
+            .get_token_by_session_id(&Some(session_id.to_string()))
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn sweeper_refreshes_only_expiring_tokens_with_refresh_token() {
+        let providers = make_providers(&start_mock_server(|| {
+            App::new().route("/token", web::post().to(mock_token_endpoint))
+        }));
+        let token_store: TTokenStore = Arc::new(MemoryTokenStore::new());
+        let expired = store_token(&token_store, "expired", Some("refresh_1"), -10).await;
+        let expiring = store_token(&token_store, "expiring", Some("refresh_2"), 20).await;
+        let fresh = store_token(&token_store, "fresh", Some("refresh_3"), 3599).await;
+        let no_refresh_token = store_token(&token_store, "no_refresh_token", None, -10).await;
+        let revoked = store_token(&token_store, "revoked", Some("revoked"), -10).await;
+
+        let tokens_refreshed =
+            refresh_expiring_tokens(&token_store, &reqwest::Client::new(), &providers)
+                .await
+                .unwrap();
+        assert_eq!(tokens_refreshed, 2);
+
+        for (session_id, refresh_token) in [(expired, "refresh_1"), (expiring, "refresh_2")] {
+            let token_data = get_token(&token_store, session_id).await;
+            assert_eq!(
+                token_data.access_token,
+                format!("refreshed_with_{}", refresh_token)
+            );
+            assert_eq!(
+                token_data.possible_refresh_token,
+                Some(refresh_token.to_string())
+            );
+            assert!(!needs_refresh(&token_data));
+        }
+        for (session_id, access_token) in [
+            (fresh, "access_fresh"),
+            (no_refresh_token, "access_no_refresh_token"),
+            (revoked, "access_revoked"),
+        ] {
+            assert_eq!(
+                get_token(&token_store, session_id).await.access_token,
+                access_token
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn refresh_without_refresh_token_fails() {
+        let token_store: TTokenStore = Arc::new(MemoryTokenStore::new());
+        let session_id = store_token(&token_store, "no_refresh_token", None, -10).await;
+        let token_data = get_token(&token_store, session_id).await;
+        assert!(needs_refresh(&token_data));
+        assert!(refresh_token_data(
+            &token_store,
+            &reqwest::Client::new(),
+            make_providers("http://127.0.0.1:1").default_provider(), // never reached
+            &token_data,
+        )
+        .await
+        .is_err());
+    }
+}
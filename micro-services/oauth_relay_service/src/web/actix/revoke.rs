@@ -0,0 +1,89 @@
+use crate::{
+    data::{OAuth2RevokeTokenRequest, SessionEvent, SessionEventType, TokenData},
+    messenger::TMessenger,
+    providers::TOAuth2Providers,
+    storage::TTokenStore,
+};
+use anyhow::Result as AnyResult;
+
+// HTTP POST to REVOCATION_ENDPOINT_POST via OAuth2RevokeTokenRequest
+// Sample request:
+//      POST /revoke HTTP/1.1
+//      Host: oauth2.googleapis.com
+//      Content-Type: application/x-www-form-urlencoded
+//
+//      token=refresh_token_or_access_token
+// Google responds with 200 on success, and 400 {"error": "invalid_token"} if the token has
+// already expired or been revoked
+pub(crate) async fn revoke_token(
+    http_client: &reqwest::Client,
+    revocation_endpoint: &str,
+    token: &str,
+) -> AnyResult<()> {
+    let response = http_client
+        .post(revocation_endpoint)
+        .form(&OAuth2RevokeTokenRequest {
+            token: token.to_string(),
+        })
+        .send()
+        .await?;
+    let status = response.status();
+    if !status.is_success() {
+        anyhow::bail!(
+            "Google OAuth2 refused to revoke token: {} {}",
+            status,
+            response.text().await.unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+// Ends the session for good:
+//  - revoke the token at Google (refresh_token if we have one, since it takes its access_tokens
+//    with it); best effort, an expired token may well be refused, which is what we want anyways
+//    (and skipped for providers without revocation endpoint, i.e. GitHub)
+//  - delete the session from storage
+//  - publish the session event so that other services can drop their state for that session_id
+pub(crate) async fn end_session(
+    token_store: &TTokenStore,
+    messenger: &TMessenger,
+    http_client: &reqwest::Client,
+    providers: &TOAuth2Providers,
+    token_data: &TokenData,
+    event_type: SessionEventType,
+) -> AnyResult<()> {
+    let session_id = match token_data.session_id() {
+        Some(session_id) => session_id,
+        None => anyhow::bail!("Cannot end a session without session_id"),
+    };
+    let token = token_data
+        .possible_refresh_token
+        .as_ref()
+        .unwrap_or(&token_data.access_token);
+    let possible_revocation_endpoint = providers
+        .get(Some(token_data.provider.as_str()))
+        .and_then(|provider| provider.endpoints.possible_revocation_endpoint.clone());
+    match possible_revocation_endpoint {
+        Some(revocation_endpoint) => {
+            if let Err(e) = revoke_token(http_client, &revocation_endpoint, token).await {
+                println!(
+                    "Revoke: Failed to revoke token for session_id: {} with error: {:?}",
+                    session_id, e
+                );
+            }
+        }
+        None => println!(
+            "Revoke: Provider '{}' cannot revoke, dropping token for session_id: {}",
+            token_data.provider, session_id
+        ),
+    }
+    token_store.delete_token_by_session_id(session_id).await?;
+    messenger
+        .post_session_event(&SessionEvent::new(event_type, token_data))
+        .await?;
+    println!(
+        "Revoke: Ended session_id: {} ({:?})",
+        session_id, event_type
+    );
+    Ok(())
+}
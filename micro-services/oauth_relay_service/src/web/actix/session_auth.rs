@@ -0,0 +1,49 @@
+use crate::{data::SessionTokenClaims, error::RelayError, session_token::SessionTokenIssuer};
+use actix_web::{http::header, HttpRequest};
+
+// Proves the caller owns the session: session_ids are sequential (see migrations), so knowing one
+// means nothing, the session token handed out by /login (or the last /keepalive) is what counts.
+// Expected as "Authorization: Bearer <session token>", signed by us, unexpired, and for that very
+// session_id; anything else is a 401, whether the session exists or not (no probing for ids)
+pub(crate) fn authenticate_session(
+    client_http_request: &HttpRequest,
+    session_token_issuer: &SessionTokenIssuer,
+    last_session_id: &str,
+) -> Result<SessionTokenClaims, RelayError> {
+    let session_token = match client_http_request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        Some(session_token) => session_token.trim(),
+        None => {
+            return Err(RelayError::Unauthorized(
+                "Missing session token, please login".to_string(),
+            ))
+        }
+    };
+    let claims = match session_token_issuer.verify(session_token) {
+        Ok(claims) => claims,
+        Err(e) => {
+            println!(
+                "SessionAuth: Rejected session token for session_id: {} with error: {:?}",
+                last_session_id, e
+            );
+            return Err(RelayError::Unauthorized(
+                "Invalid session token, please login".to_string(),
+            ));
+        }
+    };
+    if last_session_id.parse::<u64>().ok() != Some(claims.sid) {
+        println!(
+            "SessionAuth: Session token of session_id: {} presented for session_id: {}",
+            claims.sid, last_session_id
+        );
+        return Err(RelayError::Unauthorized(format!(
+            "Session token is not for session '{}', please login",
+            last_session_id
+        )));
+    }
+    Ok(claims)
+}
@@ -0,0 +1,395 @@
+use crate::{config::TlsConfig, error::RelayError};
+use actix_web::{http::header, web, HttpRequest, HttpResponse};
+use anyhow::{Context, Result as AnyResult};
+use openssl::{
+    asn1::Asn1Time,
+    bn::{BigNum, MsbOption},
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    ssl::{SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod},
+    x509::{extension::SubjectAlternativeName, X509NameBuilder, X509},
+};
+use std::sync::{Arc, RwLock};
+
+// Native HTTPS (see Config::possible_tls), so that the relay does not need a TLS terminating proxy
+// in front of it (sequence_flow.puml assumes HTTPS all the way to the NAT host):
+//  - the acceptor actix binds with is built once, but every new connection gets switched over
+//    (servername callback) to whatever SslContext is current, so that reload() (SIGHUP, see
+//    spawn_tls_reloader()) swaps certificates without dropping a single connection
+//  - a certificate that fails to load on reload is logged and ignored, the current one stays
+//  - TlsConfig::SelfSigned generates a throw-away certificate for localhost (dev only)
+//  - HTTP_REDIRECT_PORT answers plain HTTP with a redirect to HTTPS (see redirect_to_https())
+
+// what the self-signed certificate is good for, and for how long
+const SELF_SIGNED_NAMES: &[&str] = &["localhost", "127.0.0.1", "::1"];
+const SELF_SIGNED_DAYS: u32 = 30;
+
+// The certificate (and key) currently served; cloning shares it (reload() on one is seen by all)
+#[derive(Clone)]
+pub struct TlsCertificates {
+    tls_config: TlsConfig,
+    current: Arc<RwLock<SslContext>>,
+}
+
+impl TlsCertificates {
+    pub fn load(tls_config: &TlsConfig) -> AnyResult<Self> {
+        Ok(TlsCertificates {
+            tls_config: tls_config.clone(),
+            current: Arc::new(RwLock::new(make_ssl_context(tls_config)?)),
+        })
+    }
+
+    // re-reads the PEM files; nothing to re-read for a self-signed one
+    pub fn reload(&self) -> AnyResult<()> {
+        if self.tls_config == TlsConfig::SelfSigned {
+            return Ok(());
+        }
+        let ssl_context = make_ssl_context(&self.tls_config)?;
+        *self.current.write().unwrap() = ssl_context;
+        Ok(())
+    }
+
+    // DER of the certificate currently served (i.e. to tell whether reload() took)
+    pub fn current_certificate_der(&self) -> AnyResult<Vec<u8>> {
+        match self.current.read().unwrap().certificate() {
+            Some(certificate) => Ok(certificate.to_der()?),
+            None => anyhow::bail!("No TLS certificate loaded"),
+        }
+    }
+
+    // What HttpServer::bind_openssl() serves HTTPS with
+    // NOTE: mozilla_intermediate_v5 is TLS 1.2 and up, which is what browsers (and reqwest) speak
+    pub fn make_ssl_acceptor_builder(&self) -> AnyResult<SslAcceptorBuilder> {
+        let mut ssl_acceptor_builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+        {
+            // same as current, for whatever OpenSSL checks before the switch below
+            let ssl_context = self.current.read().unwrap();
+            if let (Some(certificate), Some(private_key)) =
+                (ssl_context.certificate(), ssl_context.private_key())
+            {
+                ssl_acceptor_builder.set_certificate(certificate)?;
+                ssl_acceptor_builder.set_private_key(private_key)?;
+            }
+        }
+        // called on every ClientHello (SNI or not), early enough to switch certificates
+        let current = self.current.clone();
+        ssl_acceptor_builder.set_servername_callback(move |ssl, _alert| {
+            ssl.set_ssl_context(&current.read().unwrap())
+                .map_err(|_| SniError::ALERT_FATAL)
+        });
+        Ok(ssl_acceptor_builder)
+    }
+}
+
+fn make_ssl_context(tls_config: &TlsConfig) -> AnyResult<SslContext> {
+    let mut ssl_acceptor_builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+    match tls_config {
+        TlsConfig::Files {
+            cert_path,
+            key_path,
+        } => {
+            ssl_acceptor_builder
+                .set_private_key_file(key_path, SslFiletype::PEM)
+                .with_context(|| format!("Cannot load TLS key '{}'", key_path))?;
+            ssl_acceptor_builder
+                .set_certificate_chain_file(cert_path)
+                .with_context(|| format!("Cannot load TLS certificate '{}'", cert_path))?;
+        }
+        TlsConfig::SelfSigned => {
+            let (certificate, private_key) = make_self_signed_certificate()?;
+            ssl_acceptor_builder.set_private_key(&private_key)?;
+            ssl_acceptor_builder.set_certificate(&certificate)?;
+        }
+    }
+    ssl_acceptor_builder
+        .check_private_key()
+        .context("TLS key does not match the certificate")?;
+    Ok(ssl_acceptor_builder.build().into_context())
+}
+
+// P-256 key and a certificate signed with it, for SELF_SIGNED_NAMES
+pub(crate) fn make_self_signed_certificate() -> AnyResult<(X509, PKey<Private>)> {
+    let ec_group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let private_key = PKey::from_ec_key(EcKey::generate(&ec_group)?)?;
+
+    let mut name_builder = X509NameBuilder::new()?;
+    name_builder.append_entry_by_nid(Nid::COMMONNAME, SELF_SIGNED_NAMES[0])?;
+    name_builder.append_entry_by_nid(Nid::ORGANIZATIONNAME, "oauth_relay_service (self-signed)")?;
+    let name = name_builder.build();
+
+    let mut serial_number = BigNum::new()?;
+    serial_number.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    let serial_number = serial_number.to_asn1_integer()?;
+    let (not_before, not_after) = (
+        Asn1Time::days_from_now(0)?,
+        Asn1Time::days_from_now(SELF_SIGNED_DAYS)?,
+    );
+
+    let mut subject_alternative_name = SubjectAlternativeName::new();
+    for self_signed_name in SELF_SIGNED_NAMES.iter() {
+        match self_signed_name.parse::<std::net::IpAddr>() {
+            Ok(_) => subject_alternative_name.ip(self_signed_name),
+            Err(_) => subject_alternative_name.dns(self_signed_name),
+        };
+    }
+
+    let mut x509_builder = X509::builder()?;
+    x509_builder.set_version(2)?; // X509 v3
+    x509_builder.set_serial_number(&serial_number)?;
+    x509_builder.set_subject_name(&name)?;
+    x509_builder.set_issuer_name(&name)?;
+    x509_builder.set_pubkey(&private_key)?;
+    x509_builder.set_not_before(&not_before)?;
+    x509_builder.set_not_after(&not_after)?;
+    let subject_alternative_name =
+        subject_alternative_name.build(&x509_builder.x509v3_context(None, None))?;
+    x509_builder.append_extension(subject_alternative_name)?;
+    x509_builder.sign(&private_key, MessageDigest::sha256())?;
+    Ok((x509_builder.build(), private_key))
+}
+
+// SIGHUP re-reads the certificate (i.e. after certbot renewed it), for as long as the relay is up
+#[cfg(unix)]
+pub fn spawn_tls_reloader(tls_certificates: TlsCertificates) -> tokio::task::JoinHandle<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    tokio::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                println!(
+                    "TLS: Cannot listen for SIGHUP, no certificate reload: {:?}",
+                    e
+                );
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            match tls_certificates.reload() {
+                Ok(_) => println!("TLS: Reloaded certificate"),
+                Err(e) => println!(
+                    "TLS: Failed to reload certificate, still serving the previous one: {:?}",
+                    e
+                ),
+            }
+        }
+    })
+}
+
+/// Redirect route (HTTP_REDIRECT_PORT only) - anything that comes in over plain HTTP is sent to
+/// the same host, path and query on the HTTPS port (308, so that POST stays POST)
+pub async fn redirect_to_https(
+    client_http_request: HttpRequest,
+    https_port: web::Data<u16>,
+) -> Result<HttpResponse, RelayError> {
+    let connection_info = client_http_request.connection_info().clone();
+    // Host header may carry the HTTP port, which is the one thing we need to change
+    let host = match reqwest::Url::parse(&format!("http://{}", connection_info.host())) {
+        Ok(url) => url.host_str().unwrap_or_default().to_string(),
+        Err(_) => String::new(),
+    };
+    if host.is_empty() {
+        return Err(RelayError::BadRequest(format!(
+            "Cannot redirect to HTTPS, bad Host '{}'",
+            connection_info.host()
+        )));
+    }
+    let path_and_query = client_http_request
+        .uri()
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or("/");
+    let location = match **https_port {
+        443 => format!("https://{}{}", host, path_and_query),
+        https_port => format!("https://{}:{}{}", host, https_port, path_and_query),
+    };
+    Ok(HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, location))
+        .finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test, App, HttpServer};
+    use std::{env, fs, net::TcpListener};
+
+    // self-signed certificate and key as PEM files, the way certbot would leave them
+    fn write_pem_files(name: &str) -> TlsConfig {
+        let (certificate, private_key) = make_self_signed_certificate().unwrap();
+        let path = |suffix: &str| {
+            env::temp_dir()
+                .join(format!(
+                    "oauth_relay_test_{}_{}_{}",
+                    std::process::id(),
+                    name,
+                    suffix
+                ))
+                .display()
+                .to_string()
+        };
+        let (cert_path, key_path) = (path("cert.pem"), path("key.pem"));
+        fs::write(&cert_path, certificate.to_pem().unwrap()).unwrap();
+        fs::write(&key_path, private_key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        TlsConfig::Files {
+            cert_path,
+            key_path,
+        }
+    }
+
+    fn remove_pem_files(tls_config: &TlsConfig) {
+        if let TlsConfig::Files {
+            cert_path,
+            key_path,
+        } = tls_config
+        {
+            let _ = fs::remove_file(cert_path);
+            let _ = fs::remove_file(key_path);
+        }
+    }
+
+    fn file_certificate_der(tls_config: &TlsConfig) -> Vec<u8> {
+        match tls_config {
+            TlsConfig::Files { cert_path, .. } => X509::from_pem(&fs::read(cert_path).unwrap())
+                .unwrap()
+                .to_der()
+                .unwrap(),
+            TlsConfig::SelfSigned => panic!("no file"),
+        }
+    }
+
+    // DER of the certificate the server handed us, on a fresh connection
+    async fn fetch_served_certificate_der(https_url: &str) -> Vec<u8> {
+        let http_client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true) // self-signed
+            .tls_info(true)
+            .pool_max_idle_per_host(0)
+            .build()
+            .unwrap();
+        let response = http_client.get(https_url).send().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        response
+            .extensions()
+            .get::<reqwest::tls::TlsInfo>()
+            .unwrap()
+            .peer_certificate()
+            .unwrap()
+            .to_vec()
+    }
+
+    #[actix_web::test]
+    async fn serves_https_and_reloads_certificate() {
+        let tls_config = write_pem_files("reload");
+        let tls_certificates = TlsCertificates::load(&tls_config).unwrap();
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let https_url = format!("https://{}/", listener.local_addr().unwrap());
+        let http_server =
+            HttpServer::new(|| App::new().route("/", web::get().to(HttpResponse::Ok)))
+                .workers(1)
+                .listen_openssl(
+                    listener,
+                    tls_certificates.make_ssl_acceptor_builder().unwrap(),
+                )
+                .unwrap();
+        actix_web::rt::spawn(http_server.run());
+
+        let first_certificate_der = file_certificate_der(&tls_config);
+        assert_eq!(
+            fetch_served_certificate_der(&https_url).await,
+            first_certificate_der
+        );
+
+        // i.e. certbot renewed it, then SIGHUP
+        remove_pem_files(&tls_config);
+        let renewed_tls_config = write_pem_files("reload");
+        assert_eq!(renewed_tls_config, tls_config);
+        tls_certificates.reload().unwrap();
+        let renewed_certificate_der = file_certificate_der(&tls_config);
+        assert_ne!(renewed_certificate_der, first_certificate_der);
+        assert_eq!(
+            fetch_served_certificate_der(&https_url).await,
+            renewed_certificate_der
+        );
+
+        // botched renewal, keep serving what we have
+        if let TlsConfig::Files { cert_path, .. } = &tls_config {
+            fs::write(cert_path, "not a PEM").unwrap();
+        }
+        assert!(tls_certificates.reload().is_err());
+        assert_eq!(
+            tls_certificates.current_certificate_der().unwrap(),
+            renewed_certificate_der
+        );
+        assert_eq!(
+            fetch_served_certificate_der(&https_url).await,
+            renewed_certificate_der
+        );
+        remove_pem_files(&tls_config);
+    }
+
+    #[actix_web::test]
+    async fn self_signed_certificate_is_for_localhost() {
+        let tls_certificates = TlsCertificates::load(&TlsConfig::SelfSigned).unwrap();
+        let certificate =
+            X509::from_der(&tls_certificates.current_certificate_der().unwrap()).unwrap();
+        let names: Vec<String> = certificate
+            .subject_alt_names()
+            .unwrap()
+            .iter()
+            .map(|name| match (name.dnsname(), name.ipaddress()) {
+                (Some(dns_name), _) => dns_name.to_string(),
+                (None, Some(ip_address)) => ip_address.len().to_string(), // 4 or 16 bytes
+                (None, None) => String::new(),
+            })
+            .collect();
+        assert_eq!(names, vec!["localhost", "4", "16"]);
+        // nothing to re-read
+        tls_certificates.reload().unwrap();
+    }
+
+    #[actix_web::test]
+    async fn refuses_files_which_are_not_pem() {
+        let tls_config = write_pem_files("not_pem");
+        if let TlsConfig::Files { key_path, .. } = &tls_config {
+            fs::write(key_path, "not a PEM").unwrap();
+        }
+        let e = TlsCertificates::load(&tls_config).err().unwrap();
+        assert!(e.to_string().starts_with("Cannot load TLS key"));
+        remove_pem_files(&tls_config);
+    }
+
+    #[actix_web::test]
+    async fn redirects_plain_http_to_https() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(8443u16))
+                .default_service(web::to(redirect_to_https)),
+        )
+        .await;
+        for (host, uri, expected_location) in [
+            (
+                "relay.example.com:8080",
+                "/login?provider=github",
+                "https://relay.example.com:8443/login?provider=github",
+            ),
+            (
+                "localhost",
+                "/keepalive",
+                "https://localhost:8443/keepalive",
+            ),
+            ("[::1]:8080", "/", "https://[::1]:8443/"),
+        ] {
+            let request = test::TestRequest::post()
+                .uri(uri)
+                .insert_header((header::HOST, host))
+                .to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+            assert_eq!(
+                response.headers().get(header::LOCATION).unwrap(),
+                expected_location
+            );
+        }
+    }
+}
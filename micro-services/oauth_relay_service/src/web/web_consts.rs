@@ -5,8 +5,29 @@ use std::{
 
 pub const TOKEN_REFRESH_INTERVAL: Duration = Duration::from_secs(3600);
 pub const TOKEN_REFRESH_INTERVAL_MARGIN: Duration = Duration::from_secs(30);
+// how often the background sweeper (see refresh.rs) looks for tokens about to expire
+pub const TOKEN_REFRESH_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// how often the reaper (see reaper.rs) looks for expired sessions which cannot be refreshed
+pub const SESSION_REAP_INTERVAL: Duration = Duration::from_secs(300);
+// sessions with refresh_token are refreshed before they expire, so one still expired this long after
+// the fact is one the refresh sweeper keeps failing on (i.e. invalid_grant, the player revoked access)
+// and the reaper ends it as well
+pub const SESSION_REFRESH_GRACE: Duration = Duration::from_secs(3600);
+
+// session tokens (JWT) are re-issued on every keepalive, so they only need to outlive one interval
+pub const SESSION_TOKEN_TTL: Duration =
+    Duration::from_secs(TOKEN_REFRESH_INTERVAL.as_secs() + TOKEN_REFRESH_INTERVAL_MARGIN.as_secs());
 
 pub const TIMEOUT_FOR_AUTH_CODE_CALLBACK: Duration = Duration::from_secs(60);
+// states issued by /login are only good for TIMEOUT_FOR_AUTH_CODE_CALLBACK, but are kept a while
+// longer so that late (or replayed) callbacks are told so, rather than "unknown state"; the reaper
+// purges them after that
+pub const LOGIN_STATE_RETENTION: Duration = Duration::from_secs(3600);
+
+// /readyz gives up on a dependency (DB, MQ, provider DNS) which takes longer than this to answer,
+// so that the orchestrator's own probe timeout is never what fails first
+pub const READINESS_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
 
 // NOTE: To make it less error-prone, the real way to do this is to grab the (latest)
 //      JSON document from https://accounts.google.com/.well-known/openid-configuration
@@ -15,13 +36,14 @@ pub const TIMEOUT_FOR_AUTH_CODE_CALLBACK: Duration = Duration::from_secs(60);
 pub const AUTHORIZATION_ENDPOINT_GET: &str = "https://accounts.google.com/o/oauth2/v2/auth"; // GET
 pub const TOKEN_ENDPOINT_POST: &str = "https://oauth2.googleapis.com/token"; // POST
 pub const USERINFO_ENDPOINT_GET: &str = "https://openidconnect.googleapis.com/v1/userinfo"; // GET
+pub const REVOCATION_ENDPOINT_POST: &str = "https://oauth2.googleapis.com/revoke"; // POST
 
 ////////////////////////////////////////////////////////////////////////////////////
 // Though I've documented via UML, the flow is as follows:
-// 1. User login via /login (GET) which will trigger call to Google OAuth2
-//    AuthorizationEndpoint via request_auth_code_trigger_callback()
-// 2. Service requests GET request to AUTH_URL_GET (Google OAuth2 AuthorizationEndpoint)
-//    with OAuth2AuthCodeRequest, and schedules for a callback (see auth_code_callback())
+// 1. User login via /login (GET), which hands back the URL to Google OAuth2
+//    AuthorizationEndpoint (see make_auth_url())
+// 2. Client opens that URL (AUTHORIZATION_ENDPOINT_GET with OAuth2AuthCodeRequest) on the
+//    player's browser, and the service waits for the callback (see auth_code_callback())
 // 3. Google OAuth2 AuthorizationEndpoint will negotiate with client and based on
 //    prompt (consent select_account), client will consent
 // 4. Upon consentment, we'll get a HTTP GET to callback auth_code_callback() with
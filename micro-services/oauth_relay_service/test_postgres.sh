@@ -0,0 +1,44 @@
+#!/bin/bash
+set -o nounset      # treat unset vars as errors
+set -o errexit      # exit on command failure
+set -o pipefail     # capture fail exit codes in piped commands
+
+# Runs the (#[ignore]'d) PostgreSQL storage tests against a throw-away Postgres instance:
+#   $ ./test_postgres.sh            # in a Docker container (postgres:16-alpine)
+#   $ ./test_postgres.sh --local    # as temporary local instance (needs initdb/pg_ctl in PATH, NOT as root)
+# Either way, the instance is destroyed on exit.
+# NOTE: auth is 'trust' (no password), same as what .env.sh assumes for the host-level access
+TEST_DB_PORT=${TEST_DB_PORT:-55432}
+_CONTAINER_NAME=oauth_relay_test_postgres
+_MODE=${1:-"--docker"}
+
+function cleanup() {
+    if [ "${_MODE}" == "--local" ]; then
+        pg_ctl -D "${_PGDATA}" -m immediate stop > /dev/null 2>&1 || true
+        rm -rf "${_PGDATA}"
+    else
+        docker rm -f ${_CONTAINER_NAME} > /dev/null 2>&1 || true
+    fi
+}
+trap cleanup EXIT
+
+if [ "${_MODE}" == "--local" ]; then
+    _PGDATA=$(mktemp -d)
+    initdb -D "${_PGDATA}" -U postgres --auth=trust > /dev/null
+    pg_ctl -D "${_PGDATA}" -o "-p ${TEST_DB_PORT} -k ${_PGDATA} -c listen_addresses=localhost" -w start > /dev/null
+else
+    docker run --rm -d --name ${_CONTAINER_NAME} \
+        -e POSTGRES_HOST_AUTH_METHOD=trust \
+        -p ${TEST_DB_PORT}:5432 \
+        postgres:16-alpine > /dev/null
+    until docker exec ${_CONTAINER_NAME} pg_isready -U postgres > /dev/null 2>&1; do
+        echo "Waiting for Postgres at localhost:${TEST_DB_PORT}..."
+        sleep 1
+    done
+fi
+
+export TEST_DB_HOST=localhost
+export TEST_DB_PORT
+export TEST_DB_USER=postgres
+export TEST_DB_NAME=postgres
+cargo test -- --ignored postgres